@@ -0,0 +1,2 @@
+-- Track whether we are still allowed to email a subscriber
+ALTER TABLE subscriptions ADD COLUMN status TEXT NOT NULL DEFAULT 'active';
@@ -0,0 +1,10 @@
+-- Create the email_events table
+CREATE TABLE IF NOT EXISTS email_events (
+    id uuid NOT NULL PRIMARY KEY,
+    provider TEXT NOT NULL,
+    event_type TEXT NOT NULL,
+    email TEXT NOT NULL,
+    occurred_at timestamptz NOT NULL,
+    received_at timestamptz NOT NULL
+);
+CREATE INDEX IF NOT EXISTS email_events_email_idx ON email_events (email);
@@ -0,0 +1,14 @@
+-- Create the suppressions table
+CREATE TABLE IF NOT EXISTS suppressions (
+    email TEXT NOT NULL PRIMARY KEY,
+    reason TEXT NOT NULL,
+    source TEXT NOT NULL,
+    created_at timestamptz NOT NULL
+);
+-- Carry over the recipients suppressed by bounce and complaint webhooks
+INSERT INTO suppressions (email, reason, source, created_at)
+SELECT DISTINCT ON (email) email, event_type, provider, occurred_at
+FROM email_events
+WHERE event_type IN ('hard_bounce', 'complaint')
+ORDER BY email, occurred_at DESC
+ON CONFLICT (email) DO NOTHING;
@@ -0,0 +1,24 @@
+-- Create the lists table
+CREATE TABLE IF NOT EXISTS lists (
+    id uuid NOT NULL PRIMARY KEY,
+    slug TEXT NOT NULL UNIQUE,
+    name TEXT NOT NULL,
+    created_at timestamptz NOT NULL
+);
+-- One row per (list, subscriber) pair, each with its own status
+CREATE TABLE IF NOT EXISTS list_memberships (
+    list_id uuid NOT NULL REFERENCES lists (id),
+    subscriber_id uuid NOT NULL REFERENCES subscriptions (id),
+    status TEXT NOT NULL,
+    subscribed_at timestamptz NOT NULL,
+    PRIMARY KEY (list_id, subscriber_id)
+);
+-- Everybody who subscribed before lists existed belongs to the default list
+INSERT INTO lists (id, slug, name, created_at)
+VALUES (gen_random_uuid(), 'default', 'Newsletter', now())
+ON CONFLICT (slug) DO NOTHING;
+INSERT INTO list_memberships (list_id, subscriber_id, status, subscribed_at)
+SELECT lists.id, subscriptions.id, 'active', subscriptions.subscribed_at
+FROM subscriptions, lists
+WHERE lists.slug = 'default'
+ON CONFLICT (list_id, subscriber_id) DO NOTHING;
@@ -0,0 +1,3 @@
+-- Free-form per-subscriber attributes used to target segments
+ALTER TABLE subscriptions ADD COLUMN attributes JSONB NOT NULL DEFAULT '{}';
+CREATE INDEX IF NOT EXISTS subscriptions_attributes_idx ON subscriptions USING GIN (attributes);
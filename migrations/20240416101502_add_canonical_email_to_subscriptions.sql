@@ -0,0 +1,25 @@
+-- Canonical form of subscriber emails, used to detect duplicates.
+-- `email` keeps the address as the subscriber typed it.
+ALTER TABLE subscriptions ADD COLUMN email_canonical TEXT;
+-- Existing rows get the domain lowercased. Provider-specific rules only apply
+-- to new subscribers: rows that would collide keep their address as-is.
+UPDATE subscriptions s
+SET email_canonical = split_part(s.email, '@', 1) || '@' || lower(split_part(s.email, '@', 2))
+WHERE NOT EXISTS (
+    SELECT 1 FROM subscriptions o
+    WHERE o.id <> s.id
+    AND split_part(o.email, '@', 1) || '@' || lower(split_part(o.email, '@', 2))
+        = split_part(s.email, '@', 1) || '@' || lower(split_part(s.email, '@', 2))
+);
+UPDATE subscriptions SET email_canonical = email WHERE email_canonical IS NULL;
+ALTER TABLE subscriptions ALTER COLUMN email_canonical SET NOT NULL;
+CREATE UNIQUE INDEX IF NOT EXISTS subscriptions_email_canonical_idx ON subscriptions (email_canonical);
+-- Suppressions are looked up by canonical address from now on
+UPDATE suppressions s
+SET email = split_part(s.email, '@', 1) || '@' || lower(split_part(s.email, '@', 2))
+WHERE NOT EXISTS (
+    SELECT 1 FROM suppressions o
+    WHERE o.email <> s.email
+    AND split_part(o.email, '@', 1) || '@' || lower(split_part(o.email, '@', 2))
+        = split_part(s.email, '@', 1) || '@' || lower(split_part(s.email, '@', 2))
+);
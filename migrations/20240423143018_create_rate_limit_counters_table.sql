@@ -0,0 +1,7 @@
+-- Request counters of the subscription rate limits, one row per key
+CREATE TABLE IF NOT EXISTS rate_limit_counters (
+    key TEXT NOT NULL PRIMARY KEY,
+    window_start timestamptz NOT NULL,
+    hits INTEGER NOT NULL
+);
+CREATE INDEX IF NOT EXISTS rate_limit_counters_window_start_idx ON rate_limit_counters (window_start);
@@ -0,0 +1,10 @@
+-- Subscribers confirm they own their address before we email them.
+-- Existing subscribers predate confirmation, they stay `active`.
+ALTER TABLE subscriptions ADD COLUMN confirmation_sent_at timestamptz NULL;
+CREATE TABLE IF NOT EXISTS subscription_tokens (
+    subscription_token TEXT NOT NULL PRIMARY KEY,
+    subscriber_id uuid NOT NULL REFERENCES subscriptions (id),
+    created_at timestamptz NOT NULL
+);
+CREATE INDEX IF NOT EXISTS subscription_tokens_subscriber_id_idx
+    ON subscription_tokens (subscriber_id);
@@ -0,0 +1,4 @@
+-- Preferences subscribers manage themselves
+ALTER TABLE subscriptions ADD COLUMN digest_frequency TEXT NOT NULL DEFAULT 'immediate';
+-- The address a subscriber asked to switch to, until they confirm it
+ALTER TABLE subscriptions ADD COLUMN pending_email TEXT NULL;
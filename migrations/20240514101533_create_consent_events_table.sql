@@ -0,0 +1,34 @@
+-- Proof of how and when each person agreed to hear from us
+CREATE TABLE IF NOT EXISTS consent_events (
+    id uuid NOT NULL PRIMARY KEY,
+    -- No foreign key: the history outlives email changes
+    subscriber_id uuid NOT NULL,
+    -- Canonical form, as the subscriber had it when the event happened
+    email TEXT NOT NULL,
+    event_type TEXT NOT NULL,
+    occurred_at timestamptz NOT NULL,
+    ip TEXT NULL,
+    user_agent TEXT NULL,
+    -- The form or link the event came through
+    source TEXT NOT NULL,
+    -- The version of the wording the subscriber was shown
+    text_version TEXT NULL,
+    details jsonb NOT NULL DEFAULT '{}'
+);
+CREATE INDEX IF NOT EXISTS consent_events_subscriber_id_idx ON consent_events (subscriber_id);
+CREATE INDEX IF NOT EXISTS consent_events_email_idx ON consent_events (email);
+
+-- Events are never changed, and only deleted when the subscriber asks us to
+-- erase their data: the erasure sets `newsletter.erasing` for its transaction
+CREATE OR REPLACE FUNCTION forbid_consent_event_changes() RETURNS trigger AS $$
+BEGIN
+    IF TG_OP = 'DELETE' AND current_setting('newsletter.erasing', true) = 'on' THEN
+        RETURN OLD;
+    END IF;
+    RAISE EXCEPTION 'consent_events is append-only';
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER consent_events_append_only
+BEFORE UPDATE OR DELETE ON consent_events
+FOR EACH ROW EXECUTE FUNCTION forbid_consent_event_changes();
@@ -0,0 +1,8 @@
+-- Admins besides the one of the configuration, see `create-admin`
+CREATE TABLE IF NOT EXISTS admin_users (
+    user_id uuid NOT NULL PRIMARY KEY,
+    username TEXT NOT NULL UNIQUE,
+    -- Argon2id, as a PHC string
+    password_hash TEXT NOT NULL,
+    created_at timestamptz NOT NULL
+);
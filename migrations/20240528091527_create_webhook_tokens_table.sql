@@ -0,0 +1,6 @@
+-- Tokens of the signed webhooks received recently, to reject replays
+CREATE TABLE IF NOT EXISTS webhook_tokens (
+    token TEXT NOT NULL PRIMARY KEY,
+    received_at timestamptz NOT NULL
+);
+CREATE INDEX IF NOT EXISTS webhook_tokens_received_at_idx ON webhook_tokens (received_at);
@@ -0,0 +1,8 @@
+-- Memberships were always written as active: those of subscribers who have
+-- not confirmed yet are pending, like the subscribers themselves
+UPDATE list_memberships
+SET status = 'pending_confirmation'
+FROM subscriptions
+WHERE subscriptions.id = list_memberships.subscriber_id
+    AND subscriptions.status = 'pending_confirmation'
+    AND list_memberships.status = 'active';
@@ -0,0 +1,15 @@
+-- Published issues, and the emails left to send for each of them
+CREATE TABLE newsletter_issues (
+    newsletter_issue_id uuid NOT NULL PRIMARY KEY,
+    title TEXT NOT NULL,
+    text_content TEXT NOT NULL,
+    html_content TEXT NOT NULL,
+    published_at timestamptz NOT NULL
+);
+CREATE TABLE issue_delivery_queue (
+    newsletter_issue_id uuid NOT NULL REFERENCES newsletter_issues (newsletter_issue_id),
+    subscriber_id uuid NOT NULL REFERENCES subscriptions (id),
+    n_retries SMALLINT NOT NULL DEFAULT 0,
+    execute_after timestamptz NOT NULL DEFAULT now(),
+    PRIMARY KEY (newsletter_issue_id, subscriber_id)
+);
@@ -0,0 +1,6 @@
+-- Duplicates are detected on the canonical form only. A second unique
+-- constraint, on the address as typed, made inserts that expect conflicts on
+-- the canonical form fail instead.
+-- The canonical forms stored before provider rules applied are fixed by the
+-- `migrate` command, after the migrations.
+ALTER TABLE subscriptions DROP CONSTRAINT IF EXISTS subscriptions_email_key;
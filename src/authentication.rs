@@ -0,0 +1,270 @@
+use actix_web::http::header::{self, HeaderMap};
+use argon2::password_hash::SaltString;
+use argon2::{Algorithm, Argon2, Params, PasswordHash, PasswordHasher, PasswordVerifier, Version};
+use base64::Engine;
+use chrono::Utc;
+use secrecy::{ExposeSecret, Secret};
+use sha2::{Digest, Sha256};
+use sqlx::PgPool;
+use subtle::ConstantTimeEq;
+use uuid::Uuid;
+
+/// Shortest password we accept for the admins we create.
+pub const MIN_PASSWORD_LENGTH: usize = 12;
+
+/// Checked against when the username is unknown, so that unknown usernames
+/// take as long to reject as wrong passwords.
+const DUMMY_PASSWORD_HASH: &str = "$argon2id$v=19$m=15000,t=2,p=1$\
+    gZiV/M1gPc22ElAH/Jh1Hw$CWOrkoo7oJBQ/iyh7uJ0LO2aLEfrHwTWllSAxT0zRno";
+
+pub struct Credentials {
+    pub username: String,
+    pub password: Secret<String>,
+}
+
+impl Credentials {
+    /// Whether these are `username` and `password`, taking as long whatever
+    /// they differ by.
+    pub fn matches(&self, username: &str, password: &Secret<String>) -> bool {
+        let username_matches = constant_time_eq(&self.username, username);
+        let password_matches =
+            constant_time_eq(self.password.expose_secret(), password.expose_secret());
+        bool::from(username_matches & password_matches)
+    }
+}
+
+/// Compares digests, so that the time taken does not give the length away
+/// either.
+fn constant_time_eq(a: &str, b: &str) -> subtle::Choice {
+    Sha256::digest(a).ct_eq(&Sha256::digest(b))
+}
+
+/// Extract the credentials of an `Authorization: Basic ...` header.
+///
+/// Returns `None` if the header is missing or malformed.
+pub fn basic_authentication(headers: &HeaderMap) -> Option<Credentials> {
+    let encoded = headers
+        .get(header::AUTHORIZATION)?
+        .to_str()
+        .ok()?
+        .strip_prefix("Basic ")?;
+    let decoded = base64::engine::general_purpose::STANDARD
+        .decode(encoded)
+        .ok()?;
+    let decoded = String::from_utf8(decoded).ok()?;
+    let (username, password) = decoded.split_once(':')?;
+    Some(Credentials {
+        username: username.to_owned(),
+        password: Secret::new(password.to_owned()),
+    })
+}
+
+#[derive(Debug)]
+pub enum CreateAdminError {
+    /// The username or the password cannot be used, see the message.
+    Invalid(String),
+    /// There is an admin with this username already.
+    Exists(String),
+    Hash(argon2::password_hash::Error),
+    Database(sqlx::Error),
+}
+
+impl std::fmt::Display for CreateAdminError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Invalid(e) => write!(f, "{}", e),
+            Self::Exists(username) => write!(f, "There is an admin named {} already.", username),
+            Self::Hash(e) => write!(f, "Failed to hash the password: {}", e),
+            Self::Database(_) => write!(f, "Failed to store the admin."),
+        }
+    }
+}
+
+impl std::error::Error for CreateAdminError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Database(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<sqlx::Error> for CreateAdminError {
+    fn from(e: sqlx::Error) -> Self {
+        Self::Database(e)
+    }
+}
+
+fn argon2() -> Argon2<'static> {
+    Argon2::new(
+        Algorithm::Argon2id,
+        Version::V0x13,
+        Params::new(15000, 2, 1, None).expect("Invalid Argon2 parameters."),
+    )
+}
+
+/// Hash `password` for storage, with Argon2id and a random salt.
+pub fn compute_password_hash(
+    password: &Secret<String>,
+) -> Result<Secret<String>, argon2::password_hash::Error> {
+    let salt = SaltString::generate(&mut rand::thread_rng());
+    let hash = argon2().hash_password(password.expose_secret().as_bytes(), &salt)?;
+    Ok(Secret::new(hash.to_string()))
+}
+
+fn verify_password_hash(expected_hash: &Secret<String>, password: &Secret<String>) -> bool {
+    PasswordHash::new(expected_hash.expose_secret()).is_ok_and(|expected_hash| {
+        argon2()
+            .verify_password(password.expose_secret().as_bytes(), &expected_hash)
+            .is_ok()
+    })
+}
+
+/// Store an admin who can use the admin API, besides the one of the
+/// settings, and return their id.
+#[tracing::instrument(name = "Creating an admin", skip(password, db_pool))]
+pub async fn create_admin_user(
+    username: &str,
+    password: Secret<String>,
+    db_pool: &PgPool,
+) -> Result<Uuid, CreateAdminError> {
+    if username.trim().is_empty() || username.contains(':') {
+        return Err(CreateAdminError::Invalid(
+            "The username must not be empty nor contain `:`.".into(),
+        ));
+    }
+    if password.expose_secret().chars().count() < MIN_PASSWORD_LENGTH {
+        return Err(CreateAdminError::Invalid(format!(
+            "The password must be at least {} characters long.",
+            MIN_PASSWORD_LENGTH
+        )));
+    }
+    let password_hash = tokio::task::spawn_blocking(move || compute_password_hash(&password))
+        .await
+        .expect("Failed to hash the password.")
+        .map_err(CreateAdminError::Hash)?;
+    let row = sqlx::query!(
+        r#"
+    INSERT INTO admin_users (user_id, username, password_hash, created_at)
+    VALUES ($1, $2, $3, $4)
+    ON CONFLICT (username) DO NOTHING
+    RETURNING user_id
+"#,
+        Uuid::new_v4(),
+        username,
+        password_hash.expose_secret(),
+        Utc::now()
+    )
+    .fetch_optional(db_pool)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    row.map(|r| r.user_id)
+        .ok_or_else(|| CreateAdminError::Exists(username.to_string()))
+}
+
+/// Whether `credentials` are those of an admin stored by
+/// `create_admin_user`.
+#[tracing::instrument(
+    name = "Validating stored admin credentials",
+    skip(credentials, db_pool),
+    fields(username = %credentials.username)
+)]
+pub async fn validate_stored_credentials(
+    credentials: Credentials,
+    db_pool: &PgPool,
+) -> Result<bool, sqlx::Error> {
+    let row = sqlx::query!(
+        r#"SELECT password_hash FROM admin_users WHERE username = $1"#,
+        credentials.username
+    )
+    .fetch_optional(db_pool)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    let known = row.is_some();
+    let expected_hash =
+        Secret::new(row.map_or_else(|| DUMMY_PASSWORD_HASH.to_string(), |r| r.password_hash));
+    // Hashing takes long enough to stall the other requests of the thread
+    let matches = tokio::task::spawn_blocking(move || {
+        verify_password_hash(&expected_hash, &credentials.password)
+    })
+    .await
+    .unwrap_or(false);
+    Ok(known && matches)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        basic_authentication, compute_password_hash, verify_password_hash, Credentials,
+        DUMMY_PASSWORD_HASH,
+    };
+    use actix_web::http::header::{self, HeaderMap, HeaderValue};
+    use secrecy::{ExposeSecret, Secret};
+
+    #[test]
+    fn valid_basic_auth_header_is_decoded() {
+        let mut headers = HeaderMap::new();
+        // base64("admin:pass:word")
+        headers.insert(
+            header::AUTHORIZATION,
+            HeaderValue::from_static("Basic YWRtaW46cGFzczp3b3Jk"),
+        );
+        let credentials = basic_authentication(&headers).unwrap();
+        assert_eq!("admin", credentials.username);
+        assert_eq!("pass:word", credentials.password.expose_secret());
+    }
+
+    #[test]
+    fn missing_or_malformed_header_is_rejected() {
+        let mut headers = HeaderMap::new();
+        assert!(basic_authentication(&headers).is_none());
+        headers.insert(
+            header::AUTHORIZATION,
+            HeaderValue::from_static("Bearer YWRtaW46cGFzcw=="),
+        );
+        assert!(basic_authentication(&headers).is_none());
+        headers.insert(
+            header::AUTHORIZATION,
+            HeaderValue::from_static("Basic not-base64!"),
+        );
+        assert!(basic_authentication(&headers).is_none());
+    }
+
+    #[test]
+    fn password_hashes_only_match_their_password() {
+        let password = Secret::new("correct horse battery staple".to_string());
+
+        let hash = compute_password_hash(&password).unwrap();
+
+        assert!(hash.expose_secret().starts_with("$argon2id$"));
+        assert!(verify_password_hash(&hash, &password));
+        assert!(!verify_password_hash(
+            &hash,
+            &Secret::new("wrong horse battery staple".to_string())
+        ));
+        // The dummy hash is well formed, checking against it takes as long
+        assert!(!verify_password_hash(
+            &Secret::new(DUMMY_PASSWORD_HASH.to_string()),
+            &password
+        ));
+    }
+
+    #[test]
+    fn credentials_only_match_their_username_and_password() {
+        let credentials = Credentials {
+            username: "admin".into(),
+            password: Secret::new("password".into()),
+        };
+
+        assert!(credentials.matches("admin", &Secret::new("password".into())));
+        assert!(!credentials.matches("admin", &Secret::new("passwort".into())));
+        assert!(!credentials.matches("admin", &Secret::new("password ".into())));
+        assert!(!credentials.matches("admi", &Secret::new("password".into())));
+    }
+}
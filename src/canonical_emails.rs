@@ -0,0 +1,143 @@
+use std::collections::HashMap;
+
+use sqlx::PgPool;
+
+use crate::domain::SubscriberEmail;
+
+/// What `canonicalize_stored_emails` did.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct CanonicalizationReport {
+    /// Subscribers whose canonical form was brought up to date.
+    pub subscribers: usize,
+    /// Suppressions stored again under the canonical form.
+    pub suppressions: usize,
+    /// Addresses of subscribers left as they were, because another
+    /// subscriber has their canonical form: they must be merged by hand.
+    pub duplicates: Vec<String>,
+}
+
+/// Store the canonical form `SubscriberEmail` gives each address, for the
+/// subscribers and suppressions saved before it applied provider rules: SQL
+/// migrations cannot apply them.
+///
+/// The oldest subscriber of a mailbox gets its canonical form, unless
+/// another one has it already. Of several suppressions of a mailbox, only
+/// the newest one is kept, as it is the one lookups find. Tombstones and
+/// addresses that do not parse anymore are left alone.
+///
+/// Running it again does nothing new: `migrate` runs it every time, after
+/// the migrations.
+#[tracing::instrument(name = "Canonicalizing stored emails", skip(db_pool))]
+pub async fn canonicalize_stored_emails(
+    db_pool: &PgPool,
+) -> Result<CanonicalizationReport, sqlx::Error> {
+    let mut report = CanonicalizationReport::default();
+    let mut transaction = db_pool.begin().await?;
+
+    let subscribers = sqlx::query!(
+        r#"
+    SELECT id, email, email_canonical FROM subscriptions
+    ORDER BY subscribed_at
+    FOR UPDATE
+"#
+    )
+    .fetch_all(&mut *transaction)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    let mut mailboxes: HashMap<String, Vec<_>> = HashMap::new();
+    for subscriber in subscribers {
+        if let Ok(email) = SubscriberEmail::parse(subscriber.email.clone()) {
+            mailboxes
+                .entry(email.canonical().to_string())
+                .or_default()
+                .push(subscriber);
+        }
+    }
+    for (canonical, subscribers) in mailboxes {
+        let holder = subscribers
+            .iter()
+            .find(|subscriber| subscriber.email_canonical == canonical)
+            .unwrap_or(&subscribers[0]);
+        for subscriber in &subscribers {
+            if subscriber.email_canonical == canonical {
+                continue;
+            }
+            if subscriber.id != holder.id {
+                tracing::warn!(
+                    email = %subscriber.email,
+                    "Leaving a duplicate subscriber as it was"
+                );
+                report.duplicates.push(subscriber.email.clone());
+                continue;
+            }
+            sqlx::query!(
+                r#"UPDATE subscriptions SET email_canonical = $1 WHERE id = $2"#,
+                canonical,
+                subscriber.id
+            )
+            .execute(&mut *transaction)
+            .await
+            .map_err(|e| {
+                tracing::error!("Failed to execute query: {:?}", e);
+                e
+            })?;
+            report.subscribers += 1;
+        }
+    }
+
+    let suppressions = sqlx::query!(
+        r#"
+    SELECT email FROM suppressions
+    ORDER BY created_at DESC
+    FOR UPDATE
+"#
+    )
+    .fetch_all(&mut *transaction)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    let mut mailboxes: HashMap<String, Vec<String>> = HashMap::new();
+    for suppression in suppressions {
+        if let Ok(email) = SubscriberEmail::parse(suppression.email.clone()) {
+            mailboxes
+                .entry(email.canonical().to_string())
+                .or_default()
+                .push(suppression.email);
+        }
+    }
+    for (canonical, emails) in mailboxes {
+        if emails == [canonical.as_str()] {
+            continue;
+        }
+        // Newest first
+        let (newest, older) = emails.split_first().unwrap();
+        sqlx::query!(r#"DELETE FROM suppressions WHERE email = ANY($1)"#, older)
+            .execute(&mut *transaction)
+            .await
+            .map_err(|e| {
+                tracing::error!("Failed to execute query: {:?}", e);
+                e
+            })?;
+        sqlx::query!(
+            r#"UPDATE suppressions SET email = $1 WHERE email = $2"#,
+            canonical,
+            newest
+        )
+        .execute(&mut *transaction)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to execute query: {:?}", e);
+            e
+        })?;
+        report.suppressions += 1;
+    }
+
+    transaction.commit().await?;
+    report.duplicates.sort();
+    Ok(report)
+}
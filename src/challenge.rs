@@ -0,0 +1,151 @@
+use reqwest::Client;
+use secrecy::{ExposeSecret, Secret};
+
+/// Verifies the token a challenge widget (hCaptcha, Turnstile...) hands to
+/// the signup form once the visitor solved it.
+#[async_trait::async_trait]
+pub trait ChallengeVerifier: Send + Sync {
+    /// Whether `response` proves a challenge was solved, optionally from
+    /// `remote_ip`.
+    async fn verify(&self, response: &str, remote_ip: Option<&str>)
+        -> Result<bool, reqwest::Error>;
+}
+
+/// Verifies challenge responses through a `siteverify` endpoint, the API
+/// hCaptcha, Turnstile and reCAPTCHA share.
+pub struct SiteVerifyChallengeVerifier {
+    http_client: Client,
+    verify_url: String,
+    secret_key: Secret<String>,
+}
+
+impl SiteVerifyChallengeVerifier {
+    pub fn new(
+        verify_url: String,
+        secret_key: Secret<String>,
+        timeout: std::time::Duration,
+    ) -> Self {
+        let http_client = Client::builder().timeout(timeout).build().unwrap();
+        Self {
+            http_client,
+            verify_url,
+            secret_key,
+        }
+    }
+}
+
+#[derive(serde::Serialize)]
+struct VerifyRequest<'a> {
+    secret: &'a str,
+    response: &'a str,
+    #[serde(rename = "remoteip", skip_serializing_if = "Option::is_none")]
+    remote_ip: Option<&'a str>,
+}
+
+#[derive(serde::Deserialize)]
+struct VerifyResponse {
+    success: bool,
+}
+
+#[async_trait::async_trait]
+impl ChallengeVerifier for SiteVerifyChallengeVerifier {
+    #[tracing::instrument(name = "Verifying challenge response", skip(self, response))]
+    async fn verify(
+        &self,
+        response: &str,
+        remote_ip: Option<&str>,
+    ) -> Result<bool, reqwest::Error> {
+        let request_body = VerifyRequest {
+            secret: self.secret_key.expose_secret(),
+            response,
+            remote_ip,
+        };
+        let verification: VerifyResponse = self
+            .http_client
+            .post(&self.verify_url)
+            .form(&request_body)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        Ok(verification.success)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use claims::{assert_err, assert_ok_eq};
+    use secrecy::Secret;
+    use wiremock::matchers::{body_string_contains, method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    use super::{ChallengeVerifier, SiteVerifyChallengeVerifier};
+
+    fn verifier(base_url: String) -> SiteVerifyChallengeVerifier {
+        SiteVerifyChallengeVerifier::new(
+            format!("{}/siteverify", base_url),
+            Secret::new("challenge-secret".to_string()),
+            std::time::Duration::from_millis(200),
+        )
+    }
+
+    #[tokio::test]
+    async fn verify_posts_the_secret_response_and_ip() {
+        // Arrange
+        let mock_server = MockServer::start().await;
+        Mock::given(path("/siteverify"))
+            .and(method("POST"))
+            .and(body_string_contains("secret=challenge-secret"))
+            .and(body_string_contains("response=token"))
+            .and(body_string_contains("remoteip=127.0.0.1"))
+            .respond_with(
+                ResponseTemplate::new(200).set_body_json(serde_json::json!({ "success": true })),
+            )
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        // Act
+        let outcome = verifier(mock_server.uri())
+            .verify("token", Some("127.0.0.1"))
+            .await;
+
+        // Assert
+        assert_ok_eq!(outcome, true);
+    }
+
+    #[tokio::test]
+    async fn unsolved_challenges_are_reported() {
+        // Arrange
+        let mock_server = MockServer::start().await;
+        Mock::given(path("/siteverify"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(
+                serde_json::json!({ "success": false, "error-codes": ["invalid-input-response"] }),
+            ))
+            .mount(&mock_server)
+            .await;
+
+        // Act
+        let outcome = verifier(mock_server.uri()).verify("token", None).await;
+
+        // Assert
+        assert_ok_eq!(outcome, false);
+    }
+
+    #[tokio::test]
+    async fn verify_fails_if_the_server_returns_500() {
+        // Arrange
+        let mock_server = MockServer::start().await;
+        Mock::given(path("/siteverify"))
+            .respond_with(ResponseTemplate::new(500))
+            .mount(&mock_server)
+            .await;
+
+        // Act
+        let outcome = verifier(mock_server.uri()).verify("token", None).await;
+
+        // Assert
+        assert_err!(outcome);
+    }
+}
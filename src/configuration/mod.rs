@@ -0,0 +1,591 @@
+use secrecy::{ExposeSecret, Secret};
+use serde_aux::field_attributes::{
+    deserialize_number_from_string, deserialize_option_number_from_string,
+};
+use sqlx::postgres::PgConnectOptions;
+use sqlx::postgres::PgPoolOptions;
+use sqlx::postgres::PgSslMode;
+use sqlx::ConnectOptions;
+use std::path::{Path, PathBuf};
+
+use crate::domain::SubscriberEmail;
+
+mod show;
+mod sources;
+mod validation;
+
+pub use show::*;
+pub use sources::*;
+pub use validation::*;
+
+/// The settings holding secrets. Each of them can be read from a file
+/// instead, set by the same setting with `_file` appended, e.g.
+/// `APP_DATABASE__PASSWORD_FILE=/run/secrets/database-password`.
+pub const SECRETS: [&str; 8] = [
+    "database.password",
+    "email_client.authorization_token",
+    "email_webhooks.basic_auth_password",
+    "email_webhooks.signing_key",
+    "admin.password",
+    "challenge.secret_key",
+    "magic_link.signing_key",
+    "suppression_list.tombstone_key",
+];
+
+#[derive(serde::Deserialize, serde::Serialize)]
+pub struct Settings {
+    pub database: DatabaseSettings,
+    pub application: ApplicationSettings,
+    pub email_client: EmailClientSettings,
+    pub email_webhooks: EmailWebhookSettings,
+    pub admin: AdminSettings,
+    pub email_domain_check: EmailDomainCheckSettings,
+    pub domain_policy: DomainPolicySettings,
+    pub subscriber_name: SubscriberNameSettings,
+    pub rate_limit: RateLimitSettings,
+    pub bot_detection: BotDetectionSettings,
+    pub challenge: ChallengeSettings,
+    pub subscription_confirmation: SubscriptionConfirmationSettings,
+    pub magic_link: MagicLinkSettings,
+    pub suppression_list: SuppressionListSettings,
+    pub log: LogSettings,
+    /// The environment the settings were read for.
+    #[serde(skip)]
+    pub environment: Environment,
+    #[serde(skip)]
+    pub sources: SettingSources,
+}
+
+#[derive(serde::Deserialize, serde::Serialize)]
+pub struct ApplicationSettings {
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub port: u16,
+    pub host: String,
+    /// Where the links we put in emails point to.
+    pub base_url: String,
+    /// How often the configuration files are checked for changes, to apply
+    /// the settings that do not need a restart.
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub reload_interval_seconds: u64,
+}
+
+impl ApplicationSettings {
+    pub fn reload_interval(&self) -> std::time::Duration {
+        std::time::Duration::from_secs(self.reload_interval_seconds)
+    }
+}
+
+#[derive(serde::Deserialize, serde::Serialize)]
+pub struct DatabaseSettings {
+    pub username: String,
+    #[serde(serialize_with = "show::mask_secret")]
+    pub password: Secret<String>,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub port: u16,
+    pub host: String,
+    pub database_name: String,
+    pub ssl_mode: DatabaseSslMode,
+    /// The certificate authorities to check the certificate of the server
+    /// against, instead of the system ones.
+    pub ssl_root_cert: Option<PathBuf>,
+    /// The certificate and key to authenticate with, for servers asking
+    /// clients for one.
+    pub ssl_client_cert: Option<PathBuf>,
+    pub ssl_client_key: Option<PathBuf>,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub max_connections: u32,
+    /// Connections kept open even when idle.
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub min_connections: u32,
+    /// How long to wait for a connection when all of them are in use.
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub acquire_timeout_seconds: u64,
+    /// How long an idle connection is kept open, if not forever.
+    #[serde(default, deserialize_with = "deserialize_option_number_from_string")]
+    pub idle_timeout_seconds: Option<u64>,
+    /// How long a statement can run before the database cancels it, if not
+    /// forever.
+    #[serde(default, deserialize_with = "deserialize_option_number_from_string")]
+    pub statement_timeout_milliseconds: Option<u64>,
+    /// How connections are labelled in `pg_stat_activity`.
+    pub application_name: String,
+}
+
+/// How much to rely on TLS to reach the database, as `sslmode` in libpq.
+#[derive(serde::Deserialize, serde::Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum DatabaseSslMode {
+    /// Never use TLS.
+    Disable,
+    /// Use TLS only if the server requires it.
+    Allow,
+    /// Use TLS if the server supports it.
+    Prefer,
+    /// Always use TLS, without checking the certificate of the server.
+    Require,
+    /// Always use TLS, with a certificate signed by a trusted authority.
+    VerifyCa,
+    /// As `VerifyCa`, and the certificate must be for the host we connect
+    /// to.
+    VerifyFull,
+}
+
+impl DatabaseSslMode {
+    pub fn as_str(&self) -> &str {
+        match self {
+            Self::Disable => "disable",
+            Self::Allow => "allow",
+            Self::Prefer => "prefer",
+            Self::Require => "require",
+            Self::VerifyCa => "verify-ca",
+            Self::VerifyFull => "verify-full",
+        }
+    }
+}
+
+impl TryFrom<String> for DatabaseSslMode {
+    type Error = String;
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        match s.as_str() {
+            "disable" => Ok(Self::Disable),
+            "allow" => Ok(Self::Allow),
+            "prefer" => Ok(Self::Prefer),
+            "require" => Ok(Self::Require),
+            "verify-ca" => Ok(Self::VerifyCa),
+            "verify-full" => Ok(Self::VerifyFull),
+            other => Err(format!(
+                "{} is not a valid SSL mode. Use either `disable`, `allow`, `prefer`, \
+                `require`, `verify-ca` or `verify-full`.",
+                other
+            )),
+        }
+    }
+}
+
+impl From<DatabaseSslMode> for PgSslMode {
+    fn from(mode: DatabaseSslMode) -> Self {
+        match mode {
+            DatabaseSslMode::Disable => Self::Disable,
+            DatabaseSslMode::Allow => Self::Allow,
+            DatabaseSslMode::Prefer => Self::Prefer,
+            DatabaseSslMode::Require => Self::Require,
+            DatabaseSslMode::VerifyCa => Self::VerifyCa,
+            DatabaseSslMode::VerifyFull => Self::VerifyFull,
+        }
+    }
+}
+
+#[derive(serde::Deserialize, serde::Serialize)]
+pub struct EmailClientSettings {
+    pub base_url: String,
+    pub sender_email: String,
+    #[serde(serialize_with = "show::mask_secret")]
+    pub authorization_token: Secret<String>,
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Clone)]
+pub struct EmailWebhookSettings {
+    // Postmark authenticates its webhooks with basic auth credentials
+    // embedded in the webhook URL
+    pub basic_auth_username: String,
+    #[serde(serialize_with = "show::mask_secret")]
+    pub basic_auth_password: Secret<String>,
+    // Mailgun signs every payload with HMAC-SHA256 using this key
+    #[serde(serialize_with = "show::mask_secret")]
+    pub signing_key: Secret<String>,
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Clone)]
+pub struct AdminSettings {
+    pub username: String,
+    #[serde(serialize_with = "show::mask_secret")]
+    pub password: Secret<String>,
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Clone)]
+pub struct EmailDomainCheckSettings {
+    pub mode: EmailDomainCheckMode,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub timeout_milliseconds: u64,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub cache_ttl_seconds: u64,
+}
+
+/// What to do with subscribers whose email domain cannot receive emails.
+#[derive(serde::Deserialize, serde::Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum EmailDomainCheckMode {
+    /// Do not look the domain up.
+    Off,
+    /// Log a warning but accept the subscriber.
+    Advisory,
+    /// Reject the subscriber.
+    Enforcing,
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Clone)]
+pub struct RateLimitSettings {
+    pub store: RateLimitStoreKind,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub window_seconds: u64,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub max_requests_per_ip: u32,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub max_requests_per_email: u32,
+    /// Requests to the admin API checked against a password hash, per
+    /// client IP.
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub max_admin_requests_per_ip: u32,
+    /// How many proxies in front of the application append the address they
+    /// got the request from to `X-Forwarded-For`. The header is ignored if
+    /// `0`: clients can send whatever they want in it.
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub trusted_proxies: usize,
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum RateLimitStoreKind {
+    /// Counters are kept by each instance of the application.
+    Memory,
+    /// Counters are shared by every instance through the database.
+    Postgres,
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Clone)]
+pub struct BotDetectionSettings {
+    /// Forms submitted faster than this after being shown come from bots.
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub min_fill_time_seconds: i64,
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Clone)]
+pub struct ChallengeSettings {
+    pub enabled: bool,
+    /// The `siteverify` endpoint of the challenge provider.
+    pub verify_url: String,
+    #[serde(serialize_with = "show::mask_secret")]
+    pub secret_key: Secret<String>,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub timeout_milliseconds: u64,
+}
+
+impl ChallengeSettings {
+    pub fn timeout(&self) -> std::time::Duration {
+        std::time::Duration::from_millis(self.timeout_milliseconds)
+    }
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Clone)]
+pub struct SubscriptionConfirmationSettings {
+    /// How long pending subscribers wait before we send them another
+    /// confirmation email when they subscribe again.
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub resend_cooldown_seconds: u64,
+}
+
+impl SubscriptionConfirmationSettings {
+    pub fn resend_cooldown(&self) -> std::time::Duration {
+        std::time::Duration::from_secs(self.resend_cooldown_seconds)
+    }
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Clone)]
+pub struct MagicLinkSettings {
+    /// Signs the tokens of the links we email subscribers.
+    #[serde(serialize_with = "show::mask_secret")]
+    pub signing_key: Secret<String>,
+    /// How long a link can be used once sent.
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub ttl_seconds: u64,
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Clone)]
+pub struct SuppressionListSettings {
+    /// Keys the tombstones of erased addresses. Changing it forgets which
+    /// addresses they stand for.
+    #[serde(serialize_with = "show::mask_secret")]
+    pub tombstone_key: Secret<String>,
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Clone)]
+pub struct LogSettings {
+    /// Which events are logged, e.g. `info,sqlx=warn`. `RUST_LOG` wins
+    /// over it when the application starts.
+    pub filter: String,
+    /// Only read when the application starts.
+    pub format: LogFormat,
+}
+
+/// How events are written.
+#[derive(serde::Deserialize, serde::Serialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// One Bunyan JSON object per line, for log collectors.
+    #[default]
+    Bunyan,
+    /// One line of text per event.
+    Compact,
+    /// Several lines of text per event, with the spans it happened in.
+    Pretty,
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Clone)]
+pub struct SubscriberNameSettings {
+    /// In graphemes, i.e. user-perceived characters.
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub max_length: usize,
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Clone)]
+pub struct DomainPolicySettings {
+    // Relative paths are resolved from the directory of the file setting
+    // them, see `Settings::read`
+    pub allow_list_path: Option<std::path::PathBuf>,
+    pub deny_list_path: Option<std::path::PathBuf>,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub reload_interval_seconds: u64,
+}
+
+impl DomainPolicySettings {
+    pub fn reload_interval(&self) -> std::time::Duration {
+        std::time::Duration::from_secs(self.reload_interval_seconds)
+    }
+}
+
+impl EmailDomainCheckSettings {
+    pub fn timeout(&self) -> std::time::Duration {
+        std::time::Duration::from_millis(self.timeout_milliseconds)
+    }
+
+    pub fn cache_ttl(&self) -> std::time::Duration {
+        std::time::Duration::from_secs(self.cache_ttl_seconds)
+    }
+}
+
+impl EmailClientSettings {
+    pub fn sender(&self) -> Result<SubscriberEmail, String> {
+        SubscriberEmail::parse(self.sender_email.clone())
+    }
+}
+
+/// Why the settings could not be read.
+#[derive(Debug)]
+pub enum ConfigurationError {
+    /// `APP_ENVIRONMENT` names an environment we do not know.
+    InvalidEnvironment(String),
+    /// The configuration files are missing, or ambiguous.
+    InvalidFiles(String),
+    /// A source could not be read, or a setting is missing or of the wrong
+    /// type.
+    Load(config::ConfigError),
+    /// Every setting that was read but cannot be used.
+    Invalid(Vec<InvalidSetting>),
+}
+
+impl std::fmt::Display for ConfigurationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::InvalidEnvironment(e) => write!(f, "Invalid APP_ENVIRONMENT. {}", e),
+            Self::InvalidFiles(e) => write!(f, "Invalid configuration files. {}", e),
+            Self::Load(e) => write!(f, "Failed to read the configuration: {}", e),
+            Self::Invalid(invalid) => {
+                write!(f, "Invalid configuration:")?;
+                for setting in invalid {
+                    write!(f, "\n  - {}", setting)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl std::error::Error for ConfigurationError {}
+
+impl From<config::ConfigError> for ConfigurationError {
+    fn from(e: config::ConfigError) -> Self {
+        Self::Load(e)
+    }
+}
+
+/// Where the configuration files are: `flag`, i.e. `--config`, if set, then
+/// `APP_CONFIG_DIR`, then `configuration` in the current directory.
+pub fn configuration_directory(flag: Option<PathBuf>) -> PathBuf {
+    flag.or_else(|| std::env::var_os("APP_CONFIG_DIR").map(PathBuf::from))
+        .unwrap_or_else(|| PathBuf::from("configuration"))
+}
+
+/// Read and validate the settings, from `configuration_directory(None)`.
+pub fn get_configuration() -> Result<Settings, ConfigurationError> {
+    get_configuration_in(&configuration_directory(None))
+}
+
+/// Read and validate the settings in `directory`.
+///
+/// They come from `base`, overridden by the file of the environment, e.g.
+/// `production`, then by its optional local overrides, e.g.
+/// `production.local`, then by `DATABASE_URL`, and finally by `APP_`-prefixed
+/// environment variables: `APP_APPLICATION__PORT=5001` sets
+/// `Settings.application.port`. Files can be YAML, TOML or JSON.
+///
+/// Secrets can be read from files, see `SECRETS`.
+pub fn get_configuration_in(directory: &Path) -> Result<Settings, ConfigurationError> {
+    let settings = read_configuration_in(directory)?;
+    settings.validate().map_err(ConfigurationError::Invalid)?;
+    Ok(settings)
+}
+
+/// Read the settings in `directory` as `get_configuration_in` does, but
+/// without validating them, e.g. to show what is wrong with them.
+pub fn read_configuration_in(directory: &Path) -> Result<Settings, ConfigurationError> {
+    // Detect the running environment.
+    // Default to `local` if unspecified.
+    let environment: Environment = std::env::var("APP_ENVIRONMENT")
+        .unwrap_or_else(|_| "local".into())
+        .try_into()
+        .map_err(ConfigurationError::InvalidEnvironment)?;
+    let sources = configuration_files(directory, &environment)?
+        .into_iter()
+        .map(SettingSource::File)
+        .chain([SettingSource::DatabaseUrl, SettingSource::Environment])
+        .collect();
+    Settings::read(SettingSources::load(sources)?, environment)
+}
+
+impl Settings {
+    /// The settings `sources` add up to, validated.
+    pub fn from_sources(
+        sources: SettingSources,
+        environment: Environment,
+    ) -> Result<Self, ConfigurationError> {
+        let settings = Self::read(sources, environment)?;
+        settings.validate().map_err(ConfigurationError::Invalid)?;
+        Ok(settings)
+    }
+
+    /// The settings `sources` add up to, as they are.
+    ///
+    /// Relative paths set in a file are resolved from the directory of that
+    /// file, e.g. the configuration directory, wherever the application is
+    /// started from.
+    pub fn read(
+        sources: SettingSources,
+        environment: Environment,
+    ) -> Result<Self, ConfigurationError> {
+        let mut settings: Settings = sources.merge()?.try_deserialize()?;
+        settings.environment = environment;
+        settings.sources = sources;
+        let sources = &settings.sources;
+        for (key, path) in [
+            (
+                "database.ssl_root_cert",
+                &mut settings.database.ssl_root_cert,
+            ),
+            (
+                "database.ssl_client_cert",
+                &mut settings.database.ssl_client_cert,
+            ),
+            (
+                "database.ssl_client_key",
+                &mut settings.database.ssl_client_key,
+            ),
+            (
+                "domain_policy.allow_list_path",
+                &mut settings.domain_policy.allow_list_path,
+            ),
+            (
+                "domain_policy.deny_list_path",
+                &mut settings.domain_policy.deny_list_path,
+            ),
+        ] {
+            *path = path.take().map(|path| sources.resolve(key, path));
+        }
+        Ok(settings)
+    }
+}
+
+/// The environment the application runs in, e.g. `local`, `staging` or
+/// `production`. Each has its own configuration file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Environment(String);
+
+impl Environment {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// Development environments may keep the secrets of `base`.
+    pub fn is_development(&self) -> bool {
+        matches!(self.as_str(), "local" | "test")
+    }
+}
+
+impl Default for Environment {
+    fn default() -> Self {
+        Self("local".into())
+    }
+}
+
+impl TryFrom<String> for Environment {
+    type Error = String;
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        let name = s.trim().to_lowercase();
+        let is_valid = !name.is_empty()
+            && name != "base"
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+        if is_valid {
+            Ok(Self(name))
+        } else {
+            Err(format!(
+                "{} is not a valid environment name. \
+                Use letters, digits, `-` and `_`, e.g. `staging`.",
+                s
+            ))
+        }
+    }
+}
+
+impl DatabaseSettings {
+    pub fn without_db(&self) -> PgConnectOptions {
+        let mut options = PgConnectOptions::new()
+            .host(&self.host)
+            .port(self.port)
+            .username(&self.username)
+            .password(self.password.expose_secret())
+            .ssl_mode(self.ssl_mode.into())
+            .application_name(&self.application_name);
+        if let Some(path) = &self.ssl_root_cert {
+            options = options.ssl_root_cert(path);
+        }
+        if let Some(path) = &self.ssl_client_cert {
+            options = options.ssl_client_cert(path);
+        }
+        if let Some(path) = &self.ssl_client_key {
+            options = options.ssl_client_key(path);
+        }
+        if let Some(timeout) = self.statement_timeout_milliseconds {
+            options = options.options([("statement_timeout", timeout.to_string())]);
+        }
+        options
+    }
+
+    pub fn with_db(&self) -> PgConnectOptions {
+        self.without_db()
+            .database(&self.database_name)
+            .log_statements(tracing_log::log::LevelFilter::Trace)
+    }
+
+    /// A pool sized and timed out as configured, to connect with `with_db`.
+    pub fn pool_options(&self) -> PgPoolOptions {
+        PgPoolOptions::new()
+            .max_connections(self.max_connections)
+            .min_connections(self.min_connections)
+            .acquire_timeout(std::time::Duration::from_secs(self.acquire_timeout_seconds))
+            .idle_timeout(
+                self.idle_timeout_seconds
+                    .map(std::time::Duration::from_secs),
+            )
+    }
+}
@@ -0,0 +1,146 @@
+use secrecy::Secret;
+use serde_json::Value;
+
+use super::Settings;
+
+/// What secrets are shown as.
+const MASK: &str = "********";
+
+pub(super) fn mask_secret<S: serde::Serializer>(
+    _secret: &Secret<String>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(MASK)
+}
+
+/// How `Settings::show` prints the settings.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum SettingsFormat {
+    /// Each setting followed by a comment saying where it comes from.
+    Yaml,
+    /// Each setting as `{"value": …, "origin": …}`.
+    Json,
+}
+
+impl Settings {
+    /// Every setting, secrets masked, with where its value comes from: the
+    /// last source setting it, or `default` if none does.
+    pub fn show(&self, format: SettingsFormat) -> String {
+        let values = serde_json::to_value(self).expect("Failed to serialize the settings.");
+        match format {
+            SettingsFormat::Yaml => {
+                let mut yaml = format!(
+                    "# The settings of the {} environment\n",
+                    self.environment.as_str()
+                );
+                self.write_yaml(&mut yaml, "", &values, 0);
+                yaml
+            }
+            SettingsFormat::Json => {
+                let annotated = self.annotate("", values);
+                serde_json::to_string_pretty(&annotated).expect("Failed to serialize the settings.")
+            }
+        }
+    }
+
+    fn origin(&self, key: &str) -> String {
+        self.sources
+            .source_of(key)
+            .map(|source| source.describe(key))
+            .unwrap_or_else(|| "default".into())
+    }
+
+    /// `value` with every setting in it replaced by its value and origin.
+    fn annotate(&self, key: &str, value: Value) -> Value {
+        match value {
+            Value::Object(fields) => Value::Object(
+                fields
+                    .into_iter()
+                    .map(|(name, value)| {
+                        let annotated = self.annotate(&join(key, &name), value);
+                        (name, annotated)
+                    })
+                    .collect(),
+            ),
+            value => serde_json::json!({ "value": value, "origin": self.origin(key) }),
+        }
+    }
+
+    fn write_yaml(&self, yaml: &mut String, key: &str, value: &Value, depth: usize) {
+        let Value::Object(fields) = value else {
+            return;
+        };
+        let indent = "  ".repeat(depth);
+        for (name, value) in fields {
+            let key = join(key, name);
+            match value {
+                Value::Object(_) => {
+                    yaml.push_str(&format!("{}{}:\n", indent, name));
+                    self.write_yaml(yaml, &key, value, depth + 1);
+                }
+                // JSON scalars and arrays are valid YAML too
+                value => yaml.push_str(&format!(
+                    "{}{}: {}  # {}\n",
+                    indent,
+                    name,
+                    value,
+                    self.origin(&key)
+                )),
+            }
+        }
+    }
+}
+
+fn join(key: &str, name: &str) -> String {
+    if key.is_empty() {
+        name.to_string()
+    } else {
+        format!("{}.{}", key, name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use secrecy::ExposeSecret;
+
+    use super::SettingsFormat;
+    use crate::configuration::{Environment, SettingSource, SettingSources, Settings};
+
+    fn settings() -> Settings {
+        let sources = SettingSources::load(vec![
+            SettingSource::File("configuration/base.yaml".into()),
+            SettingSource::File("configuration/local.yaml".into()),
+        ])
+        .unwrap();
+        Settings::read(sources, Environment::default()).unwrap()
+    }
+
+    #[test]
+    fn settings_are_shown_with_their_origin_and_secrets_masked() {
+        let settings = settings();
+
+        let yaml = settings.show(SettingsFormat::Yaml);
+
+        assert!(yaml.contains(
+            "\n  host: \"127.0.0.1\"  # set in configuration/local.yaml\n  port: 8000  # set in configuration/base.yaml\n"
+        ));
+        assert!(yaml.contains("\n  password: \"********\"  # set in configuration/base.yaml\n"));
+        assert!(yaml.contains("\n  statement_timeout_milliseconds: null  # default\n"));
+        assert!(!yaml.contains(settings.admin.password.expose_secret()));
+    }
+
+    #[test]
+    fn json_settings_carry_their_origin() {
+        let json: serde_json::Value =
+            serde_json::from_str(&settings().show(SettingsFormat::Json)).unwrap();
+
+        assert_eq!(
+            serde_json::json!({ "value": "********", "origin": "set in configuration/base.yaml" }),
+            json["admin"]["password"]
+        );
+        assert_eq!(
+            serde_json::json!({ "value": 5432, "origin": "set in configuration/base.yaml" }),
+            json["database"]["port"]
+        );
+    }
+}
@@ -0,0 +1,479 @@
+use std::path::Path;
+
+use super::{DatabaseSslMode, SettingSource, Settings, BASE};
+use crate::domain::SubscriberEmail;
+use crate::magic_link::MAX_TTL_SECONDS;
+use crate::telemetry::parse_log_filter;
+
+/// A setting that was read but cannot be used.
+#[derive(Debug, PartialEq, Eq)]
+pub struct InvalidSetting {
+    /// E.g. `email_client.sender_email`.
+    pub key: String,
+    pub problem: String,
+    /// Where the value was read from, if known.
+    pub source: Option<String>,
+}
+
+impl std::fmt::Display for InvalidSetting {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.source {
+            Some(source) => write!(f, "{} ({}): {}", self.key, source, self.problem),
+            None => write!(f, "{}: {}", self.key, self.problem),
+        }
+    }
+}
+
+/// The problems `Settings::validate` found, see `invalid`.
+struct Problems<'a> {
+    settings: &'a Settings,
+    invalid: Vec<InvalidSetting>,
+}
+
+impl Problems<'_> {
+    fn report(&mut self, key: &str, problem: impl Into<String>) {
+        self.invalid.push(InvalidSetting {
+            key: key.to_string(),
+            problem: problem.into(),
+            source: self
+                .settings
+                .sources
+                .source_of(key)
+                .map(|source| source.describe(key)),
+        });
+    }
+
+    fn check_port(&mut self, key: &str, port: u16) {
+        if port == 0 {
+            self.report(key, "must be between 1 and 65535.");
+        }
+    }
+
+    fn check_not_empty(&mut self, key: &str, value: &str) {
+        if value.trim().is_empty() {
+            self.report(key, "must not be empty.");
+        }
+    }
+
+    fn check_positive(&mut self, key: &str, value: u64) {
+        if value == 0 {
+            self.report(key, "must be greater than 0.");
+        }
+    }
+
+    /// We append paths to base URLs, they must not end with a slash.
+    fn check_base_url(&mut self, key: &str, url: &str) {
+        match reqwest::Url::parse(url) {
+            Ok(parsed) if !matches!(parsed.scheme(), "http" | "https") => {
+                self.report(key, "must be an http or https URL.")
+            }
+            Ok(parsed) if parsed.host().is_none() => self.report(key, "must have a host."),
+            Ok(_) if url.ends_with('/') => self.report(key, "must not end with a slash."),
+            Ok(_) => {}
+            Err(e) => self.report(key, format!("is not a valid URL: {}.", e)),
+        }
+    }
+
+    fn check_file(&mut self, key: &str, path: Option<&Path>) {
+        match path {
+            Some(path) if !path.is_file() => {
+                self.report(key, format!("{} is not a file.", path.display()))
+            }
+            _ => {}
+        }
+    }
+
+    /// Outside of development, secrets must be set for the environment
+    /// rather than left to the development values of `base`.
+    fn check_secret(&mut self, key: &str) {
+        if self.settings.environment.is_development() {
+            return;
+        }
+        let from_base = matches!(
+            self.settings.sources.source_of(key),
+            Some(SettingSource::File(path)) if path.file_stem().is_some_and(|stem| stem == BASE)
+        );
+        if from_base {
+            self.report(
+                key,
+                format!(
+                    "must be set for the {} environment, the default is for development only.",
+                    self.settings.environment.as_str()
+                ),
+            );
+        }
+    }
+}
+
+impl Settings {
+    /// Check every setting, and return all the problems found rather than
+    /// the first one.
+    pub fn validate(&self) -> Result<(), Vec<InvalidSetting>> {
+        let mut problems = Problems {
+            settings: self,
+            invalid: Vec::new(),
+        };
+
+        problems.check_port("application.port", self.application.port);
+        problems.check_not_empty("application.host", &self.application.host);
+        problems.check_base_url("application.base_url", &self.application.base_url);
+        problems.check_positive(
+            "application.reload_interval_seconds",
+            self.application.reload_interval_seconds,
+        );
+
+        problems.check_port("database.port", self.database.port);
+        problems.check_not_empty("database.host", &self.database.host);
+        problems.check_not_empty("database.username", &self.database.username);
+        problems.check_not_empty("database.database_name", &self.database.database_name);
+        problems.check_secret("database.password");
+        if self.sources.source_of("database.require_ssl").is_some() {
+            problems.report(
+                "database.require_ssl",
+                "is no longer read, set database.ssl_mode instead, e.g. to `require`.",
+            );
+        }
+        problems.check_file(
+            "database.ssl_root_cert",
+            self.database.ssl_root_cert.as_deref(),
+        );
+        problems.check_file(
+            "database.ssl_client_cert",
+            self.database.ssl_client_cert.as_deref(),
+        );
+        problems.check_file(
+            "database.ssl_client_key",
+            self.database.ssl_client_key.as_deref(),
+        );
+        if self.database.ssl_client_cert.is_some() != self.database.ssl_client_key.is_some() {
+            problems.report(
+                "database.ssl_client_cert",
+                "must be set along with database.ssl_client_key, or not at all.",
+            );
+        }
+        if self.database.ssl_root_cert.is_some()
+            && !matches!(
+                self.database.ssl_mode,
+                DatabaseSslMode::VerifyCa | DatabaseSslMode::VerifyFull
+            )
+        {
+            problems.report(
+                "database.ssl_root_cert",
+                "is only used when database.ssl_mode is `verify-ca` or `verify-full`.",
+            );
+        }
+        problems.check_positive(
+            "database.max_connections",
+            self.database.max_connections.into(),
+        );
+        if self.database.min_connections > self.database.max_connections {
+            problems.report(
+                "database.min_connections",
+                "must not be greater than database.max_connections.",
+            );
+        }
+        problems.check_positive(
+            "database.acquire_timeout_seconds",
+            self.database.acquire_timeout_seconds,
+        );
+        if let Some(timeout) = self.database.statement_timeout_milliseconds {
+            problems.check_positive("database.statement_timeout_milliseconds", timeout);
+        }
+
+        problems.check_base_url("email_client.base_url", &self.email_client.base_url);
+        if let Err(e) = SubscriberEmail::parse(self.email_client.sender_email.clone()) {
+            problems.report("email_client.sender_email", e);
+        }
+        problems.check_secret("email_client.authorization_token");
+
+        problems.check_not_empty(
+            "email_webhooks.basic_auth_username",
+            &self.email_webhooks.basic_auth_username,
+        );
+        problems.check_secret("email_webhooks.basic_auth_password");
+        problems.check_secret("email_webhooks.signing_key");
+
+        problems.check_not_empty("admin.username", &self.admin.username);
+        problems.check_secret("admin.password");
+
+        problems.check_positive(
+            "email_domain_check.timeout_milliseconds",
+            self.email_domain_check.timeout_milliseconds,
+        );
+        problems.check_positive(
+            "subscriber_name.max_length",
+            self.subscriber_name.max_length as u64,
+        );
+        // The domain policy watcher would panic, leaving the policy stale
+        problems.check_positive(
+            "domain_policy.reload_interval_seconds",
+            self.domain_policy.reload_interval_seconds,
+        );
+
+        problems.check_positive("rate_limit.window_seconds", self.rate_limit.window_seconds);
+        problems.check_positive(
+            "rate_limit.max_requests_per_ip",
+            self.rate_limit.max_requests_per_ip.into(),
+        );
+        problems.check_positive(
+            "rate_limit.max_requests_per_email",
+            self.rate_limit.max_requests_per_email.into(),
+        );
+        problems.check_positive(
+            "rate_limit.max_admin_requests_per_ip",
+            self.rate_limit.max_admin_requests_per_ip.into(),
+        );
+
+        if self.bot_detection.min_fill_time_seconds < 0 {
+            problems.report(
+                "bot_detection.min_fill_time_seconds",
+                "must not be negative.",
+            );
+        }
+
+        if self.challenge.enabled {
+            problems.check_base_url("challenge.verify_url", &self.challenge.verify_url);
+            problems.check_positive(
+                "challenge.timeout_milliseconds",
+                self.challenge.timeout_milliseconds,
+            );
+            problems.check_secret("challenge.secret_key");
+        }
+
+        problems.check_secret("magic_link.signing_key");
+        problems.check_positive("magic_link.ttl_seconds", self.magic_link.ttl_seconds);
+        // Magic links cannot be revoked before they expire
+        if self.magic_link.ttl_seconds > MAX_TTL_SECONDS {
+            problems.report(
+                "magic_link.ttl_seconds",
+                format!("must be at most {} (a week).", MAX_TTL_SECONDS),
+            );
+        }
+
+        problems.check_secret("suppression_list.tombstone_key");
+
+        if let Err(e) = parse_log_filter(&self.log.filter) {
+            problems.report("log.filter", e);
+        }
+
+        if problems.invalid.is_empty() {
+            Ok(())
+        } else {
+            Err(problems.invalid)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use claims::{assert_err, assert_ok};
+
+    use crate::configuration::sources::environment_variables;
+    use crate::configuration::{Environment, SettingSource, SettingSources, Settings};
+
+    fn production() -> Environment {
+        Environment::try_from("production".to_string()).unwrap()
+    }
+
+    /// The settings of `base.yaml` and `local.yaml`, overridden by `yaml` and
+    /// then by the environment variables in `variables`.
+    fn settings(environment: Environment, yaml: &str, variables: &[(&str, &str)]) -> Settings {
+        let override_file = SettingSource::File("override.yaml".into());
+        let sources = SettingSources::new(vec![
+            (
+                SettingSource::File("configuration/base.yaml".into()),
+                SettingSource::File("configuration/base.yaml".into())
+                    .load()
+                    .unwrap(),
+            ),
+            (
+                SettingSource::File("configuration/local.yaml".into()),
+                SettingSource::File("configuration/local.yaml".into())
+                    .load()
+                    .unwrap(),
+            ),
+            (
+                override_file,
+                config::Config::builder()
+                    .add_source(config::File::from_str(yaml, config::FileFormat::Yaml))
+                    .build()
+                    .unwrap(),
+            ),
+            (
+                SettingSource::Environment,
+                config::Config::builder()
+                    .add_source(
+                        environment_variables().source(Some(
+                            variables
+                                .iter()
+                                .map(|(name, value)| (name.to_string(), value.to_string()))
+                                .collect(),
+                        )),
+                    )
+                    .build()
+                    .unwrap(),
+            ),
+        ]);
+        let mut settings: Settings = sources.merge().unwrap().try_deserialize().unwrap();
+        settings.environment = environment;
+        settings.sources = sources;
+        settings
+    }
+
+    #[test]
+    fn the_local_configuration_is_valid() {
+        assert_ok!(settings(Environment::default(), "{}", &[]).validate());
+    }
+
+    #[test]
+    fn every_problem_is_reported_with_its_source() {
+        let settings = settings(
+            Environment::default(),
+            "email_client:\n  sender_email: \"not an email\"",
+            &[
+                ("APP_APPLICATION__BASE_URL", "127.0.0.1:8000"),
+                ("APP_DATABASE__PORT", "0"),
+                ("APP_DATABASE__MIN_CONNECTIONS", "20"),
+            ],
+        );
+
+        let invalid = settings.validate().unwrap_err();
+
+        let keys: Vec<_> = invalid.iter().map(|i| i.key.as_str()).collect();
+        assert_eq!(
+            vec![
+                "application.base_url",
+                "database.port",
+                "database.min_connections",
+                "email_client.sender_email"
+            ],
+            keys
+        );
+        assert_eq!(
+            Some("set by APP_APPLICATION__BASE_URL"),
+            invalid[0].source.as_deref()
+        );
+        assert_eq!(Some("set in override.yaml"), invalid[3].source.as_deref());
+    }
+
+    #[test]
+    fn invalid_log_filters_are_rejected() {
+        let settings = settings(
+            Environment::default(),
+            "{}",
+            &[("APP_LOG__FILTER", "info,sqlx=loud")],
+        );
+
+        let invalid = settings.validate().unwrap_err();
+
+        assert_eq!("log.filter", invalid[0].key);
+    }
+
+    #[test]
+    fn reload_intervals_must_be_positive() {
+        let settings = settings(
+            Environment::default(),
+            "{}",
+            &[
+                ("APP_APPLICATION__RELOAD_INTERVAL_SECONDS", "0"),
+                ("APP_DOMAIN_POLICY__RELOAD_INTERVAL_SECONDS", "0"),
+            ],
+        );
+
+        let invalid = settings.validate().unwrap_err();
+
+        let keys: Vec<_> = invalid.iter().map(|i| i.key.as_str()).collect();
+        assert_eq!(
+            vec![
+                "application.reload_interval_seconds",
+                "domain_policy.reload_interval_seconds"
+            ],
+            keys
+        );
+    }
+
+    #[test]
+    fn magic_link_lifetimes_are_bounded() {
+        for ttl_seconds in ["0", "604801", "18446744073709551615"] {
+            let settings = settings(
+                Environment::default(),
+                "{}",
+                &[("APP_MAGIC_LINK__TTL_SECONDS", ttl_seconds)],
+            );
+
+            let invalid = settings.validate().unwrap_err();
+
+            assert_eq!("magic_link.ttl_seconds", invalid[0].key);
+        }
+        let settings = settings(
+            Environment::default(),
+            "{}",
+            &[("APP_MAGIC_LINK__TTL_SECONDS", "604800")],
+        );
+        assert_ok!(settings.validate());
+    }
+
+    #[test]
+    fn database_tls_settings_are_checked() {
+        let settings = settings(
+            Environment::default(),
+            "database:\n  require_ssl: true\n  ssl_mode: \"require\"\n  \
+            ssl_root_cert: \"/nonexistent/ca.pem\"\n  ssl_client_cert: \"Cargo.toml\"",
+            &[],
+        );
+
+        let invalid = settings.validate().unwrap_err();
+
+        let problems: Vec<_> = invalid
+            .iter()
+            .map(|i| (i.key.as_str(), i.problem.as_str()))
+            .collect();
+        assert_eq!(
+            vec![
+                (
+                    "database.require_ssl",
+                    "is no longer read, set database.ssl_mode instead, e.g. to `require`."
+                ),
+                (
+                    "database.ssl_root_cert",
+                    "/nonexistent/ca.pem is not a file."
+                ),
+                (
+                    "database.ssl_client_cert",
+                    "must be set along with database.ssl_client_key, or not at all."
+                ),
+                (
+                    "database.ssl_root_cert",
+                    "is only used when database.ssl_mode is `verify-ca` or `verify-full`."
+                ),
+            ],
+            problems
+        );
+    }
+
+    #[test]
+    fn production_refuses_the_development_secrets() {
+        let settings = settings(production(), "{}", &[]);
+
+        let invalid = settings.validate().unwrap_err();
+
+        assert!(invalid.iter().any(|i| i.key == "admin.password"
+            && i.source.as_deref() == Some("set in configuration/base.yaml")));
+        assert!(invalid.iter().all(|i| i.problem.contains("development")));
+    }
+
+    #[test]
+    fn production_accepts_secrets_set_for_it() {
+        let secrets = [
+            ("APP_DATABASE__PASSWORD", "s3cret"),
+            ("APP_EMAIL_CLIENT__AUTHORIZATION_TOKEN", "s3cret"),
+            ("APP_EMAIL_WEBHOOKS__BASIC_AUTH_PASSWORD", "s3cret"),
+            ("APP_EMAIL_WEBHOOKS__SIGNING_KEY", "s3cret"),
+            ("APP_ADMIN__PASSWORD", "s3cret"),
+            ("APP_MAGIC_LINK__SIGNING_KEY", "s3cret"),
+            ("APP_SUPPRESSION_LIST__TOMBSTONE_KEY", "s3cret"),
+        ];
+        assert_ok!(settings(production(), "{}", &secrets).validate());
+        assert_err!(settings(production(), "{}", &secrets[1..]).validate());
+    }
+}
@@ -0,0 +1,223 @@
+use std::future::{ready, Ready};
+
+use actix_web::dev::Payload;
+use actix_web::http::header;
+use actix_web::{FromRequest, HttpRequest};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{PgExecutor, Postgres, Transaction};
+use uuid::Uuid;
+
+use crate::domain::SubscriberEmail;
+use crate::rate_limit::ClientIp;
+
+/// Longest client-supplied value we keep, longer ones are cut.
+const MAX_VALUE_LENGTH: usize = 512;
+
+/// What a subscriber agreed to, or took back.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConsentEventKind {
+    Subscribed,
+    Confirmed,
+    Unsubscribed,
+    PreferencesChanged,
+}
+
+impl ConsentEventKind {
+    pub fn as_str(&self) -> &str {
+        match self {
+            Self::Subscribed => "subscribed",
+            Self::Confirmed => "confirmed",
+            Self::Unsubscribed => "unsubscribed",
+            Self::PreferencesChanged => "preferences_changed",
+        }
+    }
+}
+
+impl TryFrom<String> for ConsentEventKind {
+    type Error = String;
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        match s.as_str() {
+            "subscribed" => Ok(Self::Subscribed),
+            "confirmed" => Ok(Self::Confirmed),
+            "unsubscribed" => Ok(Self::Unsubscribed),
+            "preferences_changed" => Ok(Self::PreferencesChanged),
+            other => Err(format!("{} is not a valid consent event.", other)),
+        }
+    }
+}
+
+/// Who a consent event came from, as far as the request tells.
+#[derive(Debug, Clone, Default)]
+pub struct ConsentOrigin {
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+}
+
+impl FromRequest for ConsentOrigin {
+    type Error = std::convert::Infallible;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(request: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        let user_agent = request
+            .headers()
+            .get(header::USER_AGENT)
+            .and_then(|value| value.to_str().ok())
+            .map(truncate);
+        ready(Ok(Self {
+            ip: ClientIp::of(request),
+            user_agent,
+        }))
+    }
+}
+
+/// A consent event, before it is recorded.
+#[derive(Debug)]
+pub struct ConsentEvent<'a> {
+    pub kind: ConsentEventKind,
+    /// The form or link the event came through, e.g. `subscriptions`.
+    pub source: &'a str,
+    /// The version of the wording the subscriber was shown, if known.
+    pub text_version: Option<&'a str>,
+    /// What exactly was agreed to, e.g. which lists.
+    pub details: serde_json::Value,
+}
+
+/// A recorded consent event, as shown to admins and in data exports.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ConsentEventRecord {
+    pub event_type: String,
+    pub email: String,
+    pub occurred_at: DateTime<Utc>,
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+    pub source: String,
+    pub text_version: Option<String>,
+    pub details: serde_json::Value,
+}
+
+fn truncate(value: &str) -> String {
+    value.chars().take(MAX_VALUE_LENGTH).collect()
+}
+
+/// Add `event` to the consent history of the subscriber.
+///
+/// Record it in the transaction that makes the change, so that the history
+/// cannot miss one.
+#[tracing::instrument(
+    name = "Recording consent event",
+    skip(email_canonical, event, origin, transaction),
+    fields(event_type = %event.kind.as_str(), source = %event.source)
+)]
+pub async fn record_consent_event(
+    subscriber_id: Uuid,
+    email_canonical: &str,
+    event: ConsentEvent<'_>,
+    origin: &ConsentOrigin,
+    transaction: &mut Transaction<'_, Postgres>,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+    INSERT INTO consent_events
+        (id, subscriber_id, email, event_type, occurred_at, ip, user_agent, source, text_version, details)
+    VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)
+"#,
+        Uuid::new_v4(),
+        subscriber_id,
+        email_canonical,
+        event.kind.as_str(),
+        Utc::now(),
+        origin.ip.as_deref(),
+        origin.user_agent.as_deref(),
+        truncate(event.source),
+        event.text_version.map(truncate),
+        event.details
+    )
+    .execute(&mut **transaction)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    Ok(())
+}
+
+/// The consent history of `email`, oldest first: the events recorded for the
+/// address, and the ones of its current subscriber under former addresses.
+#[tracing::instrument(name = "Fetching consent history", skip(email, executor))]
+pub async fn fetch_consent_history<'e>(
+    email: &SubscriberEmail,
+    executor: impl PgExecutor<'e>,
+) -> Result<Vec<ConsentEventRecord>, sqlx::Error> {
+    sqlx::query_as!(
+        ConsentEventRecord,
+        r#"
+    SELECT event_type, email, occurred_at, ip, user_agent, source, text_version, details
+    FROM consent_events
+    WHERE email = $1
+        OR subscriber_id IN (SELECT id FROM subscriptions WHERE email_canonical = $1)
+    ORDER BY occurred_at
+"#,
+        email.canonical()
+    )
+    .fetch_all(executor)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })
+}
+
+/// Delete the consent history of `email`, see `fetch_consent_history`.
+///
+/// Only meant for erasure requests: the history is otherwise append-only,
+/// and the database refuses to delete from it outside of one.
+#[tracing::instrument(name = "Erasing consent history", skip(email, transaction))]
+pub async fn erase_consent_history(
+    email: &SubscriberEmail,
+    transaction: &mut Transaction<'_, Postgres>,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(r#"SELECT set_config('newsletter.erasing', 'on', true)"#)
+        .fetch_one(&mut **transaction)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to execute query: {:?}", e);
+            e
+        })?;
+    sqlx::query!(
+        r#"
+    DELETE FROM consent_events
+    WHERE email = $1
+        OR subscriber_id IN (SELECT id FROM subscriptions WHERE email_canonical = $1)
+"#,
+        email.canonical()
+    )
+    .execute(&mut **transaction)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ConsentEventKind;
+
+    #[test]
+    fn consent_event_kinds_round_trip_through_their_names() {
+        for kind in [
+            ConsentEventKind::Subscribed,
+            ConsentEventKind::Confirmed,
+            ConsentEventKind::Unsubscribed,
+            ConsentEventKind::PreferencesChanged,
+        ] {
+            assert_eq!(
+                Ok(kind),
+                ConsentEventKind::try_from(kind.as_str().to_string())
+            );
+        }
+        assert!(ConsentEventKind::try_from("forgotten".to_string()).is_err());
+    }
+}
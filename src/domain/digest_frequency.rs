@@ -0,0 +1,59 @@
+/// How often a subscriber wants to hear from us.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DigestFrequency {
+    /// Every issue, as soon as it is published.
+    Immediate,
+    Daily,
+    Weekly,
+}
+
+impl DigestFrequency {
+    pub fn as_str(&self) -> &str {
+        match self {
+            Self::Immediate => "immediate",
+            Self::Daily => "daily",
+            Self::Weekly => "weekly",
+        }
+    }
+}
+
+impl TryFrom<String> for DigestFrequency {
+    type Error = String;
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        match s.to_lowercase().as_str() {
+            "immediate" => Ok(Self::Immediate),
+            "daily" => Ok(Self::Daily),
+            "weekly" => Ok(Self::Weekly),
+            other => Err(format!(
+                "{} is not a valid digest frequency. \
+                Use either `immediate`, `daily` or `weekly`.",
+                other
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::DigestFrequency;
+    use claims::{assert_err, assert_ok_eq};
+
+    #[test]
+    fn frequencies_round_trip_through_their_name() {
+        for frequency in [
+            DigestFrequency::Immediate,
+            DigestFrequency::Daily,
+            DigestFrequency::Weekly,
+        ] {
+            assert_ok_eq!(
+                DigestFrequency::try_from(frequency.as_str().to_string()),
+                frequency
+            );
+        }
+    }
+
+    #[test]
+    fn unknown_frequencies_are_rejected() {
+        assert_err!(DigestFrequency::try_from("hourly".to_string()));
+    }
+}
@@ -0,0 +1,52 @@
+use crate::domain::subscriber_email::SubscriberEmail;
+use chrono::{DateTime, Utc};
+
+/// What the email provider told us happened to a message we sent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EmailEventKind {
+    HardBounce,
+    SoftBounce,
+    Complaint,
+    Delivery,
+}
+
+impl EmailEventKind {
+    pub fn as_str(&self) -> &str {
+        match self {
+            Self::HardBounce => "hard_bounce",
+            Self::SoftBounce => "soft_bounce",
+            Self::Complaint => "complaint",
+            Self::Delivery => "delivery",
+        }
+    }
+
+    /// Hard bounces and spam complaints mean we must stop emailing the
+    /// recipient. Soft bounces are transient and deliveries are good news.
+    pub fn suppresses_recipient(&self) -> bool {
+        matches!(self, Self::HardBounce | Self::Complaint)
+    }
+}
+
+#[derive(Debug)]
+pub struct EmailEvent {
+    pub kind: EmailEventKind,
+    pub recipient: SubscriberEmail,
+    pub occurred_at: DateTime<Utc>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::EmailEventKind;
+
+    #[test]
+    fn hard_bounces_and_complaints_suppress_the_recipient() {
+        assert!(EmailEventKind::HardBounce.suppresses_recipient());
+        assert!(EmailEventKind::Complaint.suppresses_recipient());
+    }
+
+    #[test]
+    fn soft_bounces_and_deliveries_do_not_suppress_the_recipient() {
+        assert!(!EmailEventKind::SoftBounce.suppresses_recipient());
+        assert!(!EmailEventKind::Delivery.suppresses_recipient());
+    }
+}
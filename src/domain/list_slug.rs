@@ -0,0 +1,62 @@
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct ListSlug(String);
+
+impl ListSlug {
+    /// Returns an instance of `ListSlug` if the input is a non-empty run of
+    /// lowercase ASCII letters, digits and single dashes, e.g. `rust-weekly`.
+    pub fn parse(s: String) -> Result<ListSlug, String> {
+        let is_too_long = s.len() > 64;
+        let has_valid_characters = s
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-');
+        let has_valid_dashes = !s.starts_with('-') && !s.ends_with('-') && !s.contains("--");
+        if s.is_empty() || is_too_long || !has_valid_characters || !has_valid_dashes {
+            Err(format!("{} is not a valid list slug.", s))
+        } else {
+            Ok(Self(s))
+        }
+    }
+}
+
+impl AsRef<str> for ListSlug {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::domain::ListSlug;
+    use claims::{assert_err, assert_ok};
+
+    #[test]
+    fn a_valid_slug_is_parsed_successfully() {
+        assert_ok!(ListSlug::parse("rust-weekly-2".to_string()));
+    }
+
+    #[test]
+    fn empty_string_is_rejected() {
+        assert_err!(ListSlug::parse("".to_string()));
+    }
+
+    #[test]
+    fn slugs_with_uppercase_or_symbols_are_rejected() {
+        for slug in ["Rust", "rust_weekly", "rust weekly", "rust/weekly"] {
+            assert_err!(ListSlug::parse(slug.to_string()));
+        }
+    }
+
+    #[test]
+    fn slugs_with_misplaced_dashes_are_rejected() {
+        for slug in ["-rust", "rust-", "rust--weekly"] {
+            assert_err!(ListSlug::parse(slug.to_string()));
+        }
+    }
+
+    #[test]
+    fn a_slug_longer_than_64_characters_is_rejected() {
+        assert_err!(ListSlug::parse("a".repeat(65)));
+    }
+}
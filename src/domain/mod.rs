@@ -1,7 +1,17 @@
+mod digest_frequency;
+mod email_event;
+mod list_slug;
 mod new_subscriber;
+mod segment_filter;
+mod subscriber_attributes;
 mod subscriber_email;
 mod subscriber_name;
 
+pub use digest_frequency::DigestFrequency;
+pub use email_event::{EmailEvent, EmailEventKind};
+pub use list_slug::ListSlug;
 pub use new_subscriber::NewSubscriber;
+pub use segment_filter::SegmentFilter;
+pub use subscriber_attributes::SubscriberAttributes;
 pub use subscriber_email::SubscriberEmail;
-pub use subscriber_name::SubscriberName;
+pub use subscriber_name::{SubscriberName, SubscriberNameError};
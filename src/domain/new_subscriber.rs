@@ -1,3 +1,4 @@
+use crate::domain::subscriber_attributes::SubscriberAttributes;
 use crate::domain::subscriber_email::SubscriberEmail;
 use crate::domain::subscriber_name::SubscriberName;
 use serde::{Deserialize, Serialize};
@@ -6,4 +7,5 @@ use serde::{Deserialize, Serialize};
 pub struct NewSubscriber {
     pub email: SubscriberEmail,
     pub name: SubscriberName,
+    pub attributes: SubscriberAttributes,
 }
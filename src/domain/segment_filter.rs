@@ -0,0 +1,515 @@
+use chrono::{DateTime, Utc};
+use sqlx::{Postgres, QueryBuilder};
+
+use crate::domain::subscriber_attributes::is_valid_attribute_key;
+
+/// A filter selecting a segment of subscribers, e.g.
+///
+/// ```text
+/// attributes.country in ("FR", "BE") and not attributes.plan = "free"
+///     or subscribed_at >= "2024-01-01T00:00:00Z"
+/// ```
+///
+/// Grammar, keywords are case-insensitive:
+///
+/// ```text
+/// filter     := or
+/// or         := and ("or" and)*
+/// and        := unary ("and" unary)*
+/// unary      := "not" unary | "(" filter ")" | comparison
+/// comparison := field op value | field "in" "(" value ("," value)* ")"
+/// field      := "subscribed_at" | "attributes." key
+/// op         := "=" | "!=" | "<" | "<=" | ">" | ">="
+/// value      := "string" | number | "true" | "false"
+/// ```
+///
+/// Comparing against a missing attribute, or one of a different type, is
+/// always false.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SegmentFilter {
+    And(Box<SegmentFilter>, Box<SegmentFilter>),
+    Or(Box<SegmentFilter>, Box<SegmentFilter>),
+    Not(Box<SegmentFilter>),
+    SubscribedAt(Operator, DateTime<Utc>),
+    Attribute(String, Operator, Value),
+    AttributeIn(String, Vec<Value>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operator {
+    Eq,
+    NotEq,
+    Lt,
+    LtEq,
+    Gt,
+    GtEq,
+}
+
+impl Operator {
+    fn as_sql(&self) -> &str {
+        match self {
+            Self::Eq => " = ",
+            Self::NotEq => " <> ",
+            Self::Lt => " < ",
+            Self::LtEq => " <= ",
+            Self::Gt => " > ",
+            Self::GtEq => " >= ",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    String(String),
+    Number(f64),
+    Bool(bool),
+}
+
+impl Value {
+    fn to_json(&self) -> serde_json::Value {
+        match self {
+            Self::String(s) => serde_json::Value::from(s.as_str()),
+            Self::Number(n) => serde_json::Value::from(*n),
+            Self::Bool(b) => serde_json::Value::from(*b),
+        }
+    }
+}
+
+const MAX_FILTER_LENGTH: usize = 2048;
+const MAX_NESTING_DEPTH: usize = 32;
+
+impl SegmentFilter {
+    pub fn parse(s: String) -> Result<SegmentFilter, String> {
+        if s.len() > MAX_FILTER_LENGTH {
+            return Err(format!(
+                "Segment filters cannot be longer than {} characters.",
+                MAX_FILTER_LENGTH
+            ));
+        }
+        let tokens = tokenize(&s)?;
+        let mut parser = Parser {
+            tokens,
+            position: 0,
+            depth: 0,
+        };
+        let filter = parser.parse_or()?;
+        match parser.peek() {
+            None => Ok(filter),
+            Some(token) => Err(format!("Unexpected {} in segment filter.", token)),
+        }
+    }
+
+    /// Append the filter as a boolean SQL expression over the `subscriptions`
+    /// table. Every literal is bound as a parameter.
+    pub fn push_sql(&self, builder: &mut QueryBuilder<'_, Postgres>) {
+        match self {
+            Self::And(left, right) | Self::Or(left, right) => {
+                let keyword = if matches!(self, Self::And(..)) {
+                    " AND "
+                } else {
+                    " OR "
+                };
+                builder.push("(");
+                left.push_sql(builder);
+                builder.push(keyword);
+                right.push_sql(builder);
+                builder.push(")");
+            }
+            Self::Not(inner) => {
+                builder.push("(NOT ");
+                inner.push_sql(builder);
+                builder.push(")");
+            }
+            Self::SubscribedAt(operator, at) => {
+                builder.push("(subscribed_at");
+                builder.push(operator.as_sql());
+                builder.push_bind(*at);
+                builder.push(")");
+            }
+            Self::Attribute(key, Operator::Eq, value) => {
+                // jsonb equality also checks the type: "1" does not match 1
+                builder.push("COALESCE(attributes -> ");
+                builder.push_bind(key.clone());
+                builder.push(" = ");
+                builder.push_bind(value.to_json());
+                builder.push(", false)");
+            }
+            Self::Attribute(key, operator, value) => {
+                let (json_type, cast) = match value {
+                    Value::String(_) => ("string", ""),
+                    Value::Number(_) => ("number", "::float8"),
+                    Value::Bool(_) => ("boolean", "::boolean"),
+                };
+                // Check the type first so the cast can never fail
+                builder.push("COALESCE(CASE WHEN jsonb_typeof(attributes -> ");
+                builder.push_bind(key.clone());
+                builder.push(format!(") = '{}' THEN (attributes ->> ", json_type));
+                builder.push_bind(key.clone());
+                builder.push(format!("){}", cast));
+                builder.push(operator.as_sql());
+                match value {
+                    Value::String(s) => builder.push_bind(s.clone()),
+                    Value::Number(n) => builder.push_bind(*n),
+                    Value::Bool(b) => builder.push_bind(*b),
+                };
+                builder.push(" ELSE false END, false)");
+            }
+            Self::AttributeIn(key, values) => {
+                builder.push("COALESCE(attributes -> ");
+                builder.push_bind(key.clone());
+                builder.push(" IN (");
+                let mut separated = builder.separated(", ");
+                for value in values {
+                    separated.push_bind(value.to_json());
+                }
+                builder.push("), false)");
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Identifier(String),
+    String(String),
+    Number(f64),
+    Operator(Operator),
+    LeftParen,
+    RightParen,
+    Comma,
+}
+
+impl std::fmt::Display for Token {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Identifier(s) => write!(f, "`{}`", s),
+            Self::String(s) => write!(f, "\"{}\"", s),
+            Self::Number(n) => write!(f, "`{}`", n),
+            Self::Operator(_) => write!(f, "operator"),
+            Self::LeftParen => write!(f, "`(`"),
+            Self::RightParen => write!(f, "`)`"),
+            Self::Comma => write!(f, "`,`"),
+        }
+    }
+}
+
+fn tokenize(s: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = s.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        match c {
+            c if c.is_whitespace() => {
+                chars.next();
+            }
+            '(' | ')' | ',' => {
+                chars.next();
+                tokens.push(match c {
+                    '(' => Token::LeftParen,
+                    ')' => Token::RightParen,
+                    _ => Token::Comma,
+                });
+            }
+            '=' | '!' | '<' | '>' => {
+                chars.next();
+                let followed_by_eq = chars.next_if_eq(&'=').is_some();
+                let operator = match (c, followed_by_eq) {
+                    ('=', false) => Operator::Eq,
+                    ('!', true) => Operator::NotEq,
+                    ('<', false) => Operator::Lt,
+                    ('<', true) => Operator::LtEq,
+                    ('>', false) => Operator::Gt,
+                    ('>', true) => Operator::GtEq,
+                    _ => return Err(format!("Unknown operator `{}` in segment filter.", c)),
+                };
+                tokens.push(Token::Operator(operator));
+            }
+            '"' => {
+                chars.next();
+                let mut literal = String::new();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some(escaped @ ('"' | '\\')) => literal.push(escaped),
+                            _ => return Err("Invalid escape in segment filter.".into()),
+                        },
+                        Some(c) => literal.push(c),
+                        None => return Err("Unterminated string in segment filter.".into()),
+                    }
+                }
+                tokens.push(Token::String(literal));
+            }
+            c if c.is_ascii_digit() || c == '-' => {
+                let mut literal = String::new();
+                while let Some(c) = chars.next_if(|c| c.is_ascii_digit() || *c == '.' || *c == '-')
+                {
+                    literal.push(c);
+                }
+                let number = literal
+                    .parse::<f64>()
+                    .map_err(|_| format!("{} is not a valid number.", literal))?;
+                tokens.push(Token::Number(number));
+            }
+            c if c.is_ascii_alphabetic() || c == '_' => {
+                let mut identifier = String::new();
+                while let Some(c) =
+                    chars.next_if(|c| c.is_ascii_alphanumeric() || "_.".contains(*c))
+                {
+                    identifier.push(c);
+                }
+                tokens.push(Token::Identifier(identifier));
+            }
+            other => {
+                return Err(format!(
+                    "Unexpected character `{}` in segment filter.",
+                    other
+                ))
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+    depth: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn next(&mut self) -> Result<Token, String> {
+        let token = self
+            .tokens
+            .get(self.position)
+            .cloned()
+            .ok_or_else(|| "Unexpected end of segment filter.".to_string())?;
+        self.position += 1;
+        Ok(token)
+    }
+
+    fn next_is_keyword(&mut self, keyword: &str) -> bool {
+        match self.peek() {
+            Some(Token::Identifier(s)) if s.eq_ignore_ascii_case(keyword) => {
+                self.position += 1;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn expect(&mut self, expected: Token) -> Result<(), String> {
+        let token = self.next()?;
+        if token == expected {
+            Ok(())
+        } else {
+            Err(format!("Expected {}, found {}.", expected, token))
+        }
+    }
+
+    fn parse_or(&mut self) -> Result<SegmentFilter, String> {
+        let mut filter = self.parse_and()?;
+        while self.next_is_keyword("or") {
+            let right = self.parse_and()?;
+            filter = SegmentFilter::Or(Box::new(filter), Box::new(right));
+        }
+        Ok(filter)
+    }
+
+    fn parse_and(&mut self) -> Result<SegmentFilter, String> {
+        let mut filter = self.parse_unary()?;
+        while self.next_is_keyword("and") {
+            let right = self.parse_unary()?;
+            filter = SegmentFilter::And(Box::new(filter), Box::new(right));
+        }
+        Ok(filter)
+    }
+
+    fn parse_unary(&mut self) -> Result<SegmentFilter, String> {
+        self.depth += 1;
+        if self.depth > MAX_NESTING_DEPTH {
+            return Err("Segment filter is nested too deeply.".into());
+        }
+        let filter = if self.next_is_keyword("not") {
+            SegmentFilter::Not(Box::new(self.parse_unary()?))
+        } else if self.peek() == Some(&Token::LeftParen) {
+            self.position += 1;
+            let filter = self.parse_or()?;
+            self.expect(Token::RightParen)?;
+            filter
+        } else {
+            self.parse_comparison()?
+        };
+        self.depth -= 1;
+        Ok(filter)
+    }
+
+    fn parse_comparison(&mut self) -> Result<SegmentFilter, String> {
+        let field = match self.next()? {
+            Token::Identifier(field) => field,
+            other => return Err(format!("Expected a field, found {}.", other)),
+        };
+
+        if field == "subscribed_at" {
+            let operator = match self.next()? {
+                Token::Operator(operator) => operator,
+                other => return Err(format!("Expected an operator, found {}.", other)),
+            };
+            return match self.next()? {
+                Token::String(s) => DateTime::parse_from_rfc3339(&s)
+                    .map(|at| SegmentFilter::SubscribedAt(operator, at.with_timezone(&Utc)))
+                    .map_err(|_| format!("{} is not a valid RFC 3339 timestamp.", s)),
+                other => Err(format!(
+                    "`subscribed_at` must be compared to a timestamp, found {}.",
+                    other
+                )),
+            };
+        }
+
+        let key = match field.strip_prefix("attributes.") {
+            Some(key) if is_valid_attribute_key(key) => key.to_owned(),
+            _ => return Err(format!("{} is not a valid field.", field)),
+        };
+        if self.next_is_keyword("in") {
+            self.expect(Token::LeftParen)?;
+            let mut values = vec![self.parse_value()?];
+            while self.peek() == Some(&Token::Comma) {
+                self.position += 1;
+                values.push(self.parse_value()?);
+            }
+            self.expect(Token::RightParen)?;
+            return Ok(SegmentFilter::AttributeIn(key, values));
+        }
+        let operator = match self.next()? {
+            Token::Operator(operator) => operator,
+            other => return Err(format!("Expected an operator, found {}.", other)),
+        };
+        let value = self.parse_value()?;
+        let is_ordering = !matches!(operator, Operator::Eq | Operator::NotEq);
+        if is_ordering && matches!(value, Value::Bool(_)) {
+            return Err("Booleans can only be compared with `=` and `!=`.".into());
+        }
+        Ok(SegmentFilter::Attribute(key, operator, value))
+    }
+
+    fn parse_value(&mut self) -> Result<Value, String> {
+        match self.next()? {
+            Token::String(s) => Ok(Value::String(s)),
+            Token::Number(n) => Ok(Value::Number(n)),
+            Token::Identifier(s) if s.eq_ignore_ascii_case("true") => Ok(Value::Bool(true)),
+            Token::Identifier(s) if s.eq_ignore_ascii_case("false") => Ok(Value::Bool(false)),
+            other => Err(format!("Expected a value, found {}.", other)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Operator, SegmentFilter, Value};
+    use claims::{assert_err, assert_ok};
+    use sqlx::{Postgres, QueryBuilder};
+
+    fn attribute(key: &str, operator: Operator, value: Value) -> SegmentFilter {
+        SegmentFilter::Attribute(key.into(), operator, value)
+    }
+
+    #[test]
+    fn a_simple_comparison_is_parsed() {
+        let filter = SegmentFilter::parse(r#"attributes.country = "FR""#.into()).unwrap();
+        assert_eq!(
+            attribute("country", Operator::Eq, Value::String("FR".into())),
+            filter
+        );
+    }
+
+    #[test]
+    fn and_binds_tighter_than_or() {
+        let filter = SegmentFilter::parse(
+            "attributes.a = 1 or attributes.b = 2 AND attributes.c = 3".into(),
+        )
+        .unwrap();
+        assert_eq!(
+            SegmentFilter::Or(
+                Box::new(attribute("a", Operator::Eq, Value::Number(1.0))),
+                Box::new(SegmentFilter::And(
+                    Box::new(attribute("b", Operator::Eq, Value::Number(2.0))),
+                    Box::new(attribute("c", Operator::Eq, Value::Number(3.0))),
+                ))
+            ),
+            filter
+        );
+    }
+
+    #[test]
+    fn parentheses_not_and_in_are_parsed() {
+        let filter = SegmentFilter::parse(
+            r#"not (attributes.plan in ("free", "trial") or attributes.beta != true)"#.into(),
+        )
+        .unwrap();
+        assert_eq!(
+            SegmentFilter::Not(Box::new(SegmentFilter::Or(
+                Box::new(SegmentFilter::AttributeIn(
+                    "plan".into(),
+                    vec![Value::String("free".into()), Value::String("trial".into())]
+                )),
+                Box::new(attribute("beta", Operator::NotEq, Value::Bool(true))),
+            ))),
+            filter
+        );
+    }
+
+    #[test]
+    fn subscribed_at_requires_a_timestamp() {
+        assert_ok!(SegmentFilter::parse(
+            r#"subscribed_at >= "2024-01-01T00:00:00Z""#.into()
+        ));
+        assert_err!(SegmentFilter::parse(
+            r#"subscribed_at >= "yesterday""#.into()
+        ));
+        assert_err!(SegmentFilter::parse("subscribed_at >= 5".into()));
+    }
+
+    #[test]
+    fn invalid_filters_are_rejected() {
+        for filter in [
+            "",
+            "attributes.country",
+            r#"attributes.country = "FR" and"#,
+            r#"(attributes.country = "FR""#,
+            r#"attributes.country = "FR")"#,
+            r#"email = "a@b.com""#,
+            r#"attributes.country; DROP TABLE subscriptions = "FR""#,
+            r#"attributes.country = "unterminated"#,
+            "attributes.beta > true",
+            "attributes.plan in ()",
+        ] {
+            assert_err!(SegmentFilter::parse(filter.into()), "{}", filter);
+        }
+    }
+
+    #[test]
+    fn deeply_nested_filters_are_rejected() {
+        let filter = format!("{}attributes.a = 1{}", "(".repeat(100), ")".repeat(100));
+        assert_err!(SegmentFilter::parse(filter));
+        let filter = format!("{}attributes.a = 1", "not ".repeat(100));
+        assert_err!(SegmentFilter::parse(filter));
+    }
+
+    #[test]
+    fn literals_are_bound_as_parameters() {
+        let filter = SegmentFilter::parse(
+            r#"attributes.country = "FR'; DROP TABLE subscriptions; --" or attributes.age > 18"#
+                .into(),
+        )
+        .unwrap();
+        let mut builder = QueryBuilder::<Postgres>::new("");
+        filter.push_sql(&mut builder);
+        let sql = builder.sql();
+        assert!(!sql.contains("DROP TABLE"), "{}", sql);
+        assert!(!sql.contains("country"), "{}", sql);
+        assert!(sql.contains("$1") && sql.contains("$5"), "{}", sql);
+    }
+}
@@ -0,0 +1,102 @@
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+const MAX_ATTRIBUTES: usize = 32;
+const MAX_KEY_LENGTH: usize = 64;
+const MAX_STRING_LENGTH: usize = 256;
+
+/// Free-form facts about a subscriber (country, plan, signup source...),
+/// stored as a flat JSON object of strings, numbers and booleans.
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct SubscriberAttributes(Map<String, Value>);
+
+impl SubscriberAttributes {
+    pub fn parse(attributes: Map<String, Value>) -> Result<SubscriberAttributes, String> {
+        if attributes.len() > MAX_ATTRIBUTES {
+            return Err(format!(
+                "Subscribers cannot have more than {} attributes.",
+                MAX_ATTRIBUTES
+            ));
+        }
+        for (key, value) in &attributes {
+            if !is_valid_attribute_key(key) {
+                return Err(format!("{} is not a valid attribute name.", key));
+            }
+            let is_valid_value = match value {
+                Value::String(s) => s.chars().count() <= MAX_STRING_LENGTH,
+                Value::Number(_) | Value::Bool(_) => true,
+                Value::Null | Value::Array(_) | Value::Object(_) => false,
+            };
+            if !is_valid_value {
+                return Err(format!("The value of the {} attribute is not valid.", key));
+            }
+        }
+        Ok(Self(attributes))
+    }
+
+    pub fn as_json(&self) -> Value {
+        Value::Object(self.0.clone())
+    }
+}
+
+/// Attribute names are identifiers: ASCII letters, digits and underscores,
+/// not starting with a digit.
+pub fn is_valid_attribute_key(key: &str) -> bool {
+    let starts_with_letter = key
+        .chars()
+        .next()
+        .map(|c| c.is_ascii_alphabetic() || c == '_')
+        .unwrap_or(false);
+    starts_with_letter
+        && key.len() <= MAX_KEY_LENGTH
+        && key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::domain::SubscriberAttributes;
+    use claims::{assert_err, assert_ok};
+    use serde_json::{json, Map, Value};
+
+    fn object(value: Value) -> Map<String, Value> {
+        value.as_object().unwrap().clone()
+    }
+
+    #[test]
+    fn flat_scalar_attributes_are_accepted() {
+        let attributes = object(json!({ "country": "FR", "seats": 3, "beta": true }));
+        assert_ok!(SubscriberAttributes::parse(attributes));
+    }
+
+    #[test]
+    fn nested_or_null_values_are_rejected() {
+        for value in [
+            json!({ "a": { "b": 1 } }),
+            json!({ "a": [1] }),
+            json!({ "a": null }),
+        ] {
+            assert_err!(SubscriberAttributes::parse(object(value)));
+        }
+    }
+
+    #[test]
+    fn invalid_attribute_names_are_rejected() {
+        for key in ["", "1st", "signup source", "plan.tier", "country'"] {
+            let mut attributes = Map::new();
+            attributes.insert(key.to_string(), json!("x"));
+            assert_err!(SubscriberAttributes::parse(attributes));
+        }
+    }
+
+    #[test]
+    fn too_many_attributes_are_rejected() {
+        let attributes = (0..33).map(|i| (format!("a{}", i), json!(i))).collect();
+        assert_err!(SubscriberAttributes::parse(attributes));
+    }
+
+    #[test]
+    fn long_string_values_are_rejected() {
+        let attributes = object(json!({ "source": "a".repeat(257) }));
+        assert_err!(SubscriberAttributes::parse(attributes));
+    }
+}
@@ -1,22 +1,164 @@
 use serde::{Deserialize, Serialize};
-use validator::validate_email;
+use unicode_general_category::{get_general_category, GeneralCategory};
 
+/// RFC 5321 limits, in octets. The address limit is the 256 octets of a
+/// forward path minus its angle brackets.
+const MAX_ADDRESS_LENGTH: usize = 254;
+const MAX_LOCAL_PART_LENGTH: usize = 64;
+const MAX_DOMAIN_LENGTH: usize = 253;
+const MAX_LABEL_LENGTH: usize = 63;
+
+/// An email address as the subscriber typed it, along with its canonical
+/// form.
+///
+/// The display form is what we send emails to. The canonical form is what
+/// we compare: two addresses reaching the same mailbox, like
+/// `George@Gmail.com` and `george+news@gmail.com`, share it.
 #[derive(Debug, Deserialize, Serialize, Clone)]
-pub struct SubscriberEmail(String);
+pub struct SubscriberEmail {
+    display: String,
+    canonical: String,
+}
 
 impl SubscriberEmail {
     pub fn parse(s: String) -> Result<SubscriberEmail, String> {
-        if validate_email(&s) {
-            Ok(Self(s))
-        } else {
-            Err(format!("{} is not a valid subscriber email.", s))
-        }
+        let display = s.trim();
+        let (local_part, domain) = split_address(display)
+            .map_err(|reason| format!("{} is not a valid subscriber email: {}", s, reason))?;
+        let canonical = match Provider::for_domain(&domain) {
+            Some(provider) => provider.canonical_address(local_part, &domain),
+            None => format!("{}@{}", local_part, domain),
+        };
+        Ok(Self {
+            display: display.to_string(),
+            canonical,
+        })
+    }
+
+    /// The form of the address used to detect duplicates and look up
+    /// suppressions.
+    pub fn canonical(&self) -> &str {
+        &self.canonical
+    }
+
+    /// The domain of the address, lowercased and punycode-encoded.
+    pub fn domain(&self) -> &str {
+        // `parse` guarantees there is an `@`
+        self.canonical.rsplit_once('@').unwrap().1
     }
 }
 
 impl AsRef<str> for SubscriberEmail {
     fn as_ref(&self) -> &str {
-        &self.0
+        &self.display
+    }
+}
+
+/// Checks `address` is a mailbox we can send to and returns its local part
+/// and its domain in ASCII form, lowercased and punycode-encoded.
+///
+/// Follows RFC 5321, with the RFC 6531 extensions allowing UTF-8 in both
+/// parts. Quoted local parts (`"john doe"@example.com`), address literals
+/// (`john@[127.0.0.1]`) and dotless domains (`john@localhost`) are valid
+/// there but not on the public internet, so we reject them.
+fn split_address(address: &str) -> Result<(&str, String), &'static str> {
+    if address.len() > MAX_ADDRESS_LENGTH {
+        return Err("the address is too long");
+    }
+    let (local_part, domain) = address.rsplit_once('@').ok_or("the @ is missing")?;
+    if local_part.is_empty() || local_part.len() > MAX_LOCAL_PART_LENGTH {
+        return Err("the part before the @ must be between 1 and 64 octets long");
+    }
+    let is_valid_local_part = local_part
+        .split('.')
+        .all(|atom| !atom.is_empty() && atom.chars().all(is_atom_character));
+    if !is_valid_local_part {
+        return Err("the part before the @ contains invalid characters");
+    }
+    // UTS #46 mapping also lowercases the domain, but it does not enforce
+    // the DNS syntax: we check the labels ourselves.
+    let domain = idna::domain_to_ascii(domain).map_err(|_| "the domain is not valid")?;
+    if domain.is_empty() || domain.len() > MAX_DOMAIN_LENGTH {
+        return Err("the domain must be between 1 and 253 characters long");
+    }
+    let labels: Vec<&str> = domain.split('.').collect();
+    if labels.len() < 2 || !labels.iter().all(|label| is_valid_label(label)) {
+        return Err("the domain is not valid");
+    }
+    // Guaranteed to exist by the check above
+    let top_level_domain = labels[labels.len() - 1];
+    if top_level_domain.chars().all(|c| c.is_ascii_digit()) {
+        return Err("the domain is not valid");
+    }
+    Ok((local_part, domain))
+}
+
+/// `atext` from RFC 5322, extended with any non-ASCII character by RFC 6531.
+///
+/// Invisible formatting characters, e.g. bidi overrides or zero-width
+/// spaces, are left out: they make an address look like another one. The
+/// zero-width joiner and non-joiner are kept, some scripts need them.
+fn is_atom_character(c: char) -> bool {
+    if c.is_ascii() {
+        c.is_ascii_alphanumeric() || "!#$%&'*+-/=?^_`{|}~".contains(c)
+    } else {
+        let is_format = get_general_category(c) == GeneralCategory::Format
+            && !matches!(c, '\u{200C}' | '\u{200D}');
+        !c.is_control() && !c.is_whitespace() && !is_format
+    }
+}
+
+/// Letters, digits and hyphens, not starting or ending with a hyphen.
+fn is_valid_label(label: &str) -> bool {
+    !label.is_empty()
+        && label.len() <= MAX_LABEL_LENGTH
+        && !label.starts_with('-')
+        && !label.ends_with('-')
+        && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+}
+
+/// Mailbox providers whose addressing rules we know.
+///
+/// The list is fixed on purpose: canonical forms are stored behind a unique
+/// index, changing the rules would make existing rows disagree with new ones.
+enum Provider {
+    /// Ignores dots in the local part and anything after a `+`.
+    Gmail,
+    /// Ignores anything after a `+`.
+    PlusAddressing,
+    /// Only case-insensitive.
+    CaseInsensitive,
+}
+
+impl Provider {
+    fn for_domain(domain: &str) -> Option<Self> {
+        match domain {
+            "gmail.com" | "googlemail.com" => Some(Self::Gmail),
+            "outlook.com" | "hotmail.com" | "live.com" | "icloud.com" | "me.com"
+            | "fastmail.com" | "protonmail.com" | "proton.me" => Some(Self::PlusAddressing),
+            "yahoo.com" | "aol.com" => Some(Self::CaseInsensitive),
+            _ => None,
+        }
+    }
+
+    fn canonical_address(&self, local_part: &str, domain: &str) -> String {
+        let local_part = local_part.to_lowercase();
+        match self {
+            Self::Gmail => {
+                let local_part = strip_tag(&local_part).replace('.', "");
+                // Both domains are the same mailboxes
+                format!("{}@gmail.com", local_part)
+            }
+            Self::PlusAddressing => format!("{}@{}", strip_tag(&local_part), domain),
+            Self::CaseInsensitive => format!("{}@{}", local_part, domain),
+        }
+    }
+}
+
+fn strip_tag(local_part: &str) -> &str {
+    match local_part.split_once('+') {
+        Some((mailbox, _)) if !mailbox.is_empty() => mailbox,
+        _ => local_part,
     }
 }
 
@@ -42,6 +184,92 @@ mod tests {
         SubscriberEmail::parse(valid_email.0).is_ok()
     }
 
+    // The corpus is shared with the integration tests
+    const VALID_EMAILS: &str = include_str!("../../tests/fixtures/emails/valid.txt");
+    const INVALID_EMAILS: &str = include_str!("../../tests/fixtures/emails/invalid.txt");
+
+    fn corpus(contents: &str) -> Vec<&str> {
+        contents
+            .lines()
+            .filter(|line| !line.is_empty() && !line.starts_with("# "))
+            .collect()
+    }
+
+    #[test]
+    fn the_valid_corpus_is_accepted() {
+        for email in corpus(VALID_EMAILS) {
+            assert!(
+                SubscriberEmail::parse(email.to_string()).is_ok(),
+                "{} was rejected",
+                email
+            );
+        }
+    }
+
+    #[test]
+    fn the_invalid_corpus_is_rejected() {
+        for email in corpus(INVALID_EMAILS) {
+            assert!(
+                SubscriberEmail::parse(email.to_string()).is_err(),
+                "{} was accepted",
+                email
+            );
+        }
+    }
+
+    #[quickcheck_macros::quickcheck]
+    fn canonical_forms_parse_to_themselves(index: usize) -> bool {
+        let valid = corpus(VALID_EMAILS);
+        let email = SubscriberEmail::parse(valid[index % valid.len()].to_string()).unwrap();
+        SubscriberEmail::parse(email.canonical().to_string())
+            .map(|reparsed| reparsed.canonical() == email.canonical())
+            .unwrap_or(false)
+    }
+
+    #[quickcheck_macros::quickcheck]
+    fn canonical_forms_ignore_the_case_of_the_domain(index: usize, uppercase: Vec<bool>) -> bool {
+        let valid = corpus(VALID_EMAILS);
+        let address = valid[index % valid.len()];
+        let (local_part, domain) = address.rsplit_once('@').unwrap();
+        let domain: String = domain
+            .chars()
+            .zip(uppercase.into_iter().chain(std::iter::repeat(false)))
+            .map(|(c, upper)| if upper { c.to_ascii_uppercase() } else { c })
+            .collect();
+        let original = SubscriberEmail::parse(address.to_string()).unwrap();
+        SubscriberEmail::parse(format!("{}@{}", local_part, domain))
+            .map(|email| email.canonical() == original.canonical())
+            .unwrap_or(false)
+    }
+
+    #[quickcheck_macros::quickcheck]
+    fn a_control_character_inside_an_address_is_rejected(
+        index: usize,
+        position: usize,
+        control: u8,
+    ) -> bool {
+        let valid = corpus(VALID_EMAILS);
+        let chars: Vec<char> = valid[index % valid.len()].chars().collect();
+        // Surrounding whitespace is trimmed, only look inside the address
+        let position = 1 + position % (chars.len() - 1);
+        let mut email: String = chars[..position].iter().collect();
+        email.push((control % 32) as char);
+        email.extend(&chars[position..]);
+        SubscriberEmail::parse(email).is_err()
+    }
+
+    #[quickcheck_macros::quickcheck]
+    fn tagged_gmail_addresses_share_the_canonical_form(tag: String) -> bool {
+        let tag: String = tag
+            .chars()
+            .filter(|c| c.is_ascii_alphanumeric())
+            .take(50)
+            .collect();
+        SubscriberEmail::parse(format!("ursula+{}@gmail.com", tag))
+            .map(|email| email.canonical() == "ursula@gmail.com")
+            .unwrap_or(false)
+    }
+
     #[test]
     fn empty_string_is_rejected() {
         let email = "".to_string();
@@ -59,4 +287,51 @@ mod tests {
         let email = "@domain.com".to_string();
         assert_err!(SubscriberEmail::parse(email));
     }
+
+    #[test]
+    fn surrounding_whitespace_is_trimmed() {
+        let email = SubscriberEmail::parse("  ursula@domain.com \n".to_string()).unwrap();
+        assert_eq!(email.as_ref(), "ursula@domain.com");
+    }
+
+    #[test]
+    fn the_domain_is_lowercased_but_the_display_form_is_kept() {
+        let email = SubscriberEmail::parse("Ursula@Domain.COM".to_string()).unwrap();
+        assert_eq!(email.as_ref(), "Ursula@Domain.COM");
+        assert_eq!(email.canonical(), "Ursula@domain.com");
+    }
+
+    #[test]
+    fn internationalized_domains_are_converted_to_punycode() {
+        let email = SubscriberEmail::parse("ursula@bücher.example".to_string()).unwrap();
+        assert_eq!(email.as_ref(), "ursula@bücher.example");
+        assert_eq!(email.canonical(), "ursula@xn--bcher-kva.example");
+    }
+
+    #[test]
+    fn gmail_addresses_ignore_case_dots_and_tags() {
+        for email in [
+            "George@Gmail.com",
+            "g.e.o.r.g.e@gmail.com",
+            "george+newsletter@gmail.com",
+            "Geo.rge+x@googlemail.com",
+        ] {
+            let email = SubscriberEmail::parse(email.to_string()).unwrap();
+            assert_eq!(email.canonical(), "george@gmail.com");
+        }
+    }
+
+    #[test]
+    fn tags_are_only_stripped_for_providers_supporting_them() {
+        let outlook = SubscriberEmail::parse("Ursula+news@outlook.com".to_string()).unwrap();
+        assert_eq!(outlook.canonical(), "ursula@outlook.com");
+        let other = SubscriberEmail::parse("ursula+news@domain.com".to_string()).unwrap();
+        assert_eq!(other.canonical(), "ursula+news@domain.com");
+    }
+
+    #[test]
+    fn a_tag_alone_is_not_stripped() {
+        let email = SubscriberEmail::parse("+tag@gmail.com".to_string()).unwrap();
+        assert_eq!(email.canonical(), "+tag@gmail.com");
+    }
 }
@@ -1,28 +1,116 @@
 use serde::{Deserialize, Serialize};
+use unicode_normalization::UnicodeNormalization;
 use unicode_segmentation::UnicodeSegmentation;
 
+/// Used when the settings do not say otherwise.
+const DEFAULT_MAX_NAME_LENGTH: usize = 256;
+
+const FORBIDDEN_CHARACTERS: [char; 9] = ['/', '(', ')', '"', '<', '>', '\\', '{', '}'];
+
 #[derive(Debug, Deserialize, Serialize)]
 pub struct SubscriberName(String);
 
+/// Why a subscriber name was rejected.
+#[derive(Debug, PartialEq, Eq)]
+pub enum SubscriberNameError {
+    Empty,
+    TooLong {
+        max_length: usize,
+    },
+    ForbiddenCharacter(char),
+    ControlCharacter(char),
+    /// Characters changing the direction of the text around them, which can
+    /// make a name display differently from what it contains.
+    BidiControl(char),
+    ZeroWidthCharacter(char),
+}
+
+impl std::fmt::Display for SubscriberNameError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Empty => write!(f, "The name is empty."),
+            Self::TooLong { max_length } => {
+                write!(f, "The name is longer than {} characters.", max_length)
+            }
+            Self::ForbiddenCharacter(c) => {
+                write!(f, "The name contains a forbidden character: {}", c)
+            }
+            Self::ControlCharacter(c) => {
+                write!(f, "The name contains a control character: {:?}", c)
+            }
+            Self::BidiControl(c) => {
+                write!(f, "The name contains a text direction control: {:?}", c)
+            }
+            Self::ZeroWidthCharacter(c) => {
+                write!(f, "The name contains a zero-width character: {:?}", c)
+            }
+        }
+    }
+}
+
+impl std::error::Error for SubscriberNameError {}
+
 impl SubscriberName {
     /// Returns an instance of `SubscriberName` if the input satisfies all
-    /// our validation constraints on subscriber names.
-    /// It panics otherwise.
-    pub fn parse(s: String) -> Result<SubscriberName, String> {
-        let is_empty_or_whitespace = s.trim().is_empty();
+    /// our validation constraints on subscriber names, with a length limit
+    /// of `DEFAULT_MAX_NAME_LENGTH`.
+    pub fn parse(s: String) -> Result<SubscriberName, SubscriberNameError> {
+        Self::parse_with_max_length(s, DEFAULT_MAX_NAME_LENGTH)
+    }
+
+    /// The name is normalized to NFC, with surrounding whitespace removed and
+    /// internal runs of whitespace collapsed to a single space.
+    /// `max_length` counts graphemes.
+    pub fn parse_with_max_length(
+        s: String,
+        max_length: usize,
+    ) -> Result<SubscriberName, SubscriberNameError> {
+        let normalized: String = s.nfc().collect();
+        for c in normalized.chars() {
+            if FORBIDDEN_CHARACTERS.contains(&c) {
+                return Err(SubscriberNameError::ForbiddenCharacter(c));
+            }
+            if is_bidi_control(c) {
+                return Err(SubscriberNameError::BidiControl(c));
+            }
+            if is_zero_width(c) {
+                return Err(SubscriberNameError::ZeroWidthCharacter(c));
+            }
+            // Tabs and line breaks are whitespace, they get collapsed below
+            if c.is_control() && !c.is_whitespace() {
+                return Err(SubscriberNameError::ControlCharacter(c));
+            }
+        }
+        let collapsed = normalized.split_whitespace().collect::<Vec<_>>().join(" ");
+        if collapsed.is_empty() {
+            return Err(SubscriberNameError::Empty);
+        }
         // A grapheme is defined by the Unicode standard as a "user-perceived"
         // character: `å` is a single grapheme, but it is composed of two characters // (`a` and `̊`).
-        let is_too_long = s.graphemes(true).count() > 256;
-        let forbidden_characters = ['/', '(', ')', '"', '<', '>', '\\', '{', '}'];
-        let contains_forbidden_characters = s.chars().any(|g| forbidden_characters.contains(&g));
-        if is_empty_or_whitespace || is_too_long || contains_forbidden_characters {
-            Err(format!("{} is not a valid subscriber name.", s))
-        } else {
-            Ok(Self(s))
+        if collapsed.graphemes(true).count() > max_length {
+            return Err(SubscriberNameError::TooLong { max_length });
         }
+        Ok(Self(collapsed))
     }
 }
 
+/// Explicit directional formatting characters (UAX #9).
+fn is_bidi_control(c: char) -> bool {
+    matches!(
+        c,
+        '\u{061C}' | '\u{200E}' | '\u{200F}' | '\u{202A}'..='\u{202E}' | '\u{2066}'..='\u{2069}'
+    )
+}
+
+/// Invisible characters with no business in a name. The zero-width joiner
+/// and non-joiner are allowed: Persian and Indic names need them.
+fn is_zero_width(c: char) -> bool {
+    matches!(
+        c,
+        '\u{00AD}' | '\u{180E}' | '\u{200B}' | '\u{2060}'..='\u{2064}' | '\u{FEFF}'
+    )
+}
+
 impl AsRef<str> for SubscriberName {
     fn as_ref(&self) -> &str {
         &self.0
@@ -31,7 +119,7 @@ impl AsRef<str> for SubscriberName {
 
 #[cfg(test)]
 mod tests {
-    use crate::domain::SubscriberName;
+    use crate::domain::{SubscriberName, SubscriberNameError};
     use claims::{assert_err, assert_ok};
 
     #[test]
@@ -71,4 +159,64 @@ mod tests {
         let name = "Ursula Le Guin".to_string();
         assert_ok!(SubscriberName::parse(name));
     }
+
+    #[test]
+    fn names_are_normalized_to_nfc() {
+        // `e` followed by a combining acute accent
+        let name = SubscriberName::parse("Rene\u{0301}e".to_string()).unwrap();
+        assert_eq!(name.as_ref(), "Ren\u{00E9}e");
+    }
+
+    #[test]
+    fn whitespace_is_trimmed_and_collapsed() {
+        let name = SubscriberName::parse(" Ursula \t Le\u{00A0}\nGuin  ".to_string()).unwrap();
+        assert_eq!(name.as_ref(), "Ursula Le Guin");
+    }
+
+    #[test]
+    fn control_characters_are_rejected() {
+        assert_eq!(
+            SubscriberName::parse("Ursula\u{0007}".to_string()).unwrap_err(),
+            SubscriberNameError::ControlCharacter('\u{0007}')
+        );
+    }
+
+    #[test]
+    fn bidi_controls_are_rejected() {
+        for c in ['\u{202E}', '\u{2066}', '\u{200F}'] {
+            assert_eq!(
+                SubscriberName::parse(format!("Ursula{}niug", c)).unwrap_err(),
+                SubscriberNameError::BidiControl(c)
+            );
+        }
+    }
+
+    #[test]
+    fn zero_width_characters_are_rejected() {
+        for c in ['\u{200B}', '\u{2060}', '\u{FEFF}'] {
+            assert_eq!(
+                SubscriberName::parse(format!("Urs{}ula", c)).unwrap_err(),
+                SubscriberNameError::ZeroWidthCharacter(c)
+            );
+        }
+    }
+
+    #[test]
+    fn joiners_used_by_some_scripts_are_accepted() {
+        assert_ok!(SubscriberName::parse(
+            "\u{0645}\u{06CC}\u{200C}\u{0631}".to_string()
+        ));
+    }
+
+    #[test]
+    fn the_maximum_length_is_configurable() {
+        assert_ok!(SubscriberName::parse_with_max_length(
+            "Ursula".to_string(),
+            6
+        ));
+        assert_eq!(
+            SubscriberName::parse_with_max_length("Ursula".to_string(), 5).unwrap_err(),
+            SubscriberNameError::TooLong { max_length: 5 }
+        );
+    }
 }
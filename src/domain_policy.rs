@@ -0,0 +1,359 @@
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::SystemTime;
+
+use crate::configuration::DomainPolicySettings;
+use crate::domain::SubscriberEmail;
+
+/// A subscriber email whose domain we refuse subscriptions from.
+#[derive(Debug)]
+pub struct BlockedDomain {
+    pub domain: String,
+    /// The deny list entry matching the domain.
+    pub rule: String,
+}
+
+impl std::fmt::Display for BlockedDomain {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Subscriptions from {} are not accepted, please use another email address.",
+            self.domain
+        )
+    }
+}
+
+/// Which email domains can subscribe, e.g. to keep out throwaway addresses.
+///
+/// Domains are read from the allow and deny list files referenced in the
+/// settings, one per line. `*.example.com` matches every subdomain of
+/// `example.com`, but not `example.com` itself. Allow list entries win over
+/// deny list entries, so that a single subdomain of a denied domain can be
+/// let through. Domains on neither list are allowed.
+pub struct DomainPolicy {
+    lists: RwLock<Lists>,
+}
+
+struct Lists {
+    allow_list_path: Option<PathBuf>,
+    deny_list_path: Option<PathBuf>,
+    allowed: DomainList,
+    denied: DomainList,
+    modified_at: Vec<Option<SystemTime>>,
+}
+
+impl DomainPolicy {
+    pub fn load(settings: &DomainPolicySettings) -> Result<Self, String> {
+        let lists = read_lists(
+            settings.allow_list_path.clone(),
+            settings.deny_list_path.clone(),
+        )?;
+        Ok(Self {
+            lists: RwLock::new(lists),
+        })
+    }
+
+    /// Check emails against the lists of `policy` from now on, e.g. after
+    /// the settings changed.
+    pub fn replace(&self, policy: DomainPolicy) {
+        *self.lists.write().unwrap() = policy.lists.into_inner().unwrap();
+    }
+
+    /// Returns an error if subscriptions from the domain of `email` are
+    /// not accepted.
+    pub fn check(&self, email: &SubscriberEmail) -> Result<(), BlockedDomain> {
+        let domain = email.domain();
+        let lists = self.lists.read().unwrap();
+        if lists.allowed.find(domain).is_some() {
+            return Ok(());
+        }
+        match lists.denied.find(domain) {
+            Some(rule) => {
+                tracing::warn!("Refusing subscription from a denied domain: {}", domain);
+                Err(BlockedDomain {
+                    domain: domain.to_string(),
+                    rule,
+                })
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Read the lists again if their files changed since they were loaded.
+    ///
+    /// If a file cannot be read, the lists in use are kept.
+    pub fn reload_if_changed(&self) -> Result<bool, String> {
+        let (allow_list_path, deny_list_path) = {
+            let lists = self.lists.read().unwrap();
+            let paths = [&lists.allow_list_path, &lists.deny_list_path];
+            if lists.modified_at == modified_at_all(paths.map(|path| path.as_deref())) {
+                return Ok(false);
+            }
+            (lists.allow_list_path.clone(), lists.deny_list_path.clone())
+        };
+        let reloaded = read_lists(allow_list_path, deny_list_path)?;
+        let mut lists = self.lists.write().unwrap();
+        // The policy may have been replaced while the files were read
+        if lists.allow_list_path != reloaded.allow_list_path
+            || lists.deny_list_path != reloaded.deny_list_path
+        {
+            return Ok(false);
+        }
+        *lists = reloaded;
+        Ok(true)
+    }
+}
+
+/// Reload `policy` when its files change, checking every `interval`.
+pub async fn watch_domain_policy(policy: Arc<DomainPolicy>, interval: std::time::Duration) {
+    let mut ticks = tokio::time::interval(interval);
+    loop {
+        ticks.tick().await;
+        match policy.reload_if_changed() {
+            Ok(true) => tracing::info!("Reloaded the domain policy"),
+            Ok(false) => {}
+            Err(e) => tracing::error!("Failed to reload the domain policy: {}", e),
+        }
+    }
+}
+
+fn modified_at(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+fn modified_at_all(paths: [Option<&Path>; 2]) -> Vec<Option<SystemTime>> {
+    paths
+        .into_iter()
+        .map(|path| path.and_then(modified_at))
+        .collect()
+}
+
+fn read_lists(
+    allow_list_path: Option<PathBuf>,
+    deny_list_path: Option<PathBuf>,
+) -> Result<Lists, String> {
+    let read = |path: Option<&Path>| match path {
+        Some(path) => std::fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read {}: {}", path.display(), e))
+            .and_then(|contents| {
+                DomainList::parse(&contents).map_err(|e| format!("{}: {}", path.display(), e))
+            }),
+        None => Ok(DomainList::default()),
+    };
+    Ok(Lists {
+        allowed: read(allow_list_path.as_deref())?,
+        denied: read(deny_list_path.as_deref())?,
+        modified_at: modified_at_all([allow_list_path.as_deref(), deny_list_path.as_deref()]),
+        allow_list_path,
+        deny_list_path,
+    })
+}
+
+#[derive(Default)]
+struct DomainList {
+    domains: HashSet<String>,
+    /// Parents of the `*.` entries.
+    wildcards: HashSet<String>,
+}
+
+impl DomainList {
+    /// One domain per line. Empty lines and lines starting with `#` are
+    /// ignored.
+    fn parse(contents: &str) -> Result<Self, String> {
+        let mut list = Self::default();
+        for (number, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            // Validate entries like subscriber emails, and store them in the
+            // form `SubscriberEmail::domain` returns. A subdomain is made up
+            // for wildcards so that `*.tk` is accepted.
+            let invalid = || format!("line {} is not a valid domain: {}", number + 1, line);
+            match line.strip_prefix("*.") {
+                Some(parent) => {
+                    let email = SubscriberEmail::parse(format!("postmaster@wildcard.{}", parent))
+                        .map_err(|_| invalid())?;
+                    let parent = email.domain().strip_prefix("wildcard.").unwrap();
+                    list.wildcards.insert(parent.to_string());
+                }
+                None => {
+                    let email = SubscriberEmail::parse(format!("postmaster@{}", line))
+                        .map_err(|_| invalid())?;
+                    list.domains.insert(email.domain().to_string());
+                }
+            }
+        }
+        Ok(list)
+    }
+
+    /// The entry matching `domain`, if any.
+    fn find(&self, domain: &str) -> Option<String> {
+        if self.domains.contains(domain) {
+            return Some(domain.to_string());
+        }
+        let mut parent = domain;
+        while let Some((_, rest)) = parent.split_once('.') {
+            if self.wildcards.contains(rest) {
+                return Some(format!("*.{}", rest));
+            }
+            parent = rest;
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use claims::{assert_err, assert_ok};
+    use tempfile::TempDir;
+    use uuid::Uuid;
+
+    use super::DomainPolicy;
+    use crate::configuration::DomainPolicySettings;
+    use crate::domain::SubscriberEmail;
+
+    fn email(s: &str) -> SubscriberEmail {
+        SubscriberEmail::parse(s.to_string()).unwrap()
+    }
+
+    fn write_list(directory: &TempDir, contents: &str) -> PathBuf {
+        let path = directory.path().join(format!("{}.txt", Uuid::new_v4()));
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+
+    fn policy(directory: &TempDir, allow: &str, deny: &str) -> DomainPolicy {
+        DomainPolicy::load(&DomainPolicySettings {
+            allow_list_path: Some(write_list(directory, allow)),
+            deny_list_path: Some(write_list(directory, deny)),
+            reload_interval_seconds: 1,
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn denied_domains_are_blocked() {
+        let directory = TempDir::new().unwrap();
+        let policy = policy(&directory, "", "# Throwaway addresses\nmailinator.com\n");
+        let error = policy.check(&email("ursula@Mailinator.com")).unwrap_err();
+        assert_eq!(error.rule, "mailinator.com");
+        assert_ok!(policy.check(&email("ursula@gmail.com")));
+    }
+
+    #[test]
+    fn wildcards_match_subdomains_only() {
+        let directory = TempDir::new().unwrap();
+        let policy = policy(&directory, "", "*.throwaway.example\n");
+        assert_err!(policy.check(&email("ursula@a.throwaway.example")));
+        assert_err!(policy.check(&email("ursula@a.b.throwaway.example")));
+        assert_ok!(policy.check(&email("ursula@throwaway.example")));
+        assert_ok!(policy.check(&email("ursula@notthrowaway.example")));
+    }
+
+    #[test]
+    fn allowed_domains_win_over_denied_ones() {
+        let directory = TempDir::new().unwrap();
+        let policy = policy(&directory, "good.throwaway.example", "*.throwaway.example");
+        assert_ok!(policy.check(&email("ursula@good.throwaway.example")));
+        assert_err!(policy.check(&email("ursula@bad.throwaway.example")));
+    }
+
+    #[test]
+    fn internationalized_entries_match_punycode_domains() {
+        let directory = TempDir::new().unwrap();
+        let policy = policy(&directory, "", "bücher.example");
+        assert_err!(policy.check(&email("ursula@BÜCHER.example")));
+    }
+
+    #[test]
+    fn top_level_domains_can_be_denied() {
+        let directory = TempDir::new().unwrap();
+        let policy = policy(&directory, "", "*.tk");
+        assert_err!(policy.check(&email("ursula@free.tk")));
+    }
+
+    #[test]
+    fn invalid_entries_are_rejected() {
+        let directory = TempDir::new().unwrap();
+        let settings = DomainPolicySettings {
+            allow_list_path: None,
+            deny_list_path: Some(write_list(&directory, "mailinator.com\nmail*.com\n")),
+            reload_interval_seconds: 1,
+        };
+        assert!(DomainPolicy::load(&settings).is_err());
+    }
+
+    #[test]
+    fn missing_files_are_rejected() {
+        let directory = TempDir::new().unwrap();
+        let settings = DomainPolicySettings {
+            allow_list_path: None,
+            deny_list_path: Some(directory.path().join(Uuid::new_v4().to_string())),
+            reload_interval_seconds: 1,
+        };
+        assert!(DomainPolicy::load(&settings).is_err());
+    }
+
+    #[test]
+    fn changed_files_are_reloaded() {
+        let directory = TempDir::new().unwrap();
+        let deny_list_path = write_list(&directory, "mailinator.com");
+        let policy = DomainPolicy::load(&DomainPolicySettings {
+            allow_list_path: None,
+            deny_list_path: Some(deny_list_path.clone()),
+            reload_interval_seconds: 1,
+        })
+        .unwrap();
+        assert_eq!(policy.reload_if_changed(), Ok(false));
+
+        // Modification times are not precise enough to tell writes apart
+        // within a test, force the change
+        std::fs::write(&deny_list_path, "yopmail.com").unwrap();
+        policy.lists.write().unwrap().modified_at.clear();
+
+        assert_eq!(policy.reload_if_changed(), Ok(true));
+        assert_ok!(policy.check(&email("ursula@mailinator.com")));
+        assert_err!(policy.check(&email("ursula@yopmail.com")));
+    }
+
+    #[test]
+    fn replaced_policies_check_against_the_new_lists() {
+        let directory = TempDir::new().unwrap();
+        let policy = policy(&directory, "", "mailinator.com");
+
+        policy.replace(
+            DomainPolicy::load(&DomainPolicySettings {
+                allow_list_path: None,
+                deny_list_path: Some(write_list(&directory, "yopmail.com")),
+                reload_interval_seconds: 1,
+            })
+            .unwrap(),
+        );
+
+        assert_ok!(policy.check(&email("ursula@mailinator.com")));
+        assert_err!(policy.check(&email("ursula@yopmail.com")));
+        assert_eq!(policy.reload_if_changed(), Ok(false));
+    }
+
+    #[test]
+    fn broken_reloads_keep_the_current_lists() {
+        let directory = TempDir::new().unwrap();
+        let deny_list_path = write_list(&directory, "mailinator.com");
+        let policy = DomainPolicy::load(&DomainPolicySettings {
+            allow_list_path: None,
+            deny_list_path: Some(deny_list_path.clone()),
+            reload_interval_seconds: 1,
+        })
+        .unwrap();
+
+        std::fs::write(&deny_list_path, "not a domain").unwrap();
+        policy.lists.write().unwrap().modified_at.clear();
+
+        assert_err!(policy.reload_if_changed());
+        assert_err!(policy.check(&email("ursula@mailinator.com")));
+    }
+}
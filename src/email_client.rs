@@ -1,12 +1,59 @@
 use crate::domain::SubscriberEmail;
+use crate::suppression_list::{SuppressionList, SuppressionReason};
 use reqwest::Client;
+use secrecy::{ExposeSecret, Secret};
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
 
 #[derive(Clone)]
 pub struct EmailClient {
     http_client: Client,
     base_url: String,
     sender: SubscriberEmail,
-    authorization_token: Secret<String>,
+    // Shared by the clones, so that rotating it reaches all of them
+    authorization_token: Arc<RwLock<Secret<String>>>,
+    suppression_list: Arc<dyn SuppressionList>,
+}
+
+#[derive(Debug)]
+pub enum SendEmailError {
+    /// The recipient is on the suppression list, nothing was sent.
+    Suppressed(SuppressionReason),
+    /// We could not tell whether the recipient is suppressed, so nothing was sent.
+    SuppressionCheck(sqlx::Error),
+    Request(reqwest::Error),
+}
+
+impl std::fmt::Display for SendEmailError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Suppressed(reason) => write!(
+                f,
+                "The recipient is suppressed ({}), refusing to send.",
+                reason.as_str()
+            ),
+            Self::SuppressionCheck(_) => {
+                write!(f, "Failed to check whether the recipient is suppressed.")
+            }
+            Self::Request(_) => write!(f, "Failed to send the email."),
+        }
+    }
+}
+
+impl std::error::Error for SendEmailError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Suppressed(_) => None,
+            Self::SuppressionCheck(e) => Some(e),
+            Self::Request(e) => Some(e),
+        }
+    }
+}
+
+impl From<reqwest::Error> for SendEmailError {
+    fn from(e: reqwest::Error) -> Self {
+        Self::Request(e)
+    }
 }
 
 impl EmailClient {
@@ -14,22 +61,82 @@ impl EmailClient {
         base_url: String,
         sender: SubscriberEmail,
         authorization_token: Secret<String>,
+        suppression_list: Arc<dyn SuppressionList>,
     ) -> Self {
         Self {
             http_client: Client::new(),
             base_url,
             sender,
-            authorization_token,
+            authorization_token: Arc::new(RwLock::new(authorization_token)),
+            suppression_list,
         }
     }
 
+    /// Use `authorization_token` from now on, e.g. once it was rotated.
+    pub fn set_authorization_token(&self, authorization_token: Secret<String>) {
+        *self.authorization_token.write().unwrap() = authorization_token;
+    }
+
     pub async fn send_email(
         &self,
         recipient: SubscriberEmail,
         subject: &str,
         html_content: &str,
         text_content: &str,
-    ) -> Result<(), String> {
+    ) -> Result<(), SendEmailError> {
+        // Never email an address that bounced, complained or asked us to stop,
+        // whoever the caller is.
+        self.refuse_suppressed(&recipient, &[]).await?;
+        self.post_email(recipient, subject, html_content, text_content)
+            .await
+    }
+
+    /// Send an email the recipient just asked for, e.g. a link to confirm
+    /// their address.
+    ///
+    /// Unlike `send_email` it goes through to addresses that unsubscribed:
+    /// they may be asking to come back. Addresses that bounced or complained
+    /// are still refused.
+    pub async fn send_transactional_email(
+        &self,
+        recipient: SubscriberEmail,
+        subject: &str,
+        html_content: &str,
+        text_content: &str,
+    ) -> Result<(), SendEmailError> {
+        self.refuse_suppressed(&recipient, &[SuppressionReason::Unsubscribed])
+            .await?;
+        self.post_email(recipient, subject, html_content, text_content)
+            .await
+    }
+
+    async fn refuse_suppressed(
+        &self,
+        recipient: &SubscriberEmail,
+        allowed: &[SuppressionReason],
+    ) -> Result<(), SendEmailError> {
+        match self.suppression_list.find(recipient).await {
+            Ok(None) => Ok(()),
+            Ok(Some(reason)) if allowed.contains(&reason) => Ok(()),
+            Ok(Some(reason)) => {
+                tracing::warn!(
+                    recipient = %recipient.as_ref(),
+                    reason = reason.as_str(),
+                    "Refusing to send an email to a suppressed recipient"
+                );
+                Err(SendEmailError::Suppressed(reason))
+            }
+            Err(e) => Err(SendEmailError::SuppressionCheck(e)),
+        }
+    }
+
+    async fn post_email(
+        &self,
+        recipient: SubscriberEmail,
+        subject: &str,
+        html_content: &str,
+        text_content: &str,
+    ) -> Result<(), SendEmailError> {
         let url = format!("{}/email", self.base_url);
 
         let request_body = SendEmailRequest {
@@ -40,12 +147,53 @@ impl EmailClient {
             text_body: text_content.to_owned(),
         };
 
-        let builder = self.http_client.post(&url).json(&request_body); //.json does serialization and adds the content-header
+        let authorization_token = self.authorization_token.read().unwrap().clone();
+        self.http_client
+            .post(&url)
+            .header(
+                "X-Postmark-Server-Token",
+                authorization_token.expose_secret(),
+            )
+            .json(&request_body) //.json does serialization and adds the content-header
+            .send()
+            .await?
+            .error_for_status()?;
         Ok(())
     }
 }
 
+/// Read the authorization token of `email_client` again from `path` on every
+/// SIGHUP, so that it can be rotated without a restart.
+///
+/// If the file cannot be read, the token in use is kept.
+#[cfg(unix)]
+pub async fn reload_authorization_token_on_sighup(email_client: EmailClient, path: PathBuf) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangups = match signal(SignalKind::hangup()) {
+        Ok(hangups) => hangups,
+        Err(e) => {
+            tracing::error!("Failed to listen for SIGHUP: {}", e);
+            return;
+        }
+    };
+    while hangups.recv().await.is_some() {
+        match crate::configuration::read_secret_file(&path) {
+            Ok(authorization_token) => {
+                email_client.set_authorization_token(authorization_token);
+                tracing::info!("Reloaded the email authorization token");
+            }
+            Err(e) => tracing::error!(
+                "Failed to reload the email authorization token from {}: {}",
+                path.display(),
+                e
+            ),
+        }
+    }
+}
+
 #[derive(serde::Serialize)]
+#[serde(rename_all = "PascalCase")]
 struct SendEmailRequest {
     from: String,
     to: String,
@@ -56,21 +204,46 @@ struct SendEmailRequest {
 
 #[cfg(test)]
 mod tests {
-    use crate::domain::{SubscriberEmail, SubscriberEmaill};
-    use crate::email_client::EmailClient;
+    use crate::domain::SubscriberEmail;
+    use crate::email_client::{EmailClient, SendEmailError};
+    use crate::suppression_list::{SuppressionList, SuppressionReason};
+    use claims::assert_err;
     use fake::faker::internet::en::SafeEmail;
     use fake::faker::lorem::en::{Paragraph, Sentence};
     use fake::{Fake, Faker};
     use secrecy::Secret;
-    use wiremock::matchers::any;
+    use std::sync::Arc;
+    use wiremock::matchers::{any, header};
     use wiremock::{Mock, MockServer, ResponseTemplate};
 
+    /// Suppresses every address it was built with, for the same reason.
+    struct FixedSuppressionList(Vec<String>, SuppressionReason);
+
+    #[async_trait::async_trait]
+    impl SuppressionList for FixedSuppressionList {
+        async fn find(
+            &self,
+            email: &SubscriberEmail,
+        ) -> Result<Option<SuppressionReason>, sqlx::Error> {
+            Ok(self
+                .0
+                .iter()
+                .any(|e| e == email.canonical())
+                .then_some(self.1))
+        }
+    }
+
     #[tokio::test]
     async fn send_email_fires_a_request_to_base_url() {
         // Setup
         let mock_server = MockServer::start().await;
-        let sender = SubscriberEmaill::parse(SafeEmail.fake()).unwrap();
-        let email_client = EmailClient::new(mock_server.uri(), sender, Secret::new(Faker.fake()));
+        let sender = SubscriberEmail::parse(SafeEmail().fake()).unwrap();
+        let email_client = EmailClient::new(
+            mock_server.uri(),
+            sender,
+            Secret::new(Faker.fake()),
+            Arc::new(FixedSuppressionList(vec![], SuppressionReason::Complaint)),
+        );
 
         Mock::given(any())
             .respond_with(ResponseTemplate::new(200))
@@ -92,4 +265,122 @@ mod tests {
         // With that we are saying that 1 request has to go through
         // before it gets out of scope, if not it panics.
     }
+
+    #[tokio::test]
+    async fn send_email_uses_the_rotated_authorization_token() {
+        // Setup
+        let mock_server = MockServer::start().await;
+        let sender = SubscriberEmail::parse(SafeEmail().fake()).unwrap();
+        let email_client = EmailClient::new(
+            mock_server.uri(),
+            sender,
+            Secret::new("old-token".to_string()),
+            Arc::new(FixedSuppressionList(vec![], SuppressionReason::Complaint)),
+        );
+
+        Mock::given(header("X-Postmark-Server-Token", "new-token"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        // Action
+        // Clones share the token, e.g. the one reloading it on SIGHUP
+        email_client
+            .clone()
+            .set_authorization_token(Secret::new("new-token".to_string()));
+        let outcome = email_client
+            .send_email(
+                SubscriberEmail::parse(SafeEmail().fake()).unwrap(),
+                &Sentence(1..2).fake::<String>(),
+                &Paragraph(1..10).fake::<String>(),
+                &Paragraph(1..10).fake::<String>(),
+            )
+            .await;
+
+        // Assert
+        claims::assert_ok!(outcome);
+    }
+
+    #[tokio::test]
+    async fn send_email_refuses_suppressed_recipients() {
+        // Setup
+        let mock_server = MockServer::start().await;
+        let sender = SubscriberEmail::parse(SafeEmail().fake()).unwrap();
+        let subscriber_email = SubscriberEmail::parse(SafeEmail().fake()).unwrap();
+        let email_client = EmailClient::new(
+            mock_server.uri(),
+            sender,
+            Secret::new(Faker.fake()),
+            Arc::new(FixedSuppressionList(
+                vec![subscriber_email.as_ref().to_owned()],
+                SuppressionReason::Complaint,
+            )),
+        );
+
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(200))
+            .expect(0)
+            .mount(&mock_server)
+            .await;
+
+        let subject: String = Sentence(1..2).fake();
+        let content: String = Paragraph(1..10).fake();
+
+        // Action
+        let outcome = email_client
+            .send_email(subscriber_email, &subject, &content, &content)
+            .await;
+
+        // Assert
+        assert_err!(&outcome);
+        assert!(matches!(
+            outcome,
+            Err(SendEmailError::Suppressed(SuppressionReason::Complaint))
+        ));
+    }
+
+    #[tokio::test]
+    async fn transactional_emails_reach_addresses_that_unsubscribed() {
+        // Setup
+        let mock_server = MockServer::start().await;
+        let sender = SubscriberEmail::parse(SafeEmail().fake()).unwrap();
+        let unsubscribed = SubscriberEmail::parse(SafeEmail().fake()).unwrap();
+        let bounced = SubscriberEmail::parse(SafeEmail().fake()).unwrap();
+        let email_client = |recipient: &SubscriberEmail, reason| {
+            EmailClient::new(
+                mock_server.uri(),
+                sender.clone(),
+                Secret::new(Faker.fake()),
+                Arc::new(FixedSuppressionList(
+                    vec![recipient.as_ref().to_owned()],
+                    reason,
+                )),
+            )
+        };
+
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let subject: String = Sentence(1..2).fake();
+        let content: String = Paragraph(1..10).fake();
+
+        // Action
+        let unsubscribed_outcome = email_client(&unsubscribed, SuppressionReason::Unsubscribed)
+            .send_transactional_email(unsubscribed.clone(), &subject, &content, &content)
+            .await;
+        let bounced_outcome = email_client(&bounced, SuppressionReason::HardBounce)
+            .send_transactional_email(bounced, &subject, &content, &content)
+            .await;
+
+        // Assert
+        assert!(unsubscribed_outcome.is_ok());
+        assert!(matches!(
+            bounced_outcome,
+            Err(SendEmailError::Suppressed(SuppressionReason::HardBounce))
+        ));
+    }
 }
@@ -0,0 +1,378 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use hickory_resolver::error::{ResolveError, ResolveErrorKind};
+use hickory_resolver::TokioAsyncResolver;
+
+use crate::configuration::{EmailDomainCheckMode, EmailDomainCheckSettings};
+use crate::domain::SubscriberEmail;
+
+/// Past this many cached domains, expired entries are dropped.
+const MAX_CACHED_DOMAINS: usize = 10_000;
+
+/// Domains most of our subscribers use, checked for typos.
+const POPULAR_DOMAINS: [&str; 16] = [
+    "gmail.com",
+    "googlemail.com",
+    "yahoo.com",
+    "hotmail.com",
+    "outlook.com",
+    "live.com",
+    "msn.com",
+    "icloud.com",
+    "me.com",
+    "aol.com",
+    "protonmail.com",
+    "proton.me",
+    "fastmail.com",
+    "gmx.com",
+    "mail.com",
+    "yandex.com",
+];
+
+/// The DNS queries needed to tell whether a domain accepts emails.
+///
+/// A trait so that the subscription flow can be exercised without network
+/// access in tests.
+#[async_trait::async_trait]
+pub trait DomainResolver: Send + Sync {
+    /// The mail exchanges listed in the MX records of `domain`, or an empty
+    /// list if it has none.
+    async fn mail_exchanges(&self, domain: &str) -> Result<Vec<String>, ResolveError>;
+
+    /// Whether `domain` has A or AAAA records.
+    async fn has_addresses(&self, domain: &str) -> Result<bool, ResolveError>;
+}
+
+pub struct DnsResolver(TokioAsyncResolver);
+
+impl DnsResolver {
+    /// Uses the name servers of the host, e.g. from `/etc/resolv.conf`.
+    pub fn from_system_conf() -> Result<Self, ResolveError> {
+        TokioAsyncResolver::tokio_from_system_conf().map(Self)
+    }
+}
+
+fn is_no_records_found(e: &ResolveError) -> bool {
+    matches!(e.kind(), ResolveErrorKind::NoRecordsFound { .. })
+}
+
+#[async_trait::async_trait]
+impl DomainResolver for DnsResolver {
+    async fn mail_exchanges(&self, domain: &str) -> Result<Vec<String>, ResolveError> {
+        // The trailing dot stops the resolver from trying search domains
+        match self.0.mx_lookup(format!("{}.", domain)).await {
+            Ok(lookup) => Ok(lookup.iter().map(|mx| mx.exchange().to_ascii()).collect()),
+            Err(e) if is_no_records_found(&e) => Ok(Vec::new()),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn has_addresses(&self, domain: &str) -> Result<bool, ResolveError> {
+        match self.0.lookup_ip(format!("{}.", domain)).await {
+            Ok(lookup) => Ok(lookup.iter().next().is_some()),
+            Err(e) if is_no_records_found(&e) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// A subscriber email whose domain cannot receive emails.
+#[derive(Debug)]
+pub struct UndeliverableDomain {
+    pub domain: String,
+    /// The address the subscriber probably meant, if the domain looks like
+    /// a typo of a popular one.
+    pub suggestion: Option<String>,
+}
+
+impl std::fmt::Display for UndeliverableDomain {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} does not accept emails.", self.domain)?;
+        if let Some(suggestion) = &self.suggestion {
+            write!(f, " Did you mean {}?", suggestion)?;
+        }
+        Ok(())
+    }
+}
+
+/// Checks the domain of new subscriber emails can receive emails, to catch
+/// typos that are syntactically valid like `gmial.com`.
+///
+/// A domain accepts emails if it has MX records, or failing that A/AAAA
+/// records (RFC 5321, section 5.1). Answers are cached. Lookups that fail
+/// or time out let the subscriber through: a DNS outage must not stop
+/// people from subscribing.
+pub struct EmailDomainChecker {
+    resolver: Arc<dyn DomainResolver>,
+    mode: EmailDomainCheckMode,
+    timeout: Duration,
+    cache_ttl: Duration,
+    cache: Mutex<HashMap<String, (Instant, bool)>>,
+}
+
+impl EmailDomainChecker {
+    pub fn new(resolver: Arc<dyn DomainResolver>, settings: &EmailDomainCheckSettings) -> Self {
+        Self {
+            resolver,
+            mode: settings.mode,
+            timeout: settings.timeout(),
+            cache_ttl: settings.cache_ttl(),
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns an error if `email` must be rejected.
+    ///
+    /// In advisory mode undeliverable domains are only logged.
+    #[tracing::instrument(name = "Checking email domain", skip(self, email))]
+    pub async fn check(&self, email: &SubscriberEmail) -> Result<(), UndeliverableDomain> {
+        if self.mode == EmailDomainCheckMode::Off {
+            return Ok(());
+        }
+        let domain = email.domain();
+        if self.accepts_mail(domain).await {
+            return Ok(());
+        }
+        let error = UndeliverableDomain {
+            domain: domain.to_string(),
+            suggestion: suggest_domain(domain).map(|suggestion| {
+                // Keep the local part as the subscriber typed it
+                let (local_part, _) = email.as_ref().rsplit_once('@').unwrap();
+                format!("{}@{}", local_part, suggestion)
+            }),
+        };
+        match self.mode {
+            EmailDomainCheckMode::Enforcing => Err(error),
+            EmailDomainCheckMode::Advisory | EmailDomainCheckMode::Off => {
+                tracing::warn!("Accepting subscriber email anyway: {}", error);
+                Ok(())
+            }
+        }
+    }
+
+    async fn accepts_mail(&self, domain: &str) -> bool {
+        if let Some(accepts_mail) = self.cached(domain) {
+            return accepts_mail;
+        }
+        let lookup = tokio::time::timeout(self.timeout, self.lookup(domain)).await;
+        match lookup {
+            Ok(Ok(accepts_mail)) => {
+                self.cache(domain, accepts_mail);
+                accepts_mail
+            }
+            Ok(Err(e)) => {
+                tracing::warn!("Failed to resolve {}: {:?}", domain, e);
+                true
+            }
+            Err(_) => {
+                tracing::warn!("Timed out resolving {}", domain);
+                true
+            }
+        }
+    }
+
+    async fn lookup(&self, domain: &str) -> Result<bool, ResolveError> {
+        let exchanges = self.resolver.mail_exchanges(domain).await?;
+        if exchanges.is_empty() {
+            return self.resolver.has_addresses(domain).await;
+        }
+        // A single "." exchange is a null MX (RFC 7505): the domain
+        // explicitly refuses emails.
+        Ok(exchanges
+            .iter()
+            .any(|exchange| !exchange.is_empty() && exchange != "."))
+    }
+
+    fn cached(&self, domain: &str) -> Option<bool> {
+        let cache = self.cache.lock().unwrap();
+        cache
+            .get(domain)
+            .filter(|(cached_at, _)| cached_at.elapsed() < self.cache_ttl)
+            .map(|(_, accepts_mail)| *accepts_mail)
+    }
+
+    fn cache(&self, domain: &str, accepts_mail: bool) {
+        let mut cache = self.cache.lock().unwrap();
+        if cache.len() >= MAX_CACHED_DOMAINS {
+            cache.retain(|_, (cached_at, _)| cached_at.elapsed() < self.cache_ttl);
+            if cache.len() >= MAX_CACHED_DOMAINS {
+                cache.clear();
+            }
+        }
+        cache.insert(domain.to_string(), (Instant::now(), accepts_mail));
+    }
+}
+
+/// The popular domain `domain` is most likely a typo of, if any.
+pub fn suggest_domain(domain: &str) -> Option<&'static str> {
+    if POPULAR_DOMAINS.contains(&domain) {
+        return None;
+    }
+    POPULAR_DOMAINS
+        .iter()
+        .map(|candidate| (edit_distance(domain, candidate), *candidate))
+        .filter(|(distance, _)| *distance <= 2)
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, candidate)| candidate)
+}
+
+/// Optimal string alignment distance: insertions, deletions, substitutions
+/// and transpositions of adjacent characters each count as one edit.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+    let mut distances = vec![vec![0; b.len() + 1]; a.len() + 1];
+    for (i, row) in distances.iter_mut().enumerate() {
+        row[0] = i;
+    }
+    for (j, distance) in distances[0].iter_mut().enumerate() {
+        *distance = j;
+    }
+    for i in 1..=a.len() {
+        for j in 1..=b.len() {
+            let cost = usize::from(a[i - 1] != b[j - 1]);
+            let mut distance = (distances[i - 1][j] + 1)
+                .min(distances[i][j - 1] + 1)
+                .min(distances[i - 1][j - 1] + cost);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                distance = distance.min(distances[i - 2][j - 2] + 1);
+            }
+            distances[i][j] = distance;
+        }
+    }
+    distances[a.len()][b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use claims::{assert_err, assert_ok};
+    use hickory_resolver::error::ResolveError;
+
+    use super::{suggest_domain, DomainResolver, EmailDomainChecker};
+    use crate::configuration::{EmailDomainCheckMode, EmailDomainCheckSettings};
+    use crate::domain::SubscriberEmail;
+
+    /// Answers from fixed records, counting the queries it receives.
+    #[derive(Default)]
+    struct FixedResolver {
+        mail_exchanges: HashMap<&'static str, Vec<String>>,
+        addresses: Vec<&'static str>,
+        delay: Duration,
+        queries: AtomicUsize,
+    }
+
+    #[async_trait::async_trait]
+    impl DomainResolver for FixedResolver {
+        async fn mail_exchanges(&self, domain: &str) -> Result<Vec<String>, ResolveError> {
+            self.queries.fetch_add(1, Ordering::SeqCst);
+            tokio::time::sleep(self.delay).await;
+            Ok(self.mail_exchanges.get(domain).cloned().unwrap_or_default())
+        }
+
+        async fn has_addresses(&self, domain: &str) -> Result<bool, ResolveError> {
+            self.queries.fetch_add(1, Ordering::SeqCst);
+            Ok(self.addresses.contains(&domain))
+        }
+    }
+
+    fn checker(resolver: Arc<FixedResolver>, mode: EmailDomainCheckMode) -> EmailDomainChecker {
+        let settings = EmailDomainCheckSettings {
+            mode,
+            timeout_milliseconds: 100,
+            cache_ttl_seconds: 60,
+        };
+        EmailDomainChecker::new(resolver, &settings)
+    }
+
+    fn email(s: &str) -> SubscriberEmail {
+        SubscriberEmail::parse(s.to_string()).unwrap()
+    }
+
+    fn resolver() -> FixedResolver {
+        FixedResolver {
+            mail_exchanges: HashMap::from([
+                ("gmail.com", vec!["gmail-smtp-in.l.google.com.".to_string()]),
+                ("nullmx.example", vec![".".to_string()]),
+            ]),
+            addresses: vec!["a-only.example", "nullmx.example"],
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn domains_with_mx_or_address_records_are_accepted() {
+        let checker = checker(Arc::new(resolver()), EmailDomainCheckMode::Enforcing);
+        assert_ok!(checker.check(&email("ursula@gmail.com")).await);
+        assert_ok!(checker.check(&email("ursula@a-only.example")).await);
+    }
+
+    #[tokio::test]
+    async fn domains_without_records_or_with_a_null_mx_are_rejected() {
+        let checker = checker(Arc::new(resolver()), EmailDomainCheckMode::Enforcing);
+        assert_err!(checker.check(&email("ursula@nowhere.example")).await);
+        assert_err!(checker.check(&email("ursula@nullmx.example")).await);
+    }
+
+    #[tokio::test]
+    async fn typos_of_popular_domains_come_with_a_suggestion() {
+        let checker = checker(Arc::new(resolver()), EmailDomainCheckMode::Enforcing);
+        let error = checker.check(&email("Ursula@GMIAL.com")).await.unwrap_err();
+        assert_eq!(error.suggestion.as_deref(), Some("Ursula@gmail.com"));
+        assert_eq!(
+            error.to_string(),
+            "gmial.com does not accept emails. Did you mean Ursula@gmail.com?"
+        );
+    }
+
+    #[tokio::test]
+    async fn advisory_mode_accepts_undeliverable_domains() {
+        let checker = checker(Arc::new(resolver()), EmailDomainCheckMode::Advisory);
+        assert_ok!(checker.check(&email("ursula@nowhere.example")).await);
+    }
+
+    #[tokio::test]
+    async fn off_mode_does_not_query_the_resolver() {
+        let resolver = Arc::new(resolver());
+        let checker = checker(resolver.clone(), EmailDomainCheckMode::Off);
+        assert_ok!(checker.check(&email("ursula@nowhere.example")).await);
+        assert_eq!(resolver.queries.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn answers_are_cached() {
+        let resolver = Arc::new(resolver());
+        let checker = checker(resolver.clone(), EmailDomainCheckMode::Enforcing);
+        for _ in 0..3 {
+            assert_err!(checker.check(&email("ursula@nowhere.example")).await);
+        }
+        // One MX query and one A/AAAA query
+        assert_eq!(resolver.queries.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn slow_lookups_let_the_subscriber_through() {
+        let resolver = FixedResolver {
+            delay: Duration::from_secs(10),
+            ..Default::default()
+        };
+        let checker = checker(Arc::new(resolver), EmailDomainCheckMode::Enforcing);
+        assert_ok!(checker.check(&email("ursula@nowhere.example")).await);
+    }
+
+    #[test]
+    fn suggestions_are_only_made_for_near_misses() {
+        assert_eq!(suggest_domain("gmial.com"), Some("gmail.com"));
+        assert_eq!(suggest_domain("gmail.con"), Some("gmail.com"));
+        assert_eq!(suggest_domain("hotmial.com"), Some("hotmail.com"));
+        assert_eq!(suggest_domain("yahooo.com"), Some("yahoo.com"));
+        assert_eq!(suggest_domain("gmail.com"), None);
+        assert_eq!(suggest_domain("example.com"), None);
+    }
+}
@@ -0,0 +1,209 @@
+use chrono::Utc;
+use sqlx::{PgPool, Postgres, Transaction};
+use std::time::Duration;
+use tracing::{field::display, Span};
+use uuid::Uuid;
+
+use crate::domain::SubscriberEmail;
+use crate::email_client::{EmailClient, SendEmailError};
+
+/// How many times a delivery that failed is tried again before it is given
+/// up on.
+const MAX_RETRIES: i16 = 5;
+
+pub enum ExecutionOutcome {
+    TaskCompleted,
+    EmptyQueue,
+}
+
+/// Send the queued newsletter issues, forever.
+pub async fn run_worker_until_stopped(db_pool: PgPool, email_client: EmailClient) {
+    loop {
+        match try_execute_task(&db_pool, &email_client).await {
+            Ok(ExecutionOutcome::EmptyQueue) => tokio::time::sleep(Duration::from_secs(10)).await,
+            Err(_) => tokio::time::sleep(Duration::from_secs(1)).await,
+            Ok(ExecutionOutcome::TaskCompleted) => {}
+        }
+    }
+}
+
+/// Send one queued issue to one subscriber.
+///
+/// Subscribers who left since the issue was published are skipped, and so
+/// are suppressed addresses. Deliveries that fail are tried again later, up
+/// to `MAX_RETRIES` times.
+#[tracing::instrument(
+    name = "Delivering a newsletter issue",
+    skip(db_pool, email_client),
+    fields(newsletter_issue_id = tracing::field::Empty, subscriber_id = tracing::field::Empty)
+)]
+pub async fn try_execute_task(
+    db_pool: &PgPool,
+    email_client: &EmailClient,
+) -> Result<ExecutionOutcome, sqlx::Error> {
+    let (mut transaction, task) = match dequeue_task(db_pool).await? {
+        Some(dequeued) => dequeued,
+        None => return Ok(ExecutionOutcome::EmptyQueue),
+    };
+    Span::current()
+        .record("newsletter_issue_id", display(task.newsletter_issue_id))
+        .record("subscriber_id", display(task.subscriber_id));
+
+    let email = if task.status == "active" {
+        SubscriberEmail::parse(task.email.clone())
+            .map_err(|e| tracing::error!("Skipping a subscriber with an invalid email: {}", e))
+            .ok()
+    } else {
+        tracing::info!("Skipping a subscriber who is no longer active");
+        None
+    };
+    let email = match email {
+        Some(email) => email,
+        None => {
+            delete_task(&task, &mut transaction).await?;
+            transaction.commit().await?;
+            return Ok(ExecutionOutcome::TaskCompleted);
+        }
+    };
+    let issue = get_issue(task.newsletter_issue_id, &mut transaction).await?;
+    match email_client
+        .send_email(
+            email,
+            &issue.title,
+            &issue.html_content,
+            &issue.text_content,
+        )
+        .await
+    {
+        // Suppressed recipients are logged by the email client
+        Ok(()) | Err(SendEmailError::Suppressed(_)) => delete_task(&task, &mut transaction).await?,
+        Err(e) if task.n_retries < MAX_RETRIES => {
+            tracing::warn!(
+                n_retries = task.n_retries,
+                "Failed to deliver the issue, trying again later: {:?}",
+                e
+            );
+            retry_task_later(&task, &mut transaction).await?;
+        }
+        Err(e) => {
+            tracing::error!("Failed to deliver the issue, giving up: {:?}", e);
+            delete_task(&task, &mut transaction).await?;
+        }
+    }
+    transaction.commit().await?;
+    Ok(ExecutionOutcome::TaskCompleted)
+}
+
+struct Task {
+    newsletter_issue_id: Uuid,
+    subscriber_id: Uuid,
+    n_retries: i16,
+    email: String,
+    status: String,
+}
+
+/// The next delivery that is due, locked until the transaction ends so that
+/// several workers can share the queue.
+#[tracing::instrument(name = "Dequeuing newsletter delivery", skip(db_pool))]
+async fn dequeue_task(
+    db_pool: &PgPool,
+) -> Result<Option<(Transaction<'static, Postgres>, Task)>, sqlx::Error> {
+    let mut transaction = db_pool.begin().await?;
+    let task = sqlx::query_as!(
+        Task,
+        r#"
+    SELECT issue_delivery_queue.newsletter_issue_id, issue_delivery_queue.subscriber_id,
+        issue_delivery_queue.n_retries, subscriptions.email, subscriptions.status
+    FROM issue_delivery_queue
+    JOIN subscriptions ON subscriptions.id = issue_delivery_queue.subscriber_id
+    WHERE issue_delivery_queue.execute_after <= $1
+    FOR UPDATE OF issue_delivery_queue SKIP LOCKED
+    LIMIT 1
+"#,
+        Utc::now()
+    )
+    .fetch_optional(&mut *transaction)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    Ok(task.map(|task| (transaction, task)))
+}
+
+#[tracing::instrument(name = "Deleting newsletter delivery", skip(task, transaction))]
+async fn delete_task(
+    task: &Task,
+    transaction: &mut Transaction<'_, Postgres>,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+    DELETE FROM issue_delivery_queue
+    WHERE newsletter_issue_id = $1 AND subscriber_id = $2
+"#,
+        task.newsletter_issue_id,
+        task.subscriber_id
+    )
+    .execute(&mut **transaction)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    Ok(())
+}
+
+/// Put the delivery back in the queue, waiting twice as long after each
+/// failure: 1 minute, then 2, 4...
+#[tracing::instrument(name = "Postponing newsletter delivery", skip(task, transaction))]
+async fn retry_task_later(
+    task: &Task,
+    transaction: &mut Transaction<'_, Postgres>,
+) -> Result<(), sqlx::Error> {
+    let delay = Duration::from_secs(60 << task.n_retries);
+    sqlx::query!(
+        r#"
+    UPDATE issue_delivery_queue
+    SET n_retries = n_retries + 1, execute_after = $3
+    WHERE newsletter_issue_id = $1 AND subscriber_id = $2
+"#,
+        task.newsletter_issue_id,
+        task.subscriber_id,
+        Utc::now() + delay
+    )
+    .execute(&mut **transaction)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    Ok(())
+}
+
+struct NewsletterIssue {
+    title: String,
+    text_content: String,
+    html_content: String,
+}
+
+#[tracing::instrument(name = "Fetching newsletter issue", skip(transaction))]
+async fn get_issue(
+    newsletter_issue_id: Uuid,
+    transaction: &mut Transaction<'_, Postgres>,
+) -> Result<NewsletterIssue, sqlx::Error> {
+    sqlx::query_as!(
+        NewsletterIssue,
+        r#"
+    SELECT title, text_content, html_content
+    FROM newsletter_issues
+    WHERE newsletter_issue_id = $1
+"#,
+        newsletter_issue_id
+    )
+    .fetch_one(&mut **transaction)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })
+}
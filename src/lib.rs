@@ -1,7 +1,20 @@
+pub mod authentication;
+pub mod canonical_emails;
+pub mod challenge;
 pub mod configuration;
+pub mod consent;
 pub mod domain;
+pub mod domain_policy;
 pub mod email_client;
+pub mod email_domain_check;
+pub mod issue_delivery_worker;
+pub mod magic_link;
+pub mod rate_limit;
 pub mod routes;
+pub mod settings_reload;
 pub mod startup;
+pub mod subscriber_data;
+pub mod subscriber_transfer;
+pub mod suppression_list;
 pub mod telemetry;
 pub mod utils;
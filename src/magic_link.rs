@@ -0,0 +1,272 @@
+use base64::Engine;
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use secrecy::{ExposeSecret, Secret};
+use sha2::Sha256;
+use uuid::Uuid;
+
+use crate::configuration::MagicLinkSettings;
+
+/// The longest lifetime `magic_link.ttl_seconds` may give links: a week.
+pub const MAX_TTL_SECONDS: u64 = 7 * 24 * 60 * 60;
+
+/// What a magic link lets its holder do.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MagicLinkPurpose {
+    /// Read and change the subscriber's preferences.
+    Preferences,
+    /// Switch the subscriber to the address the link was sent to.
+    EmailChange,
+}
+
+/// Why a magic link was refused. Callers answer the same way whatever the
+/// reason, the variants are for logs.
+#[derive(Debug, PartialEq, Eq)]
+pub enum MagicLinkError {
+    Malformed,
+    InvalidSignature,
+    Expired,
+    WrongPurpose,
+}
+
+impl std::fmt::Display for MagicLinkError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Malformed => write!(f, "The token is malformed."),
+            Self::InvalidSignature => write!(f, "The token signature is invalid."),
+            Self::Expired => write!(f, "The token expired."),
+            Self::WrongPurpose => write!(f, "The token was issued for something else."),
+        }
+    }
+}
+
+impl std::error::Error for MagicLinkError {}
+
+/// What a token vouches for.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+pub struct MagicLinkClaims {
+    pub purpose: MagicLinkPurpose,
+    #[serde(with = "uuid_as_string")]
+    pub subscriber_id: Uuid,
+    /// Seconds since the Unix epoch.
+    pub expires_at: i64,
+    /// The address the link was sent to, when it is not the subscriber's
+    /// current one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub email: Option<String>,
+}
+
+mod uuid_as_string {
+    use serde::{Deserialize, Deserializer, Serializer};
+    use uuid::Uuid;
+
+    pub fn serialize<S: Serializer>(id: &Uuid, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(id)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Uuid, D::Error> {
+        let id = String::deserialize(deserializer)?;
+        Uuid::parse_str(&id).map_err(serde::de::Error::custom)
+    }
+}
+
+/// Issues and checks the tokens of the links we email subscribers so they
+/// can act on their subscription without a password.
+///
+/// Tokens are signed rather than stored: `<claims>.<signature>`, where the
+/// claims are base64url-encoded JSON and the signature an HMAC-SHA256 of
+/// them. They cannot be revoked before they expire, keep their lifetime
+/// short.
+pub struct MagicLinks {
+    base_url: String,
+    signing_key: Secret<String>,
+    ttl_seconds: i64,
+}
+
+impl MagicLinks {
+    pub fn new(base_url: String, settings: &MagicLinkSettings) -> Self {
+        Self {
+            base_url,
+            signing_key: settings.signing_key.clone(),
+            // `validate` rejects longer lifetimes already
+            ttl_seconds: i64::try_from(settings.ttl_seconds.min(MAX_TTL_SECONDS))
+                .expect("The maximum lifetime fits in an i64"),
+        }
+    }
+
+    /// A link to `path` carrying a token issued now.
+    pub fn link(
+        &self,
+        path: &str,
+        purpose: MagicLinkPurpose,
+        subscriber_id: Uuid,
+        email: Option<String>,
+    ) -> String {
+        let token = self.issue(purpose, subscriber_id, email, Utc::now());
+        format!("{}{}?token={}", self.base_url, path, token)
+    }
+
+    pub fn issue(
+        &self,
+        purpose: MagicLinkPurpose,
+        subscriber_id: Uuid,
+        email: Option<String>,
+        now: DateTime<Utc>,
+    ) -> String {
+        let claims = MagicLinkClaims {
+            purpose,
+            subscriber_id,
+            expires_at: now
+                .timestamp()
+                .checked_add(self.ttl_seconds)
+                .expect("Lifetimes are too short to overflow"),
+            email,
+        };
+        let claims = base64::engine::general_purpose::URL_SAFE_NO_PAD
+            .encode(serde_json::to_vec(&claims).expect("Claims always serialize"));
+        let signature = hex::encode(self.mac(&claims).finalize().into_bytes());
+        format!("{}.{}", claims, signature)
+    }
+
+    /// The claims of `token`, if it is genuine, unexpired and was issued for
+    /// `purpose`.
+    pub fn verify(
+        &self,
+        token: &str,
+        purpose: MagicLinkPurpose,
+        now: DateTime<Utc>,
+    ) -> Result<MagicLinkClaims, MagicLinkError> {
+        let (claims, signature) = token.split_once('.').ok_or(MagicLinkError::Malformed)?;
+        let signature = hex::decode(signature).map_err(|_| MagicLinkError::Malformed)?;
+        // `verify_slice` compares in constant time
+        self.mac(claims)
+            .verify_slice(&signature)
+            .map_err(|_| MagicLinkError::InvalidSignature)?;
+        let claims = base64::engine::general_purpose::URL_SAFE_NO_PAD
+            .decode(claims)
+            .map_err(|_| MagicLinkError::Malformed)?;
+        let claims: MagicLinkClaims =
+            serde_json::from_slice(&claims).map_err(|_| MagicLinkError::Malformed)?;
+        if claims.purpose != purpose {
+            return Err(MagicLinkError::WrongPurpose);
+        }
+        if claims.expires_at <= now.timestamp() {
+            return Err(MagicLinkError::Expired);
+        }
+        Ok(claims)
+    }
+
+    fn mac(&self, claims: &str) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(self.signing_key.expose_secret().as_bytes())
+            .expect("HMAC can take a key of any size");
+        mac.update(claims.as_bytes());
+        mac
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Duration, Utc};
+    use claims::{assert_err_eq, assert_ok};
+    use secrecy::Secret;
+    use uuid::Uuid;
+
+    use super::{MagicLinkError, MagicLinkPurpose, MagicLinks};
+    use crate::configuration::MagicLinkSettings;
+
+    fn magic_links(signing_key: &str) -> MagicLinks {
+        MagicLinks::new(
+            "http://127.0.0.1".to_string(),
+            &MagicLinkSettings {
+                signing_key: Secret::new(signing_key.to_string()),
+                ttl_seconds: 900,
+            },
+        )
+    }
+
+    #[test]
+    fn issued_tokens_are_accepted_until_they_expire() {
+        let links = magic_links("key");
+        let subscriber_id = Uuid::new_v4();
+        let now = Utc::now();
+        let token = links.issue(
+            MagicLinkPurpose::EmailChange,
+            subscriber_id,
+            Some("ursula@example.com".to_string()),
+            now,
+        );
+
+        let claims = links
+            .verify(&token, MagicLinkPurpose::EmailChange, now)
+            .unwrap();
+        assert_eq!(claims.subscriber_id, subscriber_id);
+        assert_eq!(claims.email.as_deref(), Some("ursula@example.com"));
+        assert_ok!(links.verify(
+            &token,
+            MagicLinkPurpose::EmailChange,
+            now + Duration::try_seconds(899).unwrap()
+        ));
+        assert_err_eq!(
+            links.verify(
+                &token,
+                MagicLinkPurpose::EmailChange,
+                now + Duration::try_seconds(900).unwrap()
+            ),
+            MagicLinkError::Expired
+        );
+    }
+
+    #[test]
+    fn oversized_lifetimes_do_not_wrap() {
+        let links = MagicLinks::new(
+            "http://127.0.0.1".to_string(),
+            &MagicLinkSettings {
+                signing_key: Secret::new("key".to_string()),
+                ttl_seconds: u64::MAX,
+            },
+        );
+        let now = Utc::now();
+        let token = links.issue(MagicLinkPurpose::Preferences, Uuid::new_v4(), None, now);
+
+        assert_ok!(links.verify(&token, MagicLinkPurpose::Preferences, now));
+    }
+
+    #[test]
+    fn tokens_only_grant_what_they_were_issued_for() {
+        let links = magic_links("key");
+        let now = Utc::now();
+        let token = links.issue(MagicLinkPurpose::Preferences, Uuid::new_v4(), None, now);
+        assert_err_eq!(
+            links.verify(&token, MagicLinkPurpose::EmailChange, now),
+            MagicLinkError::WrongPurpose
+        );
+    }
+
+    #[test]
+    fn tampered_tokens_are_rejected() {
+        let links = magic_links("key");
+        let now = Utc::now();
+        let token = links.issue(MagicLinkPurpose::Preferences, Uuid::new_v4(), None, now);
+        let other = links.issue(MagicLinkPurpose::Preferences, Uuid::new_v4(), None, now);
+        let (claims, _) = other.split_once('.').unwrap();
+        let (_, signature) = token.split_once('.').unwrap();
+
+        assert_err_eq!(
+            links.verify(
+                &format!("{}.{}", claims, signature),
+                MagicLinkPurpose::Preferences,
+                now
+            ),
+            MagicLinkError::InvalidSignature
+        );
+        assert_err_eq!(
+            magic_links("another key").verify(&token, MagicLinkPurpose::Preferences, now),
+            MagicLinkError::InvalidSignature
+        );
+        assert_err_eq!(
+            links.verify("not a token", MagicLinkPurpose::Preferences, now),
+            MagicLinkError::Malformed
+        );
+    }
+}
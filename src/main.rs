@@ -1,36 +1,359 @@
+use clap::{Parser, Subcommand};
+use secrecy::Secret;
+use sqlx::PgPool;
+use std::io::BufRead;
 use std::net::TcpListener;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use zero_to_prod_example::authentication::create_admin_user;
+use zero_to_prod_example::canonical_emails::canonicalize_stored_emails;
+use zero_to_prod_example::challenge::{ChallengeVerifier, SiteVerifyChallengeVerifier};
+use zero_to_prod_example::configuration::{DatabaseSettings, RateLimitStoreKind, Settings};
+use zero_to_prod_example::domain::{SegmentFilter, SubscriberEmail};
+use zero_to_prod_example::domain_policy::{watch_domain_policy, DomainPolicy};
+#[cfg(unix)]
+use zero_to_prod_example::email_client::reload_authorization_token_on_sighup;
 use zero_to_prod_example::email_client::EmailClient;
+use zero_to_prod_example::email_domain_check::{DnsResolver, EmailDomainChecker};
+use zero_to_prod_example::issue_delivery_worker::run_worker_until_stopped;
+use zero_to_prod_example::magic_link::MagicLinks;
+use zero_to_prod_example::rate_limit::{
+    InMemoryRateLimitStore, PgRateLimitStore, RateLimit, RateLimitStore,
+};
+use zero_to_prod_example::routes::{SubscriptionConfirmation, SubscriptionValidator};
+use zero_to_prod_example::settings_reload::{watch_settings, ReloadableSettings};
+use zero_to_prod_example::subscriber_transfer::{
+    export_subscribers, import_subscribers, parse_subscribers,
+};
+use zero_to_prod_example::suppression_list::{PgSuppressionList, TombstoneKey};
 
-use sqlx::postgres::PgPoolOptions;
 use zero_to_prod_example::{
-    configuration::get_configuration,
+    configuration::{
+        configuration_directory, get_configuration_in, read_configuration_in, ConfigurationError,
+        SettingsFormat,
+    },
     startup::run,
-    telemetry::{get_subscriber, init_subscriber},
+    telemetry::{get_subscriber, init_subscriber, LogFilter},
 };
 
+#[derive(Parser)]
+#[command(version, about)]
+struct Cli {
+    /// The directory the configuration files are in [default: $APP_CONFIG_DIR,
+    /// then ./configuration]
+    #[arg(long, value_name = "DIR", global = true)]
+    config: Option<PathBuf>,
+    /// What to do [default: serve]
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Serve requests
+    Serve,
+    /// Send the queued newsletter issues, without serving requests
+    Worker,
+    /// Apply the database migrations that were not applied yet
+    Migrate,
+    /// Create an admin who can use the admin API, reading their password
+    /// from the standard input
+    CreateAdmin {
+        #[arg(long)]
+        username: String,
+    },
+    /// Import or export subscribers
+    #[command(subcommand)]
+    Subscribers(SubscribersCommand),
+    /// Send an email, to check the email settings
+    SendTestEmail {
+        /// The address to send the email to
+        #[arg(long, value_name = "EMAIL")]
+        to: String,
+    },
+    /// Inspect the configuration
+    #[command(subcommand)]
+    Config(ConfigCommand),
+}
+
+#[derive(Subcommand)]
+enum SubscribersCommand {
+    /// Import active subscribers, one JSON object per line as written by
+    /// `export`, all of them or none
+    Import {
+        /// Where to read the subscribers from [default: the standard input]
+        file: Option<PathBuf>,
+    },
+    /// Write subscribers to the standard output, one JSON object per line
+    Export {
+        /// Only export the subscribers matching this filter, e.g.
+        /// `attributes.plan = "pro"`
+        #[arg(long)]
+        segment: Option<String>,
+    },
+}
+
+#[derive(Subcommand)]
+enum ConfigCommand {
+    /// Print the settings in use, secrets masked, with where each of them
+    /// comes from
+    Show {
+        #[arg(long, value_enum, default_value_t = SettingsFormat::Yaml)]
+        format: SettingsFormat,
+    },
+    /// Check the settings, exiting with a non-zero status if they are invalid
+    Check,
+}
+
 #[tokio::main] // <- this is the same as tokio::main
-async fn main() -> Result<(), std::io::Error> {
-    let subscriber = get_subscriber(
-        "zero_to_prod_example".into(),
-        "info".into(),
-        std::io::stdout,
+async fn main() {
+    let cli = Cli::parse();
+    let command = cli.command.unwrap_or(Command::Serve);
+    let directory = configuration_directory(cli.config);
+    // Settings that cannot be read are reported by the command, until then
+    // logs are in the default format
+    let log_format = read_configuration_in(&directory)
+        .map(|settings| settings.log.format)
+        .unwrap_or_default();
+    // Other commands may print what they were asked for, keep it apart from
+    // the logs
+    let log_filter = if matches!(command, Command::Serve | Command::Worker) {
+        let (subscriber, log_filter) = get_subscriber(
+            "zero_to_prod_example".into(),
+            "info".into(),
+            log_format,
+            std::io::stdout,
+        );
+        init_subscriber(subscriber);
+        log_filter
+    } else {
+        let (subscriber, log_filter) = get_subscriber(
+            "zero_to_prod_example".into(),
+            "info".into(),
+            log_format,
+            std::io::stderr,
+        );
+        init_subscriber(subscriber);
+        log_filter
+    };
+
+    if let Err(e) = run_command(command, &directory, log_filter).await {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
+
+async fn run_command(
+    command: Command,
+    directory: &Path,
+    log_filter: LogFilter,
+) -> Result<(), Box<dyn std::error::Error>> {
+    // Refuse to run with settings we cannot use, listing every problem
+    let configuration = || get_configuration_in(directory);
+    match command {
+        Command::Serve => serve(configuration()?, directory, log_filter).await?,
+        Command::Worker => worker(configuration()?, log_filter).await,
+        Command::Migrate => {
+            let connection_pool = connection_pool(&configuration()?.database);
+            sqlx::migrate!("./migrations").run(&connection_pool).await?;
+            let report = canonicalize_stored_emails(&connection_pool).await?;
+            println!(
+                "The database is up to date. Fixed the canonical form of {} subscribers and {} \
+                suppressions.",
+                report.subscribers, report.suppressions
+            );
+            if !report.duplicates.is_empty() {
+                println!(
+                    "These subscribers share their mailbox with an older one, merge them by \
+                    hand:\n  - {}",
+                    report.duplicates.join("\n  - ")
+                );
+            }
+        }
+        Command::CreateAdmin { username } => {
+            let connection_pool = connection_pool(&configuration()?.database);
+            let password = read_password()?;
+            create_admin_user(&username, password, &connection_pool).await?;
+            println!("Created the admin {}.", username);
+        }
+        Command::Subscribers(SubscribersCommand::Import { file }) => {
+            let configuration = configuration()?;
+            let max_name_length = configuration.subscriber_name.max_length;
+            let parsed = match file {
+                Some(file) => parse_subscribers(
+                    std::io::BufReader::new(std::fs::File::open(file)?),
+                    max_name_length,
+                ),
+                None => parse_subscribers(std::io::stdin().lock(), max_name_length),
+            };
+            let import = parsed.map_err(|invalid| {
+                format!(
+                    "Nothing was imported, fix these lines first:\n  - {}",
+                    invalid.join("\n  - ")
+                )
+            })?;
+            let connection_pool = connection_pool(&configuration.database);
+            let report = import_subscribers(
+                &import.subscribers,
+                &TombstoneKey::new(&configuration.suppression_list),
+                &connection_pool,
+            )
+            .await?;
+            println!(
+                "Imported {} subscribers. Left out {} existing subscribers, {} suppressed \
+                addresses and {} subscribers who are not active.",
+                report.imported, report.existing, report.suppressed, import.inactive
+            );
+        }
+        Command::Subscribers(SubscribersCommand::Export { segment }) => {
+            let segment = segment.map(SegmentFilter::parse).transpose()?;
+            let connection_pool = connection_pool(&configuration()?.database);
+            let exported =
+                export_subscribers(segment.as_ref(), &connection_pool, std::io::stdout().lock())
+                    .await?;
+            eprintln!("Exported {} subscribers.", exported);
+        }
+        Command::SendTestEmail { to } => {
+            let configuration = configuration()?;
+            let recipient = SubscriberEmail::parse(to)?;
+            let connection_pool = connection_pool(&configuration.database);
+            email_client(&configuration, &connection_pool)
+                .send_email(
+                    recipient.clone(),
+                    "Test email",
+                    "This is a test email, sent to check the email settings.",
+                    "This is a test email, sent to check the email settings.",
+                )
+                .await?;
+            println!("Sent a test email to {}.", recipient.as_ref());
+        }
+        Command::Config(ConfigCommand::Show { format }) => {
+            let settings = read_configuration_in(directory)?;
+            print!("{}", settings.show(format));
+            // Showing invalid settings is how one finds out what is wrong
+            if let Err(invalid) = settings.validate() {
+                eprintln!("{}", ConfigurationError::Invalid(invalid));
+            }
+        }
+        Command::Config(ConfigCommand::Check) => println!(
+            "The settings of the {} environment are valid.",
+            configuration()?.environment.as_str()
+        ),
+    }
+    Ok(())
+}
+
+fn connection_pool(settings: &DatabaseSettings) -> PgPool {
+    settings
+        .pool_options()
+        .connect_lazy_with(settings.with_db())
+}
+
+fn email_client(configuration: &Settings, connection_pool: &PgPool) -> EmailClient {
+    EmailClient::new(
+        configuration.email_client.base_url.clone(),
+        configuration
+            .email_client
+            .sender()
+            .expect("Invalid sender email address."),
+        configuration.email_client.authorization_token.clone(),
+        Arc::new(PgSuppressionList::new(
+            connection_pool.clone(),
+            TombstoneKey::new(&configuration.suppression_list),
+        )),
+    )
+}
+
+/// The first line of the standard input, so that passwords stay out of the
+/// shell history.
+fn read_password() -> Result<Secret<String>, std::io::Error> {
+    let mut password = String::new();
+    std::io::stdin().lock().read_line(&mut password)?;
+    Ok(Secret::new(
+        password.trim_end_matches(['\r', '\n']).to_string(),
+    ))
+}
+
+fn set_log_filter(configuration: &Settings, log_filter: &LogFilter) {
+    // `RUST_LOG` is meant for one-off runs, it wins over the settings
+    if std::env::var_os("RUST_LOG").is_none() {
+        log_filter
+            .set(&configuration.log.filter)
+            .expect("Failed to set the log filter.");
+    }
+}
+
+/// A token read from a file can be rotated: replace the file, then send
+/// SIGHUP.
+#[cfg(unix)]
+fn watch_authorization_token(configuration: &Settings, email_client: &EmailClient) {
+    if let Some(path) = configuration
+        .sources
+        .secret_file("email_client.authorization_token")
+    {
+        tokio::spawn(reload_authorization_token_on_sighup(
+            email_client.clone(),
+            path.to_path_buf(),
+        ));
+    }
+}
+
+/// Deliver newsletter issues until the process is stopped. Several workers,
+/// and servers, can share the queue.
+async fn worker(configuration: Settings, log_filter: LogFilter) {
+    set_log_filter(&configuration, &log_filter);
+    let connection_pool = connection_pool(&configuration.database);
+    let email_client = email_client(&configuration, &connection_pool);
+    #[cfg(unix)]
+    watch_authorization_token(&configuration, &email_client);
+    run_worker_until_stopped(connection_pool, email_client).await
+}
+
+async fn serve(
+    configuration: Settings,
+    directory: &Path,
+    log_filter: LogFilter,
+) -> Result<(), std::io::Error> {
+    set_log_filter(&configuration, &log_filter);
+
+    let connection_pool = connection_pool(&configuration.database);
+    let email_client = email_client(&configuration, &connection_pool);
+    tokio::spawn(run_worker_until_stopped(
+        connection_pool.clone(),
+        email_client.clone(),
+    ));
+    #[cfg(unix)]
+    watch_authorization_token(&configuration, &email_client);
+
+    let email_domain_checker = EmailDomainChecker::new(
+        Arc::new(DnsResolver::from_system_conf().expect("Failed to read the DNS configuration.")),
+        &configuration.email_domain_check,
     );
-    init_subscriber(subscriber);
-
-    // Panic if we can't read configuration
-    let configuration = get_configuration().expect("Failed to read configuration.");
-    let connection_pool = PgPoolOptions::new().connect_lazy_with(configuration.database.with_db());
-
-    // Build an `EmailClient` using `configuration`
-    let sender_email = configuration
-        .email_client
-        .sender()
-        .expect("Invalid sender email address.");
-    let email_client = EmailClient::new(
-        configuration.email_client.base_url,
-        sender_email,
-        configuration.email_client.authorization_token,
+
+    let domain_policy = Arc::new(
+        DomainPolicy::load(&configuration.domain_policy)
+            .expect("Failed to load the domain policy."),
     );
+    tokio::spawn(watch_domain_policy(
+        domain_policy.clone(),
+        configuration.domain_policy.reload_interval(),
+    ));
+
+    let challenge_verifier: Option<Arc<dyn ChallengeVerifier>> = if configuration.challenge.enabled
+    {
+        Some(Arc::new(SiteVerifyChallengeVerifier::new(
+            configuration.challenge.verify_url.clone(),
+            configuration.challenge.secret_key.clone(),
+            configuration.challenge.timeout(),
+        )))
+    } else {
+        None
+    };
+    let rate_limit_store: Arc<dyn RateLimitStore> = match configuration.rate_limit.store {
+        RateLimitStoreKind::Memory => Arc::new(InMemoryRateLimitStore::default()),
+        RateLimitStoreKind::Postgres => Arc::new(PgRateLimitStore::new(connection_pool.clone())),
+    };
+    let rate_limit = RateLimit::new(rate_limit_store, configuration.rate_limit.clone());
 
     let address = format!(
         "{}:{}",
@@ -39,6 +362,41 @@ async fn main() -> Result<(), std::io::Error> {
 
     let listener = TcpListener::bind(&address).expect("Failed to bind random port");
     println!("Server running on: http://{}", address);
-    run(listener, connection_pool, email_client)?.await;
-    Ok(())
+    let server = run(
+        listener,
+        connection_pool,
+        email_client,
+        SubscriptionConfirmation::new(
+            configuration.application.base_url.clone(),
+            &configuration.subscription_confirmation,
+        ),
+        MagicLinks::new(
+            configuration.application.base_url.clone(),
+            &configuration.magic_link,
+        ),
+        configuration.email_webhooks.clone(),
+        configuration.admin.clone(),
+        SubscriptionValidator::new(
+            configuration.subscriber_name.clone(),
+            domain_policy.clone(),
+            email_domain_checker,
+            configuration.bot_detection.clone(),
+            challenge_verifier,
+        ),
+        rate_limit.clone(),
+        log_filter.clone(),
+        TombstoneKey::new(&configuration.suppression_list),
+    )?;
+    // The log filter, rate limits and domain policy can be changed without
+    // a restart: edit the configuration files, or send SIGHUP
+    tokio::spawn(watch_settings(
+        directory.to_path_buf(),
+        configuration,
+        ReloadableSettings {
+            log_filter,
+            rate_limit,
+            domain_policy,
+        },
+    ));
+    server.await
 }
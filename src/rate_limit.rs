@@ -0,0 +1,414 @@
+use std::collections::HashMap;
+use std::future::{ready, Future, Ready};
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+
+use actix_web::body::EitherBody;
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::{web, HttpMessage, HttpRequest, HttpResponse};
+use chrono::{DateTime, TimeZone, Utc};
+use sqlx::PgPool;
+
+use crate::configuration::RateLimitSettings;
+use crate::domain::SubscriberEmail;
+
+/// Past this many counters, the in-memory store drops the ones of past
+/// windows.
+const MAX_IN_MEMORY_COUNTERS: usize = 100_000;
+
+/// How often the Postgres store deletes the counters of past windows.
+const PG_CLEANUP_EVERY_N_HITS: u64 = 1_000;
+
+/// Where request counters are kept.
+///
+/// Counters are per fixed window: they start again from zero at the
+/// beginning of every window.
+#[async_trait::async_trait]
+pub trait RateLimitStore: Send + Sync {
+    /// Count one more request for `key` in the window starting at
+    /// `window_start`, and return how many requests it made in that window.
+    async fn hit(&self, key: &str, window_start: DateTime<Utc>) -> Result<u32, sqlx::Error>;
+}
+
+/// Counters local to the process: limits are per instance of the
+/// application.
+#[derive(Default)]
+pub struct InMemoryRateLimitStore {
+    counters: Mutex<HashMap<String, (DateTime<Utc>, u32)>>,
+}
+
+#[async_trait::async_trait]
+impl RateLimitStore for InMemoryRateLimitStore {
+    async fn hit(&self, key: &str, window_start: DateTime<Utc>) -> Result<u32, sqlx::Error> {
+        let mut counters = self.counters.lock().unwrap();
+        if counters.len() >= MAX_IN_MEMORY_COUNTERS {
+            counters.retain(|_, (start, _)| *start >= window_start);
+        }
+        let counter = counters.entry(key.to_string()).or_insert((window_start, 0));
+        if counter.0 != window_start {
+            *counter = (window_start, 0);
+        }
+        counter.1 += 1;
+        Ok(counter.1)
+    }
+}
+
+/// Counters shared by every instance of the application.
+pub struct PgRateLimitStore {
+    db_pool: PgPool,
+    hits: AtomicU64,
+}
+
+impl PgRateLimitStore {
+    pub fn new(db_pool: PgPool) -> Self {
+        Self {
+            db_pool,
+            hits: AtomicU64::new(0),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl RateLimitStore for PgRateLimitStore {
+    #[tracing::instrument(name = "Counting rate limited request", skip(self, key))]
+    async fn hit(&self, key: &str, window_start: DateTime<Utc>) -> Result<u32, sqlx::Error> {
+        let hits = self.hits.fetch_add(1, Ordering::Relaxed);
+        if hits.is_multiple_of(PG_CLEANUP_EVERY_N_HITS) {
+            sqlx::query!(
+                r#"DELETE FROM rate_limit_counters WHERE window_start < $1"#,
+                window_start
+            )
+            .execute(&self.db_pool)
+            .await
+            .map_err(|e| {
+                tracing::error!("Failed to execute query: {:?}", e);
+                e
+            })?;
+        }
+        let row = sqlx::query!(
+            r#"
+    INSERT INTO rate_limit_counters (key, window_start, hits)
+    VALUES ($1, $2, 1)
+    ON CONFLICT (key) DO UPDATE
+    SET hits = CASE
+            WHEN rate_limit_counters.window_start = EXCLUDED.window_start
+            THEN rate_limit_counters.hits + 1
+            ELSE 1
+        END,
+        window_start = EXCLUDED.window_start
+    RETURNING hits
+"#,
+            key,
+            window_start
+        )
+        .fetch_one(&self.db_pool)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to execute query: {:?}", e);
+            e
+        })?;
+        Ok(row.hits as u32)
+    }
+}
+
+/// The address of the client, as seen by the rate limiter.
+///
+/// Added to the request extensions so that handlers do not have to work it
+/// out again.
+#[derive(Debug, Clone)]
+pub struct ClientIp(pub String);
+
+impl ClientIp {
+    /// The IP address of the client: the one the rate limiter found, or
+    /// worked out the same way on the routes it does not cover.
+    pub fn of(request: &HttpRequest) -> Option<String> {
+        if let Some(ip) = request.extensions().get::<ClientIp>() {
+            return Some(ip.0.clone());
+        }
+        let trusted_proxies = request
+            .app_data::<web::Data<RateLimit>>()
+            .map_or(0, |limits| limits.settings().trusted_proxies);
+        client_ip(request, trusted_proxies)
+    }
+}
+
+/// The IP address of the client, without the port.
+///
+/// Behind `trusted_proxies` proxies, it is the address the outermost one
+/// appended to `X-Forwarded-For`, counting from the right. The entries left
+/// of it were sent by the client, who can put anything there.
+pub fn client_ip(request: &HttpRequest, trusted_proxies: usize) -> Option<String> {
+    if trusted_proxies > 0 {
+        let forwarded_for: Vec<&str> = request
+            .headers()
+            .get_all("X-Forwarded-For")
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(str::trim)
+            .collect();
+        // Short of an entry per proxy, the request did not come through them
+        if let Some(ip) = forwarded_for
+            .len()
+            .checked_sub(trusted_proxies)
+            .map(|i| forwarded_for[i])
+        {
+            // Forwarded addresses may come with a port
+            return Some(match ip.parse::<std::net::SocketAddr>() {
+                Ok(address) => address.ip().to_string(),
+                Err(_) => ip.to_string(),
+            });
+        }
+    }
+    request.peer_addr().map(|address| address.ip().to_string())
+}
+
+/// Middleware limiting how many subscription requests are accepted per
+/// client IP and per email address.
+///
+/// Requests over a limit get a 429. If the store cannot be reached requests
+/// are let through, the other protections still apply.
+#[derive(Clone)]
+pub struct RateLimit {
+    store: Arc<dyn RateLimitStore>,
+    settings: Arc<RwLock<RateLimitSettings>>,
+}
+
+impl RateLimit {
+    pub fn new(store: Arc<dyn RateLimitStore>, settings: RateLimitSettings) -> Self {
+        Self {
+            store,
+            settings: Arc::new(RwLock::new(settings)),
+        }
+    }
+
+    pub fn settings(&self) -> RateLimitSettings {
+        self.settings.read().unwrap().clone()
+    }
+
+    /// Count one more request for `key`, and return the response to send
+    /// back if it went over `max_requests` in the current window.
+    async fn hit(
+        &self,
+        key: &str,
+        max_requests: u32,
+        settings: &RateLimitSettings,
+    ) -> Option<HttpResponse> {
+        let window_start = window_start(settings.window_seconds, Utc::now());
+        match self.store.hit(key, window_start).await {
+            Ok(hits) if hits > max_requests => {
+                tracing::warn!("Rate limit exceeded for {}", key);
+                Some(
+                    HttpResponse::TooManyRequests()
+                        .insert_header((
+                            "Retry-After",
+                            seconds_until_next_window(settings.window_seconds, window_start)
+                                .to_string(),
+                        ))
+                        .body("Too many requests, please try again later."),
+                )
+            }
+            Ok(_) => None,
+            Err(e) => {
+                tracing::error!("Failed to check the rate limit: {:?}", e);
+                None
+            }
+        }
+    }
+
+    /// Count a request to the admin API whose password is about to be
+    /// checked against a hash, and return the response to send back if its
+    /// client went over `max_admin_requests_per_ip`.
+    ///
+    /// Hashing is slow on purpose: without a limit, anybody could keep the
+    /// CPUs busy with wrong passwords.
+    pub async fn hit_admin(&self, request: &HttpRequest) -> Option<HttpResponse> {
+        let settings = self.settings();
+        let ip = client_ip(request, settings.trusted_proxies)?;
+        self.hit(
+            &format!("admin:ip:{}", ip),
+            settings.max_admin_requests_per_ip,
+            &settings,
+        )
+        .await
+    }
+
+    /// Limit requests with `settings` from now on. The store cannot be
+    /// changed: the one in use is kept, whatever `settings.store` says.
+    pub fn set_settings(&self, settings: RateLimitSettings) {
+        let mut current = self.settings.write().unwrap();
+        *current = RateLimitSettings {
+            store: current.store,
+            ..settings
+        };
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for RateLimit
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = actix_web::Error;
+    type Transform = RateLimitMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RateLimitMiddleware {
+            service: Rc::new(service),
+            limits: self.clone(),
+        }))
+    }
+}
+
+pub struct RateLimitMiddleware<S> {
+    service: Rc<S>,
+    limits: RateLimit,
+}
+
+/// The only field the rate limiter needs from the body.
+#[derive(serde::Deserialize)]
+struct EmailField {
+    email: String,
+}
+
+impl<S, B> Service<ServiceRequest> for RateLimitMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = actix_web::Error;
+    #[allow(clippy::type_complexity)]
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    actix_web::dev::forward_ready!(service);
+
+    fn call(&self, mut req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        let limits = self.limits.clone();
+        Box::pin(async move {
+            let settings = limits.settings();
+            let ip = client_ip(req.request(), settings.trusted_proxies);
+            // Read the body to find the email, then put it back for the handler
+            let body = req.extract::<web::Bytes>().await?;
+            let email = serde_json::from_slice::<EmailField>(&body)
+                .ok()
+                .map(|field| match SubscriberEmail::parse(field.email.clone()) {
+                    Ok(email) => email.canonical().to_string(),
+                    Err(_) => field.email.trim().to_lowercase(),
+                });
+            req.set_payload(bytes_to_payload(body));
+
+            let mut keys = Vec::new();
+            if let Some(ip) = &ip {
+                req.extensions_mut().insert(ClientIp(ip.clone()));
+                keys.push((format!("subscribe:ip:{}", ip), settings.max_requests_per_ip));
+            }
+            if let Some(email) = email {
+                keys.push((
+                    format!("subscribe:email:{}", email),
+                    settings.max_requests_per_email,
+                ));
+            }
+            for (key, max_requests) in keys {
+                if let Some(response) = limits.hit(&key, max_requests, &settings).await {
+                    return Ok(req.into_response(response).map_into_right_body());
+                }
+            }
+            service.call(req).await.map(|res| res.map_into_left_body())
+        })
+    }
+}
+
+/// The start of the window `now` is in, windows being aligned on their
+/// length.
+fn window_start(window_seconds: u64, now: DateTime<Utc>) -> DateTime<Utc> {
+    let window = window_seconds.max(1) as i64;
+    let start = now.timestamp() - now.timestamp().rem_euclid(window);
+    Utc.timestamp_opt(start, 0).unwrap()
+}
+
+fn seconds_until_next_window(window_seconds: u64, window_start: DateTime<Utc>) -> i64 {
+    let window_end = window_start.timestamp() + window_seconds.max(1) as i64;
+    (window_end - Utc::now().timestamp()).max(1)
+}
+
+fn bytes_to_payload(body: web::Bytes) -> actix_web::dev::Payload {
+    let (_, mut payload) = actix_http::h1::Payload::create(true);
+    payload.unread_data(body);
+    actix_web::dev::Payload::from(payload)
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::test::TestRequest;
+    use chrono::{TimeZone, Utc};
+
+    use super::{client_ip, window_start, InMemoryRateLimitStore, RateLimit, RateLimitStore};
+    use crate::configuration::{RateLimitSettings, RateLimitStoreKind};
+
+    #[tokio::test]
+    async fn in_memory_counters_start_again_every_window() {
+        let store = InMemoryRateLimitStore::default();
+        let first_window = Utc.timestamp_opt(3_600, 0).unwrap();
+        let second_window = Utc.timestamp_opt(7_200, 0).unwrap();
+
+        assert_eq!(store.hit("ip:1", first_window).await.unwrap(), 1);
+        assert_eq!(store.hit("ip:1", first_window).await.unwrap(), 2);
+        assert_eq!(store.hit("ip:2", first_window).await.unwrap(), 1);
+        assert_eq!(store.hit("ip:1", second_window).await.unwrap(), 1);
+    }
+
+    fn settings(window_seconds: u64, max_requests: u32) -> RateLimitSettings {
+        RateLimitSettings {
+            store: RateLimitStoreKind::Memory,
+            window_seconds,
+            max_requests_per_ip: max_requests,
+            max_requests_per_email: max_requests,
+            max_admin_requests_per_ip: max_requests,
+            trusted_proxies: 0,
+        }
+    }
+
+    #[test]
+    fn windows_are_aligned_on_their_length() {
+        let now = Utc.timestamp_opt(1_234, 0).unwrap();
+        assert_eq!(window_start(600, now), Utc.timestamp_opt(1_200, 0).unwrap());
+    }
+
+    #[test]
+    fn changed_settings_keep_the_store_in_use() {
+        let limits = RateLimit::new(
+            std::sync::Arc::new(InMemoryRateLimitStore::default()),
+            settings(600, 1),
+        );
+
+        limits.set_settings(RateLimitSettings {
+            store: RateLimitStoreKind::Postgres,
+            ..settings(60, 10)
+        });
+
+        let settings = limits.settings();
+        assert_eq!(settings.store, RateLimitStoreKind::Memory);
+        assert_eq!(settings.window_seconds, 60);
+        assert_eq!(settings.max_requests_per_ip, 10);
+    }
+
+    #[test]
+    fn client_ips_are_counted_from_the_right_of_forwarded_for() {
+        let request = TestRequest::default()
+            .peer_addr("10.0.0.1:4321".parse().unwrap())
+            .insert_header(("X-Forwarded-For", "1.1.1.1, 2.2.2.2"))
+            .append_header(("X-Forwarded-For", "3.3.3.3:80"))
+            .to_http_request();
+
+        assert_eq!(client_ip(&request, 0).unwrap(), "10.0.0.1");
+        assert_eq!(client_ip(&request, 1).unwrap(), "3.3.3.3");
+        assert_eq!(client_ip(&request, 2).unwrap(), "2.2.2.2");
+        assert_eq!(client_ip(&request, 4).unwrap(), "10.0.0.1");
+    }
+}
@@ -0,0 +1,69 @@
+use actix_web::{web, HttpRequest, HttpResponse, Responder};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use super::reject_non_admin;
+use crate::configuration::AdminSettings;
+use crate::domain::ListSlug;
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ListData {
+    pub slug: String,
+    pub name: String,
+}
+
+#[tracing::instrument(
+    name = "Creating a new list",
+    skip(request, data, db_pool, settings),
+    fields(list_slug = %data.slug)
+)]
+pub async fn create_list(
+    request: HttpRequest,
+    data: web::Json<ListData>,
+    db_pool: web::Data<PgPool>,
+    settings: web::Data<AdminSettings>,
+) -> impl Responder {
+    if let Some(response) = reject_non_admin(&request, &settings, &db_pool).await {
+        return response;
+    }
+    let data = data.into_inner();
+    let slug = match ListSlug::parse(data.slug) {
+        Ok(slug) => slug,
+        Err(_) => return HttpResponse::BadRequest().body("Invalid list slug."),
+    };
+    if data.name.trim().is_empty() {
+        return HttpResponse::BadRequest().body("Invalid list name.");
+    }
+
+    match insert_list(&slug, &data.name, &db_pool).await {
+        Ok(true) => HttpResponse::Created().finish(),
+        Ok(false) => HttpResponse::Conflict().body("A list with this slug already exists."),
+        Err(_) => HttpResponse::InternalServerError().body("Internal Server Error"),
+    }
+}
+
+/// Returns `false` if a list with the same slug already exists.
+#[tracing::instrument(name = "Saving new list in the database", skip(slug, name, db_pool))]
+async fn insert_list(slug: &ListSlug, name: &str, db_pool: &PgPool) -> Result<bool, sqlx::Error> {
+    let inserted = sqlx::query!(
+        r#"
+    INSERT INTO lists (id, slug, name, created_at)
+    VALUES ($1, $2, $3, $4)
+    ON CONFLICT (slug) DO NOTHING
+"#,
+        Uuid::new_v4(),
+        slug.as_ref(),
+        name,
+        Utc::now()
+    )
+    .execute(db_pool)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?
+    .rows_affected();
+    Ok(inserted > 0)
+}
@@ -0,0 +1,83 @@
+use actix_web::{web, HttpRequest, HttpResponse, Responder};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+
+use super::reject_non_admin;
+use crate::configuration::AdminSettings;
+use crate::telemetry::LogFilter;
+
+/// The log filter in use.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct LogLevel {
+    /// E.g. `info,sqlx=warn`.
+    pub directives: String,
+    /// When the previous directives are put back, if they are.
+    pub reverts_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct LogLevelData {
+    pub directives: String,
+    /// How long to use the directives for, if not until the next change.
+    pub ttl_seconds: Option<u64>,
+}
+
+impl LogLevel {
+    fn of(log_filter: &LogFilter) -> Self {
+        Self {
+            directives: log_filter.directives(),
+            reverts_at: log_filter.reverts_at(),
+        }
+    }
+}
+
+#[tracing::instrument(
+    name = "Getting the log level",
+    skip(request, db_pool, settings, log_filter)
+)]
+pub async fn get_log_level(
+    request: HttpRequest,
+    db_pool: web::Data<PgPool>,
+    settings: web::Data<AdminSettings>,
+    log_filter: web::Data<LogFilter>,
+) -> impl Responder {
+    if let Some(response) = reject_non_admin(&request, &settings, &db_pool).await {
+        return response;
+    }
+    HttpResponse::Ok().json(LogLevel::of(&log_filter))
+}
+
+/// Changes made here are lost on restart, and replaced if `log.filter`
+/// changes in the settings.
+#[tracing::instrument(
+    name = "Changing the log level",
+    skip(request, data, db_pool, settings, log_filter),
+    fields(directives = %data.directives, ttl_seconds = ?data.ttl_seconds)
+)]
+pub async fn set_log_level(
+    request: HttpRequest,
+    data: web::Json<LogLevelData>,
+    db_pool: web::Data<PgPool>,
+    settings: web::Data<AdminSettings>,
+    log_filter: web::Data<LogFilter>,
+) -> impl Responder {
+    if let Some(response) = reject_non_admin(&request, &settings, &db_pool).await {
+        return response;
+    }
+    let changed = match data.ttl_seconds {
+        Some(0) => return HttpResponse::BadRequest().body("The TTL must be positive."),
+        Some(ttl_seconds) => log_filter.set_for(
+            &data.directives,
+            std::time::Duration::from_secs(ttl_seconds),
+        ),
+        None => log_filter.set(&data.directives),
+    };
+    match changed {
+        Ok(()) => {
+            tracing::info!("Changed the log filter to {}", data.directives);
+            HttpResponse::Ok().json(LogLevel::of(&log_filter))
+        }
+        Err(e) => HttpResponse::BadRequest().body(e),
+    }
+}
@@ -0,0 +1,53 @@
+mod lists;
+mod log_level;
+mod newsletters;
+mod subscribers;
+mod suppressions;
+
+pub use lists::*;
+pub use log_level::*;
+pub use newsletters::*;
+pub use subscribers::*;
+pub use suppressions::*;
+
+use actix_web::http::header;
+use actix_web::{web, HttpRequest, HttpResponse};
+use sqlx::PgPool;
+
+use crate::authentication::{basic_authentication, validate_stored_credentials};
+use crate::configuration::AdminSettings;
+use crate::rate_limit::RateLimit;
+
+/// Returns the response to send back if `request` does not carry the
+/// credentials of an admin, `None` if the caller is allowed through.
+///
+/// Admins are the one of the settings, and the ones created with
+/// `create-admin`.
+async fn reject_non_admin(
+    request: &HttpRequest,
+    settings: &AdminSettings,
+    db_pool: &PgPool,
+) -> Option<HttpResponse> {
+    let unauthorized = || {
+        HttpResponse::Unauthorized()
+            .insert_header((header::WWW_AUTHENTICATE, r#"Basic realm="admin""#))
+            .finish()
+    };
+    let credentials = match basic_authentication(request.headers()) {
+        Some(credentials) => credentials,
+        None => return Some(unauthorized()),
+    };
+    if credentials.matches(&settings.username, &settings.password) {
+        return None;
+    }
+    if let Some(limits) = request.app_data::<web::Data<RateLimit>>() {
+        if let Some(response) = limits.hit_admin(request).await {
+            return Some(response);
+        }
+    }
+    match validate_stored_credentials(credentials, db_pool).await {
+        Ok(true) => None,
+        Ok(false) => Some(unauthorized()),
+        Err(_) => Some(HttpResponse::InternalServerError().body("Internal Server Error")),
+    }
+}
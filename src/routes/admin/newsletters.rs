@@ -0,0 +1,210 @@
+use actix_web::{web, HttpRequest, HttpResponse, Responder};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use sqlx::{PgPool, Postgres, QueryBuilder, Transaction};
+use uuid::Uuid;
+
+use super::reject_non_admin;
+use crate::configuration::AdminSettings;
+use crate::domain::{ListSlug, SegmentFilter};
+use crate::routes::lists::DEFAULT_LIST_SLUG;
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct NewsletterData {
+    pub title: String,
+    pub content: NewsletterContent,
+    /// The lists to send the issue to, the default list if empty.
+    #[serde(default)]
+    pub lists: Vec<String>,
+    /// A `SegmentFilter` expression: only the subscribers of the lists who
+    /// match it get the issue.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub segment: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct NewsletterContent {
+    pub html: String,
+    pub text: String,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct PublishedIssue {
+    pub newsletter_issue_id: String,
+    /// How many subscribers the issue is queued for.
+    pub recipients: u64,
+}
+
+/// Publish an issue to the active subscribers of the chosen lists, or to
+/// those of them in a segment. The emails are sent in the background by the delivery worker.
+#[tracing::instrument(
+    name = "Publishing a newsletter issue",
+    skip(request, data, db_pool, settings),
+    fields(title = %data.title, lists = ?data.lists, segment = ?data.segment)
+)]
+pub async fn publish_newsletter(
+    request: HttpRequest,
+    data: web::Json<NewsletterData>,
+    db_pool: web::Data<PgPool>,
+    settings: web::Data<AdminSettings>,
+) -> impl Responder {
+    if let Some(response) = reject_non_admin(&request, &settings, &db_pool).await {
+        return response;
+    }
+    let data = data.into_inner();
+    if data.title.trim().is_empty() {
+        return HttpResponse::BadRequest().body("Invalid title.");
+    }
+    if data.content.html.trim().is_empty() || data.content.text.trim().is_empty() {
+        return HttpResponse::BadRequest().body("Invalid content.");
+    }
+    let slugs = if data.lists.is_empty() {
+        vec![DEFAULT_LIST_SLUG.to_string()]
+    } else {
+        data.lists
+    };
+    let slugs = match slugs
+        .into_iter()
+        .map(ListSlug::parse)
+        .collect::<Result<Vec<_>, _>>()
+    {
+        Ok(slugs) => slugs,
+        Err(e) => return HttpResponse::BadRequest().body(e),
+    };
+    let segment = match data.segment.map(SegmentFilter::parse) {
+        Some(Ok(segment)) => Some(segment),
+        Some(Err(e)) => return HttpResponse::BadRequest().body(e),
+        None => None,
+    };
+
+    let outcome = async {
+        let mut transaction = db_pool.begin().await?;
+        let list_ids = match find_list_ids(&slugs, &mut transaction).await? {
+            Ok(list_ids) => list_ids,
+            Err(unknown) => return Ok(Err(unknown)),
+        };
+        let newsletter_issue_id = insert_newsletter_issue(
+            &data.title,
+            &data.content.text,
+            &data.content.html,
+            &mut transaction,
+        )
+        .await?;
+        let recipients = enqueue_delivery_tasks(
+            newsletter_issue_id,
+            &list_ids,
+            segment.as_ref(),
+            &mut transaction,
+        )
+        .await?;
+        transaction.commit().await?;
+        Ok::<_, sqlx::Error>(Ok(PublishedIssue {
+            newsletter_issue_id: newsletter_issue_id.to_string(),
+            recipients,
+        }))
+    };
+    match outcome.await {
+        Ok(Ok(issue)) => HttpResponse::Accepted().json(issue),
+        Ok(Err(unknown)) => HttpResponse::BadRequest().body(format!("Unknown list: {}.", unknown)),
+        Err(_) => HttpResponse::InternalServerError().body("Internal Server Error"),
+    }
+}
+
+/// The ids of the lists, or the first slug no list has.
+#[tracing::instrument(name = "Looking up lists", skip(slugs, transaction))]
+async fn find_list_ids(
+    slugs: &[ListSlug],
+    transaction: &mut Transaction<'_, Postgres>,
+) -> Result<Result<Vec<Uuid>, String>, sqlx::Error> {
+    let slugs: Vec<String> = slugs.iter().map(|s| s.as_ref().to_string()).collect();
+    let lists = sqlx::query!(r#"SELECT id, slug FROM lists WHERE slug = ANY($1)"#, &slugs)
+        .fetch_all(&mut **transaction)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to execute query: {:?}", e);
+            e
+        })?;
+    match slugs
+        .into_iter()
+        .find(|slug| !lists.iter().any(|list| &list.slug == slug))
+    {
+        Some(unknown) => Ok(Err(unknown)),
+        None => Ok(Ok(lists.into_iter().map(|list| list.id).collect())),
+    }
+}
+
+#[tracing::instrument(
+    name = "Saving newsletter issue",
+    skip(title, text_content, html_content, transaction)
+)]
+async fn insert_newsletter_issue(
+    title: &str,
+    text_content: &str,
+    html_content: &str,
+    transaction: &mut Transaction<'_, Postgres>,
+) -> Result<Uuid, sqlx::Error> {
+    let newsletter_issue_id = Uuid::new_v4();
+    sqlx::query!(
+        r#"
+    INSERT INTO newsletter_issues
+        (newsletter_issue_id, title, text_content, html_content, published_at)
+    VALUES ($1, $2, $3, $4, $5)
+"#,
+        newsletter_issue_id,
+        title,
+        text_content,
+        html_content,
+        Utc::now()
+    )
+    .execute(&mut **transaction)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    Ok(newsletter_issue_id)
+}
+
+/// Queue the issue for every active subscriber with an active membership of
+/// one of the lists, once even if they are on several of them. Only the
+/// subscribers matching `segment` are queued, if it is set.
+#[tracing::instrument(
+    name = "Queuing newsletter deliveries",
+    skip(list_ids, segment, transaction)
+)]
+async fn enqueue_delivery_tasks(
+    newsletter_issue_id: Uuid,
+    list_ids: &[Uuid],
+    segment: Option<&SegmentFilter>,
+    transaction: &mut Transaction<'_, Postgres>,
+) -> Result<u64, sqlx::Error> {
+    let mut builder = QueryBuilder::new(
+        "INSERT INTO issue_delivery_queue (newsletter_issue_id, subscriber_id) SELECT ",
+    );
+    builder.push_bind(newsletter_issue_id);
+    builder.push(
+        ", subscriptions.id FROM subscriptions \
+        WHERE subscriptions.status = 'active' \
+        AND EXISTS (\
+            SELECT 1 FROM list_memberships \
+            WHERE list_memberships.subscriber_id = subscriptions.id \
+            AND list_memberships.status = 'active' \
+            AND list_memberships.list_id = ANY(",
+    );
+    builder.push_bind(list_ids.to_vec());
+    builder.push("))");
+    if let Some(segment) = segment {
+        builder.push(" AND ");
+        segment.push_sql(&mut builder);
+    }
+    let queued = builder
+        .build()
+        .execute(&mut **transaction)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to execute query: {:?}", e);
+            e
+        })?
+        .rows_affected();
+    Ok(queued)
+}
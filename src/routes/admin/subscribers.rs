@@ -0,0 +1,159 @@
+use actix_web::{web, HttpRequest, HttpResponse, Responder};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{PgPool, QueryBuilder};
+
+use super::reject_non_admin;
+use crate::configuration::AdminSettings;
+use crate::consent::fetch_consent_history;
+use crate::domain::{SegmentFilter, SubscriberEmail};
+use crate::routes::preferences::data_export_response;
+use crate::subscriber_data::{erase_subscriber_data, export_subscriber_data};
+use crate::suppression_list::TombstoneKey;
+
+#[derive(Debug, Deserialize)]
+pub struct SubscribersQuery {
+    /// A `SegmentFilter` expression, all subscribers are returned if missing.
+    pub segment: Option<String>,
+}
+
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct SubscriberSummary {
+    pub email: String,
+    pub name: String,
+    pub status: String,
+    pub subscribed_at: DateTime<Utc>,
+    pub attributes: serde_json::Value,
+}
+
+#[tracing::instrument(
+    name = "Listing subscribers in a segment",
+    skip(request, query, db_pool, settings),
+    fields(segment = ?query.segment)
+)]
+pub async fn list_subscribers(
+    request: HttpRequest,
+    query: web::Query<SubscribersQuery>,
+    db_pool: web::Data<PgPool>,
+    settings: web::Data<AdminSettings>,
+) -> impl Responder {
+    if let Some(response) = reject_non_admin(&request, &settings, &db_pool).await {
+        return response;
+    }
+    let segment = match query.into_inner().segment.map(SegmentFilter::parse) {
+        Some(Ok(segment)) => Some(segment),
+        Some(Err(e)) => return HttpResponse::BadRequest().body(e),
+        None => None,
+    };
+
+    match fetch_subscribers(segment.as_ref(), &db_pool).await {
+        Ok(subscribers) => HttpResponse::Ok().json(subscribers),
+        Err(_) => HttpResponse::InternalServerError().body("Internal Server Error"),
+    }
+}
+
+#[tracing::instrument(name = "Fetching subscribers in a segment", skip(segment, db_pool))]
+pub async fn fetch_subscribers(
+    segment: Option<&SegmentFilter>,
+    db_pool: &PgPool,
+) -> Result<Vec<SubscriberSummary>, sqlx::Error> {
+    let mut builder = QueryBuilder::new(
+        "SELECT email, name, status, subscribed_at, attributes FROM subscriptions",
+    );
+    if let Some(segment) = segment {
+        builder.push(" WHERE ");
+        segment.push_sql(&mut builder);
+    }
+    builder.push(" ORDER BY subscribed_at");
+    builder
+        .build_query_as::<SubscriberSummary>()
+        .fetch_all(db_pool)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to execute query: {:?}", e);
+            e
+        })
+}
+
+#[tracing::instrument(
+    name = "Exporting subscriber data for an admin",
+    skip(request, email, db_pool, settings, tombstone_key),
+    fields(email = %email)
+)]
+pub async fn export_subscriber(
+    request: HttpRequest,
+    email: web::Path<String>,
+    db_pool: web::Data<PgPool>,
+    settings: web::Data<AdminSettings>,
+    tombstone_key: web::Data<TombstoneKey>,
+) -> impl Responder {
+    if let Some(response) = reject_non_admin(&request, &settings, &db_pool).await {
+        return response;
+    }
+    let email = match SubscriberEmail::parse(email.into_inner()) {
+        Ok(email) => email,
+        Err(_) => return HttpResponse::BadRequest().body("Invalid email."),
+    };
+    match export_subscriber_data(&email, &tombstone_key, &db_pool).await {
+        Ok(data) => data_export_response(&data),
+        Err(_) => HttpResponse::InternalServerError().body("Internal Server Error"),
+    }
+}
+
+/// How and when the subscriber at `email` gave or withdrew their consent,
+/// oldest first.
+#[tracing::instrument(
+    name = "Showing consent history",
+    skip(request, email, db_pool, settings),
+    fields(email = %email)
+)]
+pub async fn consent_history(
+    request: HttpRequest,
+    email: web::Path<String>,
+    db_pool: web::Data<PgPool>,
+    settings: web::Data<AdminSettings>,
+) -> impl Responder {
+    if let Some(response) = reject_non_admin(&request, &settings, &db_pool).await {
+        return response;
+    }
+    let email = match SubscriberEmail::parse(email.into_inner()) {
+        Ok(email) => email,
+        Err(_) => return HttpResponse::BadRequest().body("Invalid email."),
+    };
+    match fetch_consent_history(&email, db_pool.get_ref()).await {
+        Ok(events) => HttpResponse::Ok().json(events),
+        Err(_) => HttpResponse::InternalServerError().body("Internal Server Error"),
+    }
+}
+
+/// Erase everything we store about an address, whether it is subscribed or
+/// not.
+#[tracing::instrument(
+    name = "Erasing subscriber data for an admin",
+    skip(request, email, db_pool, settings, tombstone_key),
+    fields(email = %email)
+)]
+pub async fn erase_subscriber(
+    request: HttpRequest,
+    email: web::Path<String>,
+    db_pool: web::Data<PgPool>,
+    settings: web::Data<AdminSettings>,
+    tombstone_key: web::Data<TombstoneKey>,
+) -> impl Responder {
+    if let Some(response) = reject_non_admin(&request, &settings, &db_pool).await {
+        return response;
+    }
+    let email = match SubscriberEmail::parse(email.into_inner()) {
+        Ok(email) => email,
+        Err(_) => return HttpResponse::BadRequest().body("Invalid email."),
+    };
+    let outcome = async {
+        let mut transaction = db_pool.begin().await?;
+        erase_subscriber_data(&email, &tombstone_key, &mut transaction).await?;
+        transaction.commit().await
+    };
+    match outcome.await {
+        Ok(_) => HttpResponse::NoContent().finish(),
+        Err(_) => HttpResponse::InternalServerError().body("Internal Server Error"),
+    }
+}
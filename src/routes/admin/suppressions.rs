@@ -0,0 +1,78 @@
+use actix_web::{web, HttpRequest, HttpResponse, Responder};
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+
+use super::reject_non_admin;
+use crate::configuration::AdminSettings;
+use crate::domain::SubscriberEmail;
+use crate::suppression_list::{
+    add_suppression, remove_suppression, SuppressionReason, TombstoneKey,
+};
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct SuppressionData {
+    pub email: String,
+}
+
+#[tracing::instrument(
+    name = "Manually suppressing an email address",
+    skip(request, data, db_pool, settings),
+    fields(email = %data.email)
+)]
+pub async fn add_manual_suppression(
+    request: HttpRequest,
+    data: web::Json<SuppressionData>,
+    db_pool: web::Data<PgPool>,
+    settings: web::Data<AdminSettings>,
+) -> impl Responder {
+    if let Some(response) = reject_non_admin(&request, &settings, &db_pool).await {
+        return response;
+    }
+    let email = match SubscriberEmail::parse(data.0.email) {
+        Ok(email) => email,
+        Err(_) => return HttpResponse::BadRequest().body("Invalid email."),
+    };
+
+    let outcome = async {
+        let mut transaction = db_pool.begin().await?;
+        add_suppression(&email, SuppressionReason::Manual, "admin", &mut transaction).await?;
+        transaction.commit().await
+    };
+    match outcome.await {
+        Ok(_) => HttpResponse::Ok().finish(),
+        Err(_) => HttpResponse::InternalServerError().body("Internal Server Error"),
+    }
+}
+
+#[tracing::instrument(
+    name = "Manually removing an email address suppression",
+    skip(request, email, db_pool, settings, tombstone_key),
+    fields(email = %email)
+)]
+pub async fn delete_suppression(
+    request: HttpRequest,
+    email: web::Path<String>,
+    db_pool: web::Data<PgPool>,
+    settings: web::Data<AdminSettings>,
+    tombstone_key: web::Data<TombstoneKey>,
+) -> impl Responder {
+    if let Some(response) = reject_non_admin(&request, &settings, &db_pool).await {
+        return response;
+    }
+    let email = match SubscriberEmail::parse(email.into_inner()) {
+        Ok(email) => email,
+        Err(_) => return HttpResponse::BadRequest().body("Invalid email."),
+    };
+
+    let outcome = async {
+        let mut transaction = db_pool.begin().await?;
+        let removed = remove_suppression(&email, &tombstone_key, &mut transaction).await?;
+        transaction.commit().await?;
+        Ok::<_, sqlx::Error>(removed)
+    };
+    match outcome.await {
+        Ok(true) => HttpResponse::NoContent().finish(),
+        Ok(false) => HttpResponse::NotFound().finish(),
+        Err(_) => HttpResponse::InternalServerError().body("Internal Server Error"),
+    }
+}
@@ -0,0 +1,141 @@
+use actix_web::{web, HttpResponse, Responder};
+use chrono::Utc;
+use sqlx::{PgExecutor, PgPool, Postgres, Transaction};
+use uuid::Uuid;
+
+use crate::consent::{record_consent_event, ConsentOrigin};
+use crate::domain::ListSlug;
+use crate::email_client::EmailClient;
+use crate::routes::subscriptions::{
+    has_complained, register_subscriber, rejection_response, send_confirmation_email, FormData,
+    SubscriptionConfirmation, SubscriptionValidator,
+};
+use crate::suppression_list::TombstoneKey;
+
+/// The list `POST /subscriptions` adds subscribers to.
+pub const DEFAULT_LIST_SLUG: &str = "default";
+
+#[tracing::instrument(
+    name = "Adding a subscriber to a list",
+    skip(slug, data, db_pool, email_client, confirmation, validator, tombstone_key, origin),
+    fields(
+        list_slug = %slug,
+        subscriber_email = %data.email,
+        subscriber_name = %data.name
+    )
+)]
+#[allow(clippy::too_many_arguments)]
+pub async fn subscribe_to_list(
+    slug: web::Path<String>,
+    data: web::Json<FormData>,
+    db_pool: web::Data<PgPool>,
+    email_client: web::Data<EmailClient>,
+    confirmation: web::Data<SubscriptionConfirmation>,
+    validator: web::Data<SubscriptionValidator>,
+    tombstone_key: web::Data<TombstoneKey>,
+    origin: ConsentOrigin,
+) -> impl Responder {
+    let slug = match ListSlug::parse(slug.into_inner()) {
+        Ok(slug) => slug,
+        Err(_) => return HttpResponse::NotFound().finish(),
+    };
+    let new_subscriber = match validator.validate(&data, origin.ip.as_deref()).await {
+        Ok(subscriber) => subscriber,
+        Err(e) => return rejection_response(e),
+    };
+
+    match has_complained(&new_subscriber.email, &tombstone_key, &db_pool).await {
+        Ok(true) => return HttpResponse::Ok().finish(),
+        Ok(false) => {}
+        Err(_) => return HttpResponse::InternalServerError().body("Internal Server Error"),
+    }
+
+    let outcome = async {
+        let mut transaction = db_pool.begin().await?;
+        let list_id = match find_list_id(slug.as_ref(), &mut *transaction).await? {
+            Some(list_id) => list_id,
+            None => return Ok(None),
+        };
+        // People already on another list keep their existing subscriber row
+        let registration = register_subscriber(
+            &new_subscriber,
+            confirmation.resend_cooldown,
+            &tombstone_key,
+            &mut transaction,
+        )
+        .await?;
+        add_list_membership(list_id, registration.subscriber_id, &mut transaction).await?;
+        let endpoint = format!("lists/{}/subscriptions", slug.as_ref());
+        record_consent_event(
+            registration.subscriber_id,
+            new_subscriber.email.canonical(),
+            data.consent_event(&endpoint, slug.as_ref()),
+            &origin,
+            &mut transaction,
+        )
+        .await?;
+        if let Some(token) = &registration.confirmation_token {
+            send_confirmation_email(&email_client, &new_subscriber, &confirmation, token).await?;
+        }
+        transaction.commit().await?;
+        Ok::<_, Box<dyn std::error::Error>>(Some(()))
+    };
+    match outcome.await {
+        Ok(Some(_)) => HttpResponse::Ok().finish(),
+        Ok(None) => HttpResponse::NotFound().finish(),
+        Err(e) => {
+            tracing::error!("Failed to register the subscriber: {:?}", e);
+            HttpResponse::InternalServerError().body("Internal Server Error")
+        }
+    }
+}
+
+#[tracing::instrument(name = "Looking up list", skip(executor))]
+pub async fn find_list_id<'e>(
+    slug: &str,
+    executor: impl PgExecutor<'e>,
+) -> Result<Option<Uuid>, sqlx::Error> {
+    let row = sqlx::query!(r#"SELECT id FROM lists WHERE slug = $1"#, slug)
+        .fetch_optional(executor)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to execute query: {:?}", e);
+            e
+        })?;
+    Ok(row.map(|r| r.id))
+}
+
+/// Add the subscriber to the list, or back to it if they unsubscribed from
+/// it. Does nothing if they are already on it.
+///
+/// The membership is active if the subscriber is, pending their
+/// confirmation otherwise: confirming activates it, see `confirm`.
+#[tracing::instrument(name = "Saving list membership", skip(transaction))]
+pub async fn add_list_membership(
+    list_id: Uuid,
+    subscriber_id: Uuid,
+    transaction: &mut Transaction<'_, Postgres>,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+    INSERT INTO list_memberships (list_id, subscriber_id, status, subscribed_at)
+    SELECT $1, id,
+        CASE WHEN status = 'active' THEN 'active' ELSE 'pending_confirmation' END,
+        $3
+    FROM subscriptions WHERE id = $2
+    ON CONFLICT (list_id, subscriber_id) DO UPDATE
+    SET status = EXCLUDED.status, subscribed_at = EXCLUDED.subscribed_at
+    WHERE list_memberships.status = 'unsubscribed'
+"#,
+        list_id,
+        subscriber_id,
+        Utc::now()
+    )
+    .execute(&mut **transaction)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    Ok(())
+}
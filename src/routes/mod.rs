@@ -1,5 +1,15 @@
+mod admin;
 mod health_check;
+mod lists;
+mod preferences;
 mod subscriptions;
+mod subscriptions_confirm;
+mod webhooks;
 
+pub use admin::*;
 pub use health_check::*;
+pub use lists::*;
+pub use preferences::*;
 pub use subscriptions::*;
+pub use subscriptions_confirm::*;
+pub use webhooks::*;
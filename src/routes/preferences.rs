@@ -0,0 +1,800 @@
+use actix_web::http::header;
+use actix_web::{web, HttpResponse, Responder};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use sqlx::{PgPool, Postgres, Transaction};
+use uuid::Uuid;
+
+use crate::consent::{record_consent_event, ConsentEvent, ConsentEventKind, ConsentOrigin};
+use crate::domain::{DigestFrequency, ListSlug, SubscriberEmail, SubscriberName};
+use crate::email_client::{EmailClient, SendEmailError};
+use crate::magic_link::{MagicLinkPurpose, MagicLinks};
+use crate::routes::lists::{add_list_membership, find_list_id};
+use crate::routes::subscriptions::{rejection_response, SubscriptionValidator};
+use crate::subscriber_data::{erase_subscriber_data, export_subscriber_data, SubscriberData};
+use crate::suppression_list::{add_suppression, SuppressionReason, TombstoneKey};
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct PreferencesLoginData {
+    pub email: String,
+}
+
+#[derive(Deserialize)]
+pub struct TokenParameters {
+    token: String,
+}
+
+/// Changes to a subscriber's preferences. Missing fields are left as they are.
+#[derive(Debug, Deserialize, Serialize, Default)]
+pub struct PreferencesForm {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// Only takes effect once the new address is confirmed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub email: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub digest_frequency: Option<String>,
+    /// The slugs of every list the subscriber wants to be on. None
+    /// unsubscribes them: their address is added to the suppression list.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lists: Option<Vec<String>>,
+    /// The version of the consent wording the preferences page showed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub consent_text_version: Option<String>,
+}
+
+impl PreferencesForm {
+    /// The consent-related changes, as recorded in the consent history.
+    /// `None` if the form changes none.
+    fn consent_details(&self) -> Option<serde_json::Value> {
+        let mut details = serde_json::Map::new();
+        if let Some(lists) = &self.lists {
+            details.insert("lists".into(), serde_json::json!(lists));
+        }
+        if let Some(digest_frequency) = &self.digest_frequency {
+            details.insert("digest_frequency".into(), digest_frequency.clone().into());
+        }
+        if let Some(email) = &self.email {
+            details.insert("email".into(), email.clone().into());
+        }
+        (!details.is_empty()).then_some(serde_json::Value::Object(details))
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Preferences {
+    pub name: String,
+    pub email: String,
+    pub pending_email: Option<String>,
+    pub digest_frequency: String,
+    pub lists: Vec<ListPreference>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ListPreference {
+    pub slug: String,
+    pub name: String,
+    pub subscribed: bool,
+}
+
+/// Email subscribers a link to their preferences.
+///
+/// Answers the same way whether the address is subscribed or not.
+#[tracing::instrument(
+    name = "Sending a preferences link",
+    skip(data, db_pool, email_client, magic_links),
+    fields(subscriber_email = %data.email)
+)]
+pub async fn request_preferences_link(
+    data: web::Json<PreferencesLoginData>,
+    db_pool: web::Data<PgPool>,
+    email_client: web::Data<EmailClient>,
+    magic_links: web::Data<MagicLinks>,
+) -> impl Responder {
+    let email = match SubscriberEmail::parse(data.0.email) {
+        Ok(email) => email,
+        Err(e) => return HttpResponse::BadRequest().body(format!("Invalid data. {}", e)),
+    };
+    let outcome = async {
+        let (subscriber_id, stored_email) = match find_subscriber(&email, &db_pool).await? {
+            Some(subscriber) => subscriber,
+            None => return Ok(()),
+        };
+        let link = magic_links.link(
+            "/preferences",
+            MagicLinkPurpose::Preferences,
+            subscriber_id,
+            None,
+        );
+        send_link(
+            &email_client,
+            stored_email,
+            "Manage your subscription",
+            "manage your subscription",
+            &link,
+        )
+        .await?;
+        Ok::<_, Box<dyn std::error::Error>>(())
+    };
+    match outcome.await {
+        Ok(_) => HttpResponse::Ok().finish(),
+        Err(e) => {
+            tracing::error!("Failed to send the preferences link: {:?}", e);
+            HttpResponse::InternalServerError().body("Internal Server Error")
+        }
+    }
+}
+
+#[tracing::instrument(
+    name = "Showing subscriber preferences",
+    skip(parameters, db_pool, magic_links)
+)]
+pub async fn get_preferences(
+    parameters: web::Query<TokenParameters>,
+    db_pool: web::Data<PgPool>,
+    magic_links: web::Data<MagicLinks>,
+) -> impl Responder {
+    let subscriber_id =
+        match magic_links.verify(&parameters.token, MagicLinkPurpose::Preferences, Utc::now()) {
+            Ok(claims) => claims.subscriber_id,
+            Err(e) => {
+                tracing::warn!("Rejecting preferences link: {}", e);
+                return HttpResponse::Unauthorized().finish();
+            }
+        };
+    match fetch_preferences(subscriber_id, &db_pool).await {
+        Ok(Some(preferences)) => HttpResponse::Ok().json(preferences),
+        // The subscriber is gone since the link was sent
+        Ok(None) => HttpResponse::Unauthorized().finish(),
+        Err(_) => HttpResponse::InternalServerError().body("Internal Server Error"),
+    }
+}
+
+#[tracing::instrument(
+    name = "Updating subscriber preferences",
+    skip(
+        parameters,
+        form,
+        db_pool,
+        email_client,
+        magic_links,
+        validator,
+        tombstone_key,
+        origin
+    )
+)]
+#[allow(clippy::too_many_arguments)]
+pub async fn update_preferences(
+    parameters: web::Query<TokenParameters>,
+    form: web::Json<PreferencesForm>,
+    db_pool: web::Data<PgPool>,
+    email_client: web::Data<EmailClient>,
+    magic_links: web::Data<MagicLinks>,
+    validator: web::Data<SubscriptionValidator>,
+    tombstone_key: web::Data<TombstoneKey>,
+    origin: ConsentOrigin,
+) -> impl Responder {
+    let subscriber_id =
+        match magic_links.verify(&parameters.token, MagicLinkPurpose::Preferences, Utc::now()) {
+            Ok(claims) => claims.subscriber_id,
+            Err(e) => {
+                tracing::warn!("Rejecting preferences link: {}", e);
+                return HttpResponse::Unauthorized().finish();
+            }
+        };
+    let form = form.into_inner();
+    let consent_details = form.consent_details();
+    let name = match form.name.map(|name| validator.validate_name(name)) {
+        Some(Ok(name)) => Some(name),
+        Some(Err(e)) => return rejection_response(e),
+        None => None,
+    };
+    let digest_frequency = match form.digest_frequency.map(DigestFrequency::try_from) {
+        Some(Ok(frequency)) => Some(frequency),
+        Some(Err(e)) => return HttpResponse::BadRequest().body(format!("Invalid data. {}", e)),
+        None => None,
+    };
+    let email = match form.email {
+        Some(email) => match validator.validate_email(email).await {
+            Ok(email) => Some(email),
+            Err(e) => return rejection_response(e),
+        },
+        None => None,
+    };
+    let list_ids = match form.lists {
+        Some(slugs) => match find_list_ids(slugs, &db_pool).await {
+            Ok(Ok(list_ids)) => Some(list_ids),
+            Ok(Err(e)) => return HttpResponse::BadRequest().body(format!("Invalid data. {}", e)),
+            Err(_) => return HttpResponse::InternalServerError().body("Internal Server Error"),
+        },
+        None => None,
+    };
+
+    let outcome = async {
+        let mut transaction = db_pool.begin().await?;
+        let current_email = match lock_subscriber(subscriber_id, &mut transaction).await? {
+            Some(current_email) => current_email,
+            None => return Ok(None),
+        };
+        update_subscriber(
+            subscriber_id,
+            name.as_ref(),
+            digest_frequency,
+            &mut transaction,
+        )
+        .await?;
+        if let Some(list_ids) = &list_ids {
+            set_list_memberships(subscriber_id, list_ids, &mut transaction).await?;
+        }
+        // Only a different mailbox needs confirming
+        let new_email = email.filter(|email| email.canonical() != current_email.canonical());
+        if let Some(new_email) = &new_email {
+            set_pending_email(subscriber_id, new_email, &mut transaction).await?;
+        }
+        // Leaving every list is how subscribers unsubscribe
+        let unsubscribed = list_ids
+            .as_ref()
+            .is_some_and(|list_ids| list_ids.is_empty());
+        if unsubscribed {
+            add_suppression(
+                &current_email,
+                SuppressionReason::Unsubscribed,
+                "preferences",
+                &mut transaction,
+            )
+            .await?;
+        } else if list_ids.is_some() {
+            // Choosing lists again is how they come back
+            resubscribe(
+                subscriber_id,
+                &current_email,
+                &tombstone_key,
+                &mut transaction,
+            )
+            .await?;
+        }
+        if let Some(details) = consent_details {
+            let kind = if unsubscribed {
+                ConsentEventKind::Unsubscribed
+            } else {
+                ConsentEventKind::PreferencesChanged
+            };
+            record_consent_event(
+                subscriber_id,
+                current_email.canonical(),
+                ConsentEvent {
+                    kind,
+                    source: "preferences",
+                    text_version: form.consent_text_version.as_deref(),
+                    details,
+                },
+                &origin,
+                &mut transaction,
+            )
+            .await?;
+        }
+        transaction.commit().await?;
+        if let Some(new_email) = new_email {
+            let link = magic_links.link(
+                "/preferences/email",
+                MagicLinkPurpose::EmailChange,
+                subscriber_id,
+                Some(new_email.as_ref().to_string()),
+            );
+            send_link(
+                &email_client,
+                new_email,
+                "Confirm your new email address",
+                "confirm your new email address",
+                &link,
+            )
+            .await?;
+        }
+        Ok::<_, Box<dyn std::error::Error>>(fetch_preferences(subscriber_id, &db_pool).await?)
+    };
+    match outcome.await {
+        Ok(Some(preferences)) => HttpResponse::Ok().json(preferences),
+        Ok(None) => HttpResponse::Unauthorized().finish(),
+        Err(e) => {
+            tracing::error!("Failed to update the preferences: {:?}", e);
+            HttpResponse::InternalServerError().body("Internal Server Error")
+        }
+    }
+}
+
+/// Switch the subscriber to the address the link was sent to.
+#[tracing::instrument(
+    name = "Confirming a new email address",
+    skip(parameters, db_pool, magic_links, origin)
+)]
+pub async fn confirm_email_change(
+    parameters: web::Query<TokenParameters>,
+    db_pool: web::Data<PgPool>,
+    magic_links: web::Data<MagicLinks>,
+    origin: ConsentOrigin,
+) -> impl Responder {
+    let (subscriber_id, email) =
+        match magic_links.verify(&parameters.token, MagicLinkPurpose::EmailChange, Utc::now()) {
+            Ok(claims) => match claims.email.map(SubscriberEmail::parse) {
+                Some(Ok(email)) => (claims.subscriber_id, email),
+                _ => return HttpResponse::Unauthorized().finish(),
+            },
+            Err(e) => {
+                tracing::warn!("Rejecting email change link: {}", e);
+                return HttpResponse::Unauthorized().finish();
+            }
+        };
+    let outcome = async {
+        let mut transaction = db_pool.begin().await?;
+        if !change_email(subscriber_id, &email, &mut transaction).await? {
+            return Ok(false);
+        }
+        record_consent_event(
+            subscriber_id,
+            email.canonical(),
+            ConsentEvent {
+                kind: ConsentEventKind::Confirmed,
+                source: "email_change_email",
+                text_version: None,
+                details: serde_json::json!({}),
+            },
+            &origin,
+            &mut transaction,
+        )
+        .await?;
+        transaction.commit().await?;
+        Ok::<_, sqlx::Error>(true)
+    };
+    match outcome.await {
+        Ok(true) => HttpResponse::Ok().finish(),
+        // The subscriber asked for another address since
+        Ok(false) => HttpResponse::Unauthorized().finish(),
+        Err(sqlx::Error::Database(e)) if e.is_unique_violation() => {
+            HttpResponse::Conflict().body("Somebody already subscribed with this address.")
+        }
+        Err(_) => HttpResponse::InternalServerError().body("Internal Server Error"),
+    }
+}
+
+/// Everything we store about the subscriber, as a JSON file.
+#[tracing::instrument(
+    name = "Exporting subscriber data",
+    skip(parameters, db_pool, magic_links, tombstone_key)
+)]
+pub async fn export_preferences_data(
+    parameters: web::Query<TokenParameters>,
+    db_pool: web::Data<PgPool>,
+    magic_links: web::Data<MagicLinks>,
+    tombstone_key: web::Data<TombstoneKey>,
+) -> impl Responder {
+    let subscriber_id =
+        match magic_links.verify(&parameters.token, MagicLinkPurpose::Preferences, Utc::now()) {
+            Ok(claims) => claims.subscriber_id,
+            Err(e) => {
+                tracing::warn!("Rejecting preferences link: {}", e);
+                return HttpResponse::Unauthorized().finish();
+            }
+        };
+    let outcome = async {
+        let email = match find_subscriber_email(subscriber_id, &db_pool).await? {
+            Some(email) => email,
+            None => return Ok(None),
+        };
+        export_subscriber_data(&email, &tombstone_key, &db_pool)
+            .await
+            .map(Some)
+    };
+    match outcome.await {
+        Ok(Some(data)) => data_export_response(&data),
+        Ok(None) => HttpResponse::Unauthorized().finish(),
+        Err(_) => HttpResponse::InternalServerError().body("Internal Server Error"),
+    }
+}
+
+/// Delete everything we store about the subscriber.
+#[tracing::instrument(
+    name = "Erasing subscriber data",
+    skip(parameters, db_pool, magic_links, tombstone_key)
+)]
+pub async fn erase_preferences_data(
+    parameters: web::Query<TokenParameters>,
+    db_pool: web::Data<PgPool>,
+    magic_links: web::Data<MagicLinks>,
+    tombstone_key: web::Data<TombstoneKey>,
+) -> impl Responder {
+    let subscriber_id =
+        match magic_links.verify(&parameters.token, MagicLinkPurpose::Preferences, Utc::now()) {
+            Ok(claims) => claims.subscriber_id,
+            Err(e) => {
+                tracing::warn!("Rejecting preferences link: {}", e);
+                return HttpResponse::Unauthorized().finish();
+            }
+        };
+    let outcome = async {
+        let email = match find_subscriber_email(subscriber_id, &db_pool).await? {
+            Some(email) => email,
+            None => return Ok(None),
+        };
+        let mut transaction = db_pool.begin().await?;
+        erase_subscriber_data(&email, &tombstone_key, &mut transaction).await?;
+        transaction.commit().await?;
+        Ok::<_, sqlx::Error>(Some(()))
+    };
+    match outcome.await {
+        Ok(Some(_)) => HttpResponse::Ok().finish(),
+        // Already erased
+        Ok(None) => HttpResponse::Unauthorized().finish(),
+        Err(_) => HttpResponse::InternalServerError().body("Internal Server Error"),
+    }
+}
+
+/// The response to a data export request, downloaded as a file by browsers.
+pub(crate) fn data_export_response(data: &SubscriberData) -> HttpResponse {
+    HttpResponse::Ok()
+        .insert_header((
+            header::CONTENT_DISPOSITION,
+            r#"attachment; filename="subscriber-data.json""#,
+        ))
+        .json(data)
+}
+
+/// Email `recipient` a link, e.g. to their preferences.
+///
+/// Addresses that bounced or complained are skipped silently, the response
+/// must not reveal they are known to us.
+async fn send_link(
+    email_client: &EmailClient,
+    recipient: SubscriberEmail,
+    subject: &str,
+    action: &str,
+    link: &str,
+) -> Result<(), SendEmailError> {
+    let html_body = format!(
+        "Click <a href=\"{}\">here</a> to {}.<br />\
+        The link expires soon, ignore this email if you did not ask for it.",
+        link, action
+    );
+    let plain_body = format!(
+        "Visit {} to {}.\n\
+        The link expires soon, ignore this email if you did not ask for it.",
+        link, action
+    );
+    match email_client
+        .send_transactional_email(recipient, subject, &html_body, &plain_body)
+        .await
+    {
+        Err(SendEmailError::Suppressed(_)) => Ok(()),
+        outcome => outcome,
+    }
+}
+
+#[tracing::instrument(name = "Looking up subscriber by email", skip(email, db_pool))]
+async fn find_subscriber(
+    email: &SubscriberEmail,
+    db_pool: &PgPool,
+) -> Result<Option<(Uuid, SubscriberEmail)>, sqlx::Error> {
+    let row = sqlx::query!(
+        r#"SELECT id, email FROM subscriptions WHERE email_canonical = $1"#,
+        email.canonical()
+    )
+    .fetch_optional(db_pool)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    // Stored addresses were valid when saved, fall back to the one we were
+    // given if the rules changed since
+    Ok(row.map(|r| {
+        let stored = SubscriberEmail::parse(r.email).unwrap_or_else(|_| email.clone());
+        (r.id, stored)
+    }))
+}
+
+#[tracing::instrument(name = "Looking up subscriber email", skip(db_pool))]
+async fn find_subscriber_email(
+    subscriber_id: Uuid,
+    db_pool: &PgPool,
+) -> Result<Option<SubscriberEmail>, sqlx::Error> {
+    let row = sqlx::query!(
+        r#"SELECT email FROM subscriptions WHERE id = $1"#,
+        subscriber_id
+    )
+    .fetch_optional(db_pool)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    // Stored addresses were valid when saved, treat the ones the rules
+    // reject since as gone
+    Ok(row.and_then(|r| SubscriberEmail::parse(r.email).ok()))
+}
+
+#[tracing::instrument(name = "Fetching subscriber preferences", skip(db_pool))]
+pub async fn fetch_preferences(
+    subscriber_id: Uuid,
+    db_pool: &PgPool,
+) -> Result<Option<Preferences>, sqlx::Error> {
+    let subscriber = sqlx::query!(
+        r#"
+    SELECT name, email, pending_email, digest_frequency FROM subscriptions
+    WHERE id = $1
+"#,
+        subscriber_id
+    )
+    .fetch_optional(db_pool)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    let subscriber = match subscriber {
+        Some(subscriber) => subscriber,
+        None => return Ok(None),
+    };
+    let lists = sqlx::query_as!(
+        ListPreference,
+        r#"
+    SELECT lists.slug, lists.name,
+        COALESCE(list_memberships.status = 'active', false) AS "subscribed!"
+    FROM lists
+    LEFT JOIN list_memberships
+        ON list_memberships.list_id = lists.id AND list_memberships.subscriber_id = $1
+    ORDER BY lists.slug
+"#,
+        subscriber_id
+    )
+    .fetch_all(db_pool)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    Ok(Some(Preferences {
+        name: subscriber.name,
+        email: subscriber.email,
+        pending_email: subscriber.pending_email,
+        digest_frequency: subscriber.digest_frequency,
+        lists,
+    }))
+}
+
+/// The ids of the lists behind `slugs`, or an error naming the first one
+/// that does not exist.
+async fn find_list_ids(
+    slugs: Vec<String>,
+    db_pool: &PgPool,
+) -> Result<Result<Vec<Uuid>, String>, sqlx::Error> {
+    let mut list_ids = Vec::new();
+    for slug in slugs {
+        let list_id = match ListSlug::parse(slug.clone()) {
+            Ok(slug) => find_list_id(slug.as_ref(), db_pool).await?,
+            Err(_) => None,
+        };
+        match list_id {
+            Some(list_id) => list_ids.push(list_id),
+            None => return Ok(Err(format!("{} is not a known list.", slug))),
+        }
+    }
+    Ok(Ok(list_ids))
+}
+
+/// Returns the email of the subscriber, `None` if they are gone.
+#[tracing::instrument(name = "Locking subscriber", skip(transaction))]
+async fn lock_subscriber(
+    subscriber_id: Uuid,
+    transaction: &mut Transaction<'_, Postgres>,
+) -> Result<Option<SubscriberEmail>, sqlx::Error> {
+    let row = sqlx::query!(
+        r#"SELECT email FROM subscriptions WHERE id = $1 FOR UPDATE"#,
+        subscriber_id
+    )
+    .fetch_optional(&mut **transaction)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    row.map(|r| SubscriberEmail::parse(r.email).map_err(|e| sqlx::Error::Decode(e.into())))
+        .transpose()
+}
+
+#[tracing::instrument(name = "Saving subscriber preferences", skip(name, transaction))]
+async fn update_subscriber(
+    subscriber_id: Uuid,
+    name: Option<&SubscriberName>,
+    digest_frequency: Option<DigestFrequency>,
+    transaction: &mut Transaction<'_, Postgres>,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+    UPDATE subscriptions
+    SET name = COALESCE($2, name), digest_frequency = COALESCE($3, digest_frequency)
+    WHERE id = $1
+"#,
+        subscriber_id,
+        name.map(|name| name.as_ref()),
+        digest_frequency
+            .as_ref()
+            .map(|frequency| frequency.as_str())
+    )
+    .execute(&mut **transaction)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    Ok(())
+}
+
+/// Put the subscriber on exactly `list_ids`, unsubscribing them from the
+/// other lists.
+#[tracing::instrument(name = "Saving list memberships", skip(transaction))]
+async fn set_list_memberships(
+    subscriber_id: Uuid,
+    list_ids: &[Uuid],
+    transaction: &mut Transaction<'_, Postgres>,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+    UPDATE list_memberships SET status = 'unsubscribed'
+    WHERE subscriber_id = $1 AND list_id <> ALL($2)
+"#,
+        subscriber_id,
+        list_ids
+    )
+    .execute(&mut **transaction)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    for list_id in list_ids {
+        add_list_membership(*list_id, subscriber_id, transaction).await?;
+    }
+    Ok(())
+}
+
+/// Lift the suppression of a subscriber who unsubscribed, and reactivate
+/// them and the lists they chose.
+///
+/// The preferences link was sent to their address, so following it
+/// confirms it as well as the confirmation email would. Other suppressions,
+/// e.g. for bounces, stay.
+#[tracing::instrument(
+    name = "Resubscribing subscriber",
+    skip(email, tombstone_key, transaction)
+)]
+async fn resubscribe(
+    subscriber_id: Uuid,
+    email: &SubscriberEmail,
+    tombstone_key: &TombstoneKey,
+    transaction: &mut Transaction<'_, Postgres>,
+) -> Result<(), sqlx::Error> {
+    let tombstone = tombstone_key.tombstone(email.canonical());
+    let lifted = sqlx::query!(
+        r#"
+    DELETE FROM suppressions
+    WHERE reason = $3 AND (email = $1 OR email = $2)
+"#,
+        email.canonical(),
+        tombstone,
+        SuppressionReason::Unsubscribed.as_str()
+    )
+    .execute(&mut **transaction)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?
+    .rows_affected();
+    if lifted == 0 {
+        return Ok(());
+    }
+    let reactivated = sqlx::query!(
+        r#"
+    UPDATE subscriptions SET status = 'active'
+    WHERE id = $1
+    AND NOT EXISTS (SELECT 1 FROM suppressions WHERE email = $2 OR email = $3)
+"#,
+        subscriber_id,
+        email.canonical(),
+        tombstone
+    )
+    .execute(&mut **transaction)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?
+    .rows_affected();
+    if reactivated == 0 {
+        return Ok(());
+    }
+    sqlx::query!(
+        r#"
+    UPDATE list_memberships SET status = 'active'
+    WHERE subscriber_id = $1 AND status = 'pending_confirmation'
+"#,
+        subscriber_id
+    )
+    .execute(&mut **transaction)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    Ok(())
+}
+
+#[tracing::instrument(name = "Saving pending email", skip(email, transaction))]
+async fn set_pending_email(
+    subscriber_id: Uuid,
+    email: &SubscriberEmail,
+    transaction: &mut Transaction<'_, Postgres>,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"UPDATE subscriptions SET pending_email = $2 WHERE id = $1"#,
+        subscriber_id,
+        email.as_ref()
+    )
+    .execute(&mut **transaction)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    Ok(())
+}
+
+/// Returns `false` if `email` is not the address the subscriber is waiting
+/// to switch to.
+///
+/// Following the link proves the subscriber owns the new address, so
+/// subscribers who had not confirmed the old one yet are confirmed too.
+#[tracing::instrument(name = "Changing subscriber email", skip(email, transaction))]
+async fn change_email(
+    subscriber_id: Uuid,
+    email: &SubscriberEmail,
+    transaction: &mut Transaction<'_, Postgres>,
+) -> Result<bool, sqlx::Error> {
+    let changed = sqlx::query!(
+        r#"
+    UPDATE subscriptions
+    SET email = $2,
+        email_canonical = $3,
+        pending_email = NULL,
+        status = CASE WHEN status = 'pending_confirmation' THEN 'active' ELSE status END
+    WHERE id = $1 AND pending_email = $2
+"#,
+        subscriber_id,
+        email.as_ref(),
+        email.canonical()
+    )
+    .execute(&mut **transaction)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?
+    .rows_affected();
+    if changed == 0 {
+        return Ok(false);
+    }
+    sqlx::query!(
+        r#"
+    UPDATE list_memberships SET status = 'active'
+    WHERE subscriber_id = $1 AND status = 'pending_confirmation'
+    AND EXISTS (SELECT 1 FROM subscriptions WHERE id = $1 AND status = 'active')
+"#,
+        subscriber_id
+    )
+    .execute(&mut **transaction)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    Ok(true)
+}
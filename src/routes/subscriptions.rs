@@ -1,62 +1,515 @@
 use actix_web::{web, HttpResponse, Responder};
 use chrono::Utc;
 use serde::{Deserialize, Serialize};
-use sqlx::PgPool;
+use sqlx::{PgPool, Postgres, Transaction};
+use std::sync::Arc;
 use uuid::Uuid;
 
-use crate::domain::{NewSubscriber, SubscriberEmail, SubscriberName};
+use crate::challenge::ChallengeVerifier;
+use crate::configuration::{
+    BotDetectionSettings, SubscriberNameSettings, SubscriptionConfirmationSettings,
+};
+use crate::consent::{record_consent_event, ConsentEvent, ConsentEventKind, ConsentOrigin};
+use crate::domain::{NewSubscriber, SubscriberAttributes, SubscriberEmail, SubscriberName};
+use crate::domain_policy::{BlockedDomain, DomainPolicy};
+use crate::email_client::{EmailClient, SendEmailError};
+use crate::email_domain_check::{EmailDomainChecker, UndeliverableDomain};
+use crate::routes::lists::{add_list_membership, find_list_id, DEFAULT_LIST_SLUG};
+use crate::suppression_list::{find_suppression, SuppressionReason, TombstoneKey};
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Deserialize, Serialize, Default)]
 pub struct FormData {
     pub name: String,
     pub email: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub attributes: Option<serde_json::Map<String, serde_json::Value>>,
+    /// Honeypot: hidden from humans by the signup form, only bots fill it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub website: Option<String>,
+    /// When the signup form was shown, in seconds since the Unix epoch.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rendered_at: Option<i64>,
+    /// The token handed over by the challenge widget, if challenges are
+    /// enabled.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub challenge_response: Option<String>,
+    /// Which signup form was used, e.g. `footer`. Defaults to the endpoint.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub form: Option<String>,
+    /// The version of the consent wording the form showed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub consent_text_version: Option<String>,
 }
 
-impl TryFrom<web::Json<FormData>> for NewSubscriber {
-    type Error = String;
-    fn try_from(value: web::Json<FormData>) -> Result<Self, Self::Error> {
-        let name = SubscriberName::parse(value.name.clone())?;
-        let email = SubscriberEmail::parse(value.email.clone())?;
-        Ok(Self { email, name })
+impl FormData {
+    /// The consent the subscriber gives by submitting the form to `endpoint`,
+    /// to join `list_slug`.
+    pub(crate) fn consent_event<'a>(
+        &'a self,
+        endpoint: &'a str,
+        list_slug: &str,
+    ) -> ConsentEvent<'a> {
+        ConsentEvent {
+            kind: ConsentEventKind::Subscribed,
+            source: self.form.as_deref().unwrap_or(endpoint),
+            text_version: self.consent_text_version.as_deref(),
+            details: serde_json::json!({ "list": list_slug }),
+        }
+    }
+}
+
+/// Why a subscription request was turned down. Shown to the subscriber.
+#[derive(Debug)]
+pub enum SubscriptionRejection {
+    InvalidData(String),
+    BlockedDomain(BlockedDomain),
+    UndeliverableDomain(UndeliverableDomain),
+    /// The challenge was not solved.
+    ChallengeFailed,
+    /// The honeypot was filled or the form submitted too fast. Bots get
+    /// told the request succeeded, so that they do not adapt.
+    SuspectedBot(&'static str),
+}
+
+impl std::fmt::Display for SubscriptionRejection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::InvalidData(e) => write!(f, "Invalid data. {}", e),
+            Self::BlockedDomain(e) => write!(f, "Invalid data. {}", e),
+            Self::UndeliverableDomain(e) => write!(f, "Invalid data. {}", e),
+            Self::ChallengeFailed => write!(
+                f,
+                "Invalid data. Please complete the challenge to show you are not a robot."
+            ),
+            Self::SuspectedBot(reason) => write!(f, "Suspected bot: {}", reason),
+        }
+    }
+}
+
+/// The checks subscription requests go through before we store anything.
+pub struct SubscriptionValidator {
+    name_settings: SubscriberNameSettings,
+    domain_policy: Arc<DomainPolicy>,
+    email_domain_checker: EmailDomainChecker,
+    bot_detection: BotDetectionSettings,
+    challenge_verifier: Option<Arc<dyn ChallengeVerifier>>,
+}
+
+impl SubscriptionValidator {
+    pub fn new(
+        name_settings: SubscriberNameSettings,
+        domain_policy: Arc<DomainPolicy>,
+        email_domain_checker: EmailDomainChecker,
+        bot_detection: BotDetectionSettings,
+        challenge_verifier: Option<Arc<dyn ChallengeVerifier>>,
+    ) -> Self {
+        Self {
+            name_settings,
+            domain_policy,
+            email_domain_checker,
+            bot_detection,
+            challenge_verifier,
+        }
+    }
+
+    pub async fn validate(
+        &self,
+        form: &FormData,
+        client_ip: Option<&str>,
+    ) -> Result<NewSubscriber, SubscriptionRejection> {
+        self.detect_bot(form)?;
+        self.verify_challenge(form, client_ip).await?;
+        let name = self.validate_name(form.name.clone())?;
+        let attributes = match &form.attributes {
+            Some(attributes) => SubscriberAttributes::parse(attributes.clone())
+                .map_err(SubscriptionRejection::InvalidData)?,
+            None => SubscriberAttributes::default(),
+        };
+        // Cheapest checks first, the email checks may hit the network
+        let email = self.validate_email(form.email.clone()).await?;
+        Ok(NewSubscriber {
+            email,
+            name,
+            attributes,
+        })
+    }
+
+    pub fn validate_name(&self, name: String) -> Result<SubscriberName, SubscriptionRejection> {
+        SubscriberName::parse_with_max_length(name, self.name_settings.max_length)
+            .map_err(|e| SubscriptionRejection::InvalidData(e.to_string()))
+    }
+
+    /// Parse `email` and check that we accept subscriptions from its domain.
+    pub async fn validate_email(
+        &self,
+        email: String,
+    ) -> Result<SubscriberEmail, SubscriptionRejection> {
+        let email = SubscriberEmail::parse(email).map_err(SubscriptionRejection::InvalidData)?;
+        self.domain_policy
+            .check(&email)
+            .map_err(SubscriptionRejection::BlockedDomain)?;
+        self.email_domain_checker
+            .check(&email)
+            .await
+            .map_err(SubscriptionRejection::UndeliverableDomain)?;
+        Ok(email)
+    }
+
+    fn detect_bot(&self, form: &FormData) -> Result<(), SubscriptionRejection> {
+        if form
+            .website
+            .as_deref()
+            .is_some_and(|website| !website.is_empty())
+        {
+            return Err(SubscriptionRejection::SuspectedBot(
+                "the honeypot was filled",
+            ));
+        }
+        // Clients that do not send the field are not penalized, the
+        // other protections cover them
+        if let Some(rendered_at) = form.rendered_at {
+            if Utc::now().timestamp() - rendered_at < self.bot_detection.min_fill_time_seconds {
+                return Err(SubscriptionRejection::SuspectedBot(
+                    "the form was filled too fast",
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    async fn verify_challenge(
+        &self,
+        form: &FormData,
+        client_ip: Option<&str>,
+    ) -> Result<(), SubscriptionRejection> {
+        let verifier = match &self.challenge_verifier {
+            Some(verifier) => verifier,
+            None => return Ok(()),
+        };
+        let response = match form.challenge_response.as_deref() {
+            Some(response) if !response.is_empty() => response,
+            _ => return Err(SubscriptionRejection::ChallengeFailed),
+        };
+        match verifier.verify(response, client_ip).await {
+            Ok(true) => Ok(()),
+            Ok(false) => Err(SubscriptionRejection::ChallengeFailed),
+            // An outage of the provider must not stop people from
+            // subscribing, the rate limits still apply
+            Err(e) => {
+                tracing::error!("Failed to verify the challenge response: {:?}", e);
+                Ok(())
+            }
+        }
+    }
+}
+
+/// How subscribers are asked to confirm they own their address.
+pub struct SubscriptionConfirmation {
+    /// Where the confirmation links point to.
+    pub base_url: String,
+    pub resend_cooldown: std::time::Duration,
+}
+
+impl SubscriptionConfirmation {
+    pub fn new(base_url: String, settings: &SubscriptionConfirmationSettings) -> Self {
+        Self {
+            base_url,
+            resend_cooldown: settings.resend_cooldown(),
+        }
+    }
+}
+
+/// The response to a subscription request that was turned down.
+pub(crate) fn rejection_response(rejection: SubscriptionRejection) -> HttpResponse {
+    match rejection {
+        SubscriptionRejection::SuspectedBot(reason) => {
+            tracing::warn!(
+                "Ignoring subscription request from a suspected bot: {}",
+                reason
+            );
+            HttpResponse::Ok().finish()
+        }
+        rejection => HttpResponse::BadRequest().body(rejection.to_string()),
     }
 }
 
 #[tracing::instrument(
-name = "Adding a new subscriber", skip(data, db_pool),
+name = "Adding a new subscriber", skip(data, db_pool, email_client, confirmation, validator, tombstone_key, origin),
 fields(
 subscriber_email = %data.email, subscriber_name = %data.name
 ) )]
-pub async fn subscribe(data: web::Json<FormData>, db_pool: web::Data<PgPool>) -> impl Responder {
-    let new_subscriber = match data.try_into() {
+pub async fn subscribe(
+    data: web::Json<FormData>,
+    db_pool: web::Data<PgPool>,
+    email_client: web::Data<EmailClient>,
+    confirmation: web::Data<SubscriptionConfirmation>,
+    validator: web::Data<SubscriptionValidator>,
+    tombstone_key: web::Data<TombstoneKey>,
+    origin: ConsentOrigin,
+) -> impl Responder {
+    let new_subscriber = match validator.validate(&data, origin.ip.as_deref()).await {
         Ok(subscriber) => subscriber,
-        Err(_) => return HttpResponse::BadRequest().body("Invalid data."),
+        Err(e) => return rejection_response(e),
     };
 
-    match insert_subscriber(&new_subscriber, &db_pool).await {
+    match has_complained(&new_subscriber.email, &tombstone_key, &db_pool).await {
+        Ok(true) => {
+            return HttpResponse::Ok().body(format!("Received JSON data: {:?}", new_subscriber))
+        }
+        Ok(false) => {}
+        Err(_) => return HttpResponse::InternalServerError().body("Internal Server Error"),
+    }
+
+    let outcome = async {
+        let mut transaction = db_pool.begin().await?;
+        let registration = register_subscriber(
+            &new_subscriber,
+            confirmation.resend_cooldown,
+            &tombstone_key,
+            &mut transaction,
+        )
+        .await?;
+        // The default list is created by the migrations, it is always there
+        let list_id = find_list_id(DEFAULT_LIST_SLUG, &mut *transaction)
+            .await?
+            .ok_or(sqlx::Error::RowNotFound)?;
+        add_list_membership(list_id, registration.subscriber_id, &mut transaction).await?;
+        record_consent_event(
+            registration.subscriber_id,
+            new_subscriber.email.canonical(),
+            data.consent_event("subscriptions", DEFAULT_LIST_SLUG),
+            &origin,
+            &mut transaction,
+        )
+        .await?;
+        // Sent before committing: if it fails, the subscriber can try again
+        // without waiting for the cooldown
+        if let Some(token) = &registration.confirmation_token {
+            send_confirmation_email(&email_client, &new_subscriber, &confirmation, token).await?;
+        }
+        transaction.commit().await?;
+        Ok::<_, Box<dyn std::error::Error>>(())
+    };
+    // Every outcome gets the same response, whether the address was already
+    // on the list or not
+    match outcome.await {
         Ok(_) => HttpResponse::Ok().body(format!("Received JSON data: {:?}", new_subscriber)),
-        Err(_) => HttpResponse::InternalServerError().body("Internal Server Error"),
+        Err(e) => {
+            tracing::error!("Failed to register the subscriber: {:?}", e);
+            HttpResponse::InternalServerError().body("Internal Server Error")
+        }
     }
 }
 
+/// Whether the subscriber reported us as spam.
+///
+/// We do not even send them a confirmation email - but callers answer as
+/// usual to avoid revealing the address is on our list.
+pub(crate) async fn has_complained(
+    email: &SubscriberEmail,
+    tombstone_key: &TombstoneKey,
+    db_pool: &PgPool,
+) -> Result<bool, sqlx::Error> {
+    let complained = matches!(
+        find_suppression(email, tombstone_key, db_pool).await?,
+        Some(SuppressionReason::Complaint)
+    );
+    if complained {
+        tracing::warn!("Ignoring subscription request for an address that complained");
+    }
+    Ok(complained)
+}
+
+/// A subscription request that went through.
+pub(crate) struct Registration {
+    pub subscriber_id: Uuid,
+    /// Set if the subscriber must be sent a link to confirm their address.
+    pub confirmation_token: Option<String>,
+}
+
+/// Save the subscriber if they are new, and work out whether they need a
+/// confirmation email:
+///
+/// - new subscribers are pending until they confirm their address;
+/// - pending subscribers get a new link, unless they were sent one less than
+///   `resend_cooldown` ago;
+/// - subscribers who unsubscribed get a link to come back, they stay
+///   suppressed until they follow it;
+/// - confirmed subscribers and other suppressed addresses are left alone.
+///
+/// Existing subscribers keep their name and attributes: this endpoint is
+/// unauthenticated, anybody could be submitting the form.
 #[tracing::instrument(
     name = "Saving new subscriber details in the database",
-    skip(new_subscriber, db_pool)
+    skip(new_subscriber, tombstone_key, transaction)
 )]
-pub async fn insert_subscriber(
+pub(crate) async fn register_subscriber(
     new_subscriber: &NewSubscriber,
-    db_pool: &PgPool,
-) -> Result<(), sqlx::Error> {
+    resend_cooldown: std::time::Duration,
+    tombstone_key: &TombstoneKey,
+    transaction: &mut Transaction<'_, Postgres>,
+) -> Result<Registration, sqlx::Error> {
+    if let Some(subscriber_id) = insert_subscriber(new_subscriber, transaction).await? {
+        let token = store_token(subscriber_id, transaction).await?;
+        return Ok(Registration {
+            subscriber_id,
+            confirmation_token: Some(token),
+        });
+    }
+
+    // Locked so that concurrent requests do not both send an email
+    let existing = sqlx::query!(
+        r#"
+    SELECT id, status, confirmation_sent_at FROM subscriptions
+    WHERE email_canonical = $1
+    FOR UPDATE
+"#,
+        new_subscriber.email.canonical()
+    )
+    .fetch_one(&mut **transaction)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    let needs_confirmation = match existing.status.as_str() {
+        "pending_confirmation" => true,
+        "suppressed" => matches!(
+            find_suppression(&new_subscriber.email, tombstone_key, &mut **transaction).await?,
+            Some(SuppressionReason::Unsubscribed)
+        ),
+        _ => false,
+    };
+    // A negative elapsed time means the clock went back, wait some more
+    let cooling_down = existing.confirmation_sent_at.is_some_and(|sent_at| {
+        (Utc::now() - sent_at)
+            .to_std()
+            .map_or(true, |elapsed| elapsed < resend_cooldown)
+    });
+    if !needs_confirmation || cooling_down {
+        tracing::info!(
+            status = %existing.status,
+            cooling_down,
+            "Not sending a confirmation email to an existing subscriber"
+        );
+        return Ok(Registration {
+            subscriber_id: existing.id,
+            confirmation_token: None,
+        });
+    }
+
+    sqlx::query!(
+        r#"UPDATE subscriptions SET confirmation_sent_at = $1 WHERE id = $2"#,
+        Utc::now(),
+        existing.id
+    )
+    .execute(&mut **transaction)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    let token = store_token(existing.id, transaction).await?;
+    Ok(Registration {
+        subscriber_id: existing.id,
+        confirmation_token: Some(token),
+    })
+}
+
+#[tracing::instrument(name = "Storing subscription token", skip(transaction))]
+async fn store_token(
+    subscriber_id: Uuid,
+    transaction: &mut Transaction<'_, Postgres>,
+) -> Result<String, sqlx::Error> {
+    // Version 4 UUIDs come from a cryptographically secure generator
+    let token = Uuid::new_v4().simple().to_string();
     sqlx::query!(
         r#"
-    INSERT INTO subscriptions (id, email, name, subscribed_at)
-    VALUES ($1, $2, $3, $4)
+    INSERT INTO subscription_tokens (subscription_token, subscriber_id, created_at)
+    VALUES ($1, $2, $3)
+"#,
+        token,
+        subscriber_id,
+        Utc::now()
+    )
+    .execute(&mut **transaction)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    Ok(token)
+}
+
+/// The version of the confirmation email wording, recorded when subscribers
+/// follow its link. Bump it whenever the wording changes.
+pub(crate) const CONFIRMATION_EMAIL_VERSION: &str = "2024-04-30";
+
+/// Email the subscriber a link to confirm their address.
+///
+/// Addresses that bounced are skipped silently, the response must not
+/// reveal they are known to us.
+#[tracing::instrument(
+    name = "Sending a confirmation email to a new subscriber",
+    skip(email_client, new_subscriber, confirmation, token)
+)]
+pub(crate) async fn send_confirmation_email(
+    email_client: &EmailClient,
+    new_subscriber: &NewSubscriber,
+    confirmation: &SubscriptionConfirmation,
+    token: &str,
+) -> Result<(), SendEmailError> {
+    let confirmation_link = format!(
+        "{}/subscriptions/confirm?subscription_token={}",
+        confirmation.base_url, token
+    );
+    let html_body = format!(
+        "Welcome to our newsletter!<br />\
+        Click <a href=\"{}\">here</a> to confirm your subscription.",
+        confirmation_link
+    );
+    let plain_body = format!(
+        "Welcome to our newsletter!\nVisit {} to confirm your subscription.",
+        confirmation_link
+    );
+    match email_client
+        .send_transactional_email(
+            new_subscriber.email.clone(),
+            "Please confirm your subscription",
+            &html_body,
+            &plain_body,
+        )
+        .await
+    {
+        Err(SendEmailError::Suppressed(_)) => Ok(()),
+        outcome => outcome,
+    }
+}
+
+/// Save the subscriber as pending confirmation, and return their id. Returns
+/// `None` if somebody already subscribed with the same address.
+#[tracing::instrument(name = "Inserting new subscriber", skip(new_subscriber, transaction))]
+pub async fn insert_subscriber(
+    new_subscriber: &NewSubscriber,
+    transaction: &mut Transaction<'_, Postgres>,
+) -> Result<Option<Uuid>, sqlx::Error> {
+    let now = Utc::now();
+    let row = sqlx::query!(
+        r#"
+    INSERT INTO subscriptions
+        (id, email, email_canonical, name, subscribed_at, attributes, status, confirmation_sent_at)
+    VALUES ($1, $2, $3, $4, $5, $6, 'pending_confirmation', $5)
+    ON CONFLICT (email_canonical) DO NOTHING
+    RETURNING id
 "#,
         Uuid::new_v4(),
         new_subscriber.email.as_ref(),
+        new_subscriber.email.canonical(),
         new_subscriber.name.as_ref(),
-        Utc::now()
+        now,
+        new_subscriber.attributes.as_json()
     )
-    .execute(db_pool)
+    .fetch_optional(&mut **transaction)
     .await
     .map_err(|e| {
         tracing::error!("Failed to execute query: {:?}", e);
@@ -65,5 +518,5 @@ pub async fn insert_subscriber(
         // if the function failed, returning a sqlx::Error
         // We will talk about error handling in depth later!
     })?;
-    Ok(())
+    Ok(row.map(|r| r.id))
 }
@@ -0,0 +1,157 @@
+use actix_web::{web, HttpResponse, Responder};
+use sqlx::{PgPool, Postgres, Transaction};
+use uuid::Uuid;
+
+use crate::consent::{record_consent_event, ConsentEvent, ConsentEventKind, ConsentOrigin};
+use crate::routes::subscriptions::CONFIRMATION_EMAIL_VERSION;
+use crate::suppression_list::{SuppressionReason, TombstoneKey};
+
+#[derive(serde::Deserialize)]
+pub struct Parameters {
+    subscription_token: String,
+}
+
+#[tracing::instrument(
+    name = "Confirming a pending subscriber",
+    skip(parameters, db_pool, tombstone_key, origin)
+)]
+pub async fn confirm(
+    parameters: web::Query<Parameters>,
+    db_pool: web::Data<PgPool>,
+    tombstone_key: web::Data<TombstoneKey>,
+    origin: ConsentOrigin,
+) -> impl Responder {
+    let outcome = async {
+        let mut transaction = db_pool.begin().await?;
+        let subscriber_id =
+            match find_subscriber_id(&parameters.subscription_token, &mut transaction).await? {
+                Some(subscriber_id) => subscriber_id,
+                None => return Ok(None),
+            };
+        let email_canonical =
+            confirm_subscriber(subscriber_id, &tombstone_key, &mut transaction).await?;
+        record_consent_event(
+            subscriber_id,
+            &email_canonical,
+            ConsentEvent {
+                kind: ConsentEventKind::Confirmed,
+                source: "confirmation_email",
+                text_version: Some(CONFIRMATION_EMAIL_VERSION),
+                details: serde_json::json!({}),
+            },
+            &origin,
+            &mut transaction,
+        )
+        .await?;
+        transaction.commit().await?;
+        Ok::<_, sqlx::Error>(Some(()))
+    };
+    match outcome.await {
+        Ok(Some(_)) => HttpResponse::Ok().finish(),
+        Ok(None) => HttpResponse::Unauthorized().finish(),
+        Err(_) => HttpResponse::InternalServerError().body("Internal Server Error"),
+    }
+}
+
+#[tracing::instrument(name = "Looking up subscriber from token", skip(token, transaction))]
+async fn find_subscriber_id(
+    token: &str,
+    transaction: &mut Transaction<'_, Postgres>,
+) -> Result<Option<Uuid>, sqlx::Error> {
+    let row = sqlx::query!(
+        r#"SELECT subscriber_id FROM subscription_tokens WHERE subscription_token = $1"#,
+        token
+    )
+    .fetch_optional(&mut **transaction)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    Ok(row.map(|r| r.subscriber_id))
+}
+
+/// Mark the subscriber as confirmed, along with the lists they were waiting
+/// to join, taking them off the suppression list if they had unsubscribed:
+/// following the link is how they come back. Returns their canonical email.
+///
+/// Addresses suppressed for another reason, e.g. because they bounced since
+/// the link was sent, stay suppressed.
+#[tracing::instrument(
+    name = "Marking subscriber as confirmed",
+    skip(tombstone_key, transaction)
+)]
+async fn confirm_subscriber(
+    subscriber_id: Uuid,
+    tombstone_key: &TombstoneKey,
+    transaction: &mut Transaction<'_, Postgres>,
+) -> Result<String, sqlx::Error> {
+    let email_canonical = sqlx::query!(
+        r#"SELECT email_canonical FROM subscriptions WHERE id = $1 FOR UPDATE"#,
+        subscriber_id
+    )
+    .fetch_one(&mut **transaction)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?
+    .email_canonical;
+    let tombstone = tombstone_key.tombstone(&email_canonical);
+    sqlx::query!(
+        r#"
+    DELETE FROM suppressions
+    WHERE reason = $3 AND (email = $1 OR email = $2)
+"#,
+        email_canonical,
+        tombstone,
+        SuppressionReason::Unsubscribed.as_str()
+    )
+    .execute(&mut **transaction)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    sqlx::query!(
+        r#"
+    UPDATE subscriptions SET status = 'active'
+    WHERE id = $1
+    AND NOT EXISTS (SELECT 1 FROM suppressions WHERE email = $2 OR email = $3)
+"#,
+        subscriber_id,
+        email_canonical,
+        tombstone
+    )
+    .execute(&mut **transaction)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    sqlx::query!(
+        r#"
+    UPDATE list_memberships SET status = 'active'
+    WHERE subscriber_id = $1 AND status = 'pending_confirmation'
+"#,
+        subscriber_id
+    )
+    .execute(&mut **transaction)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    // Links are single use
+    sqlx::query!(
+        r#"DELETE FROM subscription_tokens WHERE subscriber_id = $1"#,
+        subscriber_id
+    )
+    .execute(&mut **transaction)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    Ok(email_canonical)
+}
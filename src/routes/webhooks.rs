@@ -0,0 +1,343 @@
+use actix_web::http::header;
+use actix_web::{web, HttpRequest, HttpResponse, Responder};
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use secrecy::ExposeSecret;
+use serde::Deserialize;
+use sha2::Sha256;
+use sqlx::{PgPool, Postgres, Transaction};
+use uuid::Uuid;
+
+use crate::authentication::basic_authentication;
+use crate::configuration::EmailWebhookSettings;
+use crate::domain::{EmailEvent, EmailEventKind, SubscriberEmail};
+use crate::suppression_list::{add_suppression, SuppressionReason};
+
+/// How far from now the timestamp of a signed webhook may be. Older
+/// payloads could have been captured and sent again.
+const MAX_SIGNATURE_AGE_SECONDS: i64 = 5 * 60;
+
+/// The email providers we know how to receive webhooks from.
+#[derive(Debug, Clone, Copy)]
+pub enum WebhookProvider {
+    Postmark,
+    Mailgun,
+}
+
+impl WebhookProvider {
+    pub fn as_str(&self) -> &str {
+        match self {
+            Self::Postmark => "postmark",
+            Self::Mailgun => "mailgun",
+        }
+    }
+}
+
+impl TryFrom<String> for WebhookProvider {
+    type Error = String;
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        match s.to_lowercase().as_str() {
+            "postmark" => Ok(Self::Postmark),
+            "mailgun" => Ok(Self::Mailgun),
+            other => Err(format!("{} is not a supported email provider.", other)),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "RecordType")]
+enum PostmarkPayload {
+    Bounce {
+        #[serde(rename = "Type")]
+        bounce_type: String,
+        #[serde(rename = "Email")]
+        email: String,
+        #[serde(rename = "BouncedAt")]
+        bounced_at: DateTime<Utc>,
+    },
+    SpamComplaint {
+        #[serde(rename = "Email")]
+        email: String,
+        #[serde(rename = "BouncedAt")]
+        bounced_at: DateTime<Utc>,
+    },
+    Delivery {
+        #[serde(rename = "Recipient")]
+        recipient: String,
+        #[serde(rename = "DeliveredAt")]
+        delivered_at: DateTime<Utc>,
+    },
+    // Opens, clicks, subscription changes... we do not track them.
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Debug, Deserialize)]
+struct MailgunPayload {
+    signature: MailgunSignature,
+    #[serde(rename = "event-data")]
+    event_data: MailgunEventData,
+}
+
+#[derive(Debug, Deserialize)]
+struct MailgunSignature {
+    timestamp: String,
+    token: String,
+    signature: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct MailgunEventData {
+    event: String,
+    severity: Option<String>,
+    recipient: String,
+    timestamp: f64,
+}
+
+impl TryFrom<PostmarkPayload> for Option<EmailEvent> {
+    type Error = String;
+    fn try_from(value: PostmarkPayload) -> Result<Self, Self::Error> {
+        let (kind, email, occurred_at) = match value {
+            PostmarkPayload::Bounce {
+                bounce_type,
+                email,
+                bounced_at,
+            } => {
+                let kind = if bounce_type == "HardBounce" {
+                    EmailEventKind::HardBounce
+                } else {
+                    EmailEventKind::SoftBounce
+                };
+                (kind, email, bounced_at)
+            }
+            PostmarkPayload::SpamComplaint { email, bounced_at } => {
+                (EmailEventKind::Complaint, email, bounced_at)
+            }
+            PostmarkPayload::Delivery {
+                recipient,
+                delivered_at,
+            } => (EmailEventKind::Delivery, recipient, delivered_at),
+            PostmarkPayload::Other => return Ok(None),
+        };
+        let recipient = SubscriberEmail::parse(email)?;
+        Ok(Some(EmailEvent {
+            kind,
+            recipient,
+            occurred_at,
+        }))
+    }
+}
+
+impl TryFrom<MailgunEventData> for Option<EmailEvent> {
+    type Error = String;
+    fn try_from(value: MailgunEventData) -> Result<Self, Self::Error> {
+        let kind = match (value.event.as_str(), value.severity.as_deref()) {
+            ("failed", Some("permanent")) => EmailEventKind::HardBounce,
+            ("failed", _) => EmailEventKind::SoftBounce,
+            ("complained", _) => EmailEventKind::Complaint,
+            ("delivered", _) => EmailEventKind::Delivery,
+            _ => return Ok(None),
+        };
+        let occurred_at = DateTime::from_timestamp(
+            value.timestamp.trunc() as i64,
+            (value.timestamp.fract() * 1e9) as u32,
+        )
+        .ok_or_else(|| format!("{} is not a valid timestamp.", value.timestamp))?;
+        let recipient = SubscriberEmail::parse(value.recipient)?;
+        Ok(Some(EmailEvent {
+            kind,
+            recipient,
+            occurred_at,
+        }))
+    }
+}
+
+#[tracing::instrument(
+    name = "Receiving an email provider webhook",
+    skip(request, body, db_pool, settings),
+    fields(provider = %provider)
+)]
+pub async fn receive_email_webhook(
+    request: HttpRequest,
+    provider: web::Path<String>,
+    body: web::Bytes,
+    db_pool: web::Data<PgPool>,
+    settings: web::Data<EmailWebhookSettings>,
+) -> impl Responder {
+    let provider: WebhookProvider = match provider.into_inner().try_into() {
+        Ok(provider) => provider,
+        Err(_) => return HttpResponse::NotFound().finish(),
+    };
+
+    // Remembered with the event, so that the same payload is only stored once
+    let mut token = None;
+    let event = match provider {
+        WebhookProvider::Postmark => {
+            if !has_valid_basic_auth(&request, &settings) {
+                return HttpResponse::Unauthorized()
+                    .insert_header((header::WWW_AUTHENTICATE, r#"Basic realm="email_webhooks""#))
+                    .finish();
+            }
+            serde_json::from_slice::<PostmarkPayload>(&body)
+                .map_err(|e| e.to_string())
+                .and_then(|payload| payload.try_into())
+        }
+        WebhookProvider::Mailgun => {
+            let payload = match serde_json::from_slice::<MailgunPayload>(&body) {
+                Ok(payload) => payload,
+                Err(_) => return HttpResponse::BadRequest().body("Invalid payload."),
+            };
+            if !has_valid_signature(&payload.signature, &settings, Utc::now()) {
+                return HttpResponse::Unauthorized().finish();
+            }
+            token = Some(payload.signature.token);
+            payload.event_data.try_into()
+        }
+    };
+
+    let event = match event {
+        Ok(Some(event)) => event,
+        // Acknowledge the events we do not track, otherwise the provider
+        // keeps retrying them.
+        Ok(None) => return HttpResponse::Ok().finish(),
+        Err(e) => {
+            tracing::warn!("Failed to parse email webhook payload: {}", e);
+            return HttpResponse::BadRequest().body("Invalid payload.");
+        }
+    };
+
+    match store_email_event(provider, &event, token.as_deref(), &db_pool).await {
+        Ok(_) => HttpResponse::Ok().finish(),
+        Err(_) => HttpResponse::InternalServerError().body("Internal Server Error"),
+    }
+}
+
+fn has_valid_basic_auth(request: &HttpRequest, settings: &EmailWebhookSettings) -> bool {
+    match basic_authentication(request.headers()) {
+        Some(credentials) => {
+            credentials.matches(&settings.basic_auth_username, &settings.basic_auth_password)
+        }
+        None => false,
+    }
+}
+
+/// Whether `signature` was made with our signing key, no longer than
+/// `MAX_SIGNATURE_AGE_SECONDS` from `now`.
+fn has_valid_signature(
+    signature: &MailgunSignature,
+    settings: &EmailWebhookSettings,
+    now: DateTime<Utc>,
+) -> bool {
+    let is_fresh = signature
+        .timestamp
+        .parse::<i64>()
+        .is_ok_and(|timestamp| (now.timestamp() - timestamp).abs() <= MAX_SIGNATURE_AGE_SECONDS);
+    if !is_fresh {
+        return false;
+    }
+    let expected = match hex::decode(&signature.signature) {
+        Ok(expected) => expected,
+        Err(_) => return false,
+    };
+    let mut mac = Hmac::<Sha256>::new_from_slice(settings.signing_key.expose_secret().as_bytes())
+        .expect("HMAC can take a key of any size");
+    mac.update(signature.timestamp.as_bytes());
+    mac.update(signature.token.as_bytes());
+    // `verify_slice` compares in constant time
+    mac.verify_slice(&expected).is_ok()
+}
+
+/// Save `event`, suppressing its recipient if needed.
+///
+/// Events signed with a `token` that was seen already are replays, and are
+/// ignored.
+#[tracing::instrument(
+    name = "Saving email event and suppressing the recipient if needed",
+    skip(event, token, db_pool)
+)]
+pub async fn store_email_event(
+    provider: WebhookProvider,
+    event: &EmailEvent,
+    token: Option<&str>,
+    db_pool: &PgPool,
+) -> Result<(), sqlx::Error> {
+    let mut transaction = db_pool.begin().await?;
+    if let Some(token) = token {
+        if !remember_token(token, &mut transaction).await? {
+            tracing::warn!("Ignoring a webhook whose token was seen already");
+            return Ok(());
+        }
+    }
+    sqlx::query!(
+        r#"
+    INSERT INTO email_events (id, provider, event_type, email, occurred_at, received_at)
+    VALUES ($1, $2, $3, $4, $5, $6)
+"#,
+        Uuid::new_v4(),
+        provider.as_str(),
+        event.kind.as_str(),
+        event.recipient.as_ref(),
+        event.occurred_at,
+        Utc::now()
+    )
+    .execute(&mut *transaction)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+
+    if event.kind.suppresses_recipient() {
+        let reason = match event.kind {
+            EmailEventKind::Complaint => SuppressionReason::Complaint,
+            _ => SuppressionReason::HardBounce,
+        };
+        add_suppression(
+            &event.recipient,
+            reason,
+            provider.as_str(),
+            &mut transaction,
+        )
+        .await?;
+    }
+    transaction.commit().await?;
+    Ok(())
+}
+
+/// Remember `token`, forgetting the ones too old to be accepted anyway.
+///
+/// Returns `false` if it was remembered already.
+async fn remember_token(
+    token: &str,
+    transaction: &mut Transaction<'_, Postgres>,
+) -> Result<bool, sqlx::Error> {
+    let now = Utc::now();
+    // A timestamp can be up to the maximum age behind when it is first
+    // received, and as much ahead of when it is replayed
+    sqlx::query!(
+        r#"DELETE FROM webhook_tokens WHERE received_at < $1"#,
+        now - std::time::Duration::from_secs(2 * MAX_SIGNATURE_AGE_SECONDS as u64)
+    )
+    .execute(&mut **transaction)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    let inserted = sqlx::query!(
+        r#"
+    INSERT INTO webhook_tokens (token, received_at)
+    VALUES ($1, $2)
+    ON CONFLICT (token) DO NOTHING
+"#,
+        token,
+        now
+    )
+    .execute(&mut **transaction)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    Ok(inserted.rows_affected() == 1)
+}
@@ -0,0 +1,296 @@
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use serde_json::Value;
+
+use crate::configuration::{get_configuration_in, Settings};
+use crate::domain_policy::DomainPolicy;
+use crate::rate_limit::RateLimit;
+use crate::telemetry::LogFilter;
+
+/// The settings `ReloadableSettings::apply` changes while the application
+/// runs. Changes to the others only take effect after a restart.
+const RELOADABLE: [&str; 8] = [
+    "log.filter",
+    "rate_limit.window_seconds",
+    "rate_limit.max_requests_per_ip",
+    "rate_limit.max_requests_per_email",
+    "rate_limit.max_admin_requests_per_ip",
+    "rate_limit.trusted_proxies",
+    "domain_policy.allow_list_path",
+    "domain_policy.deny_list_path",
+];
+
+/// What the settings that can change without a restart are applied to.
+pub struct ReloadableSettings {
+    pub log_filter: LogFilter,
+    pub rate_limit: RateLimit,
+    pub domain_policy: Arc<DomainPolicy>,
+}
+
+impl ReloadableSettings {
+    /// Apply the reloadable settings of `settings`, replacing `current`: all
+    /// of them, or none if one of them cannot be applied.
+    pub fn apply(&self, current: &Settings, settings: &Settings) -> Result<(), String> {
+        // Everything that can fail is done before anything is changed
+        let domain_policy = if settings.domain_policy.allow_list_path
+            != current.domain_policy.allow_list_path
+            || settings.domain_policy.deny_list_path != current.domain_policy.deny_list_path
+        {
+            Some(DomainPolicy::load(&settings.domain_policy)?)
+        } else {
+            None
+        };
+        // Left alone if unchanged, not to undo `RUST_LOG`
+        if settings.log.filter != current.log.filter {
+            self.log_filter.set(&settings.log.filter)?;
+        }
+        if let Some(domain_policy) = domain_policy {
+            self.domain_policy.replace(domain_policy);
+        }
+        self.rate_limit.set_settings(settings.rate_limit.clone());
+        Ok(())
+    }
+}
+
+/// The settings that differ between `current` and `settings` but cannot be
+/// changed without a restart. Secrets are not compared.
+pub fn settings_needing_restart(current: &Settings, settings: &Settings) -> Vec<String> {
+    let mut changed = Vec::new();
+    changed_keys(
+        "",
+        &serde_json::to_value(current).expect("Failed to serialize the settings."),
+        &serde_json::to_value(settings).expect("Failed to serialize the settings."),
+        &mut changed,
+    );
+    changed.retain(|key| !RELOADABLE.contains(&key.as_str()));
+    changed
+}
+
+fn changed_keys(key: &str, current: &Value, value: &Value, changed: &mut Vec<String>) {
+    match (current, value) {
+        (Value::Object(current), Value::Object(fields)) => {
+            for (name, current) in current {
+                let key = if key.is_empty() {
+                    name.to_string()
+                } else {
+                    format!("{}.{}", key, name)
+                };
+                changed_keys(
+                    &key,
+                    current,
+                    fields.get(name).unwrap_or(&Value::Null),
+                    changed,
+                );
+            }
+        }
+        (current, value) if current != value => changed.push(key.to_string()),
+        _ => {}
+    }
+}
+
+/// Read the settings in `directory` again, apply the reloadable ones, and
+/// make them `current`.
+///
+/// Invalid settings are rejected, keeping the ones in use.
+pub fn reload_settings(
+    directory: &Path,
+    current: &mut Settings,
+    reloadable: &ReloadableSettings,
+) -> Result<(), String> {
+    let settings = get_configuration_in(directory).map_err(|e| e.to_string())?;
+    reloadable.apply(current, &settings)?;
+    let needing_restart = settings_needing_restart(current, &settings);
+    if !needing_restart.is_empty() {
+        tracing::warn!(
+            "These settings changed but need a restart to be applied: {}",
+            needing_restart.join(", ")
+        );
+    }
+    *current = settings;
+    Ok(())
+}
+
+/// Reload the settings in `directory` on SIGHUP, and when its files change,
+/// checking every `application.reload_interval_seconds` of `current`.
+pub async fn watch_settings(
+    directory: PathBuf,
+    mut current: Settings,
+    reloadable: ReloadableSettings,
+) {
+    let mut hangups = Hangups::listen();
+    let mut ticks = tokio::time::interval(current.application.reload_interval());
+    let mut modified_at = modified_at(&directory);
+    loop {
+        tokio::select! {
+            _ = ticks.tick() => {
+                let now = self::modified_at(&directory);
+                if now == modified_at {
+                    continue;
+                }
+                // A broken file is reported once, not on every tick
+                modified_at = now;
+            }
+            _ = hangups.recv() => {}
+        }
+        match reload_settings(&directory, &mut current, &reloadable) {
+            Ok(()) => tracing::info!("Reloaded the settings"),
+            Err(e) => tracing::error!(
+                "Failed to reload the settings, keeping the ones in use. {}",
+                e
+            ),
+        }
+    }
+}
+
+/// When each file in `directory` was last modified, to notice new files too.
+fn modified_at(directory: &Path) -> Vec<(PathBuf, Option<SystemTime>)> {
+    let Ok(entries) = std::fs::read_dir(directory) else {
+        return Vec::new();
+    };
+    let mut modified_at: Vec<_> = entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| {
+            let modified = entry.metadata().and_then(|m| m.modified()).ok();
+            (entry.path(), modified)
+        })
+        .collect();
+    modified_at.sort();
+    modified_at
+}
+
+/// The SIGHUPs received by the process, none where there are no signals.
+struct Hangups {
+    #[cfg(unix)]
+    signal: Option<tokio::signal::unix::Signal>,
+}
+
+impl Hangups {
+    fn listen() -> Self {
+        #[cfg(unix)]
+        {
+            use tokio::signal::unix::{signal, SignalKind};
+
+            let signal = signal(SignalKind::hangup())
+                .map_err(|e| tracing::error!("Failed to listen for SIGHUP: {}", e))
+                .ok();
+            Self { signal }
+        }
+        #[cfg(not(unix))]
+        Self {}
+    }
+
+    async fn recv(&mut self) {
+        #[cfg(unix)]
+        if let Some(signal) = &mut self.signal {
+            signal.recv().await;
+            return;
+        }
+        std::future::pending::<()>().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use claims::{assert_err, assert_ok};
+    use tempfile::TempDir;
+
+    use super::{settings_needing_restart, ReloadableSettings};
+    use crate::configuration::{Environment, SettingSource, SettingSources, Settings};
+    use crate::domain::SubscriberEmail;
+    use crate::domain_policy::DomainPolicy;
+    use crate::rate_limit::{InMemoryRateLimitStore, RateLimit};
+    use crate::telemetry::get_subscriber;
+
+    fn settings() -> Settings {
+        let sources = SettingSources::load(vec![
+            SettingSource::File("configuration/base.yaml".into()),
+            SettingSource::File("configuration/local.yaml".into()),
+        ])
+        .unwrap();
+        Settings::read(sources, Environment::default()).unwrap()
+    }
+
+    fn email(s: &str) -> SubscriberEmail {
+        SubscriberEmail::parse(s.to_string()).unwrap()
+    }
+
+    /// Runs `test` with what `settings` are applied to.
+    fn with_reloadable(settings: &Settings, test: impl FnOnce(ReloadableSettings)) {
+        // The filter can only be changed while its subscriber is alive
+        let (_subscriber, log_filter) = get_subscriber(
+            "test".into(),
+            settings.log.filter.clone(),
+            settings.log.format,
+            std::io::sink,
+        );
+        test(ReloadableSettings {
+            log_filter,
+            rate_limit: RateLimit::new(
+                Arc::new(InMemoryRateLimitStore::default()),
+                settings.rate_limit.clone(),
+            ),
+            domain_policy: Arc::new(DomainPolicy::load(&settings.domain_policy).unwrap()),
+        })
+    }
+
+    #[test]
+    fn reloadable_settings_are_applied() {
+        let current = settings();
+        let mut changed = settings();
+        changed.log.filter = "warn".into();
+        changed.rate_limit.max_requests_per_ip = 1;
+        let directory = TempDir::new().unwrap();
+        let deny_list_path = directory.path().join("deny.txt");
+        std::fs::write(&deny_list_path, "gmail.com").unwrap();
+        changed.domain_policy.deny_list_path = Some(deny_list_path);
+
+        with_reloadable(&current, |reloadable| {
+            assert_ok!(reloadable.apply(&current, &changed));
+
+            assert_eq!(reloadable.log_filter.directives(), "warn");
+            assert_eq!(reloadable.rate_limit.settings().max_requests_per_ip, 1);
+            assert_err!(reloadable.domain_policy.check(&email("ursula@gmail.com")));
+        });
+    }
+
+    #[test]
+    fn nothing_is_applied_if_a_setting_cannot_be() {
+        let current = settings();
+        let mut changed = settings();
+        changed.log.filter = "warn".into();
+        changed.rate_limit.max_requests_per_ip = 1;
+        let directory = TempDir::new().unwrap();
+        changed.domain_policy.deny_list_path = Some(directory.path().join("deny.txt"));
+
+        with_reloadable(&current, |reloadable| {
+            assert_err!(reloadable.apply(&current, &changed));
+
+            assert_eq!(reloadable.log_filter.directives(), current.log.filter);
+            assert_eq!(
+                reloadable.rate_limit.settings().max_requests_per_ip,
+                current.rate_limit.max_requests_per_ip
+            );
+        });
+    }
+
+    #[test]
+    fn settings_needing_a_restart_are_listed() {
+        let current = settings();
+        let mut changed = settings();
+        changed.log.filter = "warn".into();
+        changed.application.port = 8001;
+        changed.database.statement_timeout_milliseconds = Some(1_000);
+
+        assert_eq!(
+            settings_needing_restart(&current, &changed),
+            vec![
+                "application.port",
+                "database.statement_timeout_milliseconds"
+            ]
+        );
+    }
+}
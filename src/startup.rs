@@ -1,27 +1,118 @@
 use crate::{
-    email_client::{self, EmailClient},
-    routes::{health_check, subscribe},
+    configuration::{AdminSettings, EmailWebhookSettings},
+    email_client::EmailClient,
+    magic_link::MagicLinks,
+    rate_limit::RateLimit,
+    routes::{
+        add_manual_suppression, confirm, confirm_email_change, consent_history, create_list,
+        delete_suppression, erase_preferences_data, erase_subscriber, export_preferences_data,
+        export_subscriber, get_log_level, get_preferences, health_check, list_subscribers,
+        publish_newsletter, receive_email_webhook, request_preferences_link, set_log_level,
+        subscribe, subscribe_to_list, update_preferences, SubscriptionConfirmation,
+        SubscriptionValidator,
+    },
+    suppression_list::TombstoneKey,
+    telemetry::LogFilter,
 };
 use actix_web::{dev::Server, web, App, HttpServer};
 use sqlx::PgPool;
 use std::net::TcpListener;
 use tracing_actix_web::TracingLogger;
 
+#[allow(clippy::too_many_arguments)]
 pub fn run(
     listener: TcpListener,
     db_pool: PgPool,
     email_client: EmailClient,
+    confirmation: SubscriptionConfirmation,
+    magic_links: MagicLinks,
+    email_webhooks: EmailWebhookSettings,
+    admin: AdminSettings,
+    subscription_validator: SubscriptionValidator,
+    rate_limit: RateLimit,
+    log_filter: LogFilter,
+    tombstone_key: TombstoneKey,
 ) -> Result<Server, std::io::Error> {
     // Wrap the connection in an actix-web Data so we can pass it to the subscribe handler
     let db_pool = web::Data::new(db_pool);
     let email_client = web::Data::new(email_client);
+    let confirmation = web::Data::new(confirmation);
+    let magic_links = web::Data::new(magic_links);
+    let email_webhooks = web::Data::new(email_webhooks);
+    let admin = web::Data::new(admin);
+    let subscription_validator = web::Data::new(subscription_validator);
+    let log_filter = web::Data::new(log_filter);
+    let tombstone_key = web::Data::new(tombstone_key);
+    // Lets handlers outside of the rate limited routes find client IPs the
+    // same way
+    let client_ips = web::Data::new(rate_limit.clone());
     let server = HttpServer::new(move || {
         App::new()
             .wrap(TracingLogger::default())
             .route("/health_check", web::get().to(health_check))
-            .route("/subscriptions", web::post().to(subscribe))
+            .service(
+                web::resource("/subscriptions")
+                    .wrap(rate_limit.clone())
+                    .route(web::post().to(subscribe)),
+            )
+            .route("/subscriptions/confirm", web::get().to(confirm))
+            .service(
+                web::resource("/lists/{slug}/subscriptions")
+                    .wrap(rate_limit.clone())
+                    .route(web::post().to(subscribe_to_list)),
+            )
+            .service(
+                web::resource("/preferences/login")
+                    .wrap(rate_limit.clone())
+                    .route(web::post().to(request_preferences_link)),
+            )
+            .route("/preferences", web::get().to(get_preferences))
+            .route("/preferences", web::post().to(update_preferences))
+            .route("/preferences/email", web::get().to(confirm_email_change))
+            .route(
+                "/preferences/export",
+                web::get().to(export_preferences_data),
+            )
+            .route("/preferences/erase", web::post().to(erase_preferences_data))
+            .route(
+                "/webhooks/email/{provider}",
+                web::post().to(receive_email_webhook),
+            )
+            .route("/newsletters", web::post().to(publish_newsletter))
+            .route("/admin/lists", web::post().to(create_list))
+            .route("/admin/subscribers", web::get().to(list_subscribers))
+            .route(
+                "/admin/subscribers/{email}/export",
+                web::get().to(export_subscriber),
+            )
+            .route(
+                "/admin/subscribers/{email}/consent",
+                web::get().to(consent_history),
+            )
+            .route(
+                "/admin/subscribers/{email}",
+                web::delete().to(erase_subscriber),
+            )
+            .route(
+                "/admin/suppressions",
+                web::post().to(add_manual_suppression),
+            )
+            .route(
+                "/admin/suppressions/{email}",
+                web::delete().to(delete_suppression),
+            )
+            .route("/admin/log-level", web::get().to(get_log_level))
+            .route("/admin/log-level", web::put().to(set_log_level))
             .app_data(db_pool.clone()) // Cloning does not create a new pool, it gives a new reference
             .app_data(email_client.clone())
+            .app_data(confirmation.clone())
+            .app_data(magic_links.clone())
+            .app_data(email_webhooks.clone())
+            .app_data(admin.clone())
+            .app_data(subscription_validator.clone())
+            .app_data(client_ips.clone())
+            .app_data(log_filter.clone())
+            .app_data(tombstone_key.clone())
     })
     .listen(listener)?
     .run();
@@ -0,0 +1,315 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{PgPool, Postgres, Transaction};
+
+use crate::consent::{erase_consent_history, fetch_consent_history, ConsentEventRecord};
+use crate::domain::SubscriberEmail;
+use crate::suppression_list::{find_suppression, SuppressionReason, TombstoneKey};
+
+/// Everything we store about an email address, as handed over to its owner
+/// when they ask for it.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct SubscriberData {
+    pub email: String,
+    pub subscription: Option<SubscriptionRecord>,
+    pub list_memberships: Vec<ListMembershipRecord>,
+    pub confirmation_tokens: Vec<TokenRecord>,
+    pub email_events: Vec<EmailEventRecord>,
+    pub suppression: Option<SuppressionRecord>,
+    pub consent_events: Vec<ConsentEventRecord>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct SubscriptionRecord {
+    pub email: String,
+    pub name: String,
+    pub status: String,
+    pub subscribed_at: DateTime<Utc>,
+    pub attributes: serde_json::Value,
+    pub digest_frequency: String,
+    pub pending_email: Option<String>,
+    pub confirmation_sent_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ListMembershipRecord {
+    pub slug: String,
+    pub name: String,
+    pub status: String,
+    pub subscribed_at: DateTime<Utc>,
+}
+
+/// Token values are left out: they are credentials, not personal data.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct TokenRecord {
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct EmailEventRecord {
+    pub provider: String,
+    pub event_type: String,
+    pub email: String,
+    pub occurred_at: DateTime<Utc>,
+    pub received_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct SuppressionRecord {
+    pub reason: String,
+    pub source: String,
+    pub created_at: DateTime<Utc>,
+}
+
+/// The addresses rows about `email` may be stored under: providers report
+/// events for the address as we sent it, which may not be the one asked
+/// about.
+fn known_addresses(email: &SubscriberEmail, stored_email: Option<&str>) -> Vec<String> {
+    let mut addresses = vec![email.as_ref().to_string(), email.canonical().to_string()];
+    if let Some(stored_email) = stored_email {
+        addresses.push(stored_email.to_string());
+    }
+    addresses.sort();
+    addresses.dedup();
+    addresses
+}
+
+#[tracing::instrument(
+    name = "Exporting subscriber data",
+    skip(email, tombstone_key, db_pool)
+)]
+pub async fn export_subscriber_data(
+    email: &SubscriberEmail,
+    tombstone_key: &TombstoneKey,
+    db_pool: &PgPool,
+) -> Result<SubscriberData, sqlx::Error> {
+    let subscription = sqlx::query!(
+        r#"
+    SELECT id, email, name, status, subscribed_at, attributes, digest_frequency,
+        pending_email, confirmation_sent_at
+    FROM subscriptions
+    WHERE email_canonical = $1
+"#,
+        email.canonical()
+    )
+    .fetch_optional(db_pool)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    let addresses = known_addresses(email, subscription.as_ref().map(|s| s.email.as_str()));
+
+    let (list_memberships, confirmation_tokens) = match &subscription {
+        Some(subscription) => {
+            let list_memberships = sqlx::query_as!(
+                ListMembershipRecord,
+                r#"
+    SELECT lists.slug, lists.name, list_memberships.status, list_memberships.subscribed_at
+    FROM list_memberships
+    JOIN lists ON lists.id = list_memberships.list_id
+    WHERE list_memberships.subscriber_id = $1
+    ORDER BY lists.slug
+"#,
+                subscription.id
+            )
+            .fetch_all(db_pool)
+            .await
+            .map_err(|e| {
+                tracing::error!("Failed to execute query: {:?}", e);
+                e
+            })?;
+            let confirmation_tokens = sqlx::query_as!(
+                TokenRecord,
+                r#"
+    SELECT created_at FROM subscription_tokens
+    WHERE subscriber_id = $1
+    ORDER BY created_at
+"#,
+                subscription.id
+            )
+            .fetch_all(db_pool)
+            .await
+            .map_err(|e| {
+                tracing::error!("Failed to execute query: {:?}", e);
+                e
+            })?;
+            (list_memberships, confirmation_tokens)
+        }
+        None => (Vec::new(), Vec::new()),
+    };
+
+    let email_events = sqlx::query_as!(
+        EmailEventRecord,
+        r#"
+    SELECT provider, event_type, email, occurred_at, received_at
+    FROM email_events
+    WHERE email = ANY($1)
+    ORDER BY occurred_at
+"#,
+        &addresses
+    )
+    .fetch_all(db_pool)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+
+    let suppression = sqlx::query_as!(
+        SuppressionRecord,
+        r#"
+    SELECT reason, source, created_at FROM suppressions
+    WHERE email = $1 OR email = $2
+    ORDER BY created_at DESC
+    LIMIT 1
+"#,
+        email.canonical(),
+        tombstone_key.tombstone(email.canonical())
+    )
+    .fetch_optional(db_pool)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+
+    let consent_events = fetch_consent_history(email, db_pool).await?;
+
+    Ok(SubscriberData {
+        email: email.as_ref().to_string(),
+        subscription: subscription.map(|s| SubscriptionRecord {
+            email: s.email,
+            name: s.name,
+            status: s.status,
+            subscribed_at: s.subscribed_at,
+            attributes: s.attributes,
+            digest_frequency: s.digest_frequency,
+            pending_email: s.pending_email,
+            confirmation_sent_at: s.confirmation_sent_at,
+        }),
+        list_memberships,
+        confirmation_tokens,
+        email_events,
+        suppression,
+        consent_events,
+    })
+}
+
+/// Delete everything we store about `email`.
+///
+/// The address stays suppressed, through a tombstone that does not reveal
+/// it: for the reason it was suppressed for, or as unsubscribed. Subscribing
+/// again and confirming lifts the latter, as for any unsubscribed address.
+#[tracing::instrument(
+    name = "Erasing subscriber data",
+    skip(email, tombstone_key, transaction)
+)]
+pub async fn erase_subscriber_data(
+    email: &SubscriberEmail,
+    tombstone_key: &TombstoneKey,
+    transaction: &mut Transaction<'_, Postgres>,
+) -> Result<(), sqlx::Error> {
+    let subscription = sqlx::query!(
+        r#"SELECT id, email FROM subscriptions WHERE email_canonical = $1 FOR UPDATE"#,
+        email.canonical()
+    )
+    .fetch_optional(&mut **transaction)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    let addresses = known_addresses(email, subscription.as_ref().map(|s| s.email.as_str()));
+
+    // Before the subscriber is gone, their history is found through them
+    erase_consent_history(email, transaction).await?;
+    if let Some(subscription) = &subscription {
+        for query in [
+            sqlx::query!(
+                r#"DELETE FROM subscription_tokens WHERE subscriber_id = $1"#,
+                subscription.id
+            ),
+            sqlx::query!(
+                r#"DELETE FROM list_memberships WHERE subscriber_id = $1"#,
+                subscription.id
+            ),
+            sqlx::query!(
+                r#"DELETE FROM issue_delivery_queue WHERE subscriber_id = $1"#,
+                subscription.id
+            ),
+            sqlx::query!(
+                r#"DELETE FROM subscriptions WHERE id = $1"#,
+                subscription.id
+            ),
+        ] {
+            query.execute(&mut **transaction).await.map_err(|e| {
+                tracing::error!("Failed to execute query: {:?}", e);
+                e
+            })?;
+        }
+    }
+    // Another subscriber may have asked to switch to this address
+    sqlx::query!(
+        r#"UPDATE subscriptions SET pending_email = NULL WHERE pending_email = ANY($1)"#,
+        &addresses
+    )
+    .execute(&mut **transaction)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    sqlx::query!(
+        r#"DELETE FROM email_events WHERE email = ANY($1)"#,
+        &addresses
+    )
+    .execute(&mut **transaction)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    sqlx::query!(
+        r#"DELETE FROM rate_limit_counters WHERE key = $1"#,
+        format!("subscribe:email:{}", email.canonical())
+    )
+    .execute(&mut **transaction)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+
+    let reason = find_suppression(email, tombstone_key, &mut **transaction)
+        .await?
+        .unwrap_or(SuppressionReason::Unsubscribed);
+    sqlx::query!(
+        r#"DELETE FROM suppressions WHERE email = $1"#,
+        email.canonical()
+    )
+    .execute(&mut **transaction)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    sqlx::query!(
+        r#"
+    INSERT INTO suppressions (email, reason, source, created_at)
+    VALUES ($1, $2, 'erasure', $3)
+    ON CONFLICT (email) DO UPDATE
+    SET reason = EXCLUDED.reason, source = EXCLUDED.source, created_at = EXCLUDED.created_at
+"#,
+        tombstone_key.tombstone(email.canonical()),
+        reason.as_str(),
+        Utc::now()
+    )
+    .execute(&mut **transaction)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    Ok(())
+}
@@ -0,0 +1,222 @@
+use std::io::{BufRead, Write};
+
+use chrono::Utc;
+use serde::Deserialize;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use crate::consent::{record_consent_event, ConsentEvent, ConsentEventKind, ConsentOrigin};
+use crate::domain::{
+    NewSubscriber, SegmentFilter, SubscriberAttributes, SubscriberEmail, SubscriberName,
+};
+use crate::routes::fetch_subscribers;
+use crate::suppression_list::{find_suppression, TombstoneKey};
+
+/// A subscriber as `export_subscribers` writes them, one JSON object per
+/// line.
+#[derive(Debug, Deserialize)]
+struct SubscriberLine {
+    email: String,
+    name: String,
+    #[serde(default)]
+    attributes: serde_json::Map<String, serde_json::Value>,
+    /// Only active subscribers are imported: the others did not agree to
+    /// hear from us, or no longer do.
+    status: Option<String>,
+}
+
+/// The subscribers of an import, see `parse_subscribers`.
+#[derive(Debug, Default)]
+pub struct SubscriberImport {
+    pub subscribers: Vec<NewSubscriber>,
+    /// How many lines were about subscribers who are not active.
+    pub inactive: usize,
+}
+
+/// What `import_subscribers` did.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct ImportReport {
+    pub imported: usize,
+    /// Subscribers we had already, left alone.
+    pub existing: usize,
+    /// Addresses on the suppression list, left out.
+    pub suppressed: usize,
+}
+
+/// Read the subscribers of `input`, one JSON object per line with an
+/// `email`, a `name` and optional `attributes` and `status`, as written by
+/// `export_subscribers`.
+///
+/// Returns every invalid line rather than the first one: nothing should be
+/// imported until all of them are fixed.
+pub fn parse_subscribers(
+    input: impl BufRead,
+    max_name_length: usize,
+) -> Result<SubscriberImport, Vec<String>> {
+    let mut import = SubscriberImport::default();
+    let mut invalid = Vec::new();
+    for (index, line) in input.lines().enumerate() {
+        let line = match line {
+            Ok(line) => line,
+            Err(e) => {
+                invalid.push(format!("Line {}: {}", index + 1, e));
+                break;
+            }
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+        match parse_line(&line, max_name_length) {
+            Ok(Some(subscriber)) => import.subscribers.push(subscriber),
+            Ok(None) => import.inactive += 1,
+            Err(e) => invalid.push(format!("Line {}: {}", index + 1, e)),
+        }
+    }
+    if invalid.is_empty() {
+        Ok(import)
+    } else {
+        Err(invalid)
+    }
+}
+
+/// The subscriber of `line`, `None` if they are not active.
+fn parse_line(line: &str, max_name_length: usize) -> Result<Option<NewSubscriber>, String> {
+    let line: SubscriberLine = serde_json::from_str(line).map_err(|e| e.to_string())?;
+    if line
+        .status
+        .as_deref()
+        .is_some_and(|status| status != "active")
+    {
+        return Ok(None);
+    }
+    Ok(Some(NewSubscriber {
+        email: SubscriberEmail::parse(line.email)?,
+        name: SubscriberName::parse_with_max_length(line.name, max_name_length)
+            .map_err(|e| e.to_string())?,
+        attributes: SubscriberAttributes::parse(line.attributes)?,
+    }))
+}
+
+/// Store `subscribers` as active, all of them or none.
+///
+/// They are expected to have agreed to hear from us elsewhere, e.g. on the
+/// newsletter we migrate from: each of them gets a `subscribed` consent
+/// event with `import` as source. Existing subscribers are left alone, and
+/// suppressed addresses left out.
+#[tracing::instrument(
+    name = "Importing subscribers",
+    skip(subscribers, tombstone_key, db_pool)
+)]
+pub async fn import_subscribers(
+    subscribers: &[NewSubscriber],
+    tombstone_key: &TombstoneKey,
+    db_pool: &PgPool,
+) -> Result<ImportReport, sqlx::Error> {
+    let mut report = ImportReport::default();
+    let mut transaction = db_pool.begin().await?;
+    for subscriber in subscribers {
+        if find_suppression(&subscriber.email, tombstone_key, &mut *transaction)
+            .await?
+            .is_some()
+        {
+            report.suppressed += 1;
+            continue;
+        }
+        let row = sqlx::query!(
+            r#"
+    INSERT INTO subscriptions
+        (id, email, email_canonical, name, subscribed_at, attributes, status)
+    VALUES ($1, $2, $3, $4, $5, $6, 'active')
+    ON CONFLICT (email_canonical) DO NOTHING
+    RETURNING id
+"#,
+            Uuid::new_v4(),
+            subscriber.email.as_ref(),
+            subscriber.email.canonical(),
+            subscriber.name.as_ref(),
+            Utc::now(),
+            subscriber.attributes.as_json()
+        )
+        .fetch_optional(&mut *transaction)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to execute query: {:?}", e);
+            e
+        })?;
+        let Some(row) = row else {
+            report.existing += 1;
+            continue;
+        };
+        record_consent_event(
+            row.id,
+            subscriber.email.canonical(),
+            ConsentEvent {
+                kind: ConsentEventKind::Subscribed,
+                source: "import",
+                text_version: None,
+                details: serde_json::json!({}),
+            },
+            &ConsentOrigin::default(),
+            &mut transaction,
+        )
+        .await?;
+        report.imported += 1;
+    }
+    transaction.commit().await?;
+    Ok(report)
+}
+
+/// Write the subscribers in `segment`, or all of them, to `output` as
+/// `parse_subscribers` reads them. Returns how many were written.
+#[tracing::instrument(name = "Exporting subscribers", skip(segment, db_pool, output))]
+pub async fn export_subscribers(
+    segment: Option<&SegmentFilter>,
+    db_pool: &PgPool,
+    mut output: impl Write,
+) -> Result<usize, Box<dyn std::error::Error>> {
+    let subscribers = fetch_subscribers(segment, db_pool).await?;
+    for subscriber in &subscribers {
+        serde_json::to_writer(&mut output, subscriber)?;
+        writeln!(output)?;
+    }
+    output.flush()?;
+    Ok(subscribers.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_subscribers;
+
+    #[test]
+    fn only_active_subscribers_are_imported() {
+        let input = r#"{"email": "ursula@example.com", "name": "Ursula", "attributes": {"plan": "pro"}}
+
+{"email": "le.guin@example.com", "name": "Le Guin", "status": "active"}
+{"email": "pending@example.com", "name": "Pending", "status": "pending_confirmation"}
+"#;
+
+        let import = parse_subscribers(input.as_bytes(), 256).unwrap();
+
+        let emails: Vec<_> = import
+            .subscribers
+            .iter()
+            .map(|s| s.email.as_ref())
+            .collect();
+        assert_eq!(vec!["ursula@example.com", "le.guin@example.com"], emails);
+        assert_eq!(1, import.inactive);
+    }
+
+    #[test]
+    fn every_invalid_line_is_reported() {
+        let input = r#"{"email": "not an email", "name": "Ursula"}
+{"email": "ursula@example.com", "name": "Ursula"}
+{"email": "ursula@example.com"}
+"#;
+
+        let invalid = parse_subscribers(input.as_bytes(), 256).unwrap_err();
+
+        assert_eq!(2, invalid.len());
+        assert!(invalid[0].starts_with("Line 1: "));
+        assert!(invalid[1].starts_with("Line 3: "));
+    }
+}
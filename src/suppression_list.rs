@@ -0,0 +1,235 @@
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use secrecy::{ExposeSecret, Secret};
+use sha2::Sha256;
+use sqlx::{PgExecutor, PgPool};
+
+use crate::configuration::SuppressionListSettings;
+use crate::domain::SubscriberEmail;
+
+/// Why we must not email an address anymore.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SuppressionReason {
+    HardBounce,
+    Complaint,
+    Unsubscribed,
+    Manual,
+}
+
+impl SuppressionReason {
+    pub fn as_str(&self) -> &str {
+        match self {
+            Self::HardBounce => "hard_bounce",
+            Self::Complaint => "complaint",
+            Self::Unsubscribed => "unsubscribed",
+            Self::Manual => "manual",
+        }
+    }
+}
+
+impl TryFrom<String> for SuppressionReason {
+    type Error = String;
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        match s.as_str() {
+            "hard_bounce" => Ok(Self::HardBounce),
+            "complaint" => Ok(Self::Complaint),
+            "unsubscribed" => Ok(Self::Unsubscribed),
+            "manual" => Ok(Self::Manual),
+            other => Err(format!("{} is not a valid suppression reason.", other)),
+        }
+    }
+}
+
+/// The set of addresses we refuse to send emails to.
+///
+/// `EmailClient` consults it before every send. It is a trait so that
+/// the client can be exercised without a database in unit tests.
+#[async_trait::async_trait]
+pub trait SuppressionList: Send + Sync {
+    /// Returns the reason `email` is suppressed, or `None` if we can email it.
+    async fn find(&self, email: &SubscriberEmail)
+        -> Result<Option<SuppressionReason>, sqlx::Error>;
+}
+
+pub struct PgSuppressionList {
+    db_pool: PgPool,
+    tombstone_key: TombstoneKey,
+}
+
+impl PgSuppressionList {
+    pub fn new(db_pool: PgPool, tombstone_key: TombstoneKey) -> Self {
+        Self {
+            db_pool,
+            tombstone_key,
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl SuppressionList for PgSuppressionList {
+    async fn find(
+        &self,
+        email: &SubscriberEmail,
+    ) -> Result<Option<SuppressionReason>, sqlx::Error> {
+        find_suppression(email, &self.tombstone_key, &self.db_pool).await
+    }
+}
+
+/// The key tombstones are made with.
+#[derive(Clone)]
+pub struct TombstoneKey(Secret<String>);
+
+impl TombstoneKey {
+    pub fn new(settings: &SuppressionListSettings) -> Self {
+        Self(settings.tombstone_key.clone())
+    }
+
+    /// What is kept of a suppressed address once its owner had their data
+    /// erased: enough to recognise the address, not to recover it without
+    /// the key.
+    pub fn tombstone(&self, canonical_email: &str) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(self.0.expose_secret().as_bytes())
+            .expect("HMAC can take a key of any size");
+        mac.update(canonical_email.as_bytes());
+        format!("hmac-sha256:{}", hex::encode(mac.finalize().into_bytes()))
+    }
+}
+
+#[tracing::instrument(name = "Looking up suppression", skip(email, tombstone_key, executor))]
+pub async fn find_suppression<'e>(
+    email: &SubscriberEmail,
+    tombstone_key: &TombstoneKey,
+    executor: impl PgExecutor<'e>,
+) -> Result<Option<SuppressionReason>, sqlx::Error> {
+    // Addresses stay suppressed after their data is erased. If a tombstone
+    // and a newer entry coexist, the newer one wins.
+    let row = sqlx::query!(
+        r#"
+    SELECT reason FROM suppressions
+    WHERE email = $1 OR email = $2
+    ORDER BY created_at DESC
+    LIMIT 1
+"#,
+        email.canonical(),
+        tombstone_key.tombstone(email.canonical())
+    )
+    .fetch_optional(executor)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    // Rows are only ever written through `SuppressionReason::as_str`, an
+    // unknown value means someone edited the table by hand: stay safe.
+    Ok(row.map(|r| r.reason.try_into().unwrap_or(SuppressionReason::Manual)))
+}
+
+/// Add `email` to the suppression list, or refresh its reason if it is
+/// already there.
+///
+/// Also marks the matching subscription as suppressed. Callers are
+/// expected to pass a transaction so both writes land together.
+#[tracing::instrument(name = "Suppressing email address", skip(email, transaction))]
+pub async fn add_suppression(
+    email: &SubscriberEmail,
+    reason: SuppressionReason,
+    source: &str,
+    transaction: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+    INSERT INTO suppressions (email, reason, source, created_at)
+    VALUES ($1, $2, $3, $4)
+    ON CONFLICT (email) DO UPDATE
+    SET reason = EXCLUDED.reason, source = EXCLUDED.source, created_at = EXCLUDED.created_at
+"#,
+        email.canonical(),
+        reason.as_str(),
+        source,
+        Utc::now()
+    )
+    .execute(&mut **transaction)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    sqlx::query!(
+        r#"UPDATE subscriptions SET status = 'suppressed' WHERE email_canonical = $1"#,
+        email.canonical()
+    )
+    .execute(&mut **transaction)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    Ok(())
+}
+
+/// Take `email` off the suppression list and reactivate its subscription.
+///
+/// Returns `false` if the address was not suppressed in the first place.
+#[tracing::instrument(
+    name = "Removing email address suppression",
+    skip(email, tombstone_key, transaction)
+)]
+pub async fn remove_suppression(
+    email: &SubscriberEmail,
+    tombstone_key: &TombstoneKey,
+    transaction: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+) -> Result<bool, sqlx::Error> {
+    let deleted = sqlx::query!(
+        r#"DELETE FROM suppressions WHERE email = $1 OR email = $2"#,
+        email.canonical(),
+        tombstone_key.tombstone(email.canonical())
+    )
+    .execute(&mut **transaction)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?
+    .rows_affected();
+    sqlx::query!(
+        r#"UPDATE subscriptions SET status = 'active' WHERE email_canonical = $1 AND status = 'suppressed'"#,
+        email.canonical()
+    )
+    .execute(&mut **transaction)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    Ok(deleted > 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use secrecy::Secret;
+
+    use super::TombstoneKey;
+    use crate::configuration::SuppressionListSettings;
+
+    fn key(key: &str) -> TombstoneKey {
+        TombstoneKey::new(&SuppressionListSettings {
+            tombstone_key: Secret::new(key.to_string()),
+        })
+    }
+
+    #[test]
+    fn tombstones_do_not_contain_the_address() {
+        let tombstone = key("key").tombstone("ursula@example.com");
+        assert!(tombstone.starts_with("hmac-sha256:"));
+        assert!(!tombstone.contains("ursula"));
+        assert_eq!(tombstone, key("key").tombstone("ursula@example.com"));
+    }
+
+    #[test]
+    fn tombstones_cannot_be_matched_without_the_key() {
+        assert_ne!(
+            key("key").tombstone("ursula@example.com"),
+            key("another key").tombstone("ursula@example.com")
+        );
+    }
+}
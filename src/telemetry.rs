@@ -1,11 +1,107 @@
+use std::sync::{Arc, Mutex};
+
+use chrono::{DateTime, Utc};
 use tracing::{subscriber::set_global_default, Subscriber};
 use tracing_bunyan_formatter::{BunyanFormattingLayer, JsonStorageLayer};
 use tracing_log::LogTracer;
+
+use crate::configuration::LogFormat;
 use tracing_subscriber::fmt::MakeWriter;
-use tracing_subscriber::{layer::SubscriberExt, EnvFilter, Registry};
+use tracing_subscriber::{layer::SubscriberExt, reload, EnvFilter, Registry};
+
+/// The filter of a subscriber built by `get_subscriber`, which can be
+/// changed while the application runs.
+#[derive(Clone)]
+pub struct LogFilter {
+    handle: reload::Handle<EnvFilter, Registry>,
+    changes: Arc<Mutex<Changes>>,
+}
+
+#[derive(Default)]
+struct Changes {
+    /// How many times the filter was changed, to tell whether it was
+    /// changed again before a revert.
+    count: u64,
+    /// When the directives in use are to be replaced by the ones in use
+    /// before, if they are.
+    reverts_at: Option<DateTime<Utc>>,
+}
+
+impl LogFilter {
+    /// The directives in use, e.g. `info,sqlx=warn`.
+    pub fn directives(&self) -> String {
+        self.handle
+            .with_current(|filter| filter.to_string())
+            .unwrap_or_default()
+    }
+
+    /// When the directives in use are reverted, see `set_for`.
+    pub fn reverts_at(&self) -> Option<DateTime<Utc>> {
+        self.changes.lock().unwrap().reverts_at
+    }
+
+    /// Filter with `directives` from now on. Invalid directives are
+    /// rejected, and the filter in use is kept.
+    pub fn set(&self, directives: &str) -> Result<(), String> {
+        let filter = parse_log_filter(directives)?;
+        let mut changes = self.changes.lock().unwrap();
+        self.replace(&mut changes, filter)?;
+        Ok(())
+    }
+
+    /// Filter with `directives` for `ttl`, then with the directives in use
+    /// before, unless the filter is changed again in the meantime.
+    ///
+    /// Must be called from within a Tokio runtime.
+    pub fn set_for(&self, directives: &str, ttl: std::time::Duration) -> Result<(), String> {
+        let filter = parse_log_filter(directives)?;
+        let reverts_at = chrono::Duration::from_std(ttl)
+            .ok()
+            .and_then(|ttl| Utc::now().checked_add_signed(ttl))
+            .ok_or_else(|| "The TTL is too long.".to_string())?;
+        let mut changes = self.changes.lock().unwrap();
+        let previous = self.directives();
+        let change = self.replace(&mut changes, filter)?;
+        changes.reverts_at = Some(reverts_at);
+
+        let log_filter = self.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(ttl).await;
+            let mut changes = log_filter.changes.lock().unwrap();
+            if changes.count != change {
+                return;
+            }
+            match parse_log_filter(&previous)
+                .and_then(|filter| log_filter.replace(&mut changes, filter))
+            {
+                Ok(_) => tracing::info!("Reverted the log filter to {}", previous),
+                Err(e) => tracing::error!("Failed to revert the log filter: {}", e),
+            }
+        });
+        Ok(())
+    }
+
+    /// Returns the number of the change.
+    fn replace(&self, changes: &mut Changes, filter: EnvFilter) -> Result<u64, String> {
+        self.handle.reload(filter).map_err(|e| e.to_string())?;
+        changes.count += 1;
+        changes.reverts_at = None;
+        Ok(changes.count)
+    }
+}
+
+/// `directives` as a filter, e.g. `info,sqlx=warn`.
+pub fn parse_log_filter(directives: &str) -> Result<EnvFilter, String> {
+    EnvFilter::try_new(directives)
+        .map_err(|e| format!("{} is not a valid log filter: {}", directives, e))
+}
 
 /// Compose multiple layers into a `tracing`'s subscriber.
 ///
+/// Events are filtered with `RUST_LOG` if set, `env_filter` otherwise. The
+/// filter can be changed later through the returned `LogFilter`. They are
+/// written to `sink` in `format`.
+///
 /// # Implementation Notes
 ///
 /// We are using `impl Subscriber` as return type to avoid having to
@@ -17,18 +113,42 @@ use tracing_subscriber::{layer::SubscriberExt, EnvFilter, Registry};
 pub fn get_subscriber<Sink>(
     name: String,
     env_filter: String,
+    format: LogFormat,
     sink: Sink,
-) -> impl Subscriber + Sync + Send
+) -> (impl Subscriber + Sync + Send, LogFilter)
 where
     Sink: for<'a> MakeWriter<'a> + Send + Sync + 'static,
 {
     let env_filter =
         EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(env_filter));
-    let formatting_layer = BunyanFormattingLayer::new(name, sink);
-    Registry::default()
+    let (env_filter, handle) = reload::Layer::new(env_filter);
+    // Only one of them is set, they are of different types
+    let (bunyan, compact, pretty) = match format {
+        LogFormat::Bunyan => (Some(BunyanFormattingLayer::new(name, sink)), None, None),
+        LogFormat::Compact => (
+            None,
+            Some(tracing_subscriber::fmt::layer().compact().with_writer(sink)),
+            None,
+        ),
+        LogFormat::Pretty => (
+            None,
+            None,
+            Some(tracing_subscriber::fmt::layer().pretty().with_writer(sink)),
+        ),
+    };
+    let subscriber = Registry::default()
         .with(env_filter)
         .with(JsonStorageLayer)
-        .with(formatting_layer)
+        .with(bunyan)
+        .with(compact)
+        .with(pretty);
+    (
+        subscriber,
+        LogFilter {
+            handle,
+            changes: Default::default(),
+        },
+    )
 }
 /// Register a subscriber as global default to process span data.
 ///
@@ -37,3 +157,120 @@ pub fn init_subscriber(subscriber: impl Subscriber + Send + Sync) {
     LogTracer::init().expect("Failed to set logger");
     set_global_default(subscriber).expect("Failed to set subscriber");
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use claims::assert_err;
+
+    use super::get_subscriber;
+    use crate::configuration::LogFormat;
+
+    #[tokio::test]
+    async fn temporary_directives_are_reverted() {
+        let (_subscriber, log_filter) = get_subscriber(
+            "test".into(),
+            "info".into(),
+            LogFormat::Bunyan,
+            std::io::sink,
+        );
+
+        log_filter
+            .set_for("debug", Duration::from_millis(50))
+            .unwrap();
+        assert_eq!(log_filter.directives(), "debug");
+        assert!(log_filter.reverts_at().is_some());
+
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(log_filter.directives(), "info");
+        assert_eq!(log_filter.reverts_at(), None);
+    }
+
+    #[tokio::test]
+    async fn later_changes_are_not_reverted() {
+        let (_subscriber, log_filter) = get_subscriber(
+            "test".into(),
+            "info".into(),
+            LogFormat::Bunyan,
+            std::io::sink,
+        );
+
+        log_filter
+            .set_for("debug", Duration::from_millis(50))
+            .unwrap();
+        log_filter.set("warn").unwrap();
+
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(log_filter.directives(), "warn");
+    }
+
+    #[test]
+    fn invalid_directives_keep_the_filter_in_use() {
+        let (_subscriber, log_filter) = get_subscriber(
+            "test".into(),
+            "info".into(),
+            LogFormat::Bunyan,
+            std::io::sink,
+        );
+
+        assert_err!(log_filter.set("info,sqlx=loud"));
+        assert_eq!(log_filter.directives(), "info");
+    }
+
+    /// Keeps what is written to it.
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl std::io::Write for Buffer {
+        fn write(&mut self, bytes: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(bytes)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// What an event logged in a span is written as in `format`.
+    fn logged(format: LogFormat) -> String {
+        let buffer = Buffer::default();
+        let sink = buffer.clone();
+        let (subscriber, _) =
+            get_subscriber("test".into(), "info".into(), format, move || sink.clone());
+        tracing::subscriber::with_default(subscriber, || {
+            let _span = tracing::info_span!("Subscribing", email = "ursula@example.com").entered();
+            tracing::info!("Saved the subscriber");
+        });
+        let bytes = buffer.0.lock().unwrap().clone();
+        String::from_utf8(bytes).unwrap()
+    }
+
+    #[test]
+    fn events_are_written_in_the_configured_format() {
+        let bunyan = logged(LogFormat::Bunyan);
+        let event = bunyan
+            .lines()
+            .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+            // Messages are prefixed with the name of their span
+            .find(|event| {
+                event["msg"]
+                    .as_str()
+                    .is_some_and(|msg| msg.ends_with("Saved the subscriber"))
+            })
+            .unwrap();
+        assert_eq!(event["email"], "ursula@example.com");
+
+        let compact = logged(LogFormat::Compact);
+        assert_eq!(compact.lines().count(), 1);
+        assert!(compact.contains("Saved the subscriber"));
+
+        // Spans are listed below the event they surround
+        let pretty = logged(LogFormat::Pretty);
+        assert!(pretty.lines().count() > 1);
+        assert!(pretty.contains("Saved the subscriber"));
+        assert!(pretty.contains("Subscribing"));
+        assert!(pretty.contains(r#""ursula@example.com""#));
+    }
+}
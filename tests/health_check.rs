@@ -2,20 +2,47 @@
 // from using the #[test] attribute.
 
 use std::net::TcpListener;
-use zero_to_prod_example::email_client::EmailClient;
+use std::sync::Arc;
+use zero_to_prod_example::authentication::{create_admin_user, CreateAdminError};
+use zero_to_prod_example::canonical_emails::{canonicalize_stored_emails, CanonicalizationReport};
+use zero_to_prod_example::challenge::{ChallengeVerifier, SiteVerifyChallengeVerifier};
+use zero_to_prod_example::domain::SubscriberEmail;
+use zero_to_prod_example::domain_policy::DomainPolicy;
+use zero_to_prod_example::email_client::{EmailClient, SendEmailError};
+use zero_to_prod_example::email_domain_check::{DomainResolver, EmailDomainChecker};
+use zero_to_prod_example::issue_delivery_worker::{try_execute_task, ExecutionOutcome};
+use zero_to_prod_example::magic_link::MagicLinks;
 
+use hickory_resolver::error::ResolveError;
+use hmac::{Hmac, Mac};
 use once_cell::sync::Lazy;
+use secrecy::{ExposeSecret, Secret};
+use sha2::Sha256;
 use sqlx::{Connection, Executor, PgConnection, PgPool};
 use uuid::Uuid;
+use wiremock::matchers::{body_string_contains, method, path};
+use wiremock::{Mock, MockServer, ResponseTemplate};
 use zero_to_prod_example::{
-    configuration::{get_configuration, DatabaseSettings},
-    routes::FormData,
+    configuration::{
+        get_configuration, AdminSettings, DatabaseSettings, EmailDomainCheckMode,
+        EmailWebhookSettings, LogFormat, Settings,
+    },
+    consent::ConsentEventRecord,
+    rate_limit::{InMemoryRateLimitStore, RateLimit},
+    routes::{
+        FormData, Preferences, PreferencesForm, PreferencesLoginData, SubscriptionConfirmation,
+        SubscriptionValidator,
+    },
     startup::run,
-    telemetry::{get_subscriber, init_subscriber},
+    subscriber_data::SubscriberData,
+    subscriber_transfer::{export_subscribers, import_subscribers, parse_subscribers},
+    suppression_list::{PgSuppressionList, SuppressionReason, TombstoneKey},
+    telemetry::{get_subscriber, init_subscriber, LogFilter},
 };
 
-// Ensure that the `tracing` stack is only initialised once using `once_cell`
-static TRACING: Lazy<()> = Lazy::new(|| {
+// Ensure that the `tracing` stack is only initialised once using `once_cell`.
+// Its filter is shared by every test app.
+static TRACING: Lazy<LogFilter> = Lazy::new(|| {
     let default_filter_level = "info".to_string();
     let subscriber_name = "test".to_string();
     // We cannot assign the output of `get_subscriber` to a variable based on the
@@ -23,20 +50,123 @@ static TRACING: Lazy<()> = Lazy::new(|| {
     // `get_subscriber`, therefore they are not the same type. We could work around
     // it, but this is the most straight-forward way of moving forward.
     if std::env::var("TEST_LOG").is_ok() {
-        let subscriber = get_subscriber(subscriber_name, default_filter_level, std::io::stdout);
+        let (subscriber, log_filter) = get_subscriber(
+            subscriber_name,
+            default_filter_level,
+            LogFormat::Bunyan,
+            std::io::stdout,
+        );
         init_subscriber(subscriber);
+        log_filter
     } else {
-        let subscriber = get_subscriber(subscriber_name, default_filter_level, std::io::sink);
+        let (subscriber, log_filter) = get_subscriber(
+            subscriber_name,
+            default_filter_level,
+            LogFormat::Bunyan,
+            std::io::sink,
+        );
         init_subscriber(subscriber);
-    };
+        log_filter
+    }
 });
 
+/// Every domain accepts emails, except the ones ending in `.invalid` and
+/// `gmial.com`: tests must not depend on the network.
+struct StubResolver;
+
+#[async_trait::async_trait]
+impl DomainResolver for StubResolver {
+    async fn mail_exchanges(&self, domain: &str) -> Result<Vec<String>, ResolveError> {
+        if domain == "gmial.com" || domain.ends_with(".invalid") {
+            Ok(Vec::new())
+        } else {
+            Ok(vec![format!("mx.{}.", domain)])
+        }
+    }
+
+    async fn has_addresses(&self, _domain: &str) -> Result<bool, ResolveError> {
+        Ok(false)
+    }
+}
+
 pub struct TestApp {
     pub address: String,
     pub db_pool: PgPool,
+    pub email_server: MockServer,
+    pub email_webhooks: EmailWebhookSettings,
+    pub admin: AdminSettings,
+    pub email_client: EmailClient,
+    pub tombstone_key: TombstoneKey,
+}
+
+impl TestApp {
+    /// The recipient and the link of every email sent so far, oldest first.
+    pub async fn sent_links(&self) -> Vec<(String, String)> {
+        self.email_server
+            .received_requests()
+            .await
+            .unwrap()
+            .iter()
+            .map(|request| {
+                let body: serde_json::Value = serde_json::from_slice(&request.body).unwrap();
+                let text = body["TextBody"].as_str().unwrap();
+                let link = text
+                    .split_whitespace()
+                    .find(|word| word.starts_with(&self.address))
+                    .expect("No link in the email.");
+                (body["To"].as_str().unwrap().to_string(), link.to_string())
+            })
+            .collect()
+    }
+
+    /// The confirmation links in the emails sent so far, oldest first.
+    pub async fn confirmation_links(&self) -> Vec<String> {
+        self.sent_links()
+            .await
+            .into_iter()
+            .map(|(_, link)| link)
+            .filter(|link| link.contains("/subscriptions/confirm?"))
+            .collect()
+    }
+
+    /// Send every queued newsletter delivery, as the delivery worker would.
+    pub async fn dispatch_all_pending_emails(&self) {
+        loop {
+            if let ExecutionOutcome::EmptyQueue =
+                try_execute_task(&self.db_pool, &self.email_client)
+                    .await
+                    .unwrap()
+            {
+                break;
+            }
+        }
+    }
+
+    /// The recipients of the emails titled `subject` sent so far, sorted.
+    pub async fn recipients_of(&self, subject: &str) -> Vec<String> {
+        let mut recipients: Vec<String> = self
+            .email_server
+            .received_requests()
+            .await
+            .unwrap()
+            .iter()
+            .map(|request| serde_json::from_slice::<serde_json::Value>(&request.body).unwrap())
+            .filter(|body| body["Subject"] == subject)
+            .map(|body| body["To"].as_str().unwrap().to_string())
+            .collect();
+        recipients.sort();
+        recipients
+    }
+}
+
+/// Rows stored before the migration `version` and the ones after it were
+/// applied.
+pub struct Seed {
+    pub version: i64,
+    pub sql: &'static str,
 }
 
-pub async fn configure_database(config: &DatabaseSettings) -> PgPool {
+pub async fn configure_database(config: &DatabaseSettings, seed: Option<&Seed>) -> PgPool {
     // Create new database with a the random name to insolate the test
     let mut connection = PgConnection::connect_with(&config.without_db())
         .await
@@ -51,14 +181,51 @@ pub async fn configure_database(config: &DatabaseSettings) -> PgPool {
         .await
         .expect("Failed to connect to Postgres.");
 
-    sqlx::migrate!("./migrations")
+    let mut migrator = sqlx::migrate!("./migrations");
+    if let Some(seed) = seed {
+        let migrations = migrator.migrations.clone();
+        migrator.migrations = migrations
+            .iter()
+            .filter(|migration| migration.version < seed.version)
+            .cloned()
+            .collect::<Vec<_>>()
+            .into();
+        migrator
+            .run(&connection_pool)
+            .await
+            .expect("Failed to migrate the database");
+        connection_pool
+            .execute(seed.sql)
+            .await
+            .expect("Failed to seed the database.");
+        migrator.migrations = migrations;
+    }
+    migrator
         .run(&connection_pool)
         .await
         .expect("Failed to migrate the database");
+    // As the `migrate` command does
+    canonicalize_stored_emails(&connection_pool)
+        .await
+        .expect("Failed to canonicalize the stored emails.");
     connection_pool
 }
 
 async fn spawn_app() -> TestApp {
+    spawn_app_with(|_| {}).await
+}
+
+/// Spawn the application after letting `configure` adjust its settings.
+async fn spawn_app_with(configure: impl FnOnce(&mut Settings)) -> TestApp {
+    spawn_app_on(None, configure).await
+}
+
+/// Spawn the application on a database holding `seed`.
+async fn spawn_seeded_app(seed: Seed) -> TestApp {
+    spawn_app_on(Some(seed), |_| {}).await
+}
+
+async fn spawn_app_on(seed: Option<Seed>, configure: impl FnOnce(&mut Settings)) -> TestApp {
     // The first time `initialize` is invoked the code in `TRACING` is executed. // All other invocations will instead skip execution.
     Lazy::force(&TRACING);
 
@@ -66,25 +233,97 @@ async fn spawn_app() -> TestApp {
     let port = listener.local_addr().unwrap().port();
     let address = format!("http://127.0.0.1:{}", port);
 
+    // Emails go to a mock server, every send succeeds
+    let email_server = MockServer::start().await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&email_server)
+        .await;
+
     let mut configuration = get_configuration().expect("Failed to read configuration.");
     configuration.database.database_name = Uuid::new_v4().to_string();
-    let connection_pool = configure_database(&configuration.database).await;
+    configuration.email_client.base_url = email_server.uri();
+    configuration.application.base_url = address.clone();
+    configure(&mut configuration);
+    let connection_pool = configure_database(&configuration.database, seed.as_ref()).await;
 
     // Build a new email client
     let sender_email = configuration
         .email_client
         .sender()
         .expect("Invalid sender email address.");
-    let email_client = EmailClient::new(configuration.email_client.base_url, sender_email);
+    let email_client = EmailClient::new(
+        configuration.email_client.base_url,
+        sender_email,
+        configuration.email_client.authorization_token,
+        Arc::new(PgSuppressionList::new(
+            connection_pool.clone(),
+            TombstoneKey::new(&configuration.suppression_list),
+        )),
+    );
+
+    configuration.email_domain_check.mode = EmailDomainCheckMode::Enforcing;
+    let email_domain_checker =
+        EmailDomainChecker::new(Arc::new(StubResolver), &configuration.email_domain_check);
+
+    let domain_policy = DomainPolicy::load(&configuration.domain_policy)
+        .expect("Failed to load the domain policy.");
+
+    let challenge_verifier: Option<Arc<dyn ChallengeVerifier>> = if configuration.challenge.enabled
+    {
+        Some(Arc::new(SiteVerifyChallengeVerifier::new(
+            configuration.challenge.verify_url.clone(),
+            configuration.challenge.secret_key.clone(),
+            configuration.challenge.timeout(),
+        )))
+    } else {
+        None
+    };
 
-    let server =
-        run(listener, connection_pool.clone(), email_client).expect("Failed to bind address");
-    let _ = tokio::spawn(server);
+    let email_webhooks = configuration.email_webhooks.clone();
+    let admin = configuration.admin.clone();
+    let tombstone_key = TombstoneKey::new(&configuration.suppression_list);
+    let server = run(
+        listener,
+        connection_pool.clone(),
+        email_client.clone(),
+        SubscriptionConfirmation::new(
+            configuration.application.base_url.clone(),
+            &configuration.subscription_confirmation,
+        ),
+        MagicLinks::new(
+            configuration.application.base_url,
+            &configuration.magic_link,
+        ),
+        configuration.email_webhooks,
+        configuration.admin,
+        SubscriptionValidator::new(
+            configuration.subscriber_name,
+            Arc::new(domain_policy),
+            email_domain_checker,
+            configuration.bot_detection,
+            challenge_verifier,
+        ),
+        RateLimit::new(
+            Arc::new(InMemoryRateLimitStore::default()),
+            configuration.rate_limit,
+        ),
+        TRACING.clone(),
+        tombstone_key.clone(),
+    )
+    .expect("Failed to bind address");
+    tokio::spawn(server);
     // We launch the server in a background task
     // tokio::spawn returns a handle to the spawned future, but we don't need it here
     TestApp {
         address,
         db_pool: connection_pool,
+        email_server,
+        email_webhooks,
+        admin,
+        email_client,
+        tombstone_key,
     }
 }
 
@@ -96,7 +335,7 @@ async fn health_check_works() {
 
     // Act
     let response = client
-        .get(&format!("{}/health_check", &app.address))
+        .get(format!("{}/health_check", &app.address))
         .send()
         .await
         .expect("Failed to execute request.");
@@ -116,10 +355,12 @@ async fn subscribe_returns_a_200_for_valid_form_data() {
     let json_body = FormData {
         name: "George".to_string(),
         email: "george_t@gmail.com".to_string(),
+        attributes: None,
+        ..Default::default()
     };
 
     let response = client
-        .post(&format!("{}/subscriptions", &app.address))
+        .post(format!("{}/subscriptions", &app.address))
         .header("Content-Type", "application/json")
         .body(serde_json::to_string(&json_body).expect("Failed to parse the body to a json string"))
         .send()
@@ -136,6 +377,7 @@ async fn subscribe_returns_a_200_for_valid_form_data() {
 
     assert_eq!(json_body.email, saved.email);
     assert_eq!(json_body.name, saved.name);
+    assert_eq!(vec!["default"], list_slugs(&app, &json_body.email).await);
 }
 
 #[tokio::test]
@@ -149,6 +391,8 @@ async fn subscribe_returns_a_400_code_when_data_is_invalid() {
             FormData {
                 name: "Le guin".to_string(),
                 email: "".to_string(),
+                attributes: None,
+                ..Default::default()
             },
             "missing the email".to_string(),
         ),
@@ -156,6 +400,8 @@ async fn subscribe_returns_a_400_code_when_data_is_invalid() {
             FormData {
                 name: "".to_string(),
                 email: "ursula_le_guin@gmail.com".to_string(),
+                attributes: None,
+                ..Default::default()
             },
             "missing the name".to_string(),
         ),
@@ -163,6 +409,8 @@ async fn subscribe_returns_a_400_code_when_data_is_invalid() {
             FormData {
                 name: "".to_string(),
                 email: "".to_string(),
+                attributes: None,
+                ..Default::default()
             },
             "missing both name and email".to_string(),
         ),
@@ -170,6 +418,8 @@ async fn subscribe_returns_a_400_code_when_data_is_invalid() {
             FormData {
                 name: "Tony".to_string(),
                 email: "not_valid_email".to_string(),
+                attributes: None,
+                ..Default::default()
             },
             "has an invalid email".to_string(),
         ),
@@ -178,7 +428,7 @@ async fn subscribe_returns_a_400_code_when_data_is_invalid() {
     // Act
     for (invalid_body, error_message) in test_cases {
         let response = client
-            .post(&format!("{}/subscriptions", &app.address))
+            .post(format!("{}/subscriptions", &app.address))
             .header("Content-Type", "application/json")
             .body(
                 serde_json::to_string(&invalid_body)
@@ -197,3 +447,2620 @@ async fn subscribe_returns_a_400_code_when_data_is_invalid() {
         );
     }
 }
+
+#[tokio::test]
+async fn subscribe_explains_why_a_name_is_rejected() {
+    // Arrange
+    let app = spawn_app().await;
+
+    // Act
+    let response = reqwest::Client::new()
+        .post(format!("{}/subscriptions", &app.address))
+        .json(&FormData {
+            name: "Ursula\u{202E}niuG eL".to_string(),
+            email: "ursula_le_guin@gmail.com".to_string(),
+            attributes: None,
+            ..Default::default()
+        })
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    // Assert
+    assert_eq!(400, response.status().as_u16());
+    assert_eq!(
+        "Invalid data. The name contains a text direction control: '\\u{202e}'",
+        response.text().await.unwrap()
+    );
+}
+
+#[tokio::test]
+async fn subscribe_stores_the_normalized_name() {
+    // Arrange
+    let app = spawn_app().await;
+
+    // Act
+    let response = reqwest::Client::new()
+        .post(format!("{}/subscriptions", &app.address))
+        .json(&FormData {
+            name: "  Ursula \n Le   Guin ".to_string(),
+            email: "ursula_le_guin@gmail.com".to_string(),
+            attributes: None,
+            ..Default::default()
+        })
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    // Assert
+    assert_eq!(200, response.status().as_u16());
+    let saved = sqlx::query!("SELECT name FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch saved subscription.");
+    assert_eq!(saved.name, "Ursula Le Guin");
+}
+
+#[tokio::test]
+async fn subscribe_returns_429_past_the_limit_per_email() {
+    // Arrange
+    let app = spawn_app_with(|c| c.rate_limit.max_requests_per_email = 2).await;
+    let client = reqwest::Client::new();
+    let body = FormData {
+        name: "le guin".to_string(),
+        email: "ursula_le_guin@gmail.com".to_string(),
+        ..Default::default()
+    };
+
+    // Act
+    let mut statuses = Vec::new();
+    for _ in 0..3 {
+        let response = client
+            .post(format!("{}/subscriptions", &app.address))
+            .json(&body)
+            .send()
+            .await
+            .expect("Failed to execute request.");
+        statuses.push(response.status().as_u16());
+    }
+
+    // Assert
+    assert_ne!(429, statuses[0]);
+    assert_ne!(429, statuses[1]);
+    assert_eq!(429, statuses[2]);
+}
+
+#[tokio::test]
+async fn subscribe_returns_429_past_the_limit_per_ip() {
+    // Arrange
+    let app = spawn_app_with(|c| c.rate_limit.max_requests_per_ip = 2).await;
+    let client = reqwest::Client::new();
+
+    // Act
+    let mut responses = Vec::new();
+    for email in ["a@example.com", "b@example.com", "c@example.com"] {
+        let response = client
+            .post(format!("{}/subscriptions", &app.address))
+            .json(&FormData {
+                name: "le guin".to_string(),
+                email: email.to_string(),
+                ..Default::default()
+            })
+            .send()
+            .await
+            .expect("Failed to execute request.");
+        responses.push(response);
+    }
+
+    // Assert
+    assert_eq!(200, responses[1].status().as_u16());
+    assert_eq!(429, responses[2].status().as_u16());
+    assert!(responses[2].headers().contains_key("Retry-After"));
+    let saved = sqlx::query!("SELECT email FROM subscriptions")
+        .fetch_all(&app.db_pool)
+        .await
+        .expect("Failed to fetch saved subscriptions.");
+    assert_eq!(saved.len(), 2);
+}
+
+#[tokio::test]
+async fn forged_forwarded_for_entries_do_not_dodge_the_limit_per_ip() {
+    // Arrange
+    let app = spawn_app_with(|c| {
+        c.rate_limit.max_requests_per_ip = 2;
+        c.rate_limit.trusted_proxies = 1;
+    })
+    .await;
+    let client = reqwest::Client::new();
+
+    // Act
+    let mut statuses = Vec::new();
+    for (forged, email) in [
+        ("1.1.1.1", "a@example.com"),
+        ("2.2.2.2", "b@example.com"),
+        ("3.3.3.3", "c@example.com"),
+    ] {
+        // The client sends `forged`, the proxy appends the address it sees
+        let response = client
+            .post(format!("{}/subscriptions", &app.address))
+            .header("X-Forwarded-For", format!("{}, 203.0.113.7", forged))
+            .json(&FormData {
+                name: "le guin".to_string(),
+                email: email.to_string(),
+                ..Default::default()
+            })
+            .send()
+            .await
+            .expect("Failed to execute request.");
+        statuses.push(response.status().as_u16());
+    }
+
+    // Assert
+    assert_eq!(200, statuses[1]);
+    assert_eq!(429, statuses[2]);
+}
+
+#[tokio::test]
+async fn subscribe_pretends_to_accept_suspected_bots() {
+    // Arrange
+    let app = spawn_app().await;
+    let client = reqwest::Client::new();
+    let test_cases = vec![
+        (
+            FormData {
+                name: "le guin".to_string(),
+                email: "ursula_le_guin@gmail.com".to_string(),
+                website: Some("https://spam.example".to_string()),
+                ..Default::default()
+            },
+            "the honeypot was filled",
+        ),
+        (
+            FormData {
+                name: "le guin".to_string(),
+                email: "ursula_le_guin@gmail.com".to_string(),
+                rendered_at: Some(chrono::Utc::now().timestamp()),
+                ..Default::default()
+            },
+            "the form was submitted right after it was shown",
+        ),
+    ];
+
+    for (body, description) in test_cases {
+        // Act
+        let response = client
+            .post(format!("{}/subscriptions", &app.address))
+            .json(&body)
+            .send()
+            .await
+            .expect("Failed to execute request.");
+
+        // Assert
+        assert_eq!(
+            200,
+            response.status().as_u16(),
+            "The API did not return a 200 OK when {}.",
+            description
+        );
+    }
+    let saved = sqlx::query!("SELECT email FROM subscriptions")
+        .fetch_all(&app.db_pool)
+        .await
+        .expect("Failed to fetch saved subscriptions.");
+    assert!(saved.is_empty());
+}
+
+#[tokio::test]
+async fn subscribe_accepts_forms_filled_at_a_human_pace() {
+    // Arrange
+    let app = spawn_app().await;
+
+    // Act
+    let response = reqwest::Client::new()
+        .post(format!("{}/subscriptions", &app.address))
+        .json(&FormData {
+            name: "le guin".to_string(),
+            email: "ursula_le_guin@gmail.com".to_string(),
+            rendered_at: Some(chrono::Utc::now().timestamp() - 30),
+            ..Default::default()
+        })
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    // Assert
+    assert_eq!(200, response.status().as_u16());
+}
+
+#[tokio::test]
+async fn subscribe_checks_the_challenge_when_enabled() {
+    // Arrange
+    let mock_server = MockServer::start().await;
+    Mock::given(path("/siteverify"))
+        .and(body_string_contains("response=solved"))
+        .respond_with(
+            ResponseTemplate::new(200).set_body_json(serde_json::json!({ "success": true })),
+        )
+        .mount(&mock_server)
+        .await;
+    Mock::given(path("/siteverify"))
+        .and(body_string_contains("response=unsolved"))
+        .respond_with(
+            ResponseTemplate::new(200).set_body_json(serde_json::json!({ "success": false })),
+        )
+        .mount(&mock_server)
+        .await;
+    let verify_url = format!("{}/siteverify", mock_server.uri());
+    let app = spawn_app_with(|c| {
+        c.challenge.enabled = true;
+        c.challenge.verify_url = verify_url;
+    })
+    .await;
+    let client = reqwest::Client::new();
+    let test_cases = vec![
+        (None, 400, "no challenge response was sent"),
+        (Some("unsolved"), 400, "the challenge was not solved"),
+        (Some("solved"), 200, "the challenge was solved"),
+    ];
+
+    for (challenge_response, status, description) in test_cases {
+        // Act
+        let response = client
+            .post(format!("{}/subscriptions", &app.address))
+            .json(&FormData {
+                name: "le guin".to_string(),
+                email: "ursula_le_guin@gmail.com".to_string(),
+                challenge_response: challenge_response.map(str::to_string),
+                ..Default::default()
+            })
+            .send()
+            .await
+            .expect("Failed to execute request.");
+
+        // Assert
+        assert_eq!(
+            status,
+            response.status().as_u16(),
+            "The API did not return a {} when {}.",
+            status,
+            description
+        );
+    }
+}
+
+// Shared with the unit tests of `SubscriberEmail`
+const VALID_EMAILS: &str = include_str!("fixtures/emails/valid.txt");
+const INVALID_EMAILS: &str = include_str!("fixtures/emails/invalid.txt");
+
+fn email_corpus(contents: &str) -> Vec<&str> {
+    contents
+        .lines()
+        .filter(|line| !line.is_empty() && !line.starts_with("# "))
+        .collect()
+}
+
+#[tokio::test]
+async fn subscribe_accepts_the_valid_email_corpus() {
+    // Arrange
+    let app = spawn_app().await;
+    let client = reqwest::Client::new();
+
+    for email in email_corpus(VALID_EMAILS) {
+        // Act
+        let response = client
+            .post(format!("{}/subscriptions", &app.address))
+            .json(&FormData {
+                name: "Ursula".to_string(),
+                email: email.to_string(),
+                attributes: None,
+                ..Default::default()
+            })
+            .send()
+            .await
+            .expect("Failed to execute request.");
+
+        // Assert
+        assert_eq!(
+            200,
+            response.status().as_u16(),
+            "The API rejected {}.",
+            email
+        );
+    }
+}
+
+#[tokio::test]
+async fn subscribe_rejects_the_invalid_email_corpus() {
+    // Arrange
+    let app = spawn_app().await;
+    let client = reqwest::Client::new();
+
+    for email in email_corpus(INVALID_EMAILS) {
+        // Act
+        let response = client
+            .post(format!("{}/subscriptions", &app.address))
+            .json(&FormData {
+                name: "Ursula".to_string(),
+                email: email.to_string(),
+                attributes: None,
+                ..Default::default()
+            })
+            .send()
+            .await
+            .expect("Failed to execute request.");
+
+        // Assert
+        assert_eq!(
+            400,
+            response.status().as_u16(),
+            "The API accepted {}.",
+            email
+        );
+    }
+}
+
+#[tokio::test]
+async fn subscribe_rejects_domains_that_do_not_accept_emails() {
+    // Arrange
+    let app = spawn_app().await;
+    let client = reqwest::Client::new();
+
+    for (path, email, message) in [
+        (
+            "subscriptions",
+            "Ursula@gmial.com",
+            "Invalid data. gmial.com does not accept emails. Did you mean Ursula@gmail.com?",
+        ),
+        (
+            "lists/default/subscriptions",
+            "ursula@nowhere.invalid",
+            "Invalid data. nowhere.invalid does not accept emails.",
+        ),
+    ] {
+        // Act
+        let response = client
+            .post(format!("{}/{}", &app.address, path))
+            .json(&FormData {
+                name: "Ursula".to_string(),
+                email: email.to_string(),
+                attributes: None,
+                ..Default::default()
+            })
+            .send()
+            .await
+            .expect("Failed to execute request.");
+
+        // Assert
+        assert_eq!(400, response.status().as_u16());
+        assert_eq!(message, response.text().await.unwrap());
+    }
+    let subscribers = sqlx::query!("SELECT id FROM subscriptions")
+        .fetch_all(&app.db_pool)
+        .await
+        .expect("Failed to fetch saved subscriptions.");
+    assert!(subscribers.is_empty());
+}
+
+#[tokio::test]
+async fn subscribe_rejects_denied_domains_with_an_explanation() {
+    // Arrange
+    let app = spawn_app().await;
+    let client = reqwest::Client::new();
+
+    for path in ["subscriptions", "lists/default/subscriptions"] {
+        // Act
+        let response = client
+            .post(format!("{}/{}", &app.address, path))
+            .json(&FormData {
+                name: "Ursula".to_string(),
+                email: "ursula@inbox.mailinator.com".to_string(),
+                attributes: None,
+                ..Default::default()
+            })
+            .send()
+            .await
+            .expect("Failed to execute request.");
+
+        // Assert
+        assert_eq!(400, response.status().as_u16());
+        assert_eq!(
+            "Invalid data. Subscriptions from inbox.mailinator.com are not accepted, \
+            please use another email address.",
+            response.text().await.unwrap()
+        );
+    }
+}
+
+fn ursula() -> FormData {
+    FormData {
+        name: "Ursula".to_string(),
+        email: "ursula_le_guin@gmail.com".to_string(),
+        ..Default::default()
+    }
+}
+
+async fn post_subscriptions(app: &TestApp, body: &FormData) -> reqwest::Response {
+    reqwest::Client::new()
+        .post(format!("{}/subscriptions", &app.address))
+        .json(body)
+        .send()
+        .await
+        .expect("Failed to execute request.")
+}
+
+async fn follow(link: &str) -> reqwest::Response {
+    reqwest::get(link)
+        .await
+        .expect("Failed to execute request.")
+}
+
+#[tokio::test]
+async fn new_subscribers_are_pending_until_they_follow_the_confirmation_link() {
+    // Arrange
+    let app = spawn_app().await;
+
+    // Act
+    let response = post_subscriptions(&app, &ursula()).await;
+
+    // Assert
+    assert_eq!(200, response.status().as_u16());
+    assert_eq!(
+        "pending_confirmation",
+        subscriber_status(&app, "ursula_le_guin@gmail.com").await
+    );
+    let links = app.confirmation_links().await;
+    assert_eq!(1, links.len());
+
+    assert_eq!(200, follow(&links[0]).await.status().as_u16());
+    assert_eq!(
+        "active",
+        subscriber_status(&app, "ursula_le_guin@gmail.com").await
+    );
+    // Links are single use
+    assert_eq!(401, follow(&links[0]).await.status().as_u16());
+}
+
+#[tokio::test]
+async fn confirm_rejects_unknown_tokens() {
+    // Arrange
+    let app = spawn_app().await;
+
+    // Act
+    let response = follow(&format!(
+        "{}/subscriptions/confirm?subscription_token=unknown",
+        &app.address
+    ))
+    .await;
+
+    // Assert
+    assert_eq!(401, response.status().as_u16());
+}
+
+#[tokio::test]
+async fn subscribing_again_once_confirmed_is_a_no_op() {
+    // Arrange
+    let app = spawn_app_with(|c| c.subscription_confirmation.resend_cooldown_seconds = 0).await;
+    let first = post_subscriptions(&app, &ursula()).await;
+    let first_status = first.status().as_u16();
+    let first_body = first.text().await.unwrap();
+    follow(&app.confirmation_links().await[0]).await;
+
+    // Act
+    let second = post_subscriptions(&app, &ursula()).await;
+
+    // Assert
+    // Nothing tells the two requests apart
+    assert_eq!(first_status, second.status().as_u16());
+    assert_eq!(first_body, second.text().await.unwrap());
+    assert_eq!(1, app.confirmation_links().await.len());
+    assert_eq!(
+        "active",
+        subscriber_status(&app, "ursula_le_guin@gmail.com").await
+    );
+}
+
+#[tokio::test]
+async fn pending_subscribers_get_a_new_link_after_the_cooldown() {
+    // Arrange
+    let app = spawn_app_with(|c| c.subscription_confirmation.resend_cooldown_seconds = 0).await;
+    post_subscriptions(&app, &ursula()).await;
+
+    // Act
+    let response = post_subscriptions(&app, &ursula()).await;
+
+    // Assert
+    assert_eq!(200, response.status().as_u16());
+    let links = app.confirmation_links().await;
+    assert_eq!(2, links.len());
+    assert_eq!(200, follow(&links[1]).await.status().as_u16());
+    assert_eq!(
+        "active",
+        subscriber_status(&app, "ursula_le_guin@gmail.com").await
+    );
+}
+
+#[tokio::test]
+async fn pending_subscribers_do_not_get_a_new_link_during_the_cooldown() {
+    // Arrange
+    let app = spawn_app().await;
+    post_subscriptions(&app, &ursula()).await;
+
+    // Act
+    let response = post_subscriptions(&app, &ursula()).await;
+
+    // Assert
+    assert_eq!(200, response.status().as_u16());
+    assert_eq!(1, app.confirmation_links().await.len());
+}
+
+#[tokio::test]
+async fn unsubscribed_subscribers_come_back_once_they_confirm() {
+    // Arrange
+    // The link they confirmed with would hold back a new one otherwise
+    let app = spawn_app_with(|c| c.subscription_confirmation.resend_cooldown_seconds = 0).await;
+    let link = preferences_link(&app).await;
+    follow(&app.confirmation_links().await[0]).await;
+    post_preferences(
+        &link,
+        &PreferencesForm {
+            lists: Some(Vec::new()),
+            ..Default::default()
+        },
+    )
+    .await;
+    assert_eq!(
+        "suppressed",
+        subscriber_status(&app, "ursula_le_guin@gmail.com").await
+    );
+
+    // Act
+    let response = post_subscriptions(&app, &ursula()).await;
+
+    // Assert
+    assert_eq!(200, response.status().as_u16());
+    // Still suppressed until they confirm
+    assert_eq!(
+        "suppressed",
+        subscriber_status(&app, "ursula_le_guin@gmail.com").await
+    );
+    let links = app.confirmation_links().await;
+    assert_eq!(2, links.len());
+
+    assert_eq!(200, follow(&links[1]).await.status().as_u16());
+    assert_eq!(
+        "active",
+        subscriber_status(&app, "ursula_le_guin@gmail.com").await
+    );
+    let suppression = sqlx::query!("SELECT email FROM suppressions")
+        .fetch_optional(&app.db_pool)
+        .await
+        .expect("Failed to fetch suppressions.");
+    assert!(suppression.is_none());
+}
+
+#[tokio::test]
+async fn bounced_subscribers_are_not_sent_a_confirmation_link() {
+    // Arrange
+    let app = spawn_app().await;
+    insert_subscriber(&app, "ursula_le_guin@gmail.com").await;
+    sqlx::query!(
+        r#"
+        INSERT INTO suppressions (email, reason, source, created_at)
+        VALUES ('ursula_le_guin@gmail.com', 'hard_bounce', 'postmark', now())
+        "#
+    )
+    .execute(&app.db_pool)
+    .await
+    .expect("Failed to insert suppression.");
+    sqlx::query!("UPDATE subscriptions SET status = 'suppressed'")
+        .execute(&app.db_pool)
+        .await
+        .expect("Failed to update subscriber status.");
+
+    // Act
+    let response = post_subscriptions(&app, &ursula()).await;
+
+    // Assert
+    assert_eq!(200, response.status().as_u16());
+    assert!(app.confirmation_links().await.is_empty());
+    assert_eq!(
+        "suppressed",
+        subscriber_status(&app, "ursula_le_guin@gmail.com").await
+    );
+}
+
+/// Subscribe Ursula and return a link to her preferences.
+async fn preferences_link(app: &TestApp) -> String {
+    post_subscriptions(app, &ursula()).await;
+    let response = reqwest::Client::new()
+        .post(format!("{}/preferences/login", &app.address))
+        .json(&PreferencesLoginData {
+            email: "Ursula_Le_Guin@gmail.com".to_string(),
+        })
+        .send()
+        .await
+        .expect("Failed to execute request.");
+    assert_eq!(200, response.status().as_u16());
+    app.sent_links()
+        .await
+        .into_iter()
+        .map(|(_, link)| link)
+        .find(|link| link.contains("/preferences?"))
+        .expect("No preferences link was sent.")
+}
+
+async fn post_preferences(link: &str, form: &PreferencesForm) -> reqwest::Response {
+    reqwest::Client::new()
+        .post(link)
+        .json(form)
+        .send()
+        .await
+        .expect("Failed to execute request.")
+}
+
+#[tokio::test]
+async fn preferences_links_open_the_preferences() {
+    // Arrange
+    let app = spawn_app().await;
+    let link = preferences_link(&app).await;
+    follow(&app.confirmation_links().await[0]).await;
+
+    // Act
+    let response = follow(&link).await;
+
+    // Assert
+    assert_eq!(200, response.status().as_u16());
+    let preferences: Preferences = response.json().await.unwrap();
+    assert_eq!("Ursula", preferences.name);
+    assert_eq!("ursula_le_guin@gmail.com", preferences.email);
+    assert_eq!("immediate", preferences.digest_frequency);
+    assert_eq!(1, preferences.lists.len());
+    assert_eq!("default", preferences.lists[0].slug);
+    assert!(preferences.lists[0].subscribed);
+}
+
+#[tokio::test]
+async fn preferences_links_are_not_sent_to_unknown_addresses() {
+    // Arrange
+    let app = spawn_app().await;
+
+    // Act
+    let response = reqwest::Client::new()
+        .post(format!("{}/preferences/login", &app.address))
+        .json(&PreferencesLoginData {
+            email: "ursula_le_guin@gmail.com".to_string(),
+        })
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    // Assert
+    assert_eq!(200, response.status().as_u16());
+    assert!(app.sent_links().await.is_empty());
+}
+
+#[tokio::test]
+async fn leaving_every_list_stops_emails() {
+    // Arrange
+    let app = spawn_app().await;
+    let link = preferences_link(&app).await;
+
+    // Act
+    let response = post_preferences(
+        &link,
+        &PreferencesForm {
+            lists: Some(Vec::new()),
+            ..Default::default()
+        },
+    )
+    .await;
+
+    // Assert
+    assert_eq!(200, response.status().as_u16());
+    assert_eq!(
+        "suppressed",
+        subscriber_status(&app, "ursula_le_guin@gmail.com").await
+    );
+    let sent = app
+        .email_client
+        .send_email(
+            SubscriberEmail::parse("Ursula_Le_Guin@gmail.com".to_string()).unwrap(),
+            "Newsletter",
+            "<p>Newsletter</p>",
+            "Newsletter",
+        )
+        .await;
+    assert!(matches!(
+        sent,
+        Err(SendEmailError::Suppressed(SuppressionReason::Unsubscribed))
+    ));
+}
+
+#[tokio::test]
+async fn choosing_lists_again_after_leaving_them_all_resubscribes() {
+    // Arrange
+    let app = spawn_app().await;
+    let link = preferences_link(&app).await;
+    follow(&app.confirmation_links().await[0]).await;
+    post_preferences(
+        &link,
+        &PreferencesForm {
+            lists: Some(Vec::new()),
+            ..Default::default()
+        },
+    )
+    .await;
+
+    // Act
+    let response = post_preferences(
+        &link,
+        &PreferencesForm {
+            lists: Some(vec!["default".to_string()]),
+            ..Default::default()
+        },
+    )
+    .await;
+
+    // Assert
+    assert_eq!(200, response.status().as_u16());
+    let preferences: Preferences = response.json().await.unwrap();
+    assert!(preferences.lists[0].subscribed);
+    assert_eq!(
+        "active",
+        subscriber_status(&app, "ursula_le_guin@gmail.com").await
+    );
+    assert_eq!(
+        vec![("default".into(), "active".into())],
+        list_statuses(&app, "ursula_le_guin@gmail.com").await
+    );
+    let sent = app
+        .email_client
+        .send_email(
+            SubscriberEmail::parse("Ursula_Le_Guin@gmail.com".to_string()).unwrap(),
+            "Newsletter",
+            "<p>Newsletter</p>",
+            "Newsletter",
+        )
+        .await;
+    assert!(sent.is_ok());
+}
+
+#[tokio::test]
+async fn preferences_reject_invalid_tokens() {
+    // Arrange
+    let app = spawn_app().await;
+    let link = preferences_link(&app).await;
+    let tampered = format!("{}0", link);
+
+    // Act
+    let get_response = follow(&tampered).await;
+    let post_response = post_preferences(&tampered, &PreferencesForm::default()).await;
+
+    // Assert
+    assert_eq!(401, get_response.status().as_u16());
+    assert_eq!(401, post_response.status().as_u16());
+}
+
+#[tokio::test]
+async fn subscribers_can_update_their_preferences() {
+    // Arrange
+    let app = spawn_app().await;
+    assert_eq!(201, create_list(&app, "weekly").await.status().as_u16());
+    let link = preferences_link(&app).await;
+
+    // Act
+    let response = post_preferences(
+        &link,
+        &PreferencesForm {
+            name: Some("  Ursula   K. Le Guin ".to_string()),
+            digest_frequency: Some("weekly".to_string()),
+            lists: Some(vec!["weekly".to_string()]),
+            ..Default::default()
+        },
+    )
+    .await;
+
+    // Assert
+    assert_eq!(200, response.status().as_u16());
+    let preferences: Preferences = response.json().await.unwrap();
+    assert_eq!("Ursula K. Le Guin", preferences.name);
+    assert_eq!("weekly", preferences.digest_frequency);
+    assert_eq!(
+        vec!["weekly"],
+        list_slugs(&app, "ursula_le_guin@gmail.com").await
+    );
+}
+
+#[tokio::test]
+async fn invalid_preferences_are_rejected() {
+    // Arrange
+    let app = spawn_app().await;
+    let link = preferences_link(&app).await;
+    let test_cases = vec![
+        (
+            PreferencesForm {
+                name: Some(" ".to_string()),
+                ..Default::default()
+            },
+            "an empty name",
+        ),
+        (
+            PreferencesForm {
+                digest_frequency: Some("hourly".to_string()),
+                ..Default::default()
+            },
+            "an unknown digest frequency",
+        ),
+        (
+            PreferencesForm {
+                lists: Some(vec!["unknown".to_string()]),
+                ..Default::default()
+            },
+            "an unknown list",
+        ),
+        (
+            PreferencesForm {
+                email: Some("ursula@inbox.mailinator.com".to_string()),
+                ..Default::default()
+            },
+            "a denied email domain",
+        ),
+    ];
+
+    for (form, description) in test_cases {
+        // Act
+        let response = post_preferences(&link, &form).await;
+
+        // Assert
+        assert_eq!(
+            400,
+            response.status().as_u16(),
+            "The API did not fail with 400 Bad Request when the form had {}.",
+            description
+        );
+    }
+    let preferences: Preferences = follow(&link).await.json().await.unwrap();
+    assert_eq!("Ursula", preferences.name);
+    assert_eq!(
+        vec!["default"],
+        list_slugs(&app, "ursula_le_guin@gmail.com").await
+    );
+}
+
+#[tokio::test]
+async fn email_changes_take_effect_once_the_new_address_is_confirmed() {
+    // Arrange
+    let app = spawn_app().await;
+    let link = preferences_link(&app).await;
+
+    // Act
+    let response = post_preferences(
+        &link,
+        &PreferencesForm {
+            email: Some("ursula@example.com".to_string()),
+            ..Default::default()
+        },
+    )
+    .await;
+
+    // Assert
+    assert_eq!(200, response.status().as_u16());
+    let preferences: Preferences = response.json().await.unwrap();
+    assert_eq!("ursula_le_guin@gmail.com", preferences.email);
+    assert_eq!(
+        Some("ursula@example.com"),
+        preferences.pending_email.as_deref()
+    );
+    let (recipient, confirmation_link) = app.sent_links().await.pop().unwrap();
+    assert_eq!("ursula@example.com", recipient);
+
+    assert_eq!(200, follow(&confirmation_link).await.status().as_u16());
+    let preferences: Preferences = follow(&link).await.json().await.unwrap();
+    assert_eq!("ursula@example.com", preferences.email);
+    assert_eq!(None, preferences.pending_email);
+    // The new address is confirmed by following the link
+    assert_eq!(
+        "active",
+        subscriber_status(&app, "ursula@example.com").await
+    );
+    assert_eq!(
+        vec![("default".into(), "active".into())],
+        list_statuses(&app, "ursula@example.com").await
+    );
+    assert!(preferences.lists[0].subscribed);
+    assert_eq!(401, follow(&confirmation_link).await.status().as_u16());
+}
+
+#[tokio::test]
+async fn email_changes_to_an_address_already_subscribed_are_refused() {
+    // Arrange
+    let app = spawn_app().await;
+    let link = preferences_link(&app).await;
+    post_preferences(
+        &link,
+        &PreferencesForm {
+            email: Some("george@example.com".to_string()),
+            ..Default::default()
+        },
+    )
+    .await;
+    let (_, confirmation_link) = app.sent_links().await.pop().unwrap();
+    insert_subscriber(&app, "george@example.com").await;
+
+    // Act
+    let response = follow(&confirmation_link).await;
+
+    // Assert
+    assert_eq!(409, response.status().as_u16());
+    let preferences: Preferences = follow(&link).await.json().await.unwrap();
+    assert_eq!("ursula_le_guin@gmail.com", preferences.email);
+}
+
+/// `link` to the preferences, pointing to `path` instead.
+fn preferences_link_to(link: &str, path: &str) -> String {
+    link.replacen("/preferences?", &format!("/preferences/{}?", path), 1)
+}
+
+async fn insert_email_event(app: &TestApp, email: &str) {
+    sqlx::query!(
+        r#"
+        INSERT INTO email_events (id, provider, event_type, email, occurred_at, received_at)
+        VALUES ($1, 'postmark', 'delivery', $2, now(), now())
+        "#,
+        Uuid::new_v4(),
+        email
+    )
+    .execute(&app.db_pool)
+    .await
+    .expect("Failed to insert email event.");
+}
+
+#[tokio::test]
+async fn subscribers_can_export_their_data() {
+    // Arrange
+    let app = spawn_app().await;
+    let link = preferences_link(&app).await;
+    insert_email_event(&app, "ursula_le_guin@gmail.com").await;
+
+    // Act
+    let response = follow(&preferences_link_to(&link, "export")).await;
+
+    // Assert
+    assert_eq!(200, response.status().as_u16());
+    assert!(response.headers()["Content-Disposition"]
+        .to_str()
+        .unwrap()
+        .starts_with("attachment"));
+    let data: SubscriberData = response.json().await.unwrap();
+    let subscription = data.subscription.expect("No subscription in the export.");
+    assert_eq!("ursula_le_guin@gmail.com", subscription.email);
+    assert_eq!("Ursula", subscription.name);
+    assert_eq!(1, data.list_memberships.len());
+    assert_eq!("default", data.list_memberships[0].slug);
+    assert_eq!(1, data.confirmation_tokens.len());
+    assert_eq!(1, data.email_events.len());
+    assert!(data.suppression.is_none());
+    assert_eq!(1, data.consent_events.len());
+}
+
+#[tokio::test]
+async fn subscribers_can_erase_their_data() {
+    // Arrange
+    let app = spawn_app().await;
+    let link = preferences_link(&app).await;
+    insert_email_event(&app, "ursula_le_guin@gmail.com").await;
+
+    // Act
+    let response = reqwest::Client::new()
+        .post(preferences_link_to(&link, "erase"))
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    // Assert
+    assert_eq!(200, response.status().as_u16());
+    for table in [
+        "subscriptions",
+        "list_memberships",
+        "subscription_tokens",
+        "email_events",
+        "consent_events",
+    ] {
+        let count: i64 = sqlx::query_scalar(&format!("SELECT COUNT(*) FROM {}", table))
+            .fetch_one(&app.db_pool)
+            .await
+            .expect("Failed to count rows.");
+        assert_eq!(0, count, "{} was not emptied", table);
+    }
+    // Only a tombstone keeps the address suppressed
+    let suppression = sqlx::query!("SELECT email, reason, source FROM suppressions")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch saved suppression.");
+    assert_eq!(
+        app.tombstone_key.tombstone("ursula_le_guin@gmail.com"),
+        suppression.email
+    );
+    assert!(!suppression.email.contains("ursula"));
+    assert_eq!("unsubscribed", suppression.reason);
+    assert_eq!("erasure", suppression.source);
+    assert_eq!(401, follow(&link).await.status().as_u16());
+}
+
+#[tokio::test]
+async fn erased_addresses_come_back_once_they_confirm() {
+    // Arrange
+    let app = spawn_app().await;
+    let link = preferences_link(&app).await;
+    reqwest::Client::new()
+        .post(preferences_link_to(&link, "erase"))
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    // Act
+    let response = post_subscriptions(&app, &ursula()).await;
+
+    // Assert
+    assert_eq!(200, response.status().as_u16());
+    let links = app.confirmation_links().await;
+    assert_eq!(2, links.len());
+    assert_eq!(200, follow(&links[1]).await.status().as_u16());
+    assert_eq!(
+        "active",
+        subscriber_status(&app, "ursula_le_guin@gmail.com").await
+    );
+    let suppression = sqlx::query!("SELECT email FROM suppressions")
+        .fetch_optional(&app.db_pool)
+        .await
+        .expect("Failed to fetch suppressions.");
+    assert!(suppression.is_none());
+}
+
+#[tokio::test]
+async fn erasure_keeps_bounced_addresses_suppressed() {
+    // Arrange
+    let app = spawn_app().await;
+    insert_subscriber(&app, "ursula_le_guin@gmail.com").await;
+    sqlx::query!(
+        r#"
+        INSERT INTO suppressions (email, reason, source, created_at)
+        VALUES ('ursula_le_guin@gmail.com', 'hard_bounce', 'postmark', now())
+        "#
+    )
+    .execute(&app.db_pool)
+    .await
+    .expect("Failed to insert suppression.");
+    let client = reqwest::Client::new();
+
+    // Act
+    let response = client
+        .delete(format!(
+            "{}/admin/subscribers/ursula_le_guin@gmail.com",
+            &app.address
+        ))
+        .basic_auth(
+            &app.admin.username,
+            Some(app.admin.password.expose_secret()),
+        )
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    // Assert
+    assert_eq!(204, response.status().as_u16());
+    let response = post_subscriptions(&app, &ursula()).await;
+    assert_eq!(200, response.status().as_u16());
+    assert!(app.confirmation_links().await.is_empty());
+    let response = client
+        .get(format!(
+            "{}/admin/subscribers/ursula_le_guin@gmail.com/export",
+            &app.address
+        ))
+        .basic_auth(
+            &app.admin.username,
+            Some(app.admin.password.expose_secret()),
+        )
+        .send()
+        .await
+        .expect("Failed to execute request.");
+    assert_eq!(200, response.status().as_u16());
+    let data: SubscriberData = response.json().await.unwrap();
+    assert_eq!("hard_bounce", data.suppression.unwrap().reason);
+}
+
+#[tokio::test]
+async fn admin_data_endpoints_reject_invalid_credentials() {
+    // Arrange
+    let app = spawn_app().await;
+    insert_subscriber(&app, "ursula_le_guin@gmail.com").await;
+    let client = reqwest::Client::new();
+
+    // Act
+    let export_response = client
+        .get(format!(
+            "{}/admin/subscribers/ursula_le_guin@gmail.com/export",
+            &app.address
+        ))
+        .basic_auth(&app.admin.username, Some("wrong"))
+        .send()
+        .await
+        .expect("Failed to execute request.");
+    let erase_response = client
+        .delete(format!(
+            "{}/admin/subscribers/ursula_le_guin@gmail.com",
+            &app.address
+        ))
+        .send()
+        .await
+        .expect("Failed to execute request.");
+    let consent_response = client
+        .get(format!(
+            "{}/admin/subscribers/ursula_le_guin@gmail.com/consent",
+            &app.address
+        ))
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    // Assert
+    assert_eq!(401, export_response.status().as_u16());
+    assert_eq!(401, erase_response.status().as_u16());
+    assert_eq!(401, consent_response.status().as_u16());
+    assert_eq!(
+        "active",
+        subscriber_status(&app, "ursula_le_guin@gmail.com").await
+    );
+}
+
+#[tokio::test]
+async fn admin_api_returns_429_past_the_limit_of_password_checks_per_ip() {
+    // Arrange
+    let app = spawn_app_with(|c| c.rate_limit.max_admin_requests_per_ip = 2).await;
+    let client = reqwest::Client::new();
+
+    // Act
+    let mut statuses = Vec::new();
+    for _ in 0..3 {
+        let response = client
+            .get(format!("{}/admin/subscribers", &app.address))
+            .basic_auth("ops", Some("guess"))
+            .send()
+            .await
+            .expect("Failed to execute request.");
+        statuses.push(response.status().as_u16());
+    }
+    let admin_response = client
+        .get(format!("{}/admin/subscribers", &app.address))
+        .basic_auth(
+            &app.admin.username,
+            Some(app.admin.password.expose_secret()),
+        )
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    // Assert
+    assert_eq!(vec![401, 401, 429], statuses);
+    // The admin of the settings is checked without hashing
+    assert_eq!(200, admin_response.status().as_u16());
+}
+
+#[tokio::test]
+async fn admins_created_from_the_command_line_can_use_the_admin_api() {
+    // Arrange
+    let app = spawn_app().await;
+    let password = "correct horse battery staple";
+    create_admin_user("ops", Secret::new(password.to_string()), &app.db_pool)
+        .await
+        .expect("Failed to create the admin.");
+    let client = reqwest::Client::new();
+    let list_subscribers = |password: &str| {
+        client
+            .get(format!("{}/admin/subscribers", &app.address))
+            .basic_auth("ops", Some(password))
+            .send()
+    };
+
+    // Act
+    let response = list_subscribers(password)
+        .await
+        .expect("Failed to execute request.");
+    let wrong_password_response = list_subscribers("wrong horse battery staple")
+        .await
+        .expect("Failed to execute request.");
+    let duplicate = create_admin_user("ops", Secret::new(password.to_string()), &app.db_pool).await;
+
+    // Assert
+    assert_eq!(200, response.status().as_u16());
+    assert_eq!(401, wrong_password_response.status().as_u16());
+    assert!(matches!(duplicate, Err(CreateAdminError::Exists(_))));
+    let hash = sqlx::query!("SELECT password_hash FROM admin_users WHERE username = 'ops'")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch the admin.")
+        .password_hash;
+    assert!(hash.starts_with("$argon2id$"));
+}
+
+#[tokio::test]
+async fn imported_subscribers_are_active_and_can_be_exported_again() {
+    // Arrange
+    let app = spawn_app().await;
+    insert_subscriber(&app, "ursula_le_guin@gmail.com").await;
+    sqlx::query!(
+        r#"
+        INSERT INTO suppressions (email, reason, source, created_at)
+        VALUES ('bounced@gmail.com', 'hard_bounce', 'postmark', now())
+        "#
+    )
+    .execute(&app.db_pool)
+    .await
+    .expect("Failed to insert suppression.");
+    let input = r#"{"email": "octavia.butler@gmail.com", "name": "Octavia", "attributes": {"plan": "pro"}}
+{"email": "ursula_le_guin@gmail.com", "name": "Ursula"}
+{"email": "bounced@gmail.com", "name": "Bounced"}
+"#;
+
+    // Act
+    let import = parse_subscribers(input.as_bytes(), 256).expect("Failed to parse subscribers.");
+    let report = import_subscribers(&import.subscribers, &app.tombstone_key, &app.db_pool)
+        .await
+        .expect("Failed to import subscribers.");
+    let mut exported = Vec::new();
+    export_subscribers(None, &app.db_pool, &mut exported)
+        .await
+        .expect("Failed to export subscribers.");
+
+    // Assert
+    assert_eq!(
+        (1, 1, 1),
+        (report.imported, report.existing, report.suppressed)
+    );
+    assert_eq!(
+        "active",
+        subscriber_status(&app, "octavia.butler@gmail.com").await
+    );
+    let history = consent_history(&app, "octavia.butler@gmail.com").await;
+    assert_eq!(1, history.len());
+    assert_eq!("subscribed", history[0].event_type);
+    assert_eq!("import", history[0].source);
+    let exported = parse_subscribers(exported.as_slice(), 256).expect("Failed to parse export.");
+    let emails: Vec<_> = exported
+        .subscribers
+        .iter()
+        .map(|s| s.email.as_ref())
+        .collect();
+    assert_eq!(
+        vec!["ursula_le_guin@gmail.com", "octavia.butler@gmail.com"],
+        emails
+    );
+}
+
+async fn consent_history(app: &TestApp, email: &str) -> Vec<ConsentEventRecord> {
+    let response = reqwest::Client::new()
+        .get(format!(
+            "{}/admin/subscribers/{}/consent",
+            &app.address, email
+        ))
+        .basic_auth(
+            &app.admin.username,
+            Some(app.admin.password.expose_secret()),
+        )
+        .send()
+        .await
+        .expect("Failed to execute request.");
+    assert_eq!(200, response.status().as_u16());
+    response.json().await.unwrap()
+}
+
+#[tokio::test]
+async fn subscribing_and_confirming_are_recorded_in_the_consent_history() {
+    // Arrange
+    let app = spawn_app().await;
+    let body = FormData {
+        form: Some("footer".to_string()),
+        consent_text_version: Some("v3".to_string()),
+        ..ursula()
+    };
+
+    // Act
+    let response = reqwest::Client::new()
+        .post(format!("{}/subscriptions", &app.address))
+        .header("User-Agent", "Mozilla/5.0")
+        .json(&body)
+        .send()
+        .await
+        .expect("Failed to execute request.");
+    assert_eq!(200, response.status().as_u16());
+    follow(&app.confirmation_links().await[0]).await;
+
+    // Assert
+    let events = consent_history(&app, "Ursula_Le_Guin@gmail.com").await;
+    assert_eq!(2, events.len());
+    assert_eq!("subscribed", events[0].event_type);
+    assert_eq!("ursula_le_guin@gmail.com", events[0].email);
+    assert_eq!(Some("127.0.0.1"), events[0].ip.as_deref());
+    assert_eq!(Some("Mozilla/5.0"), events[0].user_agent.as_deref());
+    assert_eq!("footer", events[0].source);
+    assert_eq!(Some("v3"), events[0].text_version.as_deref());
+    assert_eq!("default", events[0].details["list"]);
+    assert_eq!("confirmed", events[1].event_type);
+    assert_eq!("confirmation_email", events[1].source);
+    assert!(events[1].text_version.is_some());
+}
+
+#[tokio::test]
+async fn preference_changes_are_recorded_in_the_consent_history() {
+    // Arrange
+    let app = spawn_app().await;
+    let link = preferences_link(&app).await;
+
+    // Act
+    post_preferences(
+        &link,
+        &PreferencesForm {
+            digest_frequency: Some("weekly".to_string()),
+            consent_text_version: Some("v3".to_string()),
+            ..Default::default()
+        },
+    )
+    .await;
+    post_preferences(
+        &link,
+        &PreferencesForm {
+            lists: Some(Vec::new()),
+            ..Default::default()
+        },
+    )
+    .await;
+    // Nothing changes, nothing to record
+    post_preferences(&link, &PreferencesForm::default()).await;
+
+    // Assert
+    let events = consent_history(&app, "ursula_le_guin@gmail.com").await;
+    let event_types: Vec<_> = events.iter().map(|e| e.event_type.as_str()).collect();
+    assert_eq!(
+        vec!["subscribed", "preferences_changed", "unsubscribed"],
+        event_types
+    );
+    assert_eq!("preferences", events[1].source);
+    assert_eq!("weekly", events[1].details["digest_frequency"]);
+    assert_eq!(Some("v3"), events[1].text_version.as_deref());
+    assert_eq!(serde_json::json!([]), events[2].details["lists"]);
+}
+
+#[tokio::test]
+async fn the_consent_history_follows_email_changes() {
+    // Arrange
+    let app = spawn_app().await;
+    let link = preferences_link(&app).await;
+    post_preferences(
+        &link,
+        &PreferencesForm {
+            email: Some("ursula@example.com".to_string()),
+            ..Default::default()
+        },
+    )
+    .await;
+    let (_, confirmation_link) = app.sent_links().await.pop().unwrap();
+
+    // Act
+    follow(&confirmation_link).await;
+
+    // Assert
+    let events = consent_history(&app, "ursula@example.com").await;
+    let event_types: Vec<_> = events.iter().map(|e| e.event_type.as_str()).collect();
+    assert_eq!(
+        vec!["subscribed", "preferences_changed", "confirmed"],
+        event_types
+    );
+    assert_eq!("ursula_le_guin@gmail.com", events[0].email);
+    assert_eq!("ursula@example.com", events[2].email);
+}
+
+#[tokio::test]
+async fn consent_events_cannot_be_changed() {
+    // Arrange
+    let app = spawn_app().await;
+    post_subscriptions(&app, &ursula()).await;
+
+    // Act
+    let update = sqlx::query("UPDATE consent_events SET source = 'forged'")
+        .execute(&app.db_pool)
+        .await;
+    let delete = sqlx::query("DELETE FROM consent_events")
+        .execute(&app.db_pool)
+        .await;
+
+    // Assert
+    assert!(update.is_err());
+    assert!(delete.is_err());
+    assert_eq!(
+        1,
+        consent_history(&app, "ursula_le_guin@gmail.com")
+            .await
+            .len()
+    );
+}
+
+async fn insert_subscriber(app: &TestApp, email: &str) {
+    sqlx::query!(
+        "INSERT INTO subscriptions (id, email, email_canonical, name, subscribed_at) VALUES ($1, $2, $2, $3, now())",
+        Uuid::new_v4(),
+        email,
+        "Ursula"
+    )
+    .execute(&app.db_pool)
+    .await
+    .expect("Failed to insert subscriber.");
+}
+
+async fn subscriber_status(app: &TestApp, email: &str) -> String {
+    sqlx::query!("SELECT status FROM subscriptions WHERE email = $1", email)
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch subscriber status.")
+        .status
+}
+
+#[tokio::test]
+async fn postmark_hard_bounce_suppresses_the_subscriber() {
+    // Arrange
+    let app = spawn_app().await;
+    let client = reqwest::Client::new();
+    insert_subscriber(&app, "ursula_le_guin@gmail.com").await;
+    let body = serde_json::json!({
+        "RecordType": "Bounce",
+        "Type": "HardBounce",
+        "Email": "ursula_le_guin@gmail.com",
+        "BouncedAt": "2024-03-22T16:33:54.9070259Z"
+    });
+
+    // Act
+    let response = client
+        .post(format!("{}/webhooks/email/postmark", &app.address))
+        .basic_auth(
+            &app.email_webhooks.basic_auth_username,
+            Some(app.email_webhooks.basic_auth_password.expose_secret()),
+        )
+        .json(&body)
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    // Assert
+    assert_eq!(200, response.status().as_u16());
+    assert_eq!(
+        "suppressed",
+        subscriber_status(&app, "ursula_le_guin@gmail.com").await
+    );
+    let saved = sqlx::query!("SELECT provider, event_type, email FROM email_events")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch saved email event.");
+    assert_eq!("postmark", saved.provider);
+    assert_eq!("hard_bounce", saved.event_type);
+    assert_eq!("ursula_le_guin@gmail.com", saved.email);
+    let suppression = sqlx::query!("SELECT email, reason, source FROM suppressions")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch saved suppression.");
+    assert_eq!("ursula_le_guin@gmail.com", suppression.email);
+    assert_eq!("hard_bounce", suppression.reason);
+    assert_eq!("postmark", suppression.source);
+}
+
+#[tokio::test]
+async fn postmark_delivery_does_not_suppress_the_subscriber() {
+    // Arrange
+    let app = spawn_app().await;
+    let client = reqwest::Client::new();
+    insert_subscriber(&app, "ursula_le_guin@gmail.com").await;
+    let body = serde_json::json!({
+        "RecordType": "Delivery",
+        "Recipient": "ursula_le_guin@gmail.com",
+        "DeliveredAt": "2024-03-22T16:33:54Z"
+    });
+
+    // Act
+    let response = client
+        .post(format!("{}/webhooks/email/postmark", &app.address))
+        .basic_auth(
+            &app.email_webhooks.basic_auth_username,
+            Some(app.email_webhooks.basic_auth_password.expose_secret()),
+        )
+        .json(&body)
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    // Assert
+    assert_eq!(200, response.status().as_u16());
+    assert_eq!(
+        "active",
+        subscriber_status(&app, "ursula_le_guin@gmail.com").await
+    );
+}
+
+#[tokio::test]
+async fn postmark_webhook_with_invalid_credentials_is_rejected() {
+    // Arrange
+    let app = spawn_app().await;
+    let client = reqwest::Client::new();
+    insert_subscriber(&app, "ursula_le_guin@gmail.com").await;
+    let body = serde_json::json!({
+        "RecordType": "SpamComplaint",
+        "Email": "ursula_le_guin@gmail.com",
+        "BouncedAt": "2024-03-22T16:33:54Z"
+    });
+
+    // Act
+    let response = client
+        .post(format!("{}/webhooks/email/postmark", &app.address))
+        .basic_auth(&app.email_webhooks.basic_auth_username, Some("wrong"))
+        .json(&body)
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    // Assert
+    assert_eq!(401, response.status().as_u16());
+    assert_eq!(
+        "active",
+        subscriber_status(&app, "ursula_le_guin@gmail.com").await
+    );
+}
+
+/// A Mailgun complaint about ursula_le_guin@gmail.com, signed at `timestamp`.
+fn signed_mailgun_complaint(app: &TestApp, timestamp: i64) -> serde_json::Value {
+    let token = Uuid::new_v4().simple().to_string();
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(app.email_webhooks.signing_key.expose_secret().as_bytes())
+            .unwrap();
+    mac.update(format!("{}{}", timestamp, token).as_bytes());
+    serde_json::json!({
+        "signature": {
+            "timestamp": timestamp.to_string(),
+            "token": token,
+            "signature": hex::encode(mac.finalize().into_bytes()),
+        },
+        "event-data": {
+            "event": "complained",
+            "recipient": "ursula_le_guin@gmail.com",
+            "timestamp": timestamp as f64 + 0.329574
+        }
+    })
+}
+
+#[tokio::test]
+async fn mailgun_complaint_with_valid_signature_suppresses_the_subscriber() {
+    // Arrange
+    let app = spawn_app().await;
+    let client = reqwest::Client::new();
+    insert_subscriber(&app, "ursula_le_guin@gmail.com").await;
+    let body = signed_mailgun_complaint(&app, chrono::Utc::now().timestamp());
+
+    // Act
+    let response = client
+        .post(format!("{}/webhooks/email/mailgun", &app.address))
+        .json(&body)
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    // Assert
+    assert_eq!(200, response.status().as_u16());
+    assert_eq!(
+        "suppressed",
+        subscriber_status(&app, "ursula_le_guin@gmail.com").await
+    );
+}
+
+#[tokio::test]
+async fn mailgun_webhook_signed_long_ago_is_rejected() {
+    // Arrange
+    let app = spawn_app().await;
+    let client = reqwest::Client::new();
+    insert_subscriber(&app, "ursula_le_guin@gmail.com").await;
+    let body = signed_mailgun_complaint(&app, chrono::Utc::now().timestamp() - 3_600);
+
+    // Act
+    let response = client
+        .post(format!("{}/webhooks/email/mailgun", &app.address))
+        .json(&body)
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    // Assert
+    assert_eq!(401, response.status().as_u16());
+    assert_eq!(
+        "active",
+        subscriber_status(&app, "ursula_le_guin@gmail.com").await
+    );
+}
+
+#[tokio::test]
+async fn replayed_mailgun_webhooks_do_not_suppress_again() {
+    // Arrange
+    let app = spawn_app().await;
+    let client = reqwest::Client::new();
+    insert_subscriber(&app, "ursula_le_guin@gmail.com").await;
+    let body = signed_mailgun_complaint(&app, chrono::Utc::now().timestamp());
+    let send_complaint = || {
+        client
+            .post(format!("{}/webhooks/email/mailgun", &app.address))
+            .json(&body)
+            .send()
+    };
+    send_complaint().await.expect("Failed to execute request.");
+    // The admin lifts the suppression
+    client
+        .delete(format!(
+            "{}/admin/suppressions/ursula_le_guin@gmail.com",
+            &app.address
+        ))
+        .basic_auth(
+            &app.admin.username,
+            Some(app.admin.password.expose_secret()),
+        )
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    // Act
+    let response = send_complaint().await.expect("Failed to execute request.");
+
+    // Assert
+    assert_eq!(200, response.status().as_u16());
+    assert_eq!(
+        "active",
+        subscriber_status(&app, "ursula_le_guin@gmail.com").await
+    );
+}
+
+#[tokio::test]
+async fn mailgun_webhook_with_invalid_signature_is_rejected() {
+    // Arrange
+    let app = spawn_app().await;
+    let client = reqwest::Client::new();
+    let body = serde_json::json!({
+        "signature": {
+            "timestamp": chrono::Utc::now().timestamp().to_string(),
+            "token": "a8ce0edb2dd8301dee6c2405235584e45aa91d1e9f979f3de0",
+            "signature": hex::encode([0u8; 32]),
+        },
+        "event-data": {
+            "event": "failed",
+            "severity": "permanent",
+            "recipient": "ursula_le_guin@gmail.com",
+            "timestamp": 1711125234.329574
+        }
+    });
+
+    // Act
+    let response = client
+        .post(format!("{}/webhooks/email/mailgun", &app.address))
+        .json(&body)
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    // Assert
+    assert_eq!(401, response.status().as_u16());
+}
+
+#[tokio::test]
+async fn webhook_for_an_unknown_provider_returns_a_404() {
+    // Arrange
+    let app = spawn_app().await;
+    let client = reqwest::Client::new();
+
+    // Act
+    let response = client
+        .post(format!("{}/webhooks/email/carrier-pigeon", &app.address))
+        .json(&serde_json::json!({}))
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    // Assert
+    assert_eq!(404, response.status().as_u16());
+}
+
+#[tokio::test]
+async fn admin_can_suppress_and_unsuppress_an_email() {
+    // Arrange
+    let app = spawn_app().await;
+    let client = reqwest::Client::new();
+    insert_subscriber(&app, "ursula_le_guin@gmail.com").await;
+
+    // Act - Part 1 - Suppress
+    let response = client
+        .post(format!("{}/admin/suppressions", &app.address))
+        .basic_auth(
+            &app.admin.username,
+            Some(app.admin.password.expose_secret()),
+        )
+        .json(&serde_json::json!({ "email": "ursula_le_guin@gmail.com" }))
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    // Assert - Part 1
+    assert_eq!(200, response.status().as_u16());
+    assert_eq!(
+        "suppressed",
+        subscriber_status(&app, "ursula_le_guin@gmail.com").await
+    );
+    let suppression = sqlx::query!("SELECT reason, source FROM suppressions")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch saved suppression.");
+    assert_eq!("manual", suppression.reason);
+    assert_eq!("admin", suppression.source);
+
+    // Act - Part 2 - Unsuppress
+    let response = client
+        .delete(format!(
+            "{}/admin/suppressions/ursula_le_guin@gmail.com",
+            &app.address
+        ))
+        .basic_auth(
+            &app.admin.username,
+            Some(app.admin.password.expose_secret()),
+        )
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    // Assert - Part 2
+    assert_eq!(204, response.status().as_u16());
+    assert_eq!(
+        "active",
+        subscriber_status(&app, "ursula_le_guin@gmail.com").await
+    );
+}
+
+#[tokio::test]
+async fn removing_an_unknown_suppression_returns_a_404() {
+    // Arrange
+    let app = spawn_app().await;
+    let client = reqwest::Client::new();
+
+    // Act
+    let response = client
+        .delete(format!(
+            "{}/admin/suppressions/ursula_le_guin@gmail.com",
+            &app.address
+        ))
+        .basic_auth(
+            &app.admin.username,
+            Some(app.admin.password.expose_secret()),
+        )
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    // Assert
+    assert_eq!(404, response.status().as_u16());
+}
+
+#[tokio::test]
+async fn admin_suppression_endpoints_reject_invalid_credentials() {
+    // Arrange
+    let app = spawn_app().await;
+    let client = reqwest::Client::new();
+
+    // Act
+    let add_response = client
+        .post(format!("{}/admin/suppressions", &app.address))
+        .basic_auth(&app.admin.username, Some("wrong"))
+        .json(&serde_json::json!({ "email": "ursula_le_guin@gmail.com" }))
+        .send()
+        .await
+        .expect("Failed to execute request.");
+    let delete_response = client
+        .delete(format!(
+            "{}/admin/suppressions/ursula_le_guin@gmail.com",
+            &app.address
+        ))
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    // Assert
+    assert_eq!(401, add_response.status().as_u16());
+    assert_eq!(401, delete_response.status().as_u16());
+}
+
+#[tokio::test]
+async fn admin_can_change_the_log_level_for_a_while() {
+    // Arrange
+    let app = spawn_app().await;
+    let client = reqwest::Client::new();
+    let directives = "info,zero_to_prod_example=debug";
+
+    // Act
+    let set_response = client
+        .put(format!("{}/admin/log-level", &app.address))
+        .basic_auth(
+            &app.admin.username,
+            Some(app.admin.password.expose_secret()),
+        )
+        .json(&serde_json::json!({ "directives": directives, "ttl_seconds": 60 }))
+        .send()
+        .await
+        .expect("Failed to execute request.");
+    let get_response = client
+        .get(format!("{}/admin/log-level", &app.address))
+        .basic_auth(
+            &app.admin.username,
+            Some(app.admin.password.expose_secret()),
+        )
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    // Assert
+    assert_eq!(200, set_response.status().as_u16());
+    assert_eq!(200, get_response.status().as_u16());
+    let log_level: serde_json::Value = get_response.json().await.unwrap();
+    // Directives are listed in the order the filter applies them
+    assert!(log_level["directives"]
+        .as_str()
+        .unwrap()
+        .contains("zero_to_prod_example=debug"));
+    assert!(log_level["reverts_at"].is_string());
+}
+
+#[tokio::test]
+async fn invalid_log_levels_are_rejected() {
+    // Arrange
+    let app = spawn_app().await;
+    let client = reqwest::Client::new();
+    let test_cases = vec![
+        (
+            serde_json::json!({ "directives": "info,sqlx=loud" }),
+            "invalid directives",
+        ),
+        (
+            serde_json::json!({ "directives": "debug", "ttl_seconds": 0 }),
+            "a zero TTL",
+        ),
+        (
+            serde_json::json!({ "directives": "debug", "ttl_seconds": 10_000_000_000_000u64 }),
+            "a TTL past the last representable date",
+        ),
+    ];
+
+    for (body, description) in test_cases {
+        // Act
+        let response = client
+            .put(format!("{}/admin/log-level", &app.address))
+            .basic_auth(
+                &app.admin.username,
+                Some(app.admin.password.expose_secret()),
+            )
+            .json(&body)
+            .send()
+            .await
+            .expect("Failed to execute request.");
+
+        // Assert
+        assert_eq!(
+            400,
+            response.status().as_u16(),
+            "The API did not fail with 400 Bad Request when the payload had {}.",
+            description
+        );
+    }
+}
+
+#[tokio::test]
+async fn log_level_endpoints_reject_invalid_credentials() {
+    // Arrange
+    let app = spawn_app().await;
+    let client = reqwest::Client::new();
+
+    // Act
+    let get_response = client
+        .get(format!("{}/admin/log-level", &app.address))
+        .send()
+        .await
+        .expect("Failed to execute request.");
+    let set_response = client
+        .put(format!("{}/admin/log-level", &app.address))
+        .basic_auth(&app.admin.username, Some("wrong"))
+        .json(&serde_json::json!({ "directives": "trace" }))
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    // Assert
+    assert_eq!(401, get_response.status().as_u16());
+    assert_eq!(401, set_response.status().as_u16());
+}
+
+#[tokio::test]
+async fn subscribe_does_not_add_back_an_address_that_complained() {
+    // Arrange
+    let app = spawn_app().await;
+    let client = reqwest::Client::new();
+    let complaint = serde_json::json!({
+        "RecordType": "SpamComplaint",
+        "Email": "ursula_le_guin@gmail.com",
+        "BouncedAt": "2024-03-22T16:33:54Z"
+    });
+    client
+        .post(format!("{}/webhooks/email/postmark", &app.address))
+        .basic_auth(
+            &app.email_webhooks.basic_auth_username,
+            Some(app.email_webhooks.basic_auth_password.expose_secret()),
+        )
+        .json(&complaint)
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    // Act
+    let response = client
+        .post(format!("{}/subscriptions", &app.address))
+        .json(&FormData {
+            name: "Ursula".to_string(),
+            email: "ursula_le_guin@gmail.com".to_string(),
+            attributes: None,
+            ..Default::default()
+        })
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    // Assert
+    assert_eq!(200, response.status().as_u16());
+    let saved = sqlx::query!("SELECT email FROM subscriptions")
+        .fetch_optional(&app.db_pool)
+        .await
+        .expect("Failed to fetch saved subscriptions.");
+    assert!(saved.is_none());
+}
+
+/// The lists the subscriber at `email` did not unsubscribe from.
+async fn list_slugs(app: &TestApp, email: &str) -> Vec<String> {
+    list_statuses(app, email)
+        .await
+        .into_iter()
+        .filter(|(_, status)| status != "unsubscribed")
+        .map(|(slug, _)| slug)
+        .collect()
+}
+
+async fn list_statuses(app: &TestApp, email: &str) -> Vec<(String, String)> {
+    sqlx::query!(
+        r#"
+        SELECT lists.slug, list_memberships.status FROM list_memberships
+        JOIN lists ON lists.id = list_memberships.list_id
+        JOIN subscriptions ON subscriptions.id = list_memberships.subscriber_id
+        WHERE subscriptions.email = $1
+        ORDER BY lists.slug
+        "#,
+        email
+    )
+    .fetch_all(&app.db_pool)
+    .await
+    .expect("Failed to fetch list memberships.")
+    .into_iter()
+    .map(|r| (r.slug, r.status))
+    .collect()
+}
+
+async fn create_list(app: &TestApp, slug: &str) -> reqwest::Response {
+    reqwest::Client::new()
+        .post(format!("{}/admin/lists", &app.address))
+        .basic_auth(
+            &app.admin.username,
+            Some(app.admin.password.expose_secret()),
+        )
+        .json(&serde_json::json!({ "slug": slug, "name": "Rust Weekly" }))
+        .send()
+        .await
+        .expect("Failed to execute request.")
+}
+
+#[tokio::test]
+async fn a_subscriber_can_join_several_lists() {
+    // Arrange
+    let app = spawn_app().await;
+    let client = reqwest::Client::new();
+    assert_eq!(
+        201,
+        create_list(&app, "rust-weekly").await.status().as_u16()
+    );
+    let body = FormData {
+        name: "Ursula".to_string(),
+        email: "ursula_le_guin@gmail.com".to_string(),
+        attributes: None,
+        ..Default::default()
+    };
+
+    // Act
+    for path in ["subscriptions", "lists/rust-weekly/subscriptions"] {
+        let response = client
+            .post(format!("{}/{}", &app.address, path))
+            .json(&body)
+            .send()
+            .await
+            .expect("Failed to execute request.");
+        assert_eq!(200, response.status().as_u16());
+    }
+
+    // Assert
+    assert_eq!(
+        vec!["default", "rust-weekly"],
+        list_slugs(&app, "ursula_le_guin@gmail.com").await
+    );
+    let subscribers = sqlx::query!("SELECT id FROM subscriptions")
+        .fetch_all(&app.db_pool)
+        .await
+        .expect("Failed to fetch saved subscriptions.");
+    assert_eq!(1, subscribers.len());
+}
+
+#[tokio::test]
+async fn list_memberships_follow_confirmation_and_unsubscription() {
+    // Arrange
+    let app = spawn_app().await;
+    assert_eq!(201, create_list(&app, "weekly").await.status().as_u16());
+    let link = preferences_link(&app).await;
+    let statuses = |app| list_statuses(app, "ursula_le_guin@gmail.com");
+    assert_eq!(
+        vec![("default".into(), "pending_confirmation".into())],
+        statuses(&app).await
+    );
+
+    // Act - Part 1 - Confirm
+    follow(&app.confirmation_links().await[0]).await;
+
+    // Assert - Part 1
+    assert_eq!(
+        vec![("default".into(), "active".into())],
+        statuses(&app).await
+    );
+
+    // Act - Part 2 - Switch lists
+    post_preferences(
+        &link,
+        &PreferencesForm {
+            lists: Some(vec!["weekly".to_string()]),
+            ..Default::default()
+        },
+    )
+    .await;
+
+    // Assert - Part 2
+    assert_eq!(
+        vec![
+            ("default".into(), "unsubscribed".into()),
+            ("weekly".into(), "active".into())
+        ],
+        statuses(&app).await
+    );
+}
+
+/// Subscribe `email` to the list with `slug`, and confirm the address if it
+/// was not confirmed yet.
+async fn confirmed_subscriber(app: &TestApp, email: &str, slug: &str) {
+    let response = reqwest::Client::new()
+        .post(format!("{}/lists/{}/subscriptions", &app.address, slug))
+        .json(&FormData {
+            name: "Reader".to_string(),
+            email: email.to_string(),
+            ..Default::default()
+        })
+        .send()
+        .await
+        .expect("Failed to execute request.");
+    assert_eq!(200, response.status().as_u16());
+    if subscriber_status(app, email).await == "pending_confirmation" {
+        let (_, link) = app
+            .sent_links()
+            .await
+            .into_iter()
+            .rfind(|(to, link)| to == email && link.contains("/subscriptions/confirm?"))
+            .expect("No confirmation link was sent.");
+        assert_eq!(200, follow(&link).await.status().as_u16());
+    }
+}
+
+async fn publish_newsletter(app: &TestApp, body: &serde_json::Value) -> reqwest::Response {
+    reqwest::Client::new()
+        .post(format!("{}/newsletters", &app.address))
+        .basic_auth(
+            &app.admin.username,
+            Some(app.admin.password.expose_secret()),
+        )
+        .json(body)
+        .send()
+        .await
+        .expect("Failed to execute request.")
+}
+
+fn newsletter(title: &str, lists: &[&str]) -> serde_json::Value {
+    serde_json::json!({
+        "title": title,
+        "content": {
+            "text": "Newsletter body as plain text",
+            "html": "<p>Newsletter body as HTML</p>",
+        },
+        "lists": lists,
+    })
+}
+
+#[tokio::test]
+async fn newsletters_are_delivered_to_the_active_members_of_the_chosen_lists() {
+    // Arrange
+    let app = spawn_app().await;
+    assert_eq!(201, create_list(&app, "weekly").await.status().as_u16());
+    confirmed_subscriber(&app, "ursula@gmail.com", "default").await;
+    confirmed_subscriber(&app, "ursula@gmail.com", "weekly").await;
+    confirmed_subscriber(&app, "george@gmail.com", "weekly").await;
+    // Pending, not confirmed yet
+    post_subscriptions(
+        &app,
+        &FormData {
+            name: "Tony".to_string(),
+            email: "tony@gmail.com".to_string(),
+            ..Default::default()
+        },
+    )
+    .await;
+
+    // Act
+    let weekly = publish_newsletter(&app, &newsletter("Weekly issue", &["weekly"])).await;
+    let both = publish_newsletter(&app, &newsletter("Both lists", &["default", "weekly"])).await;
+    let default = publish_newsletter(&app, &newsletter("Default issue", &[])).await;
+    app.dispatch_all_pending_emails().await;
+
+    // Assert
+    assert_eq!(202, weekly.status().as_u16());
+    assert_eq!(202, both.status().as_u16());
+    assert_eq!(202, default.status().as_u16());
+    assert_eq!(
+        vec!["george@gmail.com", "ursula@gmail.com"],
+        app.recipients_of("Weekly issue").await
+    );
+    // Once each, even if they are on both lists
+    assert_eq!(
+        vec!["george@gmail.com", "ursula@gmail.com"],
+        app.recipients_of("Both lists").await
+    );
+    assert_eq!(
+        vec!["ursula@gmail.com"],
+        app.recipients_of("Default issue").await
+    );
+}
+
+#[tokio::test]
+async fn newsletters_skip_subscribers_who_left_since_they_were_published() {
+    // Arrange
+    let app = spawn_app().await;
+    confirmed_subscriber(&app, "ursula@gmail.com", "default").await;
+    let response = publish_newsletter(&app, &newsletter("Weekly issue", &[])).await;
+    assert_eq!(202, response.status().as_u16());
+
+    // Act
+    sqlx::query!("UPDATE subscriptions SET status = 'suppressed'")
+        .execute(&app.db_pool)
+        .await
+        .expect("Failed to update subscriptions.");
+    app.dispatch_all_pending_emails().await;
+
+    // Assert
+    assert!(app.recipients_of("Weekly issue").await.is_empty());
+}
+
+#[tokio::test]
+async fn newsletters_can_target_a_segment() {
+    // Arrange
+    let app = spawn_app().await;
+    let subscribers = [
+        ("ursula@gmail.com", serde_json::json!({ "plan": "pro" })),
+        ("george@gmail.com", serde_json::json!({ "plan": "free" })),
+        ("tony@gmail.com", serde_json::json!({})),
+    ];
+    for (email, attributes) in subscribers {
+        post_subscriptions(
+            &app,
+            &FormData {
+                name: "Reader".to_string(),
+                email: email.to_string(),
+                attributes: attributes.as_object().cloned(),
+                ..Default::default()
+            },
+        )
+        .await;
+    }
+    for link in app.confirmation_links().await {
+        assert_eq!(200, follow(&link).await.status().as_u16());
+    }
+    let mut body = newsletter("Pro issue", &[]);
+    body["segment"] = r#"attributes.plan = "pro""#.into();
+
+    // Act
+    let response = publish_newsletter(&app, &body).await;
+    app.dispatch_all_pending_emails().await;
+
+    // Assert
+    assert_eq!(202, response.status().as_u16());
+    let issue: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(1, issue["recipients"]);
+    assert_eq!(
+        vec!["ursula@gmail.com"],
+        app.recipients_of("Pro issue").await
+    );
+}
+
+#[tokio::test]
+async fn publishing_with_an_invalid_segment_returns_a_400() {
+    // Arrange
+    let app = spawn_app().await;
+    let mut body = newsletter("Weekly issue", &[]);
+    body["segment"] = "attributes.plan = ".into();
+
+    // Act
+    let response = publish_newsletter(&app, &body).await;
+
+    // Assert
+    assert_eq!(400, response.status().as_u16());
+}
+
+#[tokio::test]
+async fn publishing_to_an_unknown_list_returns_a_400() {
+    // Arrange
+    let app = spawn_app().await;
+
+    // Act
+    let response = publish_newsletter(&app, &newsletter("Weekly issue", &["weekly"])).await;
+
+    // Assert
+    assert_eq!(400, response.status().as_u16());
+    let issues = sqlx::query!("SELECT newsletter_issue_id FROM newsletter_issues")
+        .fetch_all(&app.db_pool)
+        .await
+        .expect("Failed to fetch newsletter issues.");
+    assert!(issues.is_empty());
+}
+
+#[tokio::test]
+async fn publishing_requires_admin_credentials() {
+    // Arrange
+    let app = spawn_app().await;
+
+    // Act
+    let response = reqwest::Client::new()
+        .post(format!("{}/newsletters", &app.address))
+        .basic_auth(&app.admin.username, Some("wrong"))
+        .json(&newsletter("Weekly issue", &[]))
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    // Assert
+    assert_eq!(401, response.status().as_u16());
+}
+
+#[tokio::test]
+async fn subscribing_twice_to_the_same_list_is_a_no_op() {
+    // Arrange
+    let app = spawn_app().await;
+    let client = reqwest::Client::new();
+    let body = FormData {
+        name: "Ursula".to_string(),
+        email: "ursula_le_guin@gmail.com".to_string(),
+        attributes: None,
+        ..Default::default()
+    };
+
+    // Act
+    for _ in 0..2 {
+        let response = client
+            .post(format!("{}/lists/default/subscriptions", &app.address))
+            .json(&body)
+            .send()
+            .await
+            .expect("Failed to execute request.");
+        assert_eq!(200, response.status().as_u16());
+    }
+
+    // Assert
+    assert_eq!(
+        vec!["default"],
+        list_slugs(&app, "ursula_le_guin@gmail.com").await
+    );
+}
+
+#[tokio::test]
+async fn aliases_of_the_same_mailbox_share_one_subscriber() {
+    // Arrange
+    let app = spawn_app().await;
+    let client = reqwest::Client::new();
+    assert_eq!(
+        201,
+        create_list(&app, "rust-weekly").await.status().as_u16()
+    );
+
+    // Act
+    for (path, email) in [
+        ("subscriptions", " Ursula.Le.Guin@Gmail.com "),
+        (
+            "lists/rust-weekly/subscriptions",
+            "ursulaleguin+rust@googlemail.com",
+        ),
+    ] {
+        let response = client
+            .post(format!("{}/{}", &app.address, path))
+            .json(&FormData {
+                name: "Ursula".to_string(),
+                email: email.to_string(),
+                attributes: None,
+                ..Default::default()
+            })
+            .send()
+            .await
+            .expect("Failed to execute request.");
+        assert_eq!(200, response.status().as_u16());
+    }
+
+    // Assert
+    let subscribers = sqlx::query!("SELECT email, email_canonical FROM subscriptions")
+        .fetch_all(&app.db_pool)
+        .await
+        .expect("Failed to fetch saved subscriptions.");
+    assert_eq!(1, subscribers.len());
+    assert_eq!("Ursula.Le.Guin@Gmail.com", subscribers[0].email);
+    assert_eq!("ursulaleguin@gmail.com", subscribers[0].email_canonical);
+    assert_eq!(
+        vec!["default", "rust-weekly"],
+        list_slugs(&app, "Ursula.Le.Guin@Gmail.com").await
+    );
+}
+
+#[tokio::test]
+async fn addresses_stored_before_canonical_forms_are_found_by_them() {
+    // Arrange
+    let app = spawn_seeded_app(Seed {
+        // The migration adding canonical forms
+        version: 20240416101502,
+        sql: r#"
+        INSERT INTO subscriptions (id, email, name, subscribed_at, status) VALUES
+            (gen_random_uuid(), 'George.Orwell@Gmail.com', 'George', now() - interval '2 days', 'active'),
+            (gen_random_uuid(), 'Ursula@Gmail.com', 'Ursula', now() - interval '2 days', 'active'),
+            (gen_random_uuid(), 'ursula@gmail.com', 'Ursula', now() - interval '1 day', 'active');
+        INSERT INTO list_memberships (list_id, subscriber_id, status, subscribed_at)
+        SELECT lists.id, subscriptions.id, 'active', now() FROM lists, subscriptions;
+        INSERT INTO suppressions (email, reason, source, created_at) VALUES
+            ('Tony.Stark+news@GoogleMail.com', 'complaint', 'postmark', now());
+        "#,
+    })
+    .await;
+    // Act
+    let mut statuses = Vec::new();
+    for email in [
+        "George.Orwell@Gmail.com",
+        "georgeorwell@gmail.com",
+        "george.orwell+news@googlemail.com",
+        "URSULA@gmail.com",
+        "tonystark@gmail.com",
+    ] {
+        let body = FormData {
+            name: "Reader".to_string(),
+            email: email.to_string(),
+            ..Default::default()
+        };
+        statuses.push(post_subscriptions(&app, &body).await.status().as_u16());
+    }
+
+    // Assert
+    assert_eq!(vec![200; 5], statuses);
+    let subscribers =
+        sqlx::query!("SELECT email, email_canonical FROM subscriptions ORDER BY email")
+            .fetch_all(&app.db_pool)
+            .await
+            .expect("Failed to fetch saved subscriptions.");
+    let subscribers: Vec<_> = subscribers
+        .into_iter()
+        .map(|s| (s.email, s.email_canonical))
+        .collect();
+    assert_eq!(
+        vec![
+            (
+                "George.Orwell@Gmail.com".to_string(),
+                "georgeorwell@gmail.com".to_string()
+            ),
+            // Another subscriber had the canonical form already
+            (
+                "Ursula@Gmail.com".to_string(),
+                "Ursula@gmail.com".to_string()
+            ),
+            (
+                "ursula@gmail.com".to_string(),
+                "ursula@gmail.com".to_string()
+            ),
+        ],
+        subscribers
+    );
+    assert!(app.confirmation_links().await.is_empty());
+    let sent = app
+        .email_client
+        .send_email(
+            SubscriberEmail::parse("tony.stark@gmail.com".to_string()).unwrap(),
+            "Newsletter",
+            "<p>Newsletter</p>",
+            "Newsletter",
+        )
+        .await;
+    assert!(matches!(
+        sent,
+        Err(SendEmailError::Suppressed(SuppressionReason::Complaint))
+    ));
+    // Nothing is left to fix
+    assert_eq!(
+        CanonicalizationReport {
+            duplicates: vec!["Ursula@Gmail.com".to_string()],
+            ..Default::default()
+        },
+        canonicalize_stored_emails(&app.db_pool).await.unwrap()
+    );
+}
+
+#[tokio::test]
+async fn subscribing_to_an_unknown_list_returns_a_404() {
+    // Arrange
+    let app = spawn_app().await;
+    let client = reqwest::Client::new();
+
+    // Act
+    let response = client
+        .post(format!(
+            "{}/lists/does-not-exist/subscriptions",
+            &app.address
+        ))
+        .json(&FormData {
+            name: "Ursula".to_string(),
+            email: "ursula_le_guin@gmail.com".to_string(),
+            attributes: None,
+            ..Default::default()
+        })
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    // Assert
+    assert_eq!(404, response.status().as_u16());
+}
+
+#[tokio::test]
+async fn creating_a_list_twice_returns_a_409() {
+    // Arrange
+    let app = spawn_app().await;
+
+    // Act
+    let first = create_list(&app, "rust-weekly").await;
+    let second = create_list(&app, "rust-weekly").await;
+    let invalid = create_list(&app, "Rust Weekly").await;
+
+    // Assert
+    assert_eq!(201, first.status().as_u16());
+    assert_eq!(409, second.status().as_u16());
+    assert_eq!(400, invalid.status().as_u16());
+}
+
+async fn list_subscribers(app: &TestApp, segment: &str) -> reqwest::Response {
+    reqwest::Client::new()
+        .get(format!("{}/admin/subscribers", &app.address))
+        .query(&[("segment", segment)])
+        .basic_auth(
+            &app.admin.username,
+            Some(app.admin.password.expose_secret()),
+        )
+        .send()
+        .await
+        .expect("Failed to execute request.")
+}
+
+#[tokio::test]
+async fn subscribers_can_be_targeted_by_attributes() {
+    // Arrange
+    let app = spawn_app().await;
+    let client = reqwest::Client::new();
+    let subscribers = [
+        (
+            "ursula@gmail.com",
+            serde_json::json!({ "country": "FR", "seats": 5 }),
+        ),
+        (
+            "george@gmail.com",
+            serde_json::json!({ "country": "BE", "seats": 1 }),
+        ),
+        (
+            "tony@gmail.com",
+            serde_json::json!({ "country": "MX", "seats": "5" }),
+        ),
+    ];
+    for (email, attributes) in subscribers {
+        let response = client
+            .post(format!("{}/subscriptions", &app.address))
+            .json(&FormData {
+                name: "Reader".to_string(),
+                email: email.to_string(),
+                attributes: attributes.as_object().cloned(),
+                ..Default::default()
+            })
+            .send()
+            .await
+            .expect("Failed to execute request.");
+        assert_eq!(200, response.status().as_u16());
+    }
+
+    // Act
+    let response = list_subscribers(
+        &app,
+        r#"attributes.country in ("FR", "MX") and attributes.seats >= 2"#,
+    )
+    .await;
+
+    // Assert
+    assert_eq!(200, response.status().as_u16());
+    let body: Vec<serde_json::Value> = response.json().await.unwrap();
+    let emails: Vec<&str> = body.iter().map(|s| s["email"].as_str().unwrap()).collect();
+    // "5" is a string, it is not compared as a number
+    assert_eq!(vec!["ursula@gmail.com"], emails);
+
+    for (segment, expected) in [
+        (
+            r#"not attributes.country = "FR" and subscribed_at > "2000-01-01T00:00:00Z""#,
+            vec!["george@gmail.com", "tony@gmail.com"],
+        ),
+        (r#"attributes.country < "C""#, vec!["george@gmail.com"]),
+        ("attributes.missing != 1", vec![]),
+    ] {
+        let body: Vec<serde_json::Value> =
+            list_subscribers(&app, segment).await.json().await.unwrap();
+        let emails: Vec<&str> = body.iter().map(|s| s["email"].as_str().unwrap()).collect();
+        assert_eq!(expected, emails, "{}", segment);
+    }
+}
+
+#[tokio::test]
+async fn an_invalid_segment_filter_returns_a_400() {
+    // Arrange
+    let app = spawn_app().await;
+
+    // Act
+    let response = list_subscribers(&app, "attributes.country = ").await;
+
+    // Assert
+    assert_eq!(400, response.status().as_u16());
+}
+
+#[tokio::test]
+async fn subscribe_rejects_invalid_attributes() {
+    // Arrange
+    let app = spawn_app().await;
+
+    // Act
+    let response = reqwest::Client::new()
+        .post(format!("{}/subscriptions", &app.address))
+        .json(&serde_json::json!({
+            "name": "Ursula",
+            "email": "ursula_le_guin@gmail.com",
+            "attributes": { "address": { "city": "Portland" } }
+        }))
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    // Assert
+    assert_eq!(400, response.status().as_u16());
+}